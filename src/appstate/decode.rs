@@ -0,0 +1,550 @@
+use aes::Aes256;
+use block_modes::{block_padding::Pkcs7, BlockMode, Cbc};
+use protobuf::Message;
+
+use crate::{
+    binary::{
+        proto::{self as wa_proto, syncd_mutation::SyncdOperation},
+        Node, NodeContentType,
+    },
+    new_rhustapp_error,
+    store::{AppStateMutationMAC, DeviceStore},
+    RhustAppError,
+};
+
+use super::{
+    content_mac, get_app_state_keys, hash::value_mac, index_mac, patch_mac, ExpandedAppStateKeys,
+    HashState, PatchName,
+};
+
+type Aes256Cbc = Cbc<Aes256, Pkcs7>;
+
+/// The length of the IV at the start of the encrypted value of a mutation.
+const IV_LENGTH: usize = 16;
+
+/// A decrypted mutation of an app state collection. The index is a JSON array whose first
+/// element is the kind of the action (e.g. `"mute"`) and the rest identify what it applies
+/// to (e.g. the chat JID).
+#[derive(Clone, Debug)]
+pub struct Mutation {
+    pub operation: SyncdOperation,
+    pub action: wa_proto::SyncActionValue,
+    pub version: i32,
+    pub index: Vec<String>,
+    pub index_mac: Vec<u8>,
+    pub value_mac: Vec<u8>,
+}
+
+/// The snapshot and patches of a collection in a response to an `app_state_query`.
+#[derive(Clone, Debug)]
+pub struct PatchList {
+    pub name: PatchName,
+    /// True if the server has more patches, which should be fetched after applying these.
+    pub has_more_patches: bool,
+    pub patches: Vec<wa_proto::SyncdPatch>,
+    pub snapshot: Option<wa_proto::SyncdSnapshot>,
+}
+
+/// Parses the `<collection>` in the response to an `app_state_query`. The snapshot and the
+/// mutations of large patches are not sent inline, so they are fetched with `download`.
+pub fn parse_patch_list(
+    node: &Node,
+    download: &dyn Fn(&wa_proto::ExternalBlobReference) -> Result<Vec<u8>, RhustAppError>,
+) -> Result<PatchList, RhustAppError> {
+    let collection = node
+        .get_optional_child_by_tag(&["sync", "collection"])
+        .ok_or_else(|| new_rhustapp_error("didn't find collection in app state response", None))?;
+    let mut ag = collection.attr_getter();
+    let name = ag.string("name");
+    let has_more_patches = ag.optional_bool("has_more_patches");
+    if let Some(err) = ag.error() {
+        return Err(new_rhustapp_error(
+            "failed to parse app state collection",
+            Some(err.to_string()),
+        ));
+    };
+    let name: PatchName = name.unwrap().parse()?;
+    if let Some(error) = collection.get_optional_child_by_tag(&["error"]) {
+        return Err(new_rhustapp_error(
+            &format!("server returned an error for app state collection {name}"),
+            Some(
+                error
+                    .attr_getter()
+                    .optional_string("code")
+                    .unwrap_or_default(),
+            ),
+        ));
+    };
+
+    let snapshot = match collection.get_optional_child_by_tag(&["snapshot"]) {
+        Some(snapshot) => {
+            let reference = wa_proto::ExternalBlobReference::parse_from_bytes(node_bytes(
+                &snapshot,
+            )?)
+            .map_err(|err| {
+                new_rhustapp_error(
+                    "failed to unmarshal app state snapshot reference",
+                    Some(err.to_string()),
+                )
+            })?;
+            let data = download(&reference)?;
+            Some(
+                wa_proto::SyncdSnapshot::parse_from_bytes(&data).map_err(|err| {
+                    new_rhustapp_error(
+                        "failed to unmarshal app state snapshot",
+                        Some(err.to_string()),
+                    )
+                })?,
+            )
+        }
+        None => None,
+    };
+
+    let mut patches = Vec::new();
+    let patch_nodes = collection
+        .get_optional_child_by_tag(&["patches"])
+        .and_then(|patches| patches.get_children_by_tag("patch"))
+        .unwrap_or_default();
+    for patch_node in &patch_nodes {
+        let mut patch =
+            wa_proto::SyncdPatch::parse_from_bytes(node_bytes(patch_node)?).map_err(|err| {
+                new_rhustapp_error("failed to unmarshal app state patch", Some(err.to_string()))
+            })?;
+        if let Some(reference) = patch.externalMutations.as_ref() {
+            let data = download(reference)?;
+            let mutations = wa_proto::SyncdMutations::parse_from_bytes(&data).map_err(|err| {
+                new_rhustapp_error(
+                    "failed to unmarshal external app state mutations",
+                    Some(err.to_string()),
+                )
+            })?;
+            patch.mutations = mutations.mutations;
+        };
+        patches.push(patch);
+    }
+
+    Ok(PatchList {
+        name,
+        has_more_patches: has_more_patches.unwrap_or_default(),
+        patches,
+        snapshot,
+    })
+}
+
+fn node_bytes(node: &Node) -> Result<&[u8], RhustAppError> {
+    match &node.content {
+        NodeContentType::ByteArray(bytes) => Ok(bytes),
+        _ => Err(new_rhustapp_error(
+            &format!("app state {} content is not a byte array", node.tag),
+            None,
+        )),
+    }
+}
+
+/// Applies the snapshot and patches of a collection to its hash state and decrypts their
+/// mutations. The new version and the MACs of the values are saved in the store, as they are
+/// needed to apply the next patches. If `validate_macs` is true, the snapshot MAC and patch
+/// MAC of every patch and the MACs of every mutation are checked.
+pub fn decode_patches(
+    store: &dyn DeviceStore,
+    list: &PatchList,
+    initial_state: HashState,
+    validate_macs: bool,
+) -> Result<(Vec<Mutation>, HashState), RhustAppError> {
+    let mut state = initial_state;
+    let mut mutations = Vec::new();
+
+    if let Some(snapshot) = &list.snapshot {
+        state.version = snapshot.version.version();
+        let encrypted: Vec<_> = snapshot
+            .records
+            .iter()
+            .map(|record| {
+                let mut mutation = wa_proto::SyncdMutation::new();
+                mutation.operation = Some(SyncdOperation::SET.into());
+                mutation.record = Some(record.clone()).into();
+                mutation
+            })
+            .collect();
+        state.update_hash(&encrypted, |_, _| Ok(None))?;
+        if validate_macs {
+            validate_snapshot_mac(
+                store,
+                list.name,
+                &state,
+                snapshot.keyId.id(),
+                snapshot.mac(),
+            )?;
+        };
+        let output = decode_mutations(store, &encrypted, validate_macs)?;
+        store_macs(store, list.name, &state, &output)?;
+        mutations.extend(output.mutations);
+    };
+
+    for patch in &list.patches {
+        state.version = patch.version.version();
+        state.update_hash(&patch.mutations, |index_mac, position| {
+            // The index may have been set earlier in the same patch.
+            for previous in patch.mutations[..position].iter().rev() {
+                if previous.record.index.blob() == index_mac {
+                    return Ok(Some(value_mac(&previous.record)?.to_vec()));
+                };
+            }
+            store.get_app_state_mutation_mac(list.name.as_str(), index_mac)
+        })?;
+        if validate_macs {
+            let keys = validate_snapshot_mac(
+                store,
+                list.name,
+                &state,
+                patch.keyId.id(),
+                patch.snapshotMac(),
+            )?;
+            if patch_mac(patch, list.name, &keys.patch_mac, state.version)? != patch.patchMac() {
+                return Err(new_rhustapp_error(
+                    &format!("mismatching patch MAC in {} v{}", list.name, state.version),
+                    None,
+                ));
+            };
+        };
+        let output = decode_mutations(store, &patch.mutations, validate_macs)?;
+        store_macs(store, list.name, &state, &output)?;
+        mutations.extend(output.mutations);
+    }
+
+    Ok((mutations, state))
+}
+
+/// Checks the snapshot MAC of a collection at the state, and returns the keys it was checked
+/// with.
+fn validate_snapshot_mac(
+    store: &dyn DeviceStore,
+    name: PatchName,
+    state: &HashState,
+    key_id: &[u8],
+    expected: &[u8],
+) -> Result<ExpandedAppStateKeys, RhustAppError> {
+    let keys = get_app_state_keys(store, key_id)?;
+    if state.snapshot_mac(name, &keys.snapshot_mac) != expected {
+        return Err(new_rhustapp_error(
+            &format!("mismatching LTHash in {name} v{}", state.version),
+            None,
+        ));
+    };
+    Ok(keys)
+}
+
+/// The decrypted mutations of a snapshot or patch, and the changes to the value MACs of the
+/// collection.
+struct PatchOutput {
+    mutations: Vec<Mutation>,
+    added_macs: Vec<AppStateMutationMAC>,
+    removed_macs: Vec<Vec<u8>>,
+}
+
+fn decode_mutations(
+    store: &dyn DeviceStore,
+    encrypted: &[wa_proto::SyncdMutation],
+    validate_macs: bool,
+) -> Result<PatchOutput, RhustAppError> {
+    let mut output = PatchOutput {
+        mutations: Vec::with_capacity(encrypted.len()),
+        added_macs: Vec::new(),
+        removed_macs: Vec::new(),
+    };
+    for mutation in encrypted {
+        let key_id = mutation.record.keyId.id();
+        let keys = get_app_state_keys(store, key_id)?;
+        let value_mac = value_mac(&mutation.record)?;
+        let value = mutation.record.value.blob();
+        let content = &value[..value.len() - value_mac.len()];
+        if validate_macs
+            && content_mac(mutation.operation(), content, key_id, &keys.value_mac) != value_mac
+        {
+            return Err(new_rhustapp_error(
+                "mismatching content MAC in app state mutation",
+                None,
+            ));
+        };
+
+        if content.len() < IV_LENGTH {
+            return Err(new_rhustapp_error(
+                "app state mutation value is too short",
+                Some(format!("{} bytes", value.len())),
+            ));
+        };
+        let (iv, ciphertext) = content.split_at(IV_LENGTH);
+        let plaintext = Aes256Cbc::new_from_slices(&keys.value_encryption, iv)
+            .map_err(|err| new_rhustapp_error("invalid app state key", Some(err.to_string())))?
+            .decrypt_vec(ciphertext)
+            .map_err(|err| {
+                new_rhustapp_error(
+                    "failed to decrypt app state mutation",
+                    Some(err.to_string()),
+                )
+            })?;
+        let data = wa_proto::SyncActionData::parse_from_bytes(&plaintext).map_err(|err| {
+            new_rhustapp_error(
+                "failed to unmarshal app state mutation",
+                Some(err.to_string()),
+            )
+        })?;
+
+        let mutation_index_mac = mutation.record.index.blob();
+        if validate_macs && index_mac(data.index(), &keys.index) != mutation_index_mac {
+            return Err(new_rhustapp_error(
+                "mismatching index MAC in app state mutation",
+                None,
+            ));
+        };
+        let index: Vec<String> = serde_json::from_slice(data.index()).map_err(|err| {
+            new_rhustapp_error(
+                "failed to parse app state mutation index",
+                Some(err.to_string()),
+            )
+        })?;
+
+        match mutation.operation() {
+            SyncdOperation::REMOVE => output.removed_macs.push(mutation_index_mac.to_vec()),
+            SyncdOperation::SET => output.added_macs.push(AppStateMutationMAC {
+                index_mac: mutation_index_mac.to_vec(),
+                value_mac: value_mac.to_vec(),
+            }),
+        };
+        output.mutations.push(Mutation {
+            operation: mutation.operation(),
+            action: data.value.clone().unwrap_or_default(),
+            version: data.version(),
+            index,
+            index_mac: mutation_index_mac.to_vec(),
+            value_mac: value_mac.to_vec(),
+        });
+    }
+    Ok(output)
+}
+
+fn store_macs(
+    store: &dyn DeviceStore,
+    name: PatchName,
+    state: &HashState,
+    output: &PatchOutput,
+) -> Result<(), RhustAppError> {
+    store.put_app_state_version(name.as_str(), state.version, &state.hash)?;
+    if !output.removed_macs.is_empty() {
+        store.delete_app_state_mutation_macs(name.as_str(), &output.removed_macs)?;
+    };
+    if !output.added_macs.is_empty() {
+        store.put_app_state_mutation_macs(name.as_str(), state.version, &output.added_macs)?;
+    };
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        binary::{AttributeTypes, Attrs},
+        store::memory::MemoryStore,
+        testing::{
+            build_app_state_patch, encrypt_app_state_mutation, put_app_state_key, APP_STATE_KEY_ID,
+        },
+    };
+
+    use super::{super::expand_app_state_keys, *};
+
+    fn store_with_key() -> MemoryStore {
+        let store = MemoryStore::new();
+        put_app_state_key(&store);
+        store
+    }
+
+    fn mute_action(muted: bool) -> wa_proto::SyncActionValue {
+        let mut action = wa_proto::SyncActionValue::new();
+        action.timestamp = Some(1_650_000_000_000);
+        action.muteAction.mut_or_insert_default().muted = Some(muted);
+        action
+    }
+
+    #[test]
+    fn test_decode_patches() {
+        let store = store_with_key();
+        let chat = "1234@s.whatsapp.net";
+        let mut expected = HashState::default();
+        let set =
+            encrypt_app_state_mutation(SyncdOperation::SET, &["mute", chat], mute_action(true));
+        let set_mac = value_mac(&set.record).unwrap().to_vec();
+        let first = build_app_state_patch(PatchName::Regular, &mut expected, vec![set], |_| None);
+        let list = PatchList {
+            name: PatchName::Regular,
+            has_more_patches: false,
+            patches: vec![first],
+            snapshot: None,
+        };
+
+        let (mutations, state) = decode_patches(&store, &list, HashState::default(), true).unwrap();
+        assert_eq!(state, expected);
+        assert_eq!(mutations.len(), 1);
+        assert_eq!(mutations[0].operation, SyncdOperation::SET);
+        assert_eq!(mutations[0].index, vec!["mute", chat]);
+        assert_eq!(mutations[0].version, 2);
+        assert!(mutations[0].action.muteAction.muted());
+        assert_eq!(
+            store.get_app_state_version("regular").unwrap(),
+            Some((1, expected.hash))
+        );
+        let index_mac = mutations[0].index_mac.clone();
+        assert_eq!(
+            store
+                .get_app_state_mutation_mac("regular", &index_mac)
+                .unwrap(),
+            Some(set_mac.clone())
+        );
+
+        // The removal subtracts the value MAC that was saved for the index.
+        let remove =
+            encrypt_app_state_mutation(SyncdOperation::REMOVE, &["mute", chat], mute_action(false));
+        let second = build_app_state_patch(PatchName::Regular, &mut expected, vec![remove], |_| {
+            Some(set_mac.clone())
+        });
+        let list = PatchList {
+            patches: vec![second],
+            ..list
+        };
+        let (mutations, state) = decode_patches(&store, &list, state, true).unwrap();
+        assert_eq!(state, expected);
+        assert_eq!(state.hash, HashState::default().hash);
+        assert_eq!(mutations[0].operation, SyncdOperation::REMOVE);
+        assert!(store
+            .get_app_state_mutation_mac("regular", &index_mac)
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn test_decode_patches_invalid_mac() {
+        let store = store_with_key();
+        let mut state = HashState::default();
+        let mut patch = build_app_state_patch(
+            PatchName::Regular,
+            &mut state,
+            vec![encrypt_app_state_mutation(
+                SyncdOperation::SET,
+                &["pin_v1", "1234@s.whatsapp.net"],
+                wa_proto::SyncActionValue::new(),
+            )],
+            |_| None,
+        );
+        patch.snapshotMac = Some(vec![0; 32]);
+        let list = PatchList {
+            name: PatchName::Regular,
+            has_more_patches: false,
+            patches: vec![patch],
+            snapshot: None,
+        };
+        assert!(decode_patches(&store, &list, HashState::default(), true).is_err());
+        assert!(store.get_app_state_version("regular").unwrap().is_none());
+        // Without validation only the keys are needed.
+        assert!(decode_patches(&store, &list, HashState::default(), false).is_ok());
+        assert!(decode_patches(&MemoryStore::new(), &list, HashState::default(), false).is_err());
+    }
+
+    #[test]
+    fn test_decode_snapshot() {
+        let store = store_with_key();
+        let keys = expand_app_state_keys(&[5; 32]);
+        let mutation = encrypt_app_state_mutation(
+            SyncdOperation::SET,
+            &["archive", "1234@s.whatsapp.net"],
+            wa_proto::SyncActionValue::new(),
+        );
+        let mut state = HashState {
+            version: 7,
+            ..Default::default()
+        };
+        state
+            .update_hash(std::slice::from_ref(&mutation), |_, _| Ok(None))
+            .unwrap();
+
+        let mut snapshot = wa_proto::SyncdSnapshot::new();
+        snapshot.version.mut_or_insert_default().version = Some(7);
+        snapshot.records = vec![mutation.record.unwrap()];
+        snapshot.keyId.mut_or_insert_default().id = Some(APP_STATE_KEY_ID.to_vec());
+        snapshot.mac = Some(state.snapshot_mac(PatchName::CriticalBlock, &keys.snapshot_mac));
+        let list = PatchList {
+            name: PatchName::CriticalBlock,
+            has_more_patches: false,
+            patches: Vec::new(),
+            snapshot: Some(snapshot),
+        };
+        let (mutations, decoded) =
+            decode_patches(&store, &list, HashState::default(), true).unwrap();
+        assert_eq!(decoded, state);
+        assert_eq!(mutations[0].index[0], "archive");
+    }
+
+    #[test]
+    fn test_parse_patch_list() {
+        let mut state = HashState::default();
+        let mut patch =
+            build_app_state_patch(PatchName::RegularLow, &mut state, Vec::new(), |_| None);
+        patch.externalMutations.mut_or_insert_default().directPath = Some("/mutations".into());
+        let mut external = wa_proto::SyncdMutations::new();
+        external.mutations = vec![encrypt_app_state_mutation(
+            SyncdOperation::SET,
+            &["star", "1234@s.whatsapp.net", "ABCD", "1", "0"],
+            wa_proto::SyncActionValue::new(),
+        )];
+        let mut reference = wa_proto::ExternalBlobReference::new();
+        reference.directPath = Some("/snapshot".into());
+        let snapshot = wa_proto::SyncdSnapshot::new();
+
+        let bytes_node = |tag: &str, bytes: Vec<u8>| Node {
+            tag: tag.to_string(),
+            attrs: Attrs::new(),
+            content: NodeContentType::ByteArray(bytes),
+        };
+        let response = Node {
+            tag: "iq".to_string(),
+            attrs: Attrs::new(),
+            content: NodeContentType::ListOfNodes(vec![Node {
+                tag: "sync".to_string(),
+                attrs: Attrs::new(),
+                content: NodeContentType::ListOfNodes(vec![Node {
+                    tag: "collection".to_string(),
+                    attrs: Attrs::from([
+                        (
+                            "name".to_string(),
+                            AttributeTypes::String("regular_low".to_string()),
+                        ),
+                        (
+                            "has_more_patches".to_string(),
+                            AttributeTypes::String("true".to_string()),
+                        ),
+                    ]),
+                    content: NodeContentType::ListOfNodes(vec![
+                        bytes_node("snapshot", reference.write_to_bytes().unwrap()),
+                        Node {
+                            tag: "patches".to_string(),
+                            attrs: Attrs::new(),
+                            content: NodeContentType::ListOfNodes(vec![bytes_node(
+                                "patch",
+                                patch.write_to_bytes().unwrap(),
+                            )]),
+                        },
+                    ]),
+                }]),
+            }]),
+        };
+
+        let list = parse_patch_list(&response, &|reference| match reference.directPath() {
+            "/snapshot" => Ok(snapshot.write_to_bytes().unwrap()),
+            "/mutations" => Ok(external.write_to_bytes().unwrap()),
+            path => panic!("unexpected download of {path}"),
+        })
+        .unwrap();
+        assert_eq!(list.name, PatchName::RegularLow);
+        assert!(list.has_more_patches);
+        assert!(list.snapshot.is_some());
+        assert_eq!(list.patches.len(), 1);
+        assert_eq!(list.patches[0].mutations, external.mutations);
+    }
+}
@@ -0,0 +1,278 @@
+use time::OffsetDateTime;
+
+use crate::{
+    binary::proto::syncd_mutation::SyncdOperation,
+    new_rhustapp_error,
+    store::DeviceStore,
+    types::{
+        events::{Archive, Contact, DeleteForMe, Mute, Pin, RhustAppEventType, Star},
+        JID,
+    },
+    RhustAppError,
+};
+
+use super::Mutation;
+
+/// Applies a decrypted mutation to the store (e.g. saves the name of a contact or that a
+/// chat was muted) and returns the event that should be emitted for it. Removals and the
+/// kinds of actions that aren't handled return `None`.
+pub fn apply_mutation(
+    store: &dyn DeviceStore,
+    mutation: &Mutation,
+    full_sync: bool,
+) -> Result<Option<RhustAppEventType>, RhustAppError> {
+    if mutation.operation != SyncdOperation::SET || mutation.index.len() < 2 {
+        return Ok(None);
+    };
+
+    let action = &mutation.action;
+    let timestamp = parse_millis(action.timestamp())?;
+    let jid = || parse_index_jid(&mutation.index[1]);
+    let event = match mutation.index[0].as_str() {
+        "contact" if action.contactAction.is_some() => {
+            let jid = jid()?;
+            let contact = action.contactAction.clone().unwrap();
+            store.put_contact_name(&jid, contact.firstName(), contact.fullName())?;
+            RhustAppEventType::Contact(Contact {
+                jid,
+                timestamp,
+                action: contact,
+                from_full_sync: full_sync,
+            })
+        }
+        "pin_v1" if action.pinAction.is_some() => {
+            let jid = jid()?;
+            let pin = action.pinAction.clone().unwrap();
+            store.put_pinned(&jid, pin.pinned())?;
+            RhustAppEventType::Pin(Pin {
+                jid,
+                timestamp,
+                action: pin,
+                from_full_sync: full_sync,
+            })
+        }
+        "mute" if action.muteAction.is_some() => {
+            let jid = jid()?;
+            let mute = action.muteAction.clone().unwrap();
+            let muted_until = match mute.muted() {
+                true => parse_millis(mute.muteEndTimestamp())?,
+                false => OffsetDateTime::UNIX_EPOCH,
+            };
+            store.put_muted_until(&jid, muted_until)?;
+            RhustAppEventType::Mute(Mute {
+                jid,
+                timestamp,
+                action: mute,
+                from_full_sync: full_sync,
+            })
+        }
+        "archive" if action.archiveChatAction.is_some() => {
+            let jid = jid()?;
+            let archive = action.archiveChatAction.clone().unwrap();
+            store.put_archived(&jid, archive.archived())?;
+            RhustAppEventType::Archive(Archive {
+                jid,
+                timestamp,
+                action: archive,
+                from_full_sync: full_sync,
+            })
+        }
+        "star" if action.starAction.is_some() => {
+            let (chat_jid, message_id, is_from_me, sender_jid) = parse_message_index(mutation)?;
+            RhustAppEventType::Star(Star {
+                chat_jid,
+                sender_jid,
+                is_from_me,
+                message_id,
+                timestamp,
+                action: action.starAction.clone().unwrap(),
+                from_full_sync: full_sync,
+            })
+        }
+        "deleteMessageForMe" if action.deleteMessageForMeAction.is_some() => {
+            let (chat_jid, message_id, is_from_me, sender_jid) = parse_message_index(mutation)?;
+            RhustAppEventType::DeleteForMe(DeleteForMe {
+                chat_jid,
+                sender_jid,
+                is_from_me,
+                message_id,
+                timestamp,
+                action: action.deleteMessageForMeAction.clone().unwrap(),
+                from_full_sync: full_sync,
+            })
+        }
+        _ => return Ok(None),
+    };
+    Ok(Some(event))
+}
+
+fn parse_millis(millis: i64) -> Result<OffsetDateTime, RhustAppError> {
+    OffsetDateTime::from_unix_timestamp_nanos(millis as i128 * 1_000_000)
+        .map_err(|err| new_rhustapp_error("invalid app state timestamp", Some(err.to_string())))
+}
+
+fn parse_index_jid(jid: &str) -> Result<JID, RhustAppError> {
+    jid.parse().map_err(|err: RhustAppError| {
+        new_rhustapp_error(
+            &format!("invalid JID {jid} in app state index"),
+            Some(err.to_string()),
+        )
+    })
+}
+
+/// Parses the index of an action on a message: the chat, the message ID, `"1"` if the
+/// message was sent by the user, and the sender in group chats (`"0"` otherwise).
+fn parse_message_index(
+    mutation: &Mutation,
+) -> Result<(JID, String, bool, Option<JID>), RhustAppError> {
+    let (chat, message_id, from_me, sender) = match mutation.index.as_slice() {
+        [_, chat, message_id, from_me, sender] => (chat, message_id, from_me, sender),
+        _ => {
+            return Err(new_rhustapp_error(
+                "invalid app state message index",
+                Some(format!("{:?}", mutation.index)),
+            ))
+        }
+    };
+    let sender = match sender.as_str() {
+        "0" => None,
+        sender => Some(parse_index_jid(sender)?),
+    };
+    Ok((
+        parse_index_jid(chat)?,
+        message_id.clone(),
+        from_me == "1",
+        sender,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{binary::proto as wa_proto, store::memory::MemoryStore};
+
+    use super::*;
+
+    fn mutation(index: &[&str], action: wa_proto::SyncActionValue) -> Mutation {
+        Mutation {
+            operation: SyncdOperation::SET,
+            action,
+            version: 2,
+            index: index.iter().map(|part| part.to_string()).collect(),
+            index_mac: Vec::new(),
+            value_mac: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn test_apply_mutation() {
+        let store = MemoryStore::new();
+        let chat: JID = "1234@s.whatsapp.net".parse().unwrap();
+
+        let mut action = wa_proto::SyncActionValue::new();
+        action.timestamp = Some(1_650_000_000_000);
+        let contact = action.contactAction.mut_or_insert_default();
+        contact.firstName = Some("John".into());
+        contact.fullName = Some("John Doe".into());
+        let event = apply_mutation(
+            &store,
+            &mutation(&["contact", "1234@s.whatsapp.net"], action),
+            true,
+        )
+        .unwrap();
+        let contact = match event {
+            Some(RhustAppEventType::Contact(contact)) => contact,
+            _ => panic!("expected a contact event"),
+        };
+        assert_eq!(contact.jid, chat);
+        assert_eq!(contact.timestamp.unix_timestamp(), 1_650_000_000);
+        assert!(contact.from_full_sync);
+        let info = store.get_contact(&chat).unwrap().unwrap();
+        assert_eq!(info.full_name, "John Doe");
+
+        let mut action = wa_proto::SyncActionValue::new();
+        let mute = action.muteAction.mut_or_insert_default();
+        mute.muted = Some(true);
+        mute.muteEndTimestamp = Some(1_700_000_000_000);
+        let event = apply_mutation(
+            &store,
+            &mutation(&["mute", "1234@s.whatsapp.net"], action),
+            false,
+        )
+        .unwrap();
+        assert!(matches!(event, Some(RhustAppEventType::Mute(_))));
+
+        let mut action = wa_proto::SyncActionValue::new();
+        action.pinAction.mut_or_insert_default().pinned = Some(true);
+        apply_mutation(
+            &store,
+            &mutation(&["pin_v1", "1234@s.whatsapp.net"], action),
+            false,
+        )
+        .unwrap();
+        let mut action = wa_proto::SyncActionValue::new();
+        action.archiveChatAction.mut_or_insert_default().archived = Some(true);
+        apply_mutation(
+            &store,
+            &mutation(&["archive", "1234@s.whatsapp.net"], action),
+            false,
+        )
+        .unwrap();
+        let settings = store.get_chat_settings(&chat).unwrap().unwrap();
+        assert_eq!(settings.muted_until.unix_timestamp(), 1_700_000_000);
+        assert!(settings.pinned);
+        assert!(settings.archived);
+
+        let mut action = wa_proto::SyncActionValue::new();
+        action.starAction.mut_or_insert_default().starred = Some(true);
+        let event = apply_mutation(
+            &store,
+            &mutation(
+                &["star", "5678-1234@g.us", "ABCD", "0", "1234@s.whatsapp.net"],
+                action,
+            ),
+            false,
+        )
+        .unwrap();
+        let star = match event {
+            Some(RhustAppEventType::Star(star)) => star,
+            _ => panic!("expected a star event"),
+        };
+        assert_eq!(star.message_id, "ABCD");
+        assert!(!star.is_from_me);
+        assert_eq!(star.sender_jid, Some(chat.clone()));
+        assert!(star.action.starred());
+
+        let mut action = wa_proto::SyncActionValue::new();
+        action.deleteMessageForMeAction.mut_or_insert_default();
+        let event = apply_mutation(
+            &store,
+            &mutation(
+                &[
+                    "deleteMessageForMe",
+                    "1234@s.whatsapp.net",
+                    "EFGH",
+                    "1",
+                    "0",
+                ],
+                action,
+            ),
+            false,
+        )
+        .unwrap();
+        let delete = match event {
+            Some(RhustAppEventType::DeleteForMe(delete)) => delete,
+            _ => panic!("expected a delete for me event"),
+        };
+        assert!(delete.is_from_me);
+        assert!(delete.sender_jid.is_none());
+
+        // Unknown actions and actions without their value are ignored.
+        let unknown = mutation(&["setting_pushName"], wa_proto::SyncActionValue::new());
+        assert!(apply_mutation(&store, &unknown, false).unwrap().is_none());
+        let empty = mutation(
+            &["pin_v1", "1234@s.whatsapp.net"],
+            wa_proto::SyncActionValue::new(),
+        );
+        assert!(apply_mutation(&store, &empty, false).unwrap().is_none());
+    }
+}
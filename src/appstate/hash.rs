@@ -0,0 +1,262 @@
+use hkdf::Hkdf;
+use hmac::{Hmac, Mac, NewMac};
+use sha2::{Sha256, Sha512};
+
+use crate::{
+    binary::proto::{syncd_mutation::SyncdOperation, SyncdMutation, SyncdPatch, SyncdRecord},
+    new_rhustapp_error, RhustAppError,
+};
+
+use super::PatchName;
+
+/// The size of the LTHash of a collection.
+pub const LTHASH_SIZE: usize = 128;
+
+/// The length of the value MAC at the end of the value blob of a mutation.
+const VALUE_MAC_LENGTH: usize = 32;
+
+/// The version of an app state collection and the LTHash of the value MACs of all the
+/// indexes that are set in it. The hash is a sum, so setting an index again subtracts the
+/// previous value MAC, and it is checked against the snapshot MAC of every patch.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct HashState {
+    pub version: u64,
+    pub hash: [u8; LTHASH_SIZE],
+}
+
+impl Default for HashState {
+    fn default() -> Self {
+        Self {
+            version: 0,
+            hash: [0; LTHASH_SIZE],
+        }
+    }
+}
+
+impl HashState {
+    /// Adds the values set by the mutations to the hash and subtracts the values that they
+    /// replace or remove. `previous_value_mac` returns the value MAC that an index (by its
+    /// MAC) was set to before the mutation at the given position.
+    pub fn update_hash(
+        &mut self,
+        mutations: &[SyncdMutation],
+        mut previous_value_mac: impl FnMut(&[u8], usize) -> Result<Option<Vec<u8>>, RhustAppError>,
+    ) -> Result<(), RhustAppError> {
+        let mut added = Vec::new();
+        let mut removed = Vec::new();
+        for (i, mutation) in mutations.iter().enumerate() {
+            if mutation.operation() == SyncdOperation::SET {
+                added.push(value_mac(&mutation.record)?.to_vec());
+            };
+            let index_mac = mutation.record.index.blob();
+            match previous_value_mac(index_mac, i)? {
+                Some(previous) => removed.push(previous),
+                None if mutation.operation() == SyncdOperation::REMOVE => {
+                    log::warn!("didn't find the previous value of a removed app state index");
+                }
+                None => {}
+            };
+        }
+
+        for value_mac in &removed {
+            pointwise_with_overflow(&mut self.hash, &expand_value_mac(value_mac), true);
+        }
+        for value_mac in &added {
+            pointwise_with_overflow(&mut self.hash, &expand_value_mac(value_mac), false);
+        }
+        Ok(())
+    }
+
+    /// Returns the snapshot MAC of the collection at this state.
+    pub fn snapshot_mac(&self, name: PatchName, key: &[u8]) -> Vec<u8> {
+        concat_and_hmac_sha256(
+            key,
+            &[
+                &self.hash,
+                &self.version.to_be_bytes(),
+                name.as_str().as_bytes(),
+            ],
+        )
+    }
+}
+
+/// Returns the value MAC at the end of the value blob of a record.
+pub(crate) fn value_mac(record: &SyncdRecord) -> Result<&[u8], RhustAppError> {
+    let value = record.value.blob();
+    if value.len() < VALUE_MAC_LENGTH {
+        return Err(new_rhustapp_error(
+            "app state mutation value is too short",
+            Some(format!("{} bytes", value.len())),
+        ));
+    };
+    Ok(&value[value.len() - VALUE_MAC_LENGTH..])
+}
+
+/// Returns the MAC of a patch, which covers its snapshot MAC and the value MACs of its
+/// mutations.
+pub fn patch_mac(
+    patch: &SyncdPatch,
+    name: PatchName,
+    key: &[u8],
+    version: u64,
+) -> Result<Vec<u8>, RhustAppError> {
+    let mut data = vec![patch.snapshotMac()];
+    for mutation in &patch.mutations {
+        data.push(value_mac(&mutation.record)?);
+    }
+    let version = version.to_be_bytes();
+    data.push(&version);
+    data.push(name.as_str().as_bytes());
+    Ok(concat_and_hmac_sha256(key, &data))
+}
+
+/// Returns the MAC of the encrypted value of a mutation, which is appended to it.
+pub fn content_mac(operation: SyncdOperation, data: &[u8], key_id: &[u8], key: &[u8]) -> Vec<u8> {
+    let mut mac =
+        Hmac::<Sha512>::new_from_slice(key).expect("HMAC-SHA512 should accept any key size");
+    mac.update(&[operation as u8 + 1]);
+    mac.update(key_id);
+    mac.update(data);
+    mac.update(&(key_id.len() as u64 + 1).to_be_bytes());
+    mac.finalize().into_bytes()[..VALUE_MAC_LENGTH].to_vec()
+}
+
+/// Returns the MAC of the JSON index of a mutation, which identifies the index without
+/// revealing it to the server.
+pub fn index_mac(index: &[u8], key: &[u8]) -> Vec<u8> {
+    concat_and_hmac_sha256(key, &[index])
+}
+
+fn concat_and_hmac_sha256(key: &[u8], data: &[&[u8]]) -> Vec<u8> {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(key).expect("HMAC-SHA256 should accept any key size");
+    for part in data {
+        mac.update(part);
+    }
+    mac.finalize().into_bytes().to_vec()
+}
+
+/// Expands a value MAC into the 128 bytes that are added to or subtracted from the LTHash.
+fn expand_value_mac(value_mac: &[u8]) -> [u8; LTHASH_SIZE] {
+    let mut expanded = [0u8; LTHASH_SIZE];
+    Hkdf::<Sha256>::new(None, value_mac)
+        .expand(b"WhatsApp Patch Integrity", &mut expanded)
+        .expect("128 bytes is a valid HKDF-SHA256 output length");
+    expanded
+}
+
+/// Adds or subtracts the input to the base as little-endian 16-bit integers, wrapping on
+/// overflow.
+fn pointwise_with_overflow(
+    base: &mut [u8; LTHASH_SIZE],
+    input: &[u8; LTHASH_SIZE],
+    subtract: bool,
+) {
+    for (base, input) in base.chunks_exact_mut(2).zip(input.chunks_exact(2)) {
+        let x = u16::from_le_bytes([base[0], base[1]]);
+        let y = u16::from_le_bytes([input[0], input[1]]);
+        let result = match subtract {
+            true => x.wrapping_sub(y),
+            false => x.wrapping_add(y),
+        };
+        base.copy_from_slice(&result.to_le_bytes());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::binary::proto::{SyncdIndex, SyncdValue};
+
+    use super::*;
+
+    fn mutation(operation: SyncdOperation, index_mac: &[u8], value_mac: [u8; 32]) -> SyncdMutation {
+        let mut index = SyncdIndex::new();
+        index.blob = Some(index_mac.to_vec());
+        let mut value = SyncdValue::new();
+        value.blob = Some([b"encrypted".as_slice(), &value_mac].concat());
+        let mut record = SyncdRecord::new();
+        record.index = Some(index).into();
+        record.value = Some(value).into();
+        let mut mutation = SyncdMutation::new();
+        mutation.operation = Some(operation.into());
+        mutation.record = Some(record).into();
+        mutation
+    }
+
+    #[test]
+    fn test_update_hash() {
+        let mut state = HashState::default();
+        state
+            .update_hash(
+                &[
+                    mutation(SyncdOperation::SET, b"a", [1; 32]),
+                    mutation(SyncdOperation::SET, b"b", [2; 32]),
+                ],
+                |_, _| Ok(None),
+            )
+            .unwrap();
+        let with_both = state.hash;
+
+        // Setting an index again replaces its value in the hash.
+        state
+            .update_hash(&[mutation(SyncdOperation::SET, b"b", [3; 32])], |_, _| {
+                Ok(Some(vec![2; 32]))
+            })
+            .unwrap();
+        assert_ne!(state.hash, with_both);
+        state
+            .update_hash(
+                &[mutation(SyncdOperation::REMOVE, b"b", [4; 32])],
+                |_, _| Ok(Some(vec![3; 32])),
+            )
+            .unwrap();
+        // The removal only subtracts the previous value, the value MAC of the removal
+        // itself isn't added.
+        let mut only_a = HashState::default();
+        only_a
+            .update_hash(&[mutation(SyncdOperation::SET, b"a", [1; 32])], |_, _| {
+                Ok(None)
+            })
+            .unwrap();
+        assert_eq!(state.hash, only_a.hash);
+
+        let mut too_short = mutation(SyncdOperation::SET, b"a", [1; 32]);
+        too_short
+            .record
+            .mut_or_insert_default()
+            .value
+            .mut_or_insert_default()
+            .blob = Some(vec![1; 8]);
+        assert!(HashState::default()
+            .update_hash(&[too_short], |_, _| Ok(None))
+            .is_err());
+    }
+
+    #[test]
+    fn test_pointwise_with_overflow() {
+        let mut base = [0u8; LTHASH_SIZE];
+        base[0] = 0xff;
+        base[1] = 0xff;
+        let mut one = [0u8; LTHASH_SIZE];
+        one[0] = 1;
+        pointwise_with_overflow(&mut base, &one, false);
+        assert_eq!(base, [0; LTHASH_SIZE]);
+        pointwise_with_overflow(&mut base, &one, true);
+        assert_eq!(&base[..2], [0xff, 0xff]);
+    }
+
+    #[test]
+    fn test_macs() {
+        let state = HashState::default();
+        let mac = state.snapshot_mac(PatchName::Regular, &[1; 32]);
+        assert_eq!(mac.len(), 32);
+        assert_ne!(mac, state.snapshot_mac(PatchName::RegularHigh, &[1; 32]));
+
+        let set = content_mac(SyncdOperation::SET, b"data", &[7], &[1; 32]);
+        assert_eq!(set.len(), 32);
+        assert_ne!(
+            set,
+            content_mac(SyncdOperation::REMOVE, b"data", &[7], &[1; 32])
+        );
+    }
+}
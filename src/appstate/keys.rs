@@ -0,0 +1,67 @@
+use hkdf::Hkdf;
+use sha2::Sha256;
+
+use crate::{new_rhustapp_error, store::DeviceStore, RhustAppError};
+
+/// The keys expanded from an app state sync key, with which the mutations of a collection
+/// are encrypted and authenticated.
+#[derive(Clone)]
+pub struct ExpandedAppStateKeys {
+    pub index: Vec<u8>,
+    pub value_encryption: Vec<u8>,
+    pub value_mac: Vec<u8>,
+    pub snapshot_mac: Vec<u8>,
+    pub patch_mac: Vec<u8>,
+}
+
+/// Expands the data of an app state sync key with HKDF-SHA256.
+pub fn expand_app_state_keys(key_data: &[u8]) -> ExpandedAppStateKeys {
+    let mut expanded = [0u8; 160];
+    Hkdf::<Sha256>::new(None, key_data)
+        .expand(b"WhatsApp Mutation Keys", &mut expanded)
+        .expect("160 bytes is a valid HKDF-SHA256 output length");
+
+    ExpandedAppStateKeys {
+        index: expanded[..32].to_vec(),
+        value_encryption: expanded[32..64].to_vec(),
+        value_mac: expanded[64..96].to_vec(),
+        snapshot_mac: expanded[96..128].to_vec(),
+        patch_mac: expanded[128..].to_vec(),
+    }
+}
+
+/// Loads the app state sync key with the ID from the store and expands it. The keys are
+/// shared by the phone, so a missing key may arrive later.
+pub fn get_app_state_keys(
+    store: &dyn DeviceStore,
+    key_id: &[u8],
+) -> Result<ExpandedAppStateKeys, RhustAppError> {
+    let key = store.get_app_state_sync_key(key_id)?.ok_or_else(|| {
+        new_rhustapp_error(
+            "app state sync key not found",
+            Some(format!("key id {}", hex::encode(key_id))),
+        )
+    })?;
+    Ok(expand_app_state_keys(&key.data))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_expand_app_state_keys() {
+        let keys = expand_app_state_keys(&[1; 32]);
+        for key in [
+            &keys.index,
+            &keys.value_encryption,
+            &keys.value_mac,
+            &keys.snapshot_mac,
+            &keys.patch_mac,
+        ] {
+            assert_eq!(key.len(), 32);
+        }
+        assert_ne!(keys.index, keys.patch_mac);
+        assert_ne!(keys.index, expand_app_state_keys(&[2; 32]).index);
+    }
+}
@@ -0,0 +1,187 @@
+//! `appstate` contains the sync of the app state: the settings that the user's devices share,
+//! like the names of contacts and which chats are pinned, muted or archived. They are kept by
+//! the server in collections of encrypted mutations, which are fetched as a snapshot followed
+//! by patches. Every patch is checked against the LTHash of the collection with keys expanded
+//! from the app state sync keys that the phone shares, and the decrypted mutations are
+//! applied to the store and emitted as events.
+
+mod decode;
+pub use decode::*;
+
+mod events;
+pub use events::*;
+
+mod hash;
+pub use hash::*;
+
+mod keys;
+pub use keys::*;
+
+use std::{fmt, str::FromStr};
+
+use crate::{
+    binary::{AttributeTypes, Attrs, Node, NodeContentType},
+    new_rhustapp_error,
+    request::{InfoQuery, InfoQueryType},
+    types::SERVER_JID,
+    RhustAppError,
+};
+
+/// The app state collections. The critical ones are synced first after pairing, as they
+/// hold the push name and the blocklist.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PatchName {
+    /// Contains the push name and the blocklist.
+    CriticalBlock,
+    /// Contains the contact names.
+    CriticalUnblockLow,
+    /// Contains the starred messages and some settings.
+    RegularHigh,
+    /// Contains the pinned, muted and archived chats.
+    Regular,
+    /// Contains the chats that were read, cleared or deleted.
+    RegularLow,
+}
+
+impl PatchName {
+    pub const ALL: [PatchName; 5] = [
+        Self::CriticalBlock,
+        Self::CriticalUnblockLow,
+        Self::RegularHigh,
+        Self::Regular,
+        Self::RegularLow,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::CriticalBlock => "critical_block",
+            Self::CriticalUnblockLow => "critical_unblock_low",
+            Self::RegularHigh => "regular_high",
+            Self::Regular => "regular",
+            Self::RegularLow => "regular_low",
+        }
+    }
+}
+
+impl fmt::Display for PatchName {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+impl FromStr for PatchName {
+    type Err = RhustAppError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::ALL
+            .into_iter()
+            .find(|name| name.as_str() == s)
+            .ok_or_else(|| new_rhustapp_error(&format!("unknown app state collection {s}"), None))
+    }
+}
+
+/// Builds the query that fetches the patches of a collection after `version`, or its latest
+/// snapshot (and the patches after it) if `snapshot` is true.
+pub fn app_state_query(name: PatchName, version: u64, snapshot: bool) -> InfoQuery {
+    let mut attrs = Attrs::from([
+        ("name".to_string(), AttributeTypes::String(name.to_string())),
+        (
+            "return_snapshot".to_string(),
+            AttributeTypes::String(snapshot.to_string()),
+        ),
+    ]);
+    if !snapshot {
+        attrs.insert(
+            "version".to_string(),
+            AttributeTypes::String(version.to_string()),
+        );
+    };
+
+    let mut query = InfoQuery::new("w:sync:app:state", InfoQueryType::Set, SERVER_JID.clone());
+    query.content = NodeContentType::ListOfNodes(vec![Node {
+        tag: "sync".to_string(),
+        attrs: Attrs::new(),
+        content: NodeContentType::ListOfNodes(vec![Node {
+            tag: "collection".to_string(),
+            attrs,
+            content: NodeContentType::None,
+        }]),
+    }]);
+    query
+}
+
+/// Parses the collections that a `<notification type="server_sync">` says have new patches.
+/// Unknown collections are skipped.
+pub fn parse_server_sync_notification(node: &Node) -> Vec<PatchName> {
+    node.get_children_by_tag("collection")
+        .unwrap_or_default()
+        .iter()
+        .filter_map(|collection| collection.attr_getter().optional_string("name"))
+        .filter_map(|name| match PatchName::from_str(&name) {
+            Ok(name) => Some(name),
+            Err(err) => {
+                log::warn!("{err}");
+                None
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_patch_name() {
+        for name in PatchName::ALL {
+            assert_eq!(PatchName::from_str(name.as_str()).unwrap(), name);
+        }
+        assert!(PatchName::from_str("irregular").is_err());
+    }
+
+    #[test]
+    fn test_app_state_query() {
+        let node = app_state_query(PatchName::Regular, 5, false).to_node("1");
+        assert_eq!(
+            node.attr_getter().string("xmlns").unwrap(),
+            "w:sync:app:state"
+        );
+        let collection = node
+            .get_optional_child_by_tag(&["sync", "collection"])
+            .unwrap();
+        let mut ag = collection.attr_getter();
+        assert_eq!(ag.string("name").unwrap(), "regular");
+        assert_eq!(ag.string("version").unwrap(), "5");
+        assert_eq!(ag.string("return_snapshot").unwrap(), "false");
+
+        let node = app_state_query(PatchName::CriticalBlock, 0, true).to_node("2");
+        let collection = node
+            .get_optional_child_by_tag(&["sync", "collection"])
+            .unwrap();
+        let mut ag = collection.attr_getter();
+        assert!(ag.optional_string("version").is_none());
+        assert_eq!(ag.string("return_snapshot").unwrap(), "true");
+    }
+
+    #[test]
+    fn test_parse_server_sync_notification() {
+        let collection = |name: &str| Node {
+            tag: "collection".to_string(),
+            attrs: Attrs::from([("name".to_string(), AttributeTypes::String(name.to_string()))]),
+            content: NodeContentType::None,
+        };
+        let node = Node {
+            tag: "notification".to_string(),
+            attrs: Attrs::new(),
+            content: NodeContentType::ListOfNodes(vec![
+                collection("regular"),
+                collection("unknown"),
+                collection("critical_block"),
+            ]),
+        };
+        assert_eq!(
+            parse_server_sync_notification(&node),
+            vec![PatchName::Regular, PatchName::CriticalBlock]
+        );
+    }
+}
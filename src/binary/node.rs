@@ -1,17 +1,26 @@
-use std::{collections::HashMap, io::Read};
+use std::{
+    collections::HashMap,
+    io::{Read, Write},
+    ops::Range,
+    str::FromStr,
+};
 
-use time::OffsetDateTime;
+use time::{OffsetDateTime, PrimitiveDateTime};
 
 use crate::{
     new_rhustapp_error,
-    types::{EMPTY_JID, JID},
+    types::{EMPTY_JID, HIDDEN_USER_SERVER, JID},
     RhustAppError,
 };
 
 use super::token;
 
 /// The various types of content inside an XML element.
-#[derive(Clone, Debug, Default)]
+///
+/// On the wire, no content and an empty list of nodes can't be told apart, so `None` is the
+/// canonical form of both: an empty `ListOfNodes` is encoded as no content, and decoding
+/// never produces an empty `ListOfNodes`.
+#[derive(Clone, Debug, Default, PartialEq)]
 pub enum NodeContentType {
     #[default]
     None,
@@ -29,6 +38,8 @@ pub enum NodeContentType {
 }
 
 impl NodeContentType {
+    /// Returns the string form of the scalar content types, which is also how they are
+    /// encoded. The full range of each numeric type is preserved.
     pub fn other_types_to_string(&self) -> String {
         match self {
             Self::None | Self::ListOfNodes(_) | Self::ByteArray(_) => String::new(),
@@ -43,8 +54,68 @@ impl NodeContentType {
     }
 }
 
+impl From<String> for NodeContentType {
+    fn from(value: String) -> Self {
+        Self::String(value)
+    }
+}
+
+impl From<&str> for NodeContentType {
+    fn from(value: &str) -> Self {
+        Self::String(value.to_string())
+    }
+}
+
+impl From<Vec<u8>> for NodeContentType {
+    fn from(value: Vec<u8>) -> Self {
+        Self::ByteArray(value)
+    }
+}
+
+impl From<JID> for NodeContentType {
+    fn from(value: JID) -> Self {
+        Self::JID(value)
+    }
+}
+
+impl From<Vec<Node>> for NodeContentType {
+    fn from(value: Vec<Node>) -> Self {
+        Self::ListOfNodes(value)
+    }
+}
+
+impl From<bool> for NodeContentType {
+    fn from(value: bool) -> Self {
+        Self::Bool(value)
+    }
+}
+
+impl From<i32> for NodeContentType {
+    fn from(value: i32) -> Self {
+        Self::I32(value)
+    }
+}
+
+impl From<u32> for NodeContentType {
+    fn from(value: u32) -> Self {
+        Self::U32(value)
+    }
+}
+
+impl From<i64> for NodeContentType {
+    fn from(value: i64) -> Self {
+        Self::I64(value)
+    }
+}
+
+impl From<u64> for NodeContentType {
+    fn from(value: u64) -> Self {
+        Self::U64(value)
+    }
+}
+
 /// It represents an XML element.
-#[derive(Clone, Debug, Default)]
+#[derive(Clone, Debug, Default, PartialEq)]
 pub struct Node {
     /// The tag of the element.
     pub tag: String,
@@ -57,6 +128,16 @@ pub struct Node {
 impl Node {
     pub const INDENT_XML: bool = false;
     pub const MAX_BYTES_TO_PRINT_AS_HEX: usize = 128;
+    /// The maximum depth that is descended into when walking a node tree, so that a
+    /// maliciously deep frame can't make the walk unbounded.
+    pub const MAX_WALK_DEPTH: usize = 64;
+
+    /// Replaces the content of the node, converting it with the `From` impls of
+    /// `NodeContentType`, e.g. from a `&str`, a `Vec<u8>` or a `Vec<Node>`.
+    pub fn with_content(mut self, content: impl Into<NodeContentType>) -> Self {
+        self.content = content.into();
+        self
+    }
 
     /// Returns the `content` of the `Node` as a list of nodes if they exist.
     pub fn get_children(&self) -> Option<Vec<Node>> {
@@ -80,6 +161,20 @@ impl Node {
         }
     }
 
+    /// Groups the children with any of the given tags by tag in a single pass, without
+    /// cloning them. Tags without any matching children are left out of the map.
+    pub fn children_by_tags<'a>(&'a self, tags: &[&str]) -> HashMap<&'a str, Vec<&'a Node>> {
+        let mut grouped: HashMap<&'a str, Vec<&'a Node>> = HashMap::new();
+        if let NodeContentType::ListOfNodes(children) = &self.content {
+            for child in children {
+                if tags.contains(&child.tag.as_str()) {
+                    grouped.entry(child.tag.as_str()).or_default().push(child);
+                };
+            }
+        };
+        grouped
+    }
+
     /// Finds the first child with the given tag and returns it.
     // Each provided tag will recurse in, so this is useful for getting a specific nested element.
     pub fn get_optional_child_by_tag(&self, tags: &[&str]) -> Option<Node> {
@@ -103,13 +198,83 @@ impl Node {
         return Some(final_child);
     }
 
-    pub fn attr_getter(&self) -> AttrUtility {
-        AttrUtility {
-            attrs: &self.attrs,
-            errors: vec![],
+    /// Moves the content out of the node without cloning it, leaving `None` in its place.
+    pub fn take_content(&mut self) -> NodeContentType {
+        std::mem::take(&mut self.content)
+    }
+
+    /// Removes the first child with the given tag and returns it without cloning it.
+    pub fn take_child_by_tag(&mut self, tag: &str) -> Option<Node> {
+        match &mut self.content {
+            NodeContentType::ListOfNodes(nodes) => {
+                let index = nodes.iter().position(|node| node.tag.eq(tag))?;
+                Some(nodes.remove(index))
+            }
+            _ => None,
+        }
+    }
+
+    /// Returns an iterator over this node and all of its descendants in depth-first
+    /// (pre-order) order. Nodes nested deeper than `Node::MAX_WALK_DEPTH` are not visited.
+    pub fn walk(&self) -> impl Iterator<Item = &Node> {
+        NodeWalker {
+            stack: vec![(self, 0)],
         }
     }
 
+    /// Returns an error if the tag of the node isn't the expected one.
+    pub fn expect_tag(&self, tag: &str) -> Result<(), RhustAppError> {
+        if self.tag.eq(tag) {
+            Ok(())
+        } else {
+            Err(new_rhustapp_error(
+                &format!("expected tag <{tag}>, but found <{}>", self.tag),
+                None,
+            ))
+        }
+    }
+
+    /// Returns an error if the attribute under the given key is missing or doesn't have the
+    /// expected value. JID attributes are compared using their string representation.
+    pub fn expect_attr(&self, key: &str, expected: &str) -> Result<(), RhustAppError> {
+        match self.attrs.get(key) {
+            Some(value) if value.to_string().eq(expected) => Ok(()),
+            Some(value) => Err(new_rhustapp_error(
+                &format!(
+                    "expected attribute '{key}' of <{}> to be \"{expected}\", but found \"{}\"",
+                    self.tag,
+                    value.to_string()
+                ),
+                None,
+            )),
+            None => Err(new_rhustapp_error(
+                &format!(
+                    "expected attribute '{key}' of <{}> to be \"{expected}\", but it is missing",
+                    self.tag
+                ),
+                None,
+            )),
+        }
+    }
+
+    /// Returns true if the node is an error response, i.e. it has `type="error"` or an
+    /// `<error>` child.
+    pub fn is_error(&self) -> bool {
+        matches!(self.attrs.get("type"), Some(AttributeTypes::String(t)) if t.eq("error"))
+            || self.get_optional_child_by_tag(&["error"]).is_some()
+    }
+
+    /// Returns the `code` of the `<error>` child, if the node has one with a numeric code.
+    pub fn error_code(&self) -> Option<i32> {
+        self.get_optional_child_by_tag(&["error"])?
+            .attr_getter()
+            .optional_i32("code")
+    }
+
+    pub fn attr_getter(&self) -> AttrUtility {
+        AttrUtility::new(&self.attrs)
+    }
+
     pub fn xml_string(&self) -> String {
         let attributes = self.attribute_string();
         let content = self.content_string();
@@ -204,10 +369,97 @@ impl Node {
         string_attrs.sort();
         string_attrs.join(" ")
     }
+
+    /// Returns a deterministic byte form of the node to compute signatures over.
+    ///
+    /// Unlike the wire encoding, attributes are sorted by key and attributes with empty
+    /// values are kept. Every string is length-prefixed and every attribute value and content
+    /// is prefixed with a type marker, so different nodes can't have the same canonical form.
+    /// Numeric and boolean contents are canonicalized to their string form, the same way
+    /// they are sent on the wire.
+    pub fn canonical_bytes(&self) -> Vec<u8> {
+        let mut data = Vec::new();
+        self.write_canonical(&mut data);
+        data
+    }
+
+    fn write_canonical(&self, data: &mut Vec<u8>) {
+        write_canonical_bytes(data, self.tag.as_bytes());
+
+        let mut attrs = self
+            .attrs
+            .iter()
+            .collect::<Vec<(&String, &AttributeTypes)>>();
+        attrs.sort_by_key(|(key, _)| *key);
+        data.extend_from_slice(&(attrs.len() as u32).to_be_bytes());
+        for (key, value) in attrs {
+            write_canonical_bytes(data, key.as_bytes());
+            match value {
+                AttributeTypes::String(s) => {
+                    data.push(0);
+                    write_canonical_bytes(data, s.as_bytes());
+                }
+                AttributeTypes::JID(j) => {
+                    data.push(1);
+                    write_canonical_bytes(data, j.to_string().as_bytes());
+                }
+            }
+        }
+
+        match &self.content {
+            NodeContentType::None => data.push(0),
+            NodeContentType::ListOfNodes(nodes) if nodes.is_empty() => data.push(0),
+            NodeContentType::ListOfNodes(nodes) => {
+                data.push(1);
+                data.extend_from_slice(&(nodes.len() as u32).to_be_bytes());
+                for node in nodes {
+                    node.write_canonical(data);
+                }
+            }
+            NodeContentType::ByteArray(bytes) => {
+                data.push(2);
+                write_canonical_bytes(data, bytes);
+            }
+            NodeContentType::JID(j) => {
+                data.push(3);
+                write_canonical_bytes(data, j.to_string().as_bytes());
+            }
+            c => {
+                data.push(4);
+                write_canonical_bytes(data, c.other_types_to_string().as_bytes());
+            }
+        }
+    }
+}
+
+/// Writes `bytes` prefixed with their length as a big-endian `u32`.
+fn write_canonical_bytes(data: &mut Vec<u8>, bytes: &[u8]) {
+    data.extend_from_slice(&(bytes.len() as u32).to_be_bytes());
+    data.extend_from_slice(bytes);
+}
+
+/// Depth-first iterator over a `Node` tree, returned by `Node::walk`.
+struct NodeWalker<'a> {
+    stack: Vec<(&'a Node, usize)>,
+}
+
+impl<'a> Iterator for NodeWalker<'a> {
+    type Item = &'a Node;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (node, depth) = self.stack.pop()?;
+        if let NodeContentType::ListOfNodes(children) = &node.content {
+            if depth < Node::MAX_WALK_DEPTH {
+                self.stack
+                    .extend(children.iter().rev().map(|child| (child, depth + 1)));
+            }
+        }
+        Some(node)
+    }
 }
 
 /// It contains all the types for the attributes of an XML element (`Node`).
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq)]
 pub enum AttributeTypes {
     JID(JID),
     String(String),
@@ -224,18 +476,70 @@ impl AttributeTypes {
 
 pub type Attrs = HashMap<String, AttributeTypes>;
 
+/// Drops the offset of a UTC `OffsetDateTime`. Unlike converting through the local offset,
+/// this keeps the same wall-clock time that the timestamp has in UTC.
+fn utc_primitive(offset_dt: OffsetDateTime) -> PrimitiveDateTime {
+    let utc = offset_dt.to_offset(time::UtcOffset::UTC);
+    PrimitiveDateTime::new(utc.date(), utc.time())
+}
+
 pub struct AttrUtility<'a> {
     pub attrs: &'a Attrs,
     pub errors: Vec<RhustAppError>,
+    /// The type mismatches that were coerced, only used when coercing is enabled.
+    pub warnings: Vec<RhustAppError>,
+    /// Whether mistyped attributes are coerced, see `AttrUtility::coerce`.
+    pub coerce: bool,
 }
 
-impl AttrUtility<'_> {
+impl<'a> AttrUtility<'a> {
+    /// Creates a getter for the attributes, with coercing disabled.
+    pub fn new(attrs: &'a Attrs) -> Self {
+        Self {
+            attrs,
+            errors: vec![],
+            warnings: vec![],
+            coerce: false,
+        }
+    }
+
+    /// Enables or disables coercing mistyped attributes. When enabled, a String attribute
+    /// is accepted where a JID is expected if it unambiguously parses as a JID, and a JID
+    /// attribute is accepted where a String is expected using its string form. Each
+    /// coercion is recorded in `warnings` instead of storing an error.
+    ///
+    /// Coercing is disabled by default.
+    pub fn coerce(mut self, coerce: bool) -> Self {
+        self.coerce = coerce;
+        self
+    }
+
     fn get_jid(&mut self, key: &str, required: bool) -> Option<JID> {
         match self.attrs.get(key) {
             Some(val) => match val {
                 AttributeTypes::JID(jid) => {
                     return Some(jid.to_owned());
                 }
+                AttributeTypes::String(s) if self.coerce && s.contains('@') => {
+                    match JID::from_str(s) {
+                        Ok(jid) if !jid.user.is_empty() && !jid.server.is_empty() => {
+                            self.warnings.push(new_rhustapp_error(
+                                &format!("coerced String attribute '{key}' to JID"),
+                                None,
+                            ));
+                            Some(jid)
+                        }
+                        _ => {
+                            if required {
+                                self.errors.push(new_rhustapp_error(
+                                    &format!("attribute '{key}' is not a valid JID"),
+                                    None,
+                                ));
+                            };
+                            None
+                        }
+                    }
+                }
                 AttributeTypes::String(_) => {
                     if required {
                         self.errors.push(new_rhustapp_error(
@@ -268,10 +572,19 @@ impl AttrUtility<'_> {
     /// Returns the JID under the given key. If there is no valid JID under the given key,
     /// this will return an empty JID.
     /// However, if the attribute is completely missing, this will not store an error.
+    ///
+    /// Since a missing attribute and an attribute holding an empty JID both return
+    /// `EMPTY_JID`, use `jid_present` if the two cases need to be told apart.
     pub fn optional_jid_or_empty(&mut self, key: &str) -> JID {
         self.get_jid(key, false).unwrap_or(EMPTY_JID.clone())
     }
 
+    /// Returns true if there is a JID under the given key, even if it is an empty JID.
+    /// This never stores an error.
+    pub fn jid_present(&self, key: &str) -> bool {
+        matches!(self.attrs.get(key), Some(AttributeTypes::JID(_)))
+    }
+
     /// Returns the JID under the given key.
     /// If there's no valid JID under the given key, an error will be stored and None
     /// will be returned.
@@ -285,6 +598,13 @@ impl AttrUtility<'_> {
                 AttributeTypes::String(s) => {
                     return Some(s.to_owned());
                 }
+                AttributeTypes::JID(jid) if self.coerce => {
+                    self.warnings.push(new_rhustapp_error(
+                        &format!("coerced JID attribute '{key}' to String"),
+                        None,
+                    ));
+                    Some(jid.to_string())
+                }
                 AttributeTypes::JID(_) => {
                     if required {
                         self.errors.push(new_rhustapp_error(
@@ -342,8 +662,22 @@ impl AttrUtility<'_> {
     }
 
     fn get_u64(&mut self, key: &str, required: bool) -> Option<u64> {
+        self.get_u64_radix(key, 10, required)
+    }
+
+    fn get_u64_radix(&mut self, key: &str, radix: u32, required: bool) -> Option<u64> {
+        // `from_str_radix` panics on radixes it doesn't support.
+        if !(2..=36).contains(&radix) {
+            if required {
+                self.errors.push(new_rhustapp_error(
+                    &format!("failed to parse u64 in attribute '{key}'"),
+                    Some(format!("invalid radix {radix}")),
+                ));
+            };
+            return None;
+        };
         if let Some(s) = self.get_string(key, required) {
-            match s.parse::<u64>() {
+            match u64::from_str_radix(&s, radix) {
                 Ok(val) => Some(val),
                 Err(err) => {
                     if required {
@@ -364,6 +698,14 @@ impl AttrUtility<'_> {
         self.get_u64(key, true)
     }
 
+    /// Returns the u64 under the given key, parsed in the given radix (e.g. 16 for values
+    /// like hashes that are sent hex-encoded). The value must not have a prefix like `0x`.
+    /// If the attribute is missing or can't be parsed, this will not store an error. Radixes
+    /// outside of 2..=36 aren't supported and always return `None`.
+    pub fn optional_u64_radix(&mut self, key: &str, radix: u32) -> Option<u64> {
+        self.get_u64_radix(key, radix, false)
+    }
+
     fn get_bool(&mut self, key: &str, required: bool) -> Option<bool> {
         if let Some(s) = self.get_string(key, required) {
             match s.as_str() {
@@ -392,6 +734,8 @@ impl AttrUtility<'_> {
         self.get_bool(key, true)
     }
 
+    /// Returns the unix timestamp under the given key as a UTC `OffsetDateTime`, the local
+    /// offset is never applied. A timestamp that is out of range is stored as an error.
     fn get_unix_time(&mut self, key: &str, required: bool) -> Option<OffsetDateTime> {
         if let Some(ts) = self.get_i64(key, required) {
             if ts == 0 {
@@ -399,7 +743,13 @@ impl AttrUtility<'_> {
             };
             match OffsetDateTime::from_unix_timestamp(ts) {
                 Ok(offset_dt) => Some(offset_dt),
-                Err(_) => None,
+                Err(err) => {
+                    self.errors.push(new_rhustapp_error(
+                        &format!("failed to parse unix time in attribute '{key}'"),
+                        Some(err.to_string()),
+                    ));
+                    None
+                }
             }
         } else {
             None
@@ -414,6 +764,16 @@ impl AttrUtility<'_> {
         self.get_unix_time(key, true)
     }
 
+    /// Like `optional_unix_time`, but returns the UTC time as a `PrimitiveDateTime`.
+    pub fn optional_unix_time_primitive(&mut self, key: &str) -> Option<PrimitiveDateTime> {
+        self.get_unix_time(key, false).map(utc_primitive)
+    }
+
+    /// Like `unix_time`, but returns the UTC time as a `PrimitiveDateTime`.
+    pub fn unix_time_primitive(&mut self, key: &str) -> Option<PrimitiveDateTime> {
+        self.get_unix_time(key, true).map(utc_primitive)
+    }
+
     pub fn optional_i32(&mut self, key: &str) -> Option<i32> {
         match self.get_i64(key, false) {
             Some(i) => Some(i as i32),
@@ -428,6 +788,13 @@ impl AttrUtility<'_> {
         }
     }
 
+    /// Moves the errors and warnings of another `AttrUtility`, e.g. one used for a child
+    /// node, into this one, so that a single `error()` call reports the errors of both.
+    pub fn merge(&mut self, other: AttrUtility<'_>) {
+        self.errors.extend(other.errors);
+        self.warnings.extend(other.warnings);
+    }
+
     /// Returns true if there are no errors.
     pub fn ok(&self) -> bool {
         self.errors.len() == 0
@@ -460,6 +827,9 @@ pub enum DecoderError {
     ErrInvalidNode,
     ErrInvalidToken,
     ErrNonStringKey,
+    ErrDuplicateAttribute,
+    /// The length of a packed string is longer than the remaining data.
+    ErrPackedTooLarge,
 }
 
 impl DecoderError {
@@ -470,6 +840,8 @@ impl DecoderError {
             Self::ErrInvalidNode => String::from("invalid node"),
             Self::ErrInvalidToken => String::from("invalid token with tag"),
             Self::ErrNonStringKey => String::from("non-string key"),
+            Self::ErrDuplicateAttribute => String::from("duplicate attribute key"),
+            Self::ErrPackedTooLarge => String::from("packed string is longer than the data"),
         }
     }
 }
@@ -542,126 +914,160 @@ impl BinaryEncoder {
         self.push_bytes(&mut value.clone().as_bytes().to_vec())
     }
 
-    pub fn write_byte_length(&mut self, length: usize) {
+    /// Writes the length prefix of a byte array or raw string. Lengths that don't fit in
+    /// the 32 bit length are an error.
+    pub fn write_byte_length(&mut self, length: usize) -> Result<(), RhustAppError> {
         if length < 256 {
             self.push_byte(token::BINARY8);
             self.push_i_8(length as i32);
         } else if length < (1 << 20) {
             self.push_byte(token::BINARY20);
             self.push_i_20(length as i32);
-        } else if (length as i32) < std::i32::MAX {
+        } else if length < i32::MAX as usize {
             self.push_byte(token::BINARY32);
             self.push_i_32(length as i32);
         } else {
-            panic!(
-                "{}",
-                new_rhustapp_error(&format!("length is too large: {length}"), None)
-            )
+            return Err(new_rhustapp_error(
+                &format!("length is too large: {length}"),
+                None,
+            ));
         }
+        Ok(())
     }
 
-    pub fn write_node(&mut self, n: &Node) {
+    pub fn write_node(&mut self, n: &Node) -> Result<(), RhustAppError> {
         if n.tag.eq("0") {
             self.push_byte(token::LIST8);
             self.push_byte(token::LIST_EMPTY);
-            return;
+            return Ok(());
         };
 
         let has_content: i32;
-        match n.content {
+        match &n.content {
+            // An empty list is sent the same way as no content, see `NodeContentType`.
             NodeContentType::None => {
                 has_content = 0;
             }
+            NodeContentType::ListOfNodes(nodes) if nodes.is_empty() => {
+                has_content = 0;
+            }
             _ => {
                 has_content = 1;
             }
         }
 
         self.write_list_start((2 * n.attrs.len() as i32) + Self::TAG_SIZE + has_content);
-        self.write_string(&n.tag);
-        self.write_attributes(&n.attrs);
+        self.write_string(&n.tag)?;
+        self.write_attributes(&n.attrs)?;
         if has_content == 1 {
-            self.write(&n.content);
+            self.write(&n.content)?;
         }
+        Ok(())
     }
 
-    pub fn write(&mut self, data: &NodeContentType) {
+    pub fn write(&mut self, data: &NodeContentType) -> Result<(), RhustAppError> {
         match data {
             NodeContentType::None => self.push_byte(token::LIST_EMPTY),
-            NodeContentType::JID(j) => self.write_jid(j),
-            NodeContentType::String(s) => self.write_string(s),
-            NodeContentType::I32(i) => self.write_string(&format!("{i}")),
-            NodeContentType::U32(u) => self.write_string(&format!("{u}")),
-            NodeContentType::I64(i) => self.write_string(&format!("{i}")),
-            NodeContentType::U64(u) => self.write_string(&format!("{u}")),
-            NodeContentType::Bool(b) => self.write_string(&format!("{b}")),
-            NodeContentType::ByteArray(b) => self.write_bytes(b),
+            NodeContentType::JID(j) => self.write_jid(j)?,
+            NodeContentType::String(s) => self.write_string(s)?,
+            // Numbers are sent as their full decimal string (packed as nibbles), so they
+            // aren't limited by the i32 based `push_i_n`.
+            NodeContentType::I32(_)
+            | NodeContentType::U32(_)
+            | NodeContentType::I64(_)
+            | NodeContentType::U64(_)
+            | NodeContentType::Bool(_) => self.write_string(&data.other_types_to_string())?,
+            NodeContentType::ByteArray(b) => self.write_bytes(b)?,
             NodeContentType::ListOfNodes(l) => {
                 self.write_list_start(l.len() as i32);
                 for n in l.iter() {
-                    self.write_node(n);
+                    self.write_node(n)?;
                 }
             }
         }
+        Ok(())
     }
 
-    pub fn write_string(&mut self, data: &str) {
+    pub fn write_string(&mut self, data: &str) -> Result<(), RhustAppError> {
         if let Some(token_index) = token::index_of_single_token(data) {
             self.push_byte(token_index);
         } else if let Some((dict_index, token_index)) = token::index_of_double_token(data) {
             self.push_byte(token::DICTIONARY0 + dict_index);
             self.push_byte(token_index);
         } else if BinaryEncoder::validate_nibble(data) {
-            self.write_packed_bytes(data, token::NIBBLE8);
+            self.write_packed_bytes(data, token::NIBBLE8)?;
         } else if BinaryEncoder::validate_hex(data) {
-            self.write_packed_bytes(data, token::HEX8);
+            self.write_packed_bytes(data, token::HEX8)?;
         } else {
-            self.write_string_raw(data);
+            self.write_string_raw(data)?;
         }
+        Ok(())
     }
 
-    pub fn write_bytes(&mut self, data: &Vec<u8>) {
-        self.write_byte_length(data.len());
+    pub fn write_bytes(&mut self, data: &Vec<u8>) -> Result<(), RhustAppError> {
+        self.write_byte_length(data.len())?;
         self.push_bytes(&mut data.clone());
+        Ok(())
     }
 
-    pub fn write_string_raw(&mut self, data: &str) {
-        self.write_byte_length(data.len());
+    pub fn write_string_raw(&mut self, data: &str) -> Result<(), RhustAppError> {
+        self.write_byte_length(data.len())?;
         self.push_string(data);
+        Ok(())
     }
 
-    pub fn write_jid(&mut self, jid: &JID) {
+    pub fn write_jid(&mut self, jid: &JID) -> Result<(), RhustAppError> {
         if jid.is_ad() {
+            // The agent and device are written as single bytes. The explicit types make
+            // widening the fields of `JID` a compile error here instead of a truncation.
+            let agent: u8 = jid.agent.unwrap_or_default();
+            let device: u8 = jid.device.unwrap_or_default();
+            let domain = if jid.server.eq(HIDDEN_USER_SERVER) {
+                token::AD_JID_DOMAIN_LID
+            } else if agent == token::AD_JID_DOMAIN_LID {
+                // It would be read back as a JID on the lid server.
+                return Err(new_rhustapp_error(
+                    "failed to write ad jid",
+                    Some(format!(
+                        "agent {agent} of {jid} is reserved for the lid domain"
+                    )),
+                ));
+            } else {
+                agent
+            };
             self.push_byte(token::ADJID);
-            self.push_byte(jid.agent.unwrap());
-            self.push_byte(jid.device.unwrap());
-            self.write_string(&jid.user);
+            self.push_byte(domain);
+            self.push_byte(device);
+            self.write_string(&jid.user)?;
         } else {
             self.push_byte(token::JID_PAIR);
-            if jid.user.len() == 0 {
-                self.push_byte(token::LIST_EMPTY);
-            } else {
-                self.write(&NodeContentType::String(jid.user.to_string()));
+            for part in [&jid.user, &jid.server] {
+                if part.is_empty() {
+                    self.push_byte(token::LIST_EMPTY);
+                } else {
+                    self.write(&NodeContentType::String(part.to_string()))?;
+                }
             }
-            self.write(&NodeContentType::String(jid.user.to_string()));
         }
+        Ok(())
     }
 
-    pub fn write_attributes(&mut self, attributes: &Attrs) {
+    pub fn write_attributes(&mut self, attributes: &Attrs) -> Result<(), RhustAppError> {
         for (key, value) in attributes.iter() {
             match value {
                 AttributeTypes::String(s) => {
                     if !s.is_empty() {
-                        self.write_string(key);
-                        self.write(&NodeContentType::String(s.to_string()));
+                        self.write_string(key)?;
+                        self.write(&NodeContentType::String(s.to_string()))?;
                     }
                 }
                 AttributeTypes::JID(j) => {
-                    self.write_string(key);
-                    self.write(&NodeContentType::JID(j.to_owned()));
+                    self.write_string(key)?;
+                    self.write(&NodeContentType::JID(j.to_owned()))?;
                 }
             }
         }
+        Ok(())
     }
 
     pub fn write_list_start(&mut self, list_size: i32) {
@@ -676,49 +1082,54 @@ impl BinaryEncoder {
         }
     }
 
-    pub fn write_packed_bytes(&mut self, value: &str, data_type: u8) {
+    pub fn write_packed_bytes(&mut self, value: &str, data_type: u8) -> Result<(), RhustAppError> {
         if value.len() > token::PACKED_MAX {
-            panic!(
-                "{}",
-                new_rhustapp_error(&format!("too many bytes to pack: {}", value.len()), None)
-            )
+            return Err(new_rhustapp_error(
+                &format!("too many bytes to pack: {}", value.len()),
+                None,
+            ));
         }
+        let mut packed = BinaryEncoder::pack_string(value, data_type)?;
         self.push_byte(data_type);
-        let mut rounded_length = f64::ceil((value.len() as f64) / 2.0) as u8;
-        if value.len() % 2 != 0 {
-            rounded_length |= 128;
-        }
-        self.push_byte(rounded_length);
+        self.push_bytes(&mut packed);
+        Ok(())
+    }
 
-        let packer: fn(u8) -> u8;
-        match data_type {
-            token::NIBBLE8 => packer = BinaryEncoder::pack_nibble,
-            token::HEX8 => packer = BinaryEncoder::pack_hex,
+    /// Packs the string as nibbles or hex (depending on `data_type`, `token::NIBBLE8` or
+    /// `token::HEX8`), two characters per byte, prefixed with the packed length. The highest
+    /// bit of the length is set if the length of the string is odd.
+    pub fn pack_string(value: &str, data_type: u8) -> Result<Vec<u8>, RhustAppError> {
+        let packer: fn(u8) -> Result<u8, RhustAppError> = match data_type {
+            token::NIBBLE8 => BinaryEncoder::pack_nibble,
+            token::HEX8 => BinaryEncoder::pack_hex,
             _ => {
-                panic!("{}", &format!("invalid packed byte data type: {data_type}"));
+                return Err(new_rhustapp_error(
+                    &format!("invalid packed byte data type: {data_type}"),
+                    None,
+                ))
             }
-        }
+        };
 
-        for i in 0..(value.len() / 2) {
-            let packed_byte = BinaryEncoder::pack_byte_pair(
-                packer,
-                value.chars().nth(2 * i).unwrap() as u8,
-                value.chars().nth(2 * i + 1).unwrap() as u8,
-            );
-            self.push_byte(packed_byte);
+        let bytes = value.as_bytes();
+        let mut rounded_length = bytes.len().div_ceil(2) as u8;
+        if bytes.len() & 1 == 1 {
+            rounded_length |= 128;
         }
-        if value.len() % 2 != 0 {
-            let packed_byte = BinaryEncoder::pack_byte_pair(
-                packer,
-                value.chars().nth(value.len() - 1).unwrap() as u8,
-                b'\x00',
-            );
-            self.push_byte(packed_byte);
+
+        let mut packed = vec![rounded_length];
+        for pair in bytes.chunks(2) {
+            let part_2 = pair.get(1).copied().unwrap_or(b'\x00');
+            packed.push(BinaryEncoder::pack_byte_pair(packer, pair[0], part_2)?);
         }
+        Ok(packed)
     }
 
-    pub fn pack_byte_pair(packer: fn(u8) -> u8, part_1: u8, part_2: u8) -> u8 {
-        (packer(part_1) << 4) | packer(part_2)
+    pub fn pack_byte_pair(
+        packer: fn(u8) -> Result<u8, RhustAppError>,
+        part_1: u8,
+        part_2: u8,
+    ) -> Result<u8, RhustAppError> {
+        Ok((packer(part_1)? << 4) | packer(part_2)?)
     }
 
     pub fn validate_nibble(value: &str) -> bool {
@@ -734,27 +1145,19 @@ impl BinaryEncoder {
         true
     }
 
-    pub fn pack_nibble(value: u8) -> u8 {
+    pub fn pack_nibble(value: u8) -> Result<u8, RhustAppError> {
         match value {
-            b'-' => 10,
-            b'.' => 11,
-            0 => 15,
-            _ => {
-                if value >= b'0' && value <= b'9' {
-                    return value - b'0';
-                };
-                panic!(
-                    "{}",
-                    new_rhustapp_error(
-                        &format!(
-                            "invalid string to pack as nibble: {} / '{}'",
-                            value,
-                            value.to_string()
-                        ),
-                        None
-                    )
-                )
-            }
+            b'-' => Ok(10),
+            b'.' => Ok(11),
+            0 => Ok(15),
+            v if v.is_ascii_digit() => Ok(v - b'0'),
+            _ => Err(new_rhustapp_error(
+                &format!(
+                    "invalid string to pack as nibble: {value} / '{}'",
+                    value as char
+                ),
+                None,
+            )),
         }
     }
 
@@ -770,25 +1173,19 @@ impl BinaryEncoder {
         true
     }
 
-    pub fn pack_hex(value: u8) -> u8 {
+    pub fn pack_hex(value: u8) -> Result<u8, RhustAppError> {
         match value {
-            v if (v >= b'0' && v <= b'9') => v - b'0',
-            v if (v >= b'A' && v <= b'F') => 10 + v - b'A',
-            v if (v >= b'a' && v <= b'f') => 10 + v - b'a',
-            0 => 15,
-            _ => {
-                panic!(
-                    "{}",
-                    new_rhustapp_error(
-                        &format!(
-                            "invalid string to pack as hex: {} / '{}'",
-                            value,
-                            value.to_string()
-                        ),
-                        None
-                    )
-                )
-            }
+            v if v.is_ascii_digit() => Ok(v - b'0'),
+            v if (b'A'..=b'F').contains(&v) => Ok(10 + v - b'A'),
+            v if (b'a'..=b'f').contains(&v) => Ok(10 + v - b'a'),
+            0 => Ok(15),
+            _ => Err(new_rhustapp_error(
+                &format!(
+                    "invalid string to pack as hex: {value} / '{}'",
+                    value as char
+                ),
+                None,
+            )),
         }
     }
 }
@@ -797,6 +1194,17 @@ impl BinaryEncoder {
 pub struct BinaryDecoder {
     data: Vec<u8>,
     index: usize,
+    /// How many nodes deep the decoder currently is.
+    depth: usize,
+    /// The tags of the nodes that failed to decode, innermost first. It is only filled when
+    /// an error occurs, so that successful decoding doesn't pay for it.
+    error_path: Vec<String>,
+    /// Whether a duplicate attribute key is an error instead of a warning.
+    strict_attributes: bool,
+    /// The non-fatal problems found while decoding, e.g. duplicate attribute keys.
+    warnings: Vec<RhustAppError>,
+    /// Whether invalid UTF-8 in attribute values is replaced instead of failing.
+    lossy_strings: bool,
 }
 
 impl BinaryDecoder {
@@ -806,8 +1214,31 @@ impl BinaryDecoder {
         dec
     }
 
+    /// Sets whether a node with a duplicate attribute key fails to decode. By default, the
+    /// last value is kept and a warning is recorded.
+    pub fn with_strict_attributes(mut self, strict: bool) -> Self {
+        self.strict_attributes = strict;
+        self
+    }
+
+    /// Sets whether invalid UTF-8 in attribute values (e.g. a malformed `notify` name) is
+    /// replaced with U+FFFD instead of failing the whole node. Each replacement is recorded
+    /// as a warning. Tags, attribute keys and JIDs are always decoded strictly.
+    ///
+    /// Lossy decoding is disabled by default.
+    pub fn with_lossy_strings(mut self, lossy: bool) -> Self {
+        self.lossy_strings = lossy;
+        self
+    }
+
+    /// Returns the non-fatal problems found while decoding.
+    pub fn warnings(&self) -> &[RhustAppError] {
+        &self.warnings
+    }
+
     pub fn check_eos(&self, length: usize) -> Result<(), RhustAppError> {
-        if self.index + length > self.data.len() {
+        let end = self.index.checked_add(length);
+        if end.filter(|end| *end <= self.data.len()).is_none() {
             return Err(new_rhustapp_error("EOF", None));
         };
         Ok(())
@@ -844,6 +1275,11 @@ impl BinaryDecoder {
         Ok(return_value)
     }
 
+    /// Reads the length of a `BINARY8` value, which is a single unsigned byte (0..=255).
+    pub fn read_u8_len(&mut self) -> Result<usize, RhustAppError> {
+        self.read_byte().map(usize::from)
+    }
+
     pub fn read_i_8(&mut self, little_endian: bool) -> Result<i32, RhustAppError> {
         self.read_i_n(1, little_endian)
     }
@@ -874,9 +1310,21 @@ impl BinaryDecoder {
             new_rhustapp_error("failed to read packed 8 string", Some(err.to_string()))
         })?;
 
-        let mut bytes = Vec::<u8>::default();
+        let length = usize::from(start_byte & 127);
+        let remaining = self.data.len().saturating_sub(self.index);
+        if length > remaining {
+            return Err(new_rhustapp_error(
+                "failed to read packed 8 string",
+                Some(format!(
+                    "{}: {length} bytes, but only {remaining} remaining",
+                    DecoderError::ErrPackedTooLarge
+                )),
+            ));
+        };
 
-        for _ in 0..(start_byte & 127) {
+        let mut bytes = Vec::<u8>::with_capacity(length * 2);
+
+        for _ in 0..length {
             let curr_byte = self.read_byte().map_err(|err| {
                 new_rhustapp_error("failed to read packed 8 string", Some(err.to_string()))
             })?;
@@ -897,8 +1345,12 @@ impl BinaryDecoder {
             new_rhustapp_error("failed to read packed 8 string", Some(err.to_string()))
         })?;
 
-        if start_byte >> 7 != 0 {
-            ret = ret[..ret.len() - 1].to_string();
+        // The last character of an odd length string is padding.
+        if start_byte >> 7 != 0 && ret.pop().is_none() {
+            return Err(new_rhustapp_error(
+                "failed to read packed 8 string",
+                Some("odd length flag is set on an empty string".to_string()),
+            ));
         };
 
         Ok(ret)
@@ -955,6 +1407,37 @@ impl BinaryDecoder {
     }
 
     pub fn read(&mut self, as_string: bool) -> Result<NodeContentType, RhustAppError> {
+        self.read_content(as_string, false)
+    }
+
+    /// Converts the bytes of a string value, replacing invalid UTF-8 if `lossy` is set.
+    fn bytes_to_string(&mut self, bytes: Vec<u8>, lossy: bool) -> Result<String, RhustAppError> {
+        match String::from_utf8(bytes) {
+            Ok(s) => Ok(s),
+            Err(err) if lossy => {
+                self.warnings.push(new_rhustapp_error(
+                    &format!(
+                        "replaced invalid UTF-8 in string at position {}",
+                        self.index
+                    ),
+                    Some(err.to_string()),
+                ));
+                Ok(String::from_utf8_lossy(err.as_bytes()).into_owned())
+            }
+            Err(err) => Err(new_rhustapp_error(
+                "failed to convert bytes to String",
+                Some(err.to_string()),
+            )),
+        }
+    }
+
+    /// Reads the next value. `lossy` is only set for attribute values, see
+    /// `with_lossy_strings`.
+    fn read_content(
+        &mut self,
+        as_string: bool,
+        lossy: bool,
+    ) -> Result<NodeContentType, RhustAppError> {
         let tag_byte = self
             .read_byte()
             .map_err(|err| new_rhustapp_error("failed to read tag byte", Some(err.to_string())))?;
@@ -963,24 +1446,22 @@ impl BinaryDecoder {
             token::LIST_EMPTY => Ok(NodeContentType::None),
             token::LIST8 | token::LIST16 => self
                 .read_list(tag_byte)
-                .map(|val| NodeContentType::ListOfNodes(val))
+                .map(|val| match val.is_empty() {
+                    true => NodeContentType::None,
+                    false => NodeContentType::ListOfNodes(val),
+                })
                 .map_err(|err| {
                     new_rhustapp_error("failed to parse list tokens", Some(err.to_string()))
                 }),
             token::BINARY8 => {
-                let size = self.read_i_8(false).map_err(|err| {
+                let size = self.read_u8_len().map_err(|err| {
                     new_rhustapp_error("failed to parse token::BINARY8", Some(err.to_string()))
                 })?;
-                let bytes = self.read_bytes(size as usize).map_err(|err| {
+                let bytes = self.read_bytes(size).map_err(|err| {
                     new_rhustapp_error("failed to parse token::BINARY8", Some(err.to_string()))
                 })?;
                 if as_string {
-                    let s = String::from_utf8(bytes).map_err(|err| {
-                        new_rhustapp_error(
-                            "failed to convert bytes to String",
-                            Some(err.to_string()),
-                        )
-                    })?;
+                    let s = self.bytes_to_string(bytes, lossy)?;
                     return Ok(NodeContentType::String(s));
                 } else {
                     return Ok(NodeContentType::ByteArray(bytes));
@@ -994,12 +1475,7 @@ impl BinaryDecoder {
                     new_rhustapp_error("failed to parse token::BINARY20", Some(err.to_string()))
                 })?;
                 if as_string {
-                    let s = String::from_utf8(bytes).map_err(|err| {
-                        new_rhustapp_error(
-                            "failed to convert bytes to String",
-                            Some(err.to_string()),
-                        )
-                    })?;
+                    let s = self.bytes_to_string(bytes, lossy)?;
                     return Ok(NodeContentType::String(s));
                 } else {
                     return Ok(NodeContentType::ByteArray(bytes));
@@ -1009,16 +1485,17 @@ impl BinaryDecoder {
                 let size = self.read_i_32(false).map_err(|err| {
                     new_rhustapp_error("failed to parse token::BINARY32", Some(err.to_string()))
                 })?;
-                let bytes = self.read_bytes(size as usize).map_err(|err| {
+                let size = usize::try_from(size).map_err(|_| {
+                    new_rhustapp_error(
+                        "failed to parse token::BINARY32",
+                        Some(format!("negative length {size}")),
+                    )
+                })?;
+                let bytes = self.read_bytes(size).map_err(|err| {
                     new_rhustapp_error("failed to parse token::BINARY32", Some(err.to_string()))
                 })?;
                 if as_string {
-                    let s = String::from_utf8(bytes).map_err(|err| {
-                        new_rhustapp_error(
-                            "failed to convert bytes to String",
-                            Some(err.to_string()),
-                        )
-                    })?;
+                    let s = self.bytes_to_string(bytes, lossy)?;
                     return Ok(NodeContentType::String(s));
                 } else {
                     return Ok(NodeContentType::ByteArray(bytes));
@@ -1088,15 +1565,19 @@ impl BinaryDecoder {
             .read(true)
             .map_err(|err| new_rhustapp_error("failed to read jid pair", Some(err.to_string())))?;
 
-        match server {
-            NodeContentType::String(s) => match user {
-                NodeContentType::None => Ok(JID::new("", &s)),
-                NodeContentType::String(u) => Ok(JID::new(&u, &s)),
-                _ => Err(new_rhustapp_error(
+        let server = match server {
+            NodeContentType::None => String::new(),
+            NodeContentType::String(s) => s,
+            _ => {
+                return Err(new_rhustapp_error(
                     "failed to read jid pair",
                     Some(DecoderError::ErrInvalidJIDType.to_string()),
-                )),
-            },
+                ))
+            }
+        };
+        match user {
+            NodeContentType::None => Ok(JID::new("", &server)),
+            NodeContentType::String(u) => Ok(JID::new(&u, &server)),
             _ => Err(new_rhustapp_error(
                 "failed to read jid pair",
                 Some(DecoderError::ErrInvalidJIDType.to_string()),
@@ -1104,6 +1585,11 @@ impl BinaryDecoder {
         }
     }
 
+    /// Reads an AD-JID, which is encoded as the agent byte, the device byte and the user.
+    ///
+    /// In the older layout the agent byte is the raw agent and the server is always
+    /// `DEFAULT_USER_SERVER`. In the newer layout, the agent byte is the domain type instead,
+    /// where `token::AD_JID_DOMAIN_LID` means the JID is on the `HIDDEN_USER_SERVER`.
     pub fn read_ad_jid(&mut self) -> Result<JID, RhustAppError> {
         let agent = self
             .read_byte()
@@ -1116,6 +1602,12 @@ impl BinaryDecoder {
             .map_err(|err| new_rhustapp_error("failed to read ad jid", Some(err.to_string())))?;
 
         match user {
+            NodeContentType::String(u) if agent == token::AD_JID_DOMAIN_LID => Ok(JID {
+                user: u,
+                agent: Some(0),
+                device: Some(device),
+                server: HIDDEN_USER_SERVER.to_string(),
+            }),
             NodeContentType::String(u) => Ok(JID::new_ad(&u, agent, device)),
             _ => Err(new_rhustapp_error(
                 "failed to read ad jid",
@@ -1137,16 +1629,12 @@ impl BinaryDecoder {
 
             match key_ifc {
                 NodeContentType::String(key) => {
-                    let value = self.read(true).map_err(|err| {
+                    let value = self.read_content(true, self.lossy_strings).map_err(|err| {
                         new_rhustapp_error("failed to read attributes", Some(err.to_string()))
                     })?;
-                    match value {
-                        NodeContentType::JID(j) => {
-                            attrs.insert(key, AttributeTypes::JID(j));
-                        }
-                        NodeContentType::String(s) => {
-                            attrs.insert(key, AttributeTypes::String(s));
-                        }
+                    let value = match value {
+                        NodeContentType::JID(j) => AttributeTypes::JID(j),
+                        NodeContentType::String(s) => AttributeTypes::String(s),
                         _ => {
                             return Err(new_rhustapp_error(
                                 "failed to read attributes",
@@ -1156,7 +1644,23 @@ impl BinaryDecoder {
                                 )),
                             ))
                         }
-                    }
+                    };
+
+                    if attrs.contains_key(&key) {
+                        let err = new_rhustapp_error(
+                            "failed to read attributes",
+                            Some(format!(
+                                "{} '{key}' at position {}",
+                                DecoderError::ErrDuplicateAttribute.to_string(),
+                                self.index
+                            )),
+                        );
+                        if self.strict_attributes {
+                            return Err(err);
+                        };
+                        self.warnings.push(err);
+                    };
+                    attrs.insert(key, value);
                 }
                 _ => {
                     return Err(new_rhustapp_error(
@@ -1191,7 +1695,40 @@ impl BinaryDecoder {
         Ok(nodes)
     }
 
+    /// Reads a node from the data. If decoding fails inside a nested node, the error
+    /// contains the path of tags leading to it, e.g. `failed at iq>query>item`.
     pub fn read_node(&mut self) -> Result<Node, RhustAppError> {
+        if self.depth == 0 {
+            self.error_path.clear();
+        };
+        let path_len = self.error_path.len();
+
+        self.depth += 1;
+        let result = self.read_node_inner();
+        self.depth -= 1;
+
+        match result {
+            Ok(node) => {
+                self.error_path.truncate(path_len);
+                Ok(node)
+            }
+            Err(err) if self.depth == 0 && !self.error_path.is_empty() => {
+                let path = self
+                    .error_path
+                    .drain(..)
+                    .rev()
+                    .collect::<Vec<String>>()
+                    .join(">");
+                Err(new_rhustapp_error(
+                    &format!("failed at {path}"),
+                    Some(err.to_string()),
+                ))
+            }
+            result => result,
+        }
+    }
+
+    fn read_node_inner(&mut self) -> Result<Node, RhustAppError> {
         let mut node = Node::default();
 
         let size = self
@@ -1223,6 +1760,7 @@ impl BinaryDecoder {
                 node.tag = s.to_string();
 
                 let attributes = self.read_attributes((list_size - 1) >> 1).map_err(|err| {
+                    self.error_path.push(node.tag.to_string());
                     new_rhustapp_error("failed to read node", Some(err.to_string()))
                 })?;
                 node.attrs = attributes;
@@ -1232,6 +1770,7 @@ impl BinaryDecoder {
                 };
 
                 let content = self.read(false).map_err(|err| {
+                    self.error_path.push(node.tag.to_string());
                     new_rhustapp_error("failed to read node", Some(err.to_string()))
                 })?;
                 node.content = content;
@@ -1247,6 +1786,14 @@ impl BinaryDecoder {
         }
     }
 
+    /// Reads a node like `read_node`, but also returns the range of bytes in the decoder
+    /// buffer that the node was decoded from.
+    pub fn read_node_with_span(&mut self) -> Result<(Node, Range<usize>), RhustAppError> {
+        let start = self.index;
+        let node = self.read_node()?;
+        Ok((node, start..self.index))
+    }
+
     pub fn read_string(&mut self, length: usize) -> Result<String, RhustAppError> {
         let bytes = self
             .read_bytes(length)
@@ -1267,11 +1814,39 @@ impl BinaryDecoder {
     }
 }
 
+/// The size above which `pack_data` compresses the data.
+pub const PACK_COMPRESSION_THRESHOLD: usize = 16 * 1024;
+
+/// The bit of the flag byte that marks the data as zlib-compressed.
+const COMPRESSED_FLAG: u8 = 2;
+
+/// Packs the given encoded node (without the leading flag byte that `marshal` adds) for
+/// sending to the WhatsApp web API. This is the counterpart of `unpack_data`.
+///
+/// Data larger than `PACK_COMPRESSION_THRESHOLD` is compressed with zlib, smaller data is
+/// only prefixed with the flag byte.
+pub fn pack_data(data: &[u8]) -> Result<Vec<u8>, RhustAppError> {
+    if data.len() <= PACK_COMPRESSION_THRESHOLD {
+        let mut packed = Vec::with_capacity(data.len() + 1);
+        packed.push(0);
+        packed.extend_from_slice(data);
+        return Ok(packed);
+    };
+
+    let mut encoder =
+        flate2::write::ZlibEncoder::new(vec![COMPRESSED_FLAG], flate2::Compression::default());
+    encoder
+        .write_all(data)
+        .map_err(|err| new_rhustapp_error("failed to compress data", Some(err.to_string())))?;
+    encoder
+        .finish()
+        .map_err(|err| new_rhustapp_error("failed to compress data", Some(err.to_string())))
+}
+
 /// Unpacks the given decrypted data from the WhatsApp web API.
 ///
 /// It checks the first byte to decide whether to uncompress the data with zlib or just return
-/// as-is (without the first byte). There's currently no corresponding pack function because
-/// marshal returns the data with a leading zero (i.e. not compressed).
+/// as-is (without the first byte).
 pub fn unpack_data(data: &Vec<u8>) -> Result<Vec<u8>, RhustAppError> {
     if data.len() == 0 {
         return Err(new_rhustapp_error(
@@ -1282,18 +1857,51 @@ pub fn unpack_data(data: &Vec<u8>) -> Result<Vec<u8>, RhustAppError> {
 
     let data_type = data[0];
 
-    if 2 & data_type > 0 {
+    if COMPRESSED_FLAG & data_type > 0 {
         let mut decoder = flate2::read::ZlibDecoder::new(&data.as_slice()[1..]);
-        let mut decoded_string = String::new();
-        decoder.read_to_string(&mut decoded_string).map_err(|err| {
+        let mut decoded = Vec::new();
+        decoder.read_to_end(&mut decoded).map_err(|err| {
             new_rhustapp_error("failed to decompress data", Some(err.to_string()))
         })?;
-        Ok(decoded_string.as_bytes().to_vec())
+        Ok(decoded)
     } else {
         Ok(data.as_slice()[1..].to_vec())
     }
 }
 
+/// Encodes the node into the binary format, including the leading flag byte. Callers that
+/// know the node is valid can `.expect()` the result.
+pub fn marshal(node: &Node) -> Result<Vec<u8>, RhustAppError> {
+    let mut encoder = BinaryEncoder::new();
+    encoder.write_node(node)?;
+    Ok(encoder.get_data())
+}
+
+/// Unpacks and decodes a raw (decrypted) frame into a node. This is the counterpart of
+/// `marshal`.
+pub fn unmarshal(bytes: &[u8]) -> Result<Node, RhustAppError> {
+    let data = unpack_data(&bytes.to_vec())
+        .map_err(|err| new_rhustapp_error("failed to unpack frame", Some(err.to_string())))?;
+
+    let mut decoder = BinaryDecoder::new(&data);
+    decoder.read_node().map_err(|err| {
+        new_rhustapp_error(
+            &format!(
+                "failed to decode frame at offset {} of {} bytes",
+                decoder.index,
+                data.len()
+            ),
+            Some(err.to_string()),
+        )
+    })
+}
+
+/// Unpacks and decodes a raw (decrypted) frame, and renders the decoded node as an XML string.
+/// This is mostly useful for inspecting frames while debugging.
+pub fn decode_frame_to_xml(bytes: &[u8]) -> Result<String, RhustAppError> {
+    Ok(unmarshal(bytes)?.xml_string())
+}
+
 pub fn printable(data: &Vec<u8>) -> String {
     match String::from_utf8(data.to_vec()) {
         Ok(s) => {
@@ -1307,3 +1915,850 @@ pub fn printable(data: &Vec<u8>) -> String {
         Err(_) => String::new(),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::types::{DEFAULT_USER_SERVER, MAX_AD_DEVICE, SERVER_JID};
+
+    use super::*;
+
+    fn encode(node: &Node) -> Vec<u8> {
+        // Skip the leading flag byte that `BinaryEncoder::new` pushes.
+        marshal(node).unwrap()[1..].to_vec()
+    }
+
+    fn sample_node() -> Node {
+        Node {
+            tag: "iq".to_string(),
+            attrs: Attrs::from([
+                ("id".to_string(), AttributeTypes::String("1234".to_string())),
+                (
+                    "type".to_string(),
+                    AttributeTypes::String("get".to_string()),
+                ),
+            ]),
+            content: NodeContentType::ListOfNodes(vec![Node {
+                tag: "ping".to_string(),
+                attrs: Attrs::new(),
+                content: NodeContentType::None,
+            }]),
+        }
+    }
+
+    fn list_node(tag: &str, children: Vec<Node>) -> Node {
+        Node {
+            tag: tag.to_string(),
+            attrs: Attrs::new(),
+            content: NodeContentType::ListOfNodes(children),
+        }
+    }
+
+    #[test]
+    fn test_walk_pre_order() {
+        let tree = list_node(
+            "a",
+            vec![
+                list_node("b", vec![list_node("c", vec![]), list_node("d", vec![])]),
+                list_node("e", vec![list_node("f", vec![])]),
+            ],
+        );
+
+        let tags = tree
+            .walk()
+            .map(|node| node.tag.as_str())
+            .collect::<Vec<&str>>();
+        assert_eq!(tags.len(), 6);
+        assert_eq!(tags, vec!["a", "b", "c", "d", "e", "f"]);
+    }
+
+    #[test]
+    fn test_walk_max_depth() {
+        let mut tree = list_node("leaf", vec![]);
+        for _ in 0..Node::MAX_WALK_DEPTH + 10 {
+            tree = list_node("level", vec![tree]);
+        }
+        assert_eq!(tree.walk().count(), Node::MAX_WALK_DEPTH + 1);
+    }
+
+    #[test]
+    fn test_canonical_bytes_attr_order() {
+        let pairs = [
+            ("id", "1234"),
+            ("type", "get"),
+            ("xmlns", "w:p"),
+            ("to", ""),
+        ];
+
+        let mut forward = sample_node();
+        forward.attrs = Attrs::new();
+        for (k, v) in pairs.iter() {
+            forward
+                .attrs
+                .insert(k.to_string(), AttributeTypes::String(v.to_string()));
+        }
+        let mut backward = sample_node();
+        backward.attrs = Attrs::new();
+        for (k, v) in pairs.iter().rev() {
+            backward
+                .attrs
+                .insert(k.to_string(), AttributeTypes::String(v.to_string()));
+        }
+
+        assert_eq!(forward, backward);
+        assert_eq!(forward.canonical_bytes(), backward.canonical_bytes());
+
+        // The empty attribute is kept, unlike in the wire encoding.
+        let mut without_empty = forward.clone();
+        without_empty.attrs.remove("to");
+        assert_ne!(forward.canonical_bytes(), without_empty.canonical_bytes());
+    }
+
+    #[test]
+    fn test_read_node_error_path() {
+        let item = Node {
+            tag: "item".to_string(),
+            attrs: Attrs::from([(
+                "type".to_string(),
+                AttributeTypes::String("get".to_string()),
+            )]),
+            content: NodeContentType::None,
+        };
+        let tree = list_node("iq", vec![list_node("query", vec![item])]);
+
+        let mut data = encode(&tree);
+        // The last byte is the single byte token of the item's "type" attribute value,
+        // so replace it with a byte that isn't a valid token.
+        *data.last_mut().unwrap() = 240;
+
+        let err = BinaryDecoder::new(&data).read_node().unwrap_err();
+        assert!(err.description.contains("failed at iq>query>item"));
+        assert!(err
+            .to_string()
+            .contains(&DecoderError::ErrInvalidToken.to_string()));
+    }
+
+    #[test]
+    fn test_read_node_error_path_reused_decoder() {
+        let bad_tree = |outer: &str, inner: &str| {
+            let child = Node {
+                tag: inner.to_string(),
+                attrs: Attrs::from([(
+                    "type".to_string(),
+                    AttributeTypes::String("get".to_string()),
+                )]),
+                content: NodeContentType::None,
+            };
+            let mut data = encode(&list_node(outer, vec![child]));
+            *data.last_mut().unwrap() = 240;
+            data
+        };
+        let first = bad_tree("iq", "query");
+        let good = encode(&list_node("iq", vec![]));
+        let second = bad_tree("message", "enc");
+
+        let mut decoder = BinaryDecoder::new(&[first.clone(), good, second].concat());
+        let err = decoder.read_node().unwrap_err();
+        assert!(err.description.contains("failed at iq>query"));
+
+        decoder.index = first.len();
+        decoder.read_node().unwrap();
+        let err = decoder.read_node().unwrap_err();
+        assert!(err.description.contains("failed at message>enc"));
+        assert!(!err.description.contains("query"));
+        assert!(decoder.error_path.is_empty());
+    }
+
+    #[test]
+    fn test_jid_present() {
+        let attrs = Attrs::from([
+            (
+                "participant".to_string(),
+                AttributeTypes::JID(JID::new("1234", "s.whatsapp.net")),
+            ),
+            (
+                "recipient".to_string(),
+                AttributeTypes::JID(EMPTY_JID.clone()),
+            ),
+        ]);
+        let mut ag = AttrUtility::new(&attrs);
+
+        assert!(ag.jid_present("participant"));
+        assert_eq!(
+            ag.optional_jid_or_empty("participant"),
+            JID::new("1234", "s.whatsapp.net")
+        );
+
+        assert!(!ag.jid_present("from"));
+        assert_eq!(ag.optional_jid_or_empty("from"), *EMPTY_JID);
+
+        assert!(ag.jid_present("recipient"));
+        assert_eq!(ag.optional_jid_or_empty("recipient"), *EMPTY_JID);
+
+        assert!(ag.ok());
+    }
+
+    #[test]
+    fn test_unix_time_primitive() {
+        let node = Node {
+            tag: "message".to_string(),
+            attrs: Attrs::from([
+                (
+                    "t".to_string(),
+                    AttributeTypes::String("1678000000".to_string()),
+                ),
+                (
+                    "bad".to_string(),
+                    AttributeTypes::String("soon".to_string()),
+                ),
+            ]),
+            content: NodeContentType::None,
+        };
+
+        let mut ag = node.attr_getter();
+        let primitive = ag.unix_time_primitive("t").unwrap();
+        let offset_dt = ag.unix_time("t").unwrap();
+        assert_eq!(primitive, time::macros::datetime!(2023-03-05 07:06:40));
+        assert_eq!(primitive.assume_utc(), offset_dt);
+        assert!(ag.ok());
+
+        assert!(ag.optional_unix_time_primitive("missing").is_none());
+        assert!(ag.ok());
+        assert!(ag.unix_time_primitive("bad").is_none());
+        assert!(!ag.ok());
+    }
+
+    #[test]
+    fn test_merge_attr_errors() {
+        let parent = Node {
+            tag: "notification".to_string(),
+            attrs: Attrs::from([(
+                "t".to_string(),
+                AttributeTypes::String("not a number".to_string()),
+            )]),
+            content: NodeContentType::None,
+        };
+        let child = Node {
+            tag: "set".to_string(),
+            attrs: Attrs::new(),
+            content: NodeContentType::None,
+        };
+
+        let mut ag = parent.attr_getter();
+        let mut cag = child.attr_getter();
+        assert!(ag.unix_time("t").is_none());
+        assert!(cag.jid("jid").is_none());
+        assert!(ag.optional_string("missing").is_none());
+
+        ag.merge(cag);
+        assert_eq!(ag.errors.len(), 2);
+        let err = ag.error().unwrap().to_string();
+        assert!(err.contains("'t'"), "{err}");
+        assert!(err.contains("'jid'"), "{err}");
+
+        let mut ok = parent.attr_getter();
+        ok.merge(child.attr_getter());
+        assert!(ok.ok());
+    }
+
+    #[test]
+    fn test_optional_u64_radix() {
+        let attrs = Attrs::from([
+            (
+                "hash".to_string(),
+                AttributeTypes::String("1f2e".to_string()),
+            ),
+            (
+                "t".to_string(),
+                AttributeTypes::String("1678000000".to_string()),
+            ),
+        ]);
+        let mut ag = AttrUtility::new(&attrs);
+
+        assert_eq!(ag.optional_u64_radix("hash", 16), Some(0x1f2e));
+        assert_eq!(ag.optional_u64_radix("t", 10), Some(1678000000));
+        assert_eq!(ag.u64("t"), Some(1678000000));
+        assert_eq!(ag.optional_u64_radix("hash", 10), None);
+        assert_eq!(ag.optional_u64_radix("missing", 16), None);
+        // Radixes that `from_str_radix` would panic on.
+        assert_eq!(ag.optional_u64_radix("t", 0), None);
+        assert_eq!(ag.optional_u64_radix("hash", 37), None);
+        assert!(ag.ok());
+    }
+
+    #[test]
+    fn test_expect_tag_and_attr() {
+        let node = sample_node();
+
+        assert!(node.expect_tag("iq").is_ok());
+        assert!(node.expect_attr("type", "get").is_ok());
+
+        let err = node.expect_tag("message").unwrap_err();
+        assert!(err
+            .description
+            .contains("expected tag <message>, but found <iq>"));
+
+        let err = node.expect_attr("type", "set").unwrap_err();
+        assert!(err.description.contains("but found \"get\""));
+
+        let err = node.expect_attr("xmlns", "w:p").unwrap_err();
+        assert!(err.description.contains("but it is missing"));
+    }
+
+    fn round_trip_jid(jid: &JID) -> JID {
+        let mut encoder = BinaryEncoder::new();
+        encoder.write_jid(jid).unwrap();
+        let data = encoder.get_data()[1..].to_vec();
+
+        let mut decoder = BinaryDecoder::new(&data);
+        match decoder.read(true).unwrap() {
+            NodeContentType::JID(decoded) => decoded,
+            other => panic!("expected a JID, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn test_ad_jid_round_trip_legacy() {
+        let jid = JID::new_ad("919876543210", 0, 3);
+        let decoded = round_trip_jid(&jid);
+        assert_eq!(decoded, jid);
+        assert_eq!(decoded.server, DEFAULT_USER_SERVER);
+    }
+
+    #[test]
+    fn test_ad_jid_round_trip_lid_domain() {
+        let jid = JID {
+            user: "123456789012345".to_string(),
+            agent: Some(0),
+            device: Some(7),
+            server: HIDDEN_USER_SERVER.to_string(),
+        };
+        let decoded = round_trip_jid(&jid);
+        assert_eq!(decoded, jid);
+    }
+
+    #[test]
+    fn test_jid_pair_round_trip() {
+        let encode_pair = |jid: &JID| {
+            let mut encoder = BinaryEncoder::new();
+            encoder.write_jid(jid).unwrap();
+            let data = encoder.get_data()[1..].to_vec();
+            assert_eq!(data[0], token::JID_PAIR);
+            data[1..].to_vec()
+        };
+
+        let jid = JID::new("123", "s.whatsapp.net");
+        let decoded = BinaryDecoder::new(&encode_pair(&jid))
+            .read_jid_pair()
+            .unwrap();
+        assert_eq!(decoded.user, "123");
+        assert_eq!(decoded.server, "s.whatsapp.net");
+        assert_eq!(decoded, jid);
+
+        // Empty parts are written as empty lists on both sides.
+        for jid in [SERVER_JID.clone(), JID::new("123", ""), EMPTY_JID.clone()] {
+            let decoded = BinaryDecoder::new(&encode_pair(&jid))
+                .read_jid_pair()
+                .unwrap();
+            assert_eq!(decoded, jid);
+        }
+    }
+
+    #[test]
+    fn test_ad_jid_max_device() {
+        let jid = JID::new_ad("919876543210", 0, MAX_AD_DEVICE);
+        let decoded = round_trip_jid(&jid);
+        assert_eq!(decoded.device, Some(255));
+        assert_eq!(decoded, jid);
+
+        let lid = JID {
+            user: "123456789012345".to_string(),
+            agent: Some(0),
+            device: Some(MAX_AD_DEVICE),
+            server: HIDDEN_USER_SERVER.to_string(),
+        };
+        assert_eq!(round_trip_jid(&lid), lid);
+
+        let reserved_agent = JID::new_ad("919876543210", token::AD_JID_DOMAIN_LID, 2);
+        assert!(BinaryEncoder::new().write_jid(&reserved_agent).is_err());
+    }
+
+    // <iq id="1" type="result" /> with the leading flag byte, as received from the server.
+    const IQ_RESULT_FRAME: [u8; 8] = [0, token::LIST8, 5, 30, 4, 20, 8, 53];
+
+    #[test]
+    fn test_pack_data_small() {
+        let data = b"0123456789".to_vec();
+        let packed = pack_data(&data).unwrap();
+        assert_eq!(packed[0], 0);
+        assert_eq!(&packed[1..], data.as_slice());
+        assert_eq!(unpack_data(&packed).unwrap(), data);
+    }
+
+    #[test]
+    fn test_pack_data_compressed() {
+        // Binary data that isn't valid UTF-8, like most encoded nodes.
+        let data = (0..100 * 1024)
+            .map(|i| (i % 251) as u8)
+            .collect::<Vec<u8>>();
+        let packed = pack_data(&data).unwrap();
+        assert_eq!(packed[0], COMPRESSED_FLAG);
+        assert!(packed.len() < data.len());
+        assert_eq!(unpack_data(&packed).unwrap(), data);
+    }
+
+    #[test]
+    fn test_decode_frame_to_xml() {
+        let xml = decode_frame_to_xml(&IQ_RESULT_FRAME).unwrap();
+        assert!(xml.starts_with("<iq "));
+        assert_eq!(xml, "<iq id=\"1\" type=\"result\" />");
+    }
+
+    #[test]
+    fn test_decode_frame_to_xml_truncated() {
+        let err = decode_frame_to_xml(&IQ_RESULT_FRAME[..6]).unwrap_err();
+        assert!(err.description.contains("failed to decode frame at offset"));
+    }
+
+    #[test]
+    fn test_read_node_with_span() {
+        let node = sample_node();
+        let data = encode(&node);
+
+        let mut decoder = BinaryDecoder::new(&data);
+        let (decoded, span) = decoder.read_node_with_span().unwrap();
+        assert_eq!(decoded, node);
+        assert_eq!(span, 0..data.len());
+
+        let mut decoder = BinaryDecoder::new(&data[span].to_vec());
+        assert_eq!(decoder.read_node().unwrap(), node);
+    }
+
+    #[test]
+    fn test_numeric_content_boundaries() {
+        let cases = [
+            (NodeContentType::U64(u64::MAX), u64::MAX.to_string()),
+            (NodeContentType::I64(i64::MIN), i64::MIN.to_string()),
+            (NodeContentType::I64(i64::MAX), i64::MAX.to_string()),
+            (NodeContentType::U32(u32::MAX), u32::MAX.to_string()),
+            (NodeContentType::I32(i32::MIN), i32::MIN.to_string()),
+        ];
+
+        for (content, expected) in cases {
+            assert_eq!(content.other_types_to_string(), expected);
+
+            let node = Node {
+                tag: "value".to_string(),
+                attrs: Attrs::new(),
+                content,
+            };
+            let decoded = BinaryDecoder::new(&encode(&node)).read_node().unwrap();
+            assert_eq!(decoded.content, NodeContentType::String(expected));
+        }
+    }
+
+    #[test]
+    fn test_binary8_length_unsigned() {
+        let content: Vec<u8> = (0..200).map(|i| i as u8).collect();
+        let node = Node {
+            tag: "media".to_string(),
+            attrs: Attrs::new(),
+            content: NodeContentType::ByteArray(content.clone()),
+        };
+        let data = encode(&node);
+        // The length byte has its high bit set, which must not be read as a sign.
+        let length_index = data.len() - content.len() - 1;
+        assert_eq!(data[length_index - 1], token::BINARY8);
+        assert_eq!(data[length_index], 200);
+
+        let decoded = BinaryDecoder::new(&data).read_node().unwrap();
+        assert_eq!(decoded.content, NodeContentType::ByteArray(content));
+    }
+
+    #[test]
+    fn test_with_content() {
+        let node = |tag: &str| Node {
+            tag: tag.to_string(),
+            ..Default::default()
+        };
+
+        let text = node("body").with_content("hello");
+        assert_eq!(text.content, NodeContentType::String("hello".to_string()));
+
+        let bytes = node("enc").with_content(vec![1u8, 2, 3]);
+        assert_eq!(bytes.content, NodeContentType::ByteArray(vec![1, 2, 3]));
+
+        let parent = node("iq").with_content(vec![text.clone(), bytes.clone()]);
+        assert_eq!(parent.get_children().unwrap(), vec![text, bytes]);
+
+        assert_eq!(
+            node("t").with_content(1678000000u64).content,
+            NodeContentType::U64(1678000000)
+        );
+        assert_eq!(
+            node("value").with_content(true).content,
+            NodeContentType::Bool(true)
+        );
+        let jid = JID::new("919876543210", DEFAULT_USER_SERVER);
+        assert_eq!(
+            node("jid").with_content(jid.clone()).content,
+            NodeContentType::JID(jid)
+        );
+    }
+
+    #[test]
+    fn test_lossy_strings() {
+        let node = Node {
+            tag: "message".to_string(),
+            attrs: Attrs::from([(
+                "notify".to_string(),
+                AttributeTypes::String("AlQce".to_string()),
+            )]),
+            content: NodeContentType::ByteArray(vec![0xFF, 0xFE]),
+        };
+        let mut data = encode(&node);
+        let name_index = data
+            .windows(5)
+            .position(|window| window == b"AlQce")
+            .unwrap();
+        data[name_index + 2] = 0xFF;
+
+        assert!(BinaryDecoder::new(&data).read_node().is_err());
+
+        let mut decoder = BinaryDecoder::new(&data).with_lossy_strings(true);
+        let decoded = decoder.read_node().unwrap();
+        assert_eq!(
+            decoded.attr_getter().string("notify").unwrap(),
+            "Al\u{FFFD}ce"
+        );
+        // Binary content is never converted.
+        assert_eq!(
+            decoded.content,
+            NodeContentType::ByteArray(vec![0xFF, 0xFE])
+        );
+        assert_eq!(decoder.warnings().len(), 1);
+    }
+
+    #[test]
+    fn test_coerce_mistyped_attributes() {
+        let node = Node {
+            tag: "message".to_string(),
+            attrs: Attrs::from([
+                (
+                    "from".to_string(),
+                    AttributeTypes::String("1234@s.whatsapp.net".to_string()),
+                ),
+                (
+                    "id".to_string(),
+                    AttributeTypes::JID(JID::new("1234", DEFAULT_USER_SERVER)),
+                ),
+                (
+                    "participant".to_string(),
+                    AttributeTypes::String("not a jid".to_string()),
+                ),
+            ]),
+            content: NodeContentType::None,
+        };
+
+        let mut strict = node.attr_getter();
+        assert_eq!(strict.jid("from"), None);
+        assert_eq!(strict.string("id"), None);
+        assert_eq!(strict.errors.len(), 2);
+        assert!(strict.warnings.is_empty());
+
+        let mut coercing = node.attr_getter().coerce(true);
+        assert_eq!(
+            coercing.jid("from"),
+            Some(JID::new("1234", DEFAULT_USER_SERVER))
+        );
+        assert_eq!(coercing.string("id").unwrap(), "1234@s.whatsapp.net");
+        assert!(coercing.ok());
+        assert_eq!(coercing.warnings.len(), 2);
+
+        // Strings that aren't JIDs are still errors.
+        assert_eq!(coercing.jid("participant"), None);
+        assert!(!coercing.ok());
+    }
+
+    #[test]
+    fn test_read_duplicate_attribute() {
+        // <iq type="get" type="set" />, which can't be built from a `Node` as `Attrs` is a
+        // map, so the list is written by hand.
+        let mut encoder = BinaryEncoder::new();
+        encoder.write_list_start(5);
+        for string in ["iq", "type", "get", "type", "set"] {
+            encoder.write_string(string).unwrap();
+        }
+        let data = encoder.get_data()[1..].to_vec();
+
+        let mut lenient = BinaryDecoder::new(&data);
+        let node = lenient.read_node().unwrap();
+        assert_eq!(node.attr_getter().string("type").unwrap(), "set");
+        assert_eq!(lenient.warnings().len(), 1);
+        assert!(lenient.warnings()[0]
+            .to_string()
+            .contains(&DecoderError::ErrDuplicateAttribute.to_string()));
+
+        let mut strict = BinaryDecoder::new(&data).with_strict_attributes(true);
+        let err = strict.read_node().unwrap_err();
+        assert!(err
+            .to_string()
+            .contains(&DecoderError::ErrDuplicateAttribute.to_string()));
+    }
+
+    #[test]
+    fn test_empty_list_content_round_trip() {
+        let empty_list = list_node("iq", vec![]);
+        let no_content = Node {
+            tag: "iq".to_string(),
+            attrs: Attrs::new(),
+            content: NodeContentType::None,
+        };
+        assert_eq!(encode(&empty_list), encode(&no_content));
+
+        let decoded = BinaryDecoder::new(&encode(&empty_list))
+            .read_node()
+            .unwrap();
+        assert_eq!(decoded, no_content);
+    }
+
+    #[test]
+    fn test_decode_empty_list_content() {
+        // <iq> whose content is a LIST8 of size 0 rather than LIST_EMPTY.
+        let iq = token::index_of_single_token("iq").unwrap();
+        let bytes = vec![token::LIST8, 2, iq, token::LIST8, 0];
+        let decoded = BinaryDecoder::new(&bytes).read_node().unwrap();
+        assert_eq!(decoded.tag, "iq");
+        assert_eq!(decoded.content, NodeContentType::None);
+    }
+
+    #[test]
+    fn test_malformed_binary32_length() {
+        // A length with the sign bit set.
+        let negative = vec![token::BINARY32, 0xFF, 0xFF, 0xFF, 0xFE, 1, 2];
+        assert!(BinaryDecoder::new(&negative).read(false).is_err());
+        // A length far beyond the end of the frame.
+        let too_long = vec![token::BINARY32, 0x7F, 0xFF, 0xFF, 0xFF, 1, 2];
+        assert!(BinaryDecoder::new(&too_long).read(false).is_err());
+
+        let mut decoder = BinaryDecoder::new(&negative);
+        decoder.index = usize::MAX;
+        assert!(decoder.check_eos(1).is_err());
+    }
+
+    #[test]
+    fn test_children_by_tags() {
+        let child = |tag: &str, id: &str| Node {
+            tag: tag.to_string(),
+            attrs: Attrs::from([("id".to_string(), AttributeTypes::String(id.to_string()))]),
+            content: NodeContentType::None,
+        };
+        let group = Node {
+            tag: "group".to_string(),
+            attrs: Attrs::new(),
+            content: NodeContentType::ListOfNodes(vec![
+                child("participant", "1"),
+                child("description", "2"),
+                child("announcement", "3"),
+                child("participant", "4"),
+                child("locked", "5"),
+                child("participant", "6"),
+            ]),
+        };
+
+        let grouped =
+            group.children_by_tags(&["participant", "description", "announcement", "ephemeral"]);
+        assert_eq!(grouped.len(), 3);
+        let ids = |tag: &str| {
+            grouped[tag]
+                .iter()
+                .map(|node| node.attr_getter().string("id").unwrap())
+                .collect::<Vec<String>>()
+        };
+        assert_eq!(ids("participant"), vec!["1", "4", "6"]);
+        assert_eq!(ids("description"), vec!["2"]);
+        assert_eq!(ids("announcement"), vec!["3"]);
+        assert!(!grouped.contains_key("locked"));
+        assert!(!grouped.contains_key("ephemeral"));
+
+        assert!(child("group", "7")
+            .children_by_tags(&["participant"])
+            .is_empty());
+    }
+
+    #[test]
+    fn test_take_content() {
+        let mut enc = Node {
+            tag: "enc".to_string(),
+            attrs: Attrs::new(),
+            content: NodeContentType::ByteArray(vec![1, 2, 3]),
+        };
+        assert_eq!(
+            enc.take_content(),
+            NodeContentType::ByteArray(vec![1, 2, 3])
+        );
+        assert_eq!(enc.content, NodeContentType::None);
+    }
+
+    #[test]
+    fn test_take_child_by_tag() {
+        let mut node = sample_node();
+        let ping = node.take_child_by_tag("ping").unwrap();
+        assert_eq!(ping.tag, "ping");
+        assert_eq!(node.get_children().unwrap().len(), 0);
+        assert!(node.take_child_by_tag("ping").is_none());
+    }
+
+    #[test]
+    fn test_malformed_packed_data() {
+        // 0xC is not a valid nibble.
+        let invalid_nibble = [token::NIBBLE8, 0x01, 0x1C];
+        assert!(BinaryDecoder::new(&invalid_nibble.to_vec())
+            .read(true)
+            .is_err());
+        // The odd length flag without any packed bytes.
+        let empty_odd = [token::HEX8, 0x80];
+        assert!(BinaryDecoder::new(&empty_odd.to_vec()).read(true).is_err());
+        // The length says 3 bytes, but only 1 follows.
+        let truncated = [token::HEX8, 0x03, 0xAB];
+        assert!(BinaryDecoder::new(&truncated.to_vec()).read(true).is_err());
+
+        let valid = [token::HEX8, 0x82, 0xAB, 0xCF];
+        assert!(matches!(
+            BinaryDecoder::new(&valid.to_vec()).read(true).unwrap(),
+            NodeContentType::String(s) if s == "ABC"
+        ));
+    }
+
+    #[test]
+    fn test_packed_too_large() {
+        // The length says 3 bytes, but only 1 follows.
+        let truncated = [token::HEX8, 0x03, 0xAB];
+        let err = BinaryDecoder::new(&truncated.to_vec())
+            .read(true)
+            .unwrap_err()
+            .to_string();
+        assert!(
+            err.contains(&DecoderError::ErrPackedTooLarge.to_string()),
+            "{err}"
+        );
+
+        // The largest length with nothing following it.
+        let truncated = [token::NIBBLE8, 0x7F];
+        let err = BinaryDecoder::new(&truncated.to_vec())
+            .read(true)
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("127 bytes, but only 0 remaining"), "{err}");
+    }
+
+    #[test]
+    fn test_pack_invalid_characters() {
+        assert!(BinaryEncoder::pack_nibble(b'x').is_err());
+        assert!(BinaryEncoder::pack_hex(b'g').is_err());
+        assert!(BinaryEncoder::pack_string("12a4", token::NIBBLE8).is_err());
+        assert!(BinaryEncoder::pack_string("12", token::LIST8).is_err());
+        assert_eq!(
+            BinaryEncoder::pack_string("1-2", token::NIBBLE8).unwrap(),
+            vec![0x82, 0x1A, 0x2F]
+        );
+        assert_eq!(
+            BinaryEncoder::pack_string("ab", token::HEX8).unwrap(),
+            vec![0x01, 0xAB]
+        );
+    }
+
+    #[test]
+    fn test_write_oversized_inputs() {
+        let mut encoder = BinaryEncoder::new();
+        assert!(encoder.write_byte_length(i32::MAX as usize).is_err());
+        assert!(encoder.write_byte_length(usize::MAX).is_err());
+        // Lengths above u32::MAX must not wrap around into small lengths.
+        assert!(encoder.write_byte_length((1 << 32) + 5).is_err());
+        assert!(encoder.write_byte_length(1 << 20).is_ok());
+
+        let too_long = "1".repeat(token::PACKED_MAX + 1);
+        assert!(encoder
+            .write_packed_bytes(&too_long, token::NIBBLE8)
+            .is_err());
+        assert!(encoder.write_packed_bytes("12a", token::NIBBLE8).is_err());
+        assert!(encoder
+            .write_packed_bytes(&too_long[1..], token::NIBBLE8)
+            .is_ok());
+
+        // Strings too long to be packed are written raw instead.
+        let node = Node {
+            tag: "iq".to_string(),
+            attrs: Attrs::from([("id".to_string(), AttributeTypes::String(too_long))]),
+            content: NodeContentType::None,
+        };
+        assert!(marshal(&node).is_ok());
+    }
+
+    #[test]
+    fn test_is_error() {
+        let error_child = Node {
+            tag: "error".to_string(),
+            attrs: Attrs::from([
+                (
+                    "code".to_string(),
+                    AttributeTypes::String("404".to_string()),
+                ),
+                (
+                    "text".to_string(),
+                    AttributeTypes::String("item-not-found".to_string()),
+                ),
+            ]),
+            content: NodeContentType::None,
+        };
+
+        let error_typed = Node {
+            tag: "iq".to_string(),
+            attrs: Attrs::from([
+                ("id".to_string(), AttributeTypes::String("1".to_string())),
+                (
+                    "type".to_string(),
+                    AttributeTypes::String("error".to_string()),
+                ),
+            ]),
+            content: NodeContentType::ListOfNodes(vec![error_child.clone()]),
+        };
+        assert!(error_typed.is_error());
+        assert_eq!(error_typed.error_code(), Some(404));
+
+        let with_error_child = Node {
+            tag: "ack".to_string(),
+            attrs: Attrs::from([(
+                "class".to_string(),
+                AttributeTypes::String("message".to_string()),
+            )]),
+            content: NodeContentType::ListOfNodes(vec![error_child]),
+        };
+        assert!(with_error_child.is_error());
+        assert_eq!(with_error_child.error_code(), Some(404));
+
+        let without_code = Node {
+            tag: "iq".to_string(),
+            attrs: Attrs::from([(
+                "type".to_string(),
+                AttributeTypes::String("error".to_string()),
+            )]),
+            content: NodeContentType::None,
+        };
+        assert!(without_code.is_error());
+        assert_eq!(without_code.error_code(), None);
+
+        let success = Node {
+            tag: "iq".to_string(),
+            attrs: Attrs::from([(
+                "type".to_string(),
+                AttributeTypes::String("result".to_string()),
+            )]),
+            content: NodeContentType::ListOfNodes(vec![Node {
+                tag: "ping".to_string(),
+                ..Default::default()
+            }]),
+        };
+        assert!(!success.is_error());
+        assert_eq!(success.error_code(), None);
+    }
+}
@@ -1374,3 +1374,6 @@ pub const BINARY32: u8 = 254;
 pub const NIBBLE8: u8 = 255;
 
 pub const PACKED_MAX: usize = 127;
+
+/// The domain type byte of an `ADJID` for JIDs on the hidden user (`lid`) server.
+pub const AD_JID_DOMAIN_LID: u8 = 1;
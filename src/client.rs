@@ -0,0 +1,2918 @@
+//! `client` contains the `Client`, which is the entry point for applications: it owns the
+//! connection to the WhatsApp servers and the queue that received events are delivered
+//! through.
+
+use std::{
+    collections::HashMap,
+    future::Future,
+    panic::{self, AssertUnwindSafe},
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        mpsc::{self, Receiver, Sender},
+        Arc, Condvar, Mutex, Once, Weak,
+    },
+    task::{Context, Poll, Waker},
+    thread,
+    time::Duration,
+};
+
+use libsignal_protocol::KeyPair;
+use protobuf::Message as _;
+use rand::Rng;
+use time::OffsetDateTime;
+
+use crate::{
+    appstate::{
+        app_state_query, apply_mutation, decode_patches, parse_patch_list,
+        parse_server_sync_notification, HashState, PatchName,
+    },
+    binary::{marshal, proto as wa_proto, unmarshal, AttributeTypes, Node, NodeContentType},
+    dispatch::node_to_event,
+    encryption::{
+        build_sender_key_distribution_message, devices_without_session, encrypt_for_device,
+        encrypt_for_devices, encrypt_group_message, own_sender_key_distribution,
+        process_prekey_bundle,
+    },
+    event_handlers::{EventHandlers, EventKind, Handler, HandlerId},
+    event_queue::{EventQueue, EventQueueConfig},
+    group::{build_get_group_info_node, parse_group_info, parse_group_participants},
+    keepalive::{build_keepalive_node, KeepAliveConfig, KeepAliveTracker},
+    media::{
+        download_from_hosts, download_from_url, media_conn_query, parse_media_conn, upload,
+        DownloadableMessage, MediaConn, MediaType, UploadResponse,
+    },
+    message::decrypt_message,
+    new_rhustapp_error,
+    pair::{
+        build_pair_error_node, finish_pairing, make_qr_data, parse_pair_device_refs,
+        parse_pairing_ref, PairSuccessInfo, PhoneLinking,
+    },
+    prekeys::{
+        build_get_prekeys_node, build_retry_keys_node, parse_prekey_bundles,
+        parse_retry_prekey_bundle,
+    },
+    receipt::{
+        build_delivery_receipt_node, build_read_receipt_node, build_receipt_ack_node,
+        build_retry_receipt_node, parse_retry_receipt, MAX_RETRY_COUNT,
+    },
+    reconnect::ReconnectConfig,
+    request::{build_iq_result_node, iq_error_from_node, InfoQuery},
+    send::{
+        build_device_identity_node, build_device_sent_message, build_dm_participants,
+        build_message_node, build_resent_message_node, build_revoke, edit_attribute,
+        generate_message_id, message_type, parse_message_ack, participant_list_hash,
+        RecentMessages, SendResponse,
+    },
+    socket::{ConnectionState, FrameSocket, NoiseHandshake, NoiseSocket, SocketError},
+    store::{memory::MemoryStore, public_key_bytes, AppStateSyncKey, Device, DeviceStore},
+    types::{
+        events::{
+            Message, PairError, PairSuccess, PushNameUpdated, Receipt, RhustAppEventType, QR,
+        },
+        ContactInfo, GroupInfo, IsOnWhatsAppResponse, MessageID, MessageInfo, UserInfo,
+        DEFAULT_USER_SERVER, GROUP_SERVER, JID,
+    },
+    usync::{
+        build_usync_devices_query, build_usync_query, build_usync_user_info_query,
+        parse_is_on_whatsapp, parse_usync_devices, parse_usync_user_info, UsyncContext,
+    },
+    ErrorKind, RhustAppError,
+};
+
+/// How long to wait for the server to respond to the client hello.
+pub const HANDSHAKE_RESPONSE_TIMEOUT: Duration = Duration::from_secs(20);
+/// How long to wait for the response to an `<iq>` request, or for the server to acknowledge
+/// a sent message.
+pub const REQUEST_TIMEOUT: Duration = Duration::from_secs(75);
+
+/// The connection state of the socket, shared with its state change handler so that it can
+/// be read and waited on without locking the socket.
+type SharedState = Arc<(Mutex<ConnectionState>, Condvar)>;
+
+pub struct Client {
+    /// The client itself, set by `connect`, so that the connection can be replaced and its
+    /// receive thread started from methods that don't take an `Arc`.
+    this: Mutex<Weak<Client>>,
+    socket: Mutex<FrameSocket>,
+    /// Encrypts and decrypts the frames of the current connection, set once the handshake
+    /// is done. It is always locked before `socket`, so that frames are sent in the order
+    /// they were encrypted.
+    noise: Mutex<Option<NoiseSocket>>,
+    /// Incremented for every connection, so that the receive thread of a closed connection
+    /// can't close a newer one.
+    connection_id: AtomicU64,
+    state: SharedState,
+    events: Arc<EventQueue>,
+    /// The handlers that the events of the queue are passed to, once any are added.
+    handlers: Arc<EventHandlers>,
+    /// Starts the thread that passes the events to the handlers.
+    start_dispatcher: Once,
+    device: Mutex<Device>,
+    /// Persists the device once it's paired, along with its sessions and keys.
+    store: Arc<dyn DeviceStore>,
+    /// The prefix of the ids of the requests sent by this client, followed by a counter.
+    unique_id: String,
+    id_counter: AtomicU64,
+    /// The senders of the requests waiting for a response (or of the messages waiting for
+    /// their ack), by id.
+    response_waiters: Mutex<HashMap<String, Sender<Node>>>,
+    /// The pairing with a phone number started by `pair_phone`.
+    phone_linking: Mutex<Option<PhoneLinking>>,
+    keepalive: KeepAliveConfig,
+    /// The delays between the attempts to reconnect when the server asks for a restart.
+    reconnect: ReconnectConfig,
+    /// Set when the server asks the client to reconnect (stream error 515), until the
+    /// client has reconnected or `disconnect` is called.
+    restart_requested: AtomicBool,
+    /// Whether a send that fails with a transient write error is retried on a new
+    /// connection, see `with_send_retry`.
+    send_retry: bool,
+    /// Whether incoming messages are acknowledged to their sender with a delivery receipt.
+    delivery_receipts: bool,
+    /// The media hosts fetched by the last transfer, reused until they expire.
+    media_conn: Mutex<Option<MediaConn>>,
+    /// The sent messages that are sent again if a recipient can't decrypt them.
+    recent_messages: Mutex<RecentMessages>,
+    /// How many retry receipts were sent for the messages that couldn't be decrypted, by ID.
+    incoming_retries: Mutex<HashMap<String, u32>>,
+}
+
+impl Default for Client {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Client {
+    pub fn new() -> Self {
+        let state = Arc::new((Mutex::new(ConnectionState::Closed), Condvar::new()));
+        Self {
+            this: Mutex::new(Weak::new()),
+            socket: Mutex::new(Self::track_state(FrameSocket::new(), &state)),
+            noise: Mutex::new(None),
+            connection_id: AtomicU64::new(0),
+            state,
+            events: Arc::new(EventQueue::new(EventQueueConfig::default())),
+            handlers: Arc::new(EventHandlers::default()),
+            start_dispatcher: Once::new(),
+            device: Mutex::new(Device::new()),
+            store: Arc::new(MemoryStore::new()),
+            unique_id: {
+                let mut rng = rand::thread_rng();
+                format!("{}.{}-", rng.gen::<u8>(), rng.gen::<u8>())
+            },
+            id_counter: AtomicU64::new(0),
+            response_waiters: Mutex::new(HashMap::new()),
+            phone_linking: Mutex::new(None),
+            keepalive: KeepAliveConfig::default(),
+            reconnect: ReconnectConfig::default(),
+            restart_requested: AtomicBool::new(false),
+            send_retry: false,
+            delivery_receipts: true,
+            media_conn: Mutex::new(None),
+            recent_messages: Mutex::new(RecentMessages::default()),
+            incoming_retries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Sets the socket to connect with, e.g. one with a custom URL or TLS connector. The
+    /// state change handler of the socket is replaced, as the client uses it to track the
+    /// connection state.
+    pub fn with_socket(mut self, socket: FrameSocket) -> Self {
+        self.socket = Mutex::new(Self::track_state(socket, &self.state));
+        self
+    }
+
+    /// Sets the configuration of the queue that events are delivered through.
+    pub fn with_event_queue_config(mut self, config: EventQueueConfig) -> Self {
+        self.events = Arc::new(EventQueue::new(config));
+        self
+    }
+
+    /// Sets the configuration of the keepalive pings, which are sent once the client is
+    /// logged in.
+    pub fn with_keepalive_config(mut self, config: KeepAliveConfig) -> Self {
+        self.keepalive = config;
+        self
+    }
+
+    /// Sets the delays between the attempts to reconnect when the server asks the client to
+    /// restart the connection, e.g. right after pairing.
+    pub fn with_reconnect_config(mut self, config: ReconnectConfig) -> Self {
+        self.reconnect = config;
+        self
+    }
+
+    /// Sets whether sending is retried when it fails with a transient
+    /// `SocketError::WriteFailed`. When enabled, the client reconnects right away, does the
+    /// handshake again and sends the node once more, encrypted for the new connection,
+    /// before the error is returned. Other errors, like `SocketError::FrameTooLarge`, are
+    /// never retried. Retrying is disabled by default.
+    pub fn with_send_retry(mut self, enabled: bool) -> Self {
+        self.send_retry = enabled;
+        self
+    }
+
+    /// Sets whether a delivery receipt is sent for every incoming message that is decrypted,
+    /// which is how the sender's check marks turn gray. Enabled by default.
+    pub fn with_delivery_receipts(mut self, enabled: bool) -> Self {
+        self.delivery_receipts = enabled;
+        self
+    }
+
+    /// Sets the device to connect as. By default, a new device is generated, which has to
+    /// be paired by scanning the QR codes of the `QR` event.
+    pub fn with_device(mut self, device: Device) -> Self {
+        self.device = Mutex::new(device);
+        self
+    }
+
+    /// Sets the store that the device is saved to once it's paired, along with the Signal
+    /// sessions and keys. If the store already holds a device, the client connects as that
+    /// device instead of pairing a new one. By default, an in-memory store is used, which
+    /// loses everything when the client is dropped.
+    pub fn with_store(mut self, store: Arc<dyn DeviceStore>) -> Result<Self, RhustAppError> {
+        if let Some(device) = store.load_device()? {
+            self.device = Mutex::new(device);
+        };
+        self.store = store;
+        Ok(self)
+    }
+
+    fn track_state(socket: FrameSocket, state: &SharedState) -> FrameSocket {
+        let state = Arc::clone(state);
+        socket.with_state_change_handler(Box::new(move |_, new| {
+            let (current, changed) = &*state;
+            *current.lock().unwrap() = new;
+            changed.notify_all();
+        }))
+    }
+
+    /// Returns the queue that events are delivered through. The application should keep
+    /// consuming it (e.g. from a separate thread), see `EventQueueConfig`, unless it adds
+    /// event handlers instead.
+    pub fn events(&self) -> Arc<EventQueue> {
+        Arc::clone(&self.events)
+    }
+
+    /// Adds a function that every event is passed to, returning the ID with which it can be
+    /// removed again. Handlers are called in the order they were added.
+    ///
+    /// Once a handler (or a subscription) is added, the client consumes the event queue
+    /// itself on a separate thread, so the application must not pop from `events()` too.
+    /// A slow handler holds up the following events, like a slow consumer of the queue.
+    pub fn add_event_handler(
+        &self,
+        handler: impl Fn(RhustAppEventType) + Send + Sync + 'static,
+    ) -> HandlerId {
+        self.add_handler(Arc::new(move |event| {
+            handler(event.clone());
+            true
+        }))
+    }
+
+    /// Removes an event handler, returning false if it was already removed.
+    pub fn remove_event_handler(&self, id: HandlerId) -> bool {
+        self.handlers.remove(id)
+    }
+
+    /// Subscribes to a single kind of event, e.g. `client.subscribe::<events::Message>()`,
+    /// returning the receiver that the events of that kind are sent to. The subscription
+    /// ends when the receiver is dropped. Like `add_event_handler`, this makes the client
+    /// consume the event queue itself.
+    pub fn subscribe<T: EventKind>(&self) -> Receiver<T> {
+        let (sender, receiver) = mpsc::channel();
+        self.add_handler(Arc::new(move |event| match T::from_event(event) {
+            Some(data) => sender.send(data).is_ok(),
+            None => true,
+        }));
+        receiver
+    }
+
+    fn add_handler(&self, handler: Handler) -> HandlerId {
+        let id = self.handlers.add(handler);
+        self.start_dispatcher.call_once(|| {
+            let events = Arc::clone(&self.events);
+            let handlers = Arc::clone(&self.handlers);
+            thread::spawn(move || {
+                while let Some(event) = events.pop() {
+                    handlers.dispatch(&event);
+                }
+            });
+        });
+        id
+    }
+
+    /// Connects to the WhatsApp servers and does the Noise handshake, which logs in as the
+    /// device, or registers it if it isn't paired yet (then `QR` events are emitted).
+    ///
+    /// The received nodes are handled on a separate thread until the connection is closed.
+    /// If the server asks for a restart, e.g. right after pairing, that thread reconnects.
+    pub fn connect(self: &Arc<Self>) -> Result<(), RhustAppError> {
+        *self.this.lock().unwrap() = Arc::downgrade(self);
+        let (connection_id, frames) = self.open_connection()?;
+        self.spawn_receive_loop(connection_id, frames);
+        Ok(())
+    }
+
+    fn spawn_receive_loop(&self, connection_id: u64, frames: Receiver<Vec<u8>>) {
+        let client = self.this.lock().unwrap().clone();
+        thread::spawn(move || Self::receive_loop(client, connection_id, frames));
+    }
+
+    /// Connects the socket and does the handshake, returning the id of the new connection
+    /// and the frames received on it.
+    fn open_connection(&self) -> Result<(u64, Receiver<Vec<u8>>), RhustAppError> {
+        let mut noise = self.noise.lock().unwrap();
+        let mut socket = self.socket.lock().unwrap();
+        socket.connect()?;
+
+        let frames = match self.handshake(&mut socket) {
+            Ok((noise_socket, frames)) => {
+                *noise = Some(noise_socket);
+                frames
+            }
+            Err(err) => {
+                socket.close(0);
+                return Err(err);
+            }
+        };
+        socket.handshake_complete()?;
+
+        let connection_id = self.connection_id.fetch_add(1, Ordering::SeqCst) + 1;
+        Ok((connection_id, frames))
+    }
+
+    /// Does the client side of the handshake on the freshly connected socket, returning the
+    /// keys of the connection and the frames received after the handshake.
+    fn handshake(
+        &self,
+        socket: &mut FrameSocket,
+    ) -> Result<(NoiseSocket, Receiver<Vec<u8>>), RhustAppError> {
+        let frames = socket
+            .frames()
+            .ok_or_else(|| new_rhustapp_error("socket has no read pump", None))?;
+        let header = socket
+            .header
+            .map(|header| header.to_vec())
+            .unwrap_or_default();
+        let ephemeral = KeyPair::generate(&mut rand::rngs::OsRng);
+
+        let (mut handshake, hello) = NoiseHandshake::client_hello(&header, &ephemeral)?;
+        socket.send_frame(&hello)?;
+        let response = frames
+            .recv_timeout(HANDSHAKE_RESPONSE_TIMEOUT)
+            .map_err(|err| {
+                new_rhustapp_error(
+                    "failed to receive handshake response",
+                    Some(err.to_string()),
+                )
+            })?;
+        let server_ephemeral = handshake.process_server_hello(&ephemeral, &response)?;
+
+        let finish = {
+            let device = self.device.lock().unwrap();
+            let payload = device.client_payload()?.write_to_bytes().map_err(|err| {
+                new_rhustapp_error("failed to marshal client payload", Some(err.to_string()))
+            })?;
+            handshake.client_finish(&device.noise_key, &server_ephemeral, &payload)?
+        };
+        socket.send_frame(&finish)?;
+
+        Ok((handshake.finish(), frames))
+    }
+
+    /// Disconnects the websocket. This does nothing if the client isn't connected. A
+    /// pending reconnect is cancelled, and no `Disconnected` event is emitted.
+    pub fn disconnect(&self) {
+        let mut noise = self.noise.lock().unwrap();
+        self.restart_requested.store(false, Ordering::SeqCst);
+        // The receive thread of the connection sees that it was closed on purpose.
+        self.connection_id.fetch_add(1, Ordering::SeqCst);
+        self.socket.lock().unwrap().close(1000);
+        *noise = None;
+        self.cancel_requests();
+    }
+
+    /// Fails the requests that are waiting for a response, as it won't arrive once the
+    /// connection is closed.
+    fn cancel_requests(&self) {
+        self.response_waiters.lock().unwrap().clear();
+    }
+
+    /// Returns true if the websocket is connected, even if the handshake isn't complete yet.
+    pub fn is_connected(&self) -> bool {
+        matches!(
+            *self.state.0.lock().unwrap(),
+            ConnectionState::Handshaking | ConnectionState::Connected
+        )
+    }
+
+    /// Blocks until the handshake is complete and the client can send, or the timeout
+    /// passes. Returns true if the client is connected.
+    pub fn wait_for_connection(&self, timeout: Duration) -> bool {
+        let (current, changed) = &*self.state;
+        let (state, _) = changed
+            .wait_timeout_while(current.lock().unwrap(), timeout, |state| {
+                *state != ConnectionState::Connected
+            })
+            .unwrap();
+        *state == ConnectionState::Connected
+    }
+
+    /// Like `connect`, but returns a future instead of blocking, so that the client can be
+    /// driven from async code. The connection is made on a separate thread, so the future
+    /// works with any executor.
+    pub fn connect_async(self: &Arc<Self>) -> impl Future<Output = Result<(), RhustAppError>> {
+        let client = Arc::clone(self);
+        run_blocking(move || client.connect())
+    }
+
+    /// Like `disconnect`, but returns a future that completes once the websocket is closed.
+    pub fn disconnect_async(self: &Arc<Self>) -> impl Future<Output = ()> {
+        let client = Arc::clone(self);
+        run_blocking(move || client.disconnect())
+    }
+
+    /// Like `wait_for_connection`, but returns a future that resolves to whether the client
+    /// is connected instead of blocking.
+    pub fn wait_for_connection_async(
+        self: &Arc<Self>,
+        timeout: Duration,
+    ) -> impl Future<Output = bool> {
+        let client = Arc::clone(self);
+        run_blocking(move || client.wait_for_connection(timeout))
+    }
+
+    /// Encrypts and sends a node to the server. See `with_send_retry` for how transient
+    /// write errors are handled.
+    pub fn send_node(&self, node: &Node) -> Result<(), RhustAppError> {
+        let data = marshal(node)?;
+        match self.send_data(&data) {
+            Err(err)
+                if self.send_retry
+                    && *err.kind() == ErrorKind::Socket(SocketError::WriteFailed) =>
+            {
+                log::warn!("failed to send node, reconnecting to retry: {err}");
+                self.replace_connection()?;
+                self.send_data(&data)
+            }
+            result => result,
+        }
+    }
+
+    /// Encrypts the marshaled node for the current connection and sends it.
+    fn send_data(&self, data: &[u8]) -> Result<(), RhustAppError> {
+        let mut noise = self.noise.lock().unwrap();
+        let noise = noise.as_mut().ok_or_else(|| {
+            new_rhustapp_error(
+                "failed to send node",
+                Some("client is not connected".to_string()),
+            )
+            .with_kind(ErrorKind::Socket(SocketError::SocketClosed))
+        })?;
+        let frame = noise.encrypt_frame(data)?;
+        self.socket.lock().unwrap().send_frame(&frame)
+    }
+
+    /// Closes the current connection and opens a new one right away, whose frames are
+    /// handled by a new receive thread. The requests waiting for a response keep waiting.
+    /// If reconnecting fails, the client is left disconnected and `Disconnected` is emitted.
+    fn replace_connection(&self) -> Result<(), RhustAppError> {
+        {
+            let mut noise = self.noise.lock().unwrap();
+            // The receive thread of the old connection sees that it was replaced.
+            self.connection_id.fetch_add(1, Ordering::SeqCst);
+            self.socket.lock().unwrap().close(0);
+            *noise = None;
+        }
+        match self.open_connection() {
+            Ok((connection_id, frames)) => {
+                self.spawn_receive_loop(connection_id, frames);
+                Ok(())
+            }
+            Err(err) => {
+                self.cancel_requests();
+                self.events.push(RhustAppEventType::Disconnected);
+                Err(err)
+            }
+        }
+    }
+
+    /// Sends an `<iq>` request and blocks until the server responds to it, or its timeout
+    /// passes. Error responses are returned as errors of kind `ErrorKind::Iq`.
+    pub fn send_iq(&self, query: InfoQuery) -> Result<Node, RhustAppError> {
+        let id = self.generate_request_id();
+        self.send_request_with_id(
+            query.to_node(&id),
+            &id,
+            query.timeout.unwrap_or(REQUEST_TIMEOUT),
+        )
+    }
+
+    /// Same as `send_iq`, but doesn't block: the response (or the error) is delivered
+    /// through the returned channel. The client is kept alive until the request is done.
+    pub fn send_iq_async(
+        self: &Arc<Self>,
+        query: InfoQuery,
+    ) -> Receiver<Result<Node, RhustAppError>> {
+        let (sender, receiver) = mpsc::channel();
+        let client = Arc::clone(self);
+        thread::spawn(move || {
+            // The caller may not care about the response.
+            let _ = sender.send(client.send_iq(query));
+        });
+        receiver
+    }
+
+    /// Sends an `<iq>` request with a new id and blocks until the server responds to it, or
+    /// `REQUEST_TIMEOUT` passes. Error responses are returned as errors of kind
+    /// `ErrorKind::Iq`.
+    fn send_request(&self, request: Node) -> Result<Node, RhustAppError> {
+        self.send_request_timeout(request, REQUEST_TIMEOUT)
+    }
+
+    /// Same as `send_request`, but waits for the response for the given time instead.
+    fn send_request_timeout(
+        &self,
+        mut request: Node,
+        timeout: Duration,
+    ) -> Result<Node, RhustAppError> {
+        let id = self.generate_request_id();
+        request
+            .attrs
+            .insert("id".to_string(), AttributeTypes::String(id.clone()));
+        self.send_request_with_id(request, &id, timeout)
+    }
+
+    fn send_request_with_id(
+        &self,
+        request: Node,
+        id: &str,
+        timeout: Duration,
+    ) -> Result<Node, RhustAppError> {
+        let response = self.send_and_wait(&request, id, timeout)?;
+        match iq_error_from_node(&response) {
+            Some(err) => Err(err),
+            None => Ok(response),
+        }
+    }
+
+    /// Returns a new id for a request, unique within the session.
+    fn generate_request_id(&self) -> String {
+        format!(
+            "{}{}",
+            self.unique_id,
+            self.id_counter.fetch_add(1, Ordering::SeqCst)
+        )
+    }
+
+    /// Sends a node and blocks until the `<iq>` response or the `<ack>` with the given id is
+    /// received, or the timeout passes.
+    fn send_and_wait(
+        &self,
+        node: &Node,
+        id: &str,
+        timeout: Duration,
+    ) -> Result<Node, RhustAppError> {
+        let (sender, receiver) = mpsc::channel();
+        self.response_waiters
+            .lock()
+            .unwrap()
+            .insert(id.to_string(), sender);
+        let response = self.send_node(node).and_then(|_| {
+            receiver.recv_timeout(timeout).map_err(|err| {
+                new_rhustapp_error("failed to receive response", Some(err.to_string()))
+            })
+        });
+        self.response_waiters.lock().unwrap().remove(id);
+        response
+    }
+
+    /// Passes the response to the request waiting for it, returning false if there is none.
+    fn receive_response(&self, node: &Node) -> bool {
+        let mut ag = node.attr_getter();
+        let is_response = node.tag == "ack"
+            || matches!(
+                ag.optional_string("type").as_deref(),
+                Some("result") | Some("error")
+            );
+        let waiter = match ag.optional_string("id") {
+            Some(id) if is_response => self.response_waiters.lock().unwrap().remove(&id),
+            _ => None,
+        };
+
+        match waiter {
+            Some(waiter) => {
+                // The request may have timed out in the meantime.
+                let _ = waiter.send(node.clone());
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Starts pairing with a phone number instead of a QR code, returning the pairing code
+    /// (formatted as `XXXX-XXXX`) that the user has to enter on the phone. The client must be
+    /// connected, and `PairSuccess` is emitted once pairing is done like with the QR codes.
+    ///
+    /// If `show_push_notification` is true, the phone shows a notification prompting the
+    /// user to enter the code.
+    pub fn pair_phone(
+        &self,
+        phone: &str,
+        show_push_notification: bool,
+    ) -> Result<String, RhustAppError> {
+        let mut linking = PhoneLinking::new(phone);
+        let hello = linking
+            .build_companion_hello_node(&self.device.lock().unwrap(), show_push_notification)?;
+        let response = self.send_request(hello)?;
+        linking.pairing_ref = parse_pairing_ref(&response)?;
+
+        let code = linking.display_code();
+        *self.phone_linking.lock().unwrap() = Some(linking);
+        Ok(code)
+    }
+
+    /// Sends a message to a user or a group and blocks until the server acknowledges it.
+    ///
+    /// The message is encrypted for every device of the recipient (or of every participant
+    /// of the group) and for the user's own other devices, so the device lists are fetched
+    /// first, along with the prekeys of the devices that there's no session with yet.
+    pub fn send_message(
+        &self,
+        to: &JID,
+        message: &wa_proto::Message,
+    ) -> Result<SendResponse, RhustAppError> {
+        to.is_sendable()?;
+        let own_id = self.device.lock().unwrap().id.clone().ok_or_else(|| {
+            new_rhustapp_error(
+                "failed to send message",
+                Some("device is not paired".to_string()),
+            )
+        })?;
+
+        let id = generate_message_id();
+        let mut node = match to.server.as_str() {
+            DEFAULT_USER_SERVER => self.prepare_dm(&to.to_non_ad(), &own_id, &id, message)?,
+            GROUP_SERVER => self.prepare_group_message(to, &own_id, &id, message)?,
+            server => {
+                return Err(new_rhustapp_error(
+                    &format!("can't send to '{to}'"),
+                    Some(format!("sending to {server} is not supported")),
+                ))
+            }
+        };
+        if let Some(edit) = edit_attribute(message) {
+            node.attrs
+                .insert("edit".to_string(), AttributeTypes::String(edit.to_string()));
+        };
+
+        self.recent_messages.lock().unwrap().add(to, &id, message);
+        let ack = self.send_and_wait(&node, &id, REQUEST_TIMEOUT)?;
+        parse_message_ack(&ack)
+    }
+
+    /// Revokes (deletes for everyone) a previously sent message in `chat`, see
+    /// `build_revoke`. `participant` is the sender of the message, which is only needed
+    /// when revoking someone else's message in a group as an admin.
+    pub fn revoke_message(
+        &self,
+        chat: &JID,
+        message_id: &str,
+        from_me: bool,
+        participant: Option<&JID>,
+    ) -> Result<SendResponse, RhustAppError> {
+        if message_id.is_empty() {
+            return Err(new_rhustapp_error(
+                "failed to revoke message",
+                Some("message id to revoke is empty".to_string()),
+            ));
+        };
+        self.send_message(chat, &build_revoke(chat, message_id, from_me, participant))
+    }
+
+    /// Builds the `<message>` stanza of a direct message. The devices of the recipient get
+    /// the message itself, and our own other devices get it wrapped in a `DeviceSentMessage`.
+    fn prepare_dm(
+        &self,
+        to: &JID,
+        own_id: &JID,
+        id: &str,
+        message: &wa_proto::Message,
+    ) -> Result<Node, RhustAppError> {
+        let devices = self.get_user_devices(&[to.clone(), own_id.to_non_ad()])?;
+        if !devices.iter().any(|device| device.user == to.user) {
+            return Err(new_rhustapp_error(
+                &format!("can't send to '{to}'"),
+                Some("user has no devices".to_string()),
+            ));
+        };
+        let devices = without_own_device(devices, own_id);
+        self.start_sessions(&devices)?;
+
+        let device = self.device.lock().unwrap();
+        let mut include_identity = false;
+        let participants =
+            build_dm_participants(to, own_id, message, &devices, |plaintext, devices| {
+                let (participants, is_prekey) =
+                    encrypt_for_devices(&device, &*self.store, plaintext, devices);
+                include_identity |= is_prekey;
+                participants
+            })?;
+        if participants.is_empty() {
+            return Err(new_rhustapp_error(
+                "failed to encrypt message",
+                Some("couldn't encrypt for any device".to_string()),
+            ));
+        };
+
+        let mut node = build_message_node(to, id, message_type(message), participants);
+        if include_identity {
+            push_child(&mut node, build_device_identity_node(account(&device)?)?);
+        };
+        Ok(node)
+    }
+
+    /// Builds the `<message>` stanza of a group message, which is encrypted once with our
+    /// sender key. The sender key distribution message is encrypted for every device of the
+    /// participants, so that they can decrypt it.
+    fn prepare_group_message(
+        &self,
+        group: &JID,
+        own_id: &JID,
+        id: &str,
+        message: &wa_proto::Message,
+    ) -> Result<Node, RhustAppError> {
+        let participants = self.get_group_participants(group)?;
+        let devices = self.get_user_devices(&participants)?;
+        let phash = participant_list_hash(&devices);
+        let devices = without_own_device(devices, own_id);
+        self.start_sessions(&devices)?;
+
+        let plaintext = marshal_message(message)?;
+        let device = self.device.lock().unwrap();
+        let (skmsg, distribution) = encrypt_group_message(&*self.store, group, own_id, &plaintext)?;
+        let distribution =
+            marshal_message(&build_sender_key_distribution_message(group, distribution))?;
+        let (participants, include_identity) =
+            encrypt_for_devices(&device, &*self.store, &distribution, &devices);
+
+        let mut node = build_message_node(group, id, message_type(message), participants);
+        node.attrs
+            .insert("phash".to_string(), AttributeTypes::String(phash));
+        push_child(&mut node, skmsg);
+        if include_identity {
+            push_child(&mut node, build_device_identity_node(account(&device)?)?);
+        };
+        Ok(node)
+    }
+
+    /// Marks messages as read, which shows the blue check marks to their sender (unless
+    /// read receipts are disabled in the privacy settings). The messages must all be in
+    /// `chat` and sent by `sender`, which is only needed in groups and broadcast lists.
+    /// `timestamp` is when the messages were read.
+    pub fn mark_read(
+        &self,
+        ids: &[MessageID],
+        chat: &JID,
+        sender: &JID,
+        timestamp: OffsetDateTime,
+    ) -> Result<(), RhustAppError> {
+        self.send_node(&build_read_receipt_node(ids, chat, sender, timestamp)?)
+    }
+
+    /// Checks which of the phone numbers are registered on WhatsApp. The numbers are given
+    /// in international format, with or without the leading `+`.
+    pub fn is_on_whatsapp(
+        &self,
+        numbers: &[&str],
+    ) -> Result<Vec<IsOnWhatsAppResponse>, RhustAppError> {
+        let numbers = numbers
+            .iter()
+            .map(|number| number.to_string())
+            .collect::<Vec<String>>();
+        let response = self.send_request(build_usync_query(&numbers, UsyncContext::Interactive))?;
+        parse_is_on_whatsapp(&response)
+    }
+
+    /// Fetches the verified business name, the about status, the profile picture ID and the
+    /// devices of the users.
+    pub fn get_user_info(&self, users: &[JID]) -> Result<Vec<(JID, UserInfo)>, RhustAppError> {
+        let response =
+            self.send_request(build_usync_user_info_query(users, UsyncContext::Background))?;
+        parse_usync_user_info(&response)
+    }
+
+    /// Fetches the JIDs of all the devices of the users, including their phones.
+    pub fn get_user_devices(&self, users: &[JID]) -> Result<Vec<JID>, RhustAppError> {
+        let response =
+            self.send_request(build_usync_devices_query(users, UsyncContext::Message))?;
+        parse_usync_devices(&response)
+    }
+
+    /// Returns the names of the user that are in the store: the push name and verified
+    /// business name from their messages, and their name in the contacts of the phone.
+    pub fn get_contact(&self, user: &JID) -> Result<Option<ContactInfo>, RhustAppError> {
+        self.store.get_contact(&user.to_non_ad())
+    }
+
+    /// Returns the names of all the users that are in the store.
+    pub fn get_all_contacts(&self) -> Result<Vec<(JID, ContactInfo)>, RhustAppError> {
+        self.store.get_all_contacts()
+    }
+
+    /// Fetches the info of a group: its name, description and settings, and its
+    /// participants.
+    pub fn get_group_info(&self, group: &JID) -> Result<GroupInfo, RhustAppError> {
+        let response = self.send_request(build_get_group_info_node(group)?)?;
+        parse_group_info(&response)
+    }
+
+    /// Fetches the JIDs of the participants of a group.
+    fn get_group_participants(&self, group: &JID) -> Result<Vec<JID>, RhustAppError> {
+        let response = self.send_request(build_get_group_info_node(group)?)?;
+        Ok(parse_group_participants(&response)?
+            .into_iter()
+            .map(|participant| participant.jid)
+            .collect())
+    }
+
+    /// Encrypts an attachment and uploads it to the WhatsApp media servers. The response has
+    /// the details to set in the media message that the attachment is sent with.
+    pub fn upload(
+        &self,
+        plaintext: &[u8],
+        media_type: MediaType,
+    ) -> Result<UploadResponse, RhustAppError> {
+        let media_conn = self.refresh_media_conn()?;
+        upload(&media_conn, plaintext, media_type)
+    }
+
+    /// Downloads the attachment of a message and decrypts it, checking it against the hashes
+    /// in the message. Attachments without a URL are downloaded from the media hosts.
+    pub fn download(&self, message: &dyn DownloadableMessage) -> Result<Vec<u8>, RhustAppError> {
+        match message.url().filter(|url| !url.is_empty()) {
+            Some(url) => download_from_url(url, message),
+            None => download_from_hosts(&self.refresh_media_conn()?.hosts, message),
+        }
+    }
+
+    /// Fetches the new patches of an app state collection, applies their mutations to the
+    /// store and emits their events. A full sync starts again from the latest snapshot. If
+    /// `only_if_not_synced` is true, nothing is fetched if the collection was synced before.
+    pub fn fetch_app_state(
+        &self,
+        name: PatchName,
+        full_sync: bool,
+        only_if_not_synced: bool,
+    ) -> Result<(), RhustAppError> {
+        let stored = self.store.get_app_state_version(name.as_str())?;
+        if only_if_not_synced && stored.is_some() {
+            return Ok(());
+        };
+        let mut state = match stored {
+            Some((version, hash)) if !full_sync => HashState { version, hash },
+            Some(_) => {
+                self.store.delete_app_state_version(name.as_str())?;
+                HashState::default()
+            }
+            None => HashState::default(),
+        };
+
+        let mut want_snapshot = state.version == 0;
+        let mut has_more_patches = true;
+        while has_more_patches {
+            let response = self.send_iq(app_state_query(name, state.version, want_snapshot))?;
+            let list = parse_patch_list(&response, &|blob| self.download(blob))?;
+            want_snapshot = false;
+            has_more_patches = list.has_more_patches;
+
+            let (mutations, new_state) = decode_patches(&*self.store, &list, state, true)?;
+            state = new_state;
+            for mutation in &mutations {
+                match apply_mutation(&*self.store, mutation, full_sync) {
+                    Ok(Some(event)) => self.events.push(event),
+                    Ok(None) => {}
+                    Err(err) => log::warn!("failed to apply app state mutation: {err}"),
+                };
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the cached media hosts, fetching them again if they have expired.
+    fn refresh_media_conn(&self) -> Result<MediaConn, RhustAppError> {
+        let mut cached = self.media_conn.lock().unwrap();
+        if let Some(media_conn) = cached.as_ref().filter(|conn| !conn.is_expired()) {
+            return Ok(media_conn.clone());
+        };
+
+        let media_conn = parse_media_conn(&self.send_iq(media_conn_query())?)?;
+        *cached = Some(media_conn.clone());
+        Ok(media_conn)
+    }
+
+    /// Starts sessions with the devices that there's no session with yet, from their
+    /// prekey bundles. Devices whose bundle can't be fetched are skipped, as the message can
+    /// still be delivered to the others.
+    fn start_sessions(&self, devices: &[JID]) -> Result<(), RhustAppError> {
+        let missing = devices_without_session(&*self.store, devices)?;
+        if missing.is_empty() {
+            return Ok(());
+        };
+        self.fetch_sessions(&missing)
+    }
+
+    /// Starts new sessions with the devices from their prekey bundles, replacing the
+    /// existing ones. Devices whose bundle can't be fetched are skipped.
+    fn fetch_sessions(&self, devices: &[JID]) -> Result<(), RhustAppError> {
+        let response = self.send_request(build_get_prekeys_node(devices))?;
+        let device = self.device.lock().unwrap();
+        for (jid, bundle) in parse_prekey_bundles(&response)? {
+            let processed = bundle
+                .and_then(|bundle| process_prekey_bundle(&device, &*self.store, &jid, &bundle));
+            if let Err(err) = processed {
+                log::warn!("failed to start session with {}: {err}", jid.anonymized());
+            };
+        }
+        Ok(())
+    }
+
+    /// Sends a message again to the device that couldn't decrypt it, as asked by a retry
+    /// receipt. The session is started again from the prekeys in the receipt, or from
+    /// fetched ones if the device asks again or there's no session with it.
+    fn resend_message(&self, node: &Node) -> Result<(), RhustAppError> {
+        let own_id = self.device.lock().unwrap().id.clone().ok_or_else(|| {
+            new_rhustapp_error(
+                "failed to resend message",
+                Some("device is not paired".to_string()),
+            )
+        })?;
+        let source = Receipt::from_node(node, &own_id)?.source;
+        let (id, retry_count) = parse_retry_receipt(node)?;
+        if retry_count >= MAX_RETRY_COUNT {
+            return Err(new_rhustapp_error(
+                &format!("not resending message {id}"),
+                Some(format!("{retry_count} retries were asked for")),
+            ));
+        };
+        let message = self
+            .recent_messages
+            .lock()
+            .unwrap()
+            .get(&source.chat, &id)
+            .cloned()
+            .ok_or_else(|| {
+                new_rhustapp_error(&format!("message {id} to resend wasn't found"), None)
+            })?;
+
+        let message = if source.is_group {
+            // The participant may also have lost our sender key.
+            let distribution = own_sender_key_distribution(&*self.store, &source.chat, &own_id)?;
+            wa_proto::Message {
+                senderKeyDistributionMessage: build_sender_key_distribution_message(
+                    &source.chat,
+                    distribution,
+                )
+                .senderKeyDistributionMessage,
+                ..message
+            }
+        } else if source.is_from_me {
+            build_device_sent_message(&source.chat, &message)
+        } else {
+            message
+        };
+
+        let sender = &source.sender;
+        if node.get_optional_child_by_tag(&["keys"]).is_some() {
+            let bundle = parse_retry_prekey_bundle(sender, node)?;
+            process_prekey_bundle(&self.device.lock().unwrap(), &*self.store, sender, &bundle)?;
+        } else if retry_count >= 2
+            || !devices_without_session(&*self.store, std::slice::from_ref(sender))?.is_empty()
+        {
+            self.fetch_sessions(std::slice::from_ref(sender))?;
+        };
+
+        let plaintext = marshal_message(&message)?;
+        let device = self.device.lock().unwrap();
+        let (mut enc, is_prekey) = encrypt_for_device(&device, &*self.store, &plaintext, sender)?;
+        enc.attrs.insert(
+            "count".to_string(),
+            AttributeTypes::String(retry_count.to_string()),
+        );
+        let mut resent =
+            build_resent_message_node(node, &id, message_type(&message), source.is_group, enc)?;
+        if is_prekey {
+            push_child(&mut resent, build_device_identity_node(account(&device)?)?);
+        };
+        drop(device);
+        self.send_node(&resent)
+    }
+
+    /// Handles the frames of a connection until it is closed, which is then reflected in
+    /// the state of the client. If the server asked for a restart or nothing was received
+    /// within the read timeout of the socket, the client reconnects and the frames of the
+    /// new connection are handled next. Otherwise, `Disconnected` is emitted, unless the
+    /// connection was closed by `disconnect`.
+    fn receive_loop(client: Weak<Self>, mut connection_id: u64, mut frames: Receiver<Vec<u8>>) {
+        loop {
+            let read_timeout = match client.upgrade() {
+                Some(client) => client.socket.lock().unwrap().read_timeout(),
+                None => return,
+            };
+            let timed_out = loop {
+                let frame = match FrameSocket::receive_frame(&frames, read_timeout) {
+                    Ok(frame) => frame,
+                    Err(err) => break *err.kind() == ErrorKind::Socket(SocketError::ReadTimeout),
+                };
+                let client = match client.upgrade() {
+                    Some(client) => client,
+                    None => return,
+                };
+                match client.decrypt_node(&frame) {
+                    Ok(node) => client.handle_node(&node),
+                    Err(err) => log::warn!("failed to decode received frame: {err}"),
+                };
+            };
+
+            let is_current = match client.upgrade() {
+                Some(client) => client.close_connection(connection_id, timed_out),
+                None => return,
+            };
+            if !is_current {
+                return;
+            };
+            if timed_out {
+                log::warn!("nothing received within the read timeout, reconnecting");
+            };
+            match Self::reconnect(&client) {
+                Some((new_id, new_frames)) => {
+                    connection_id = new_id;
+                    frames = new_frames;
+                }
+                None => {
+                    if let Some(client) = client.upgrade() {
+                        client.events.push(RhustAppEventType::Disconnected);
+                    };
+                    return;
+                }
+            };
+        }
+    }
+
+    /// Cleans up after the connection with the given id was closed, returning false if it
+    /// isn't the current connection anymore. If `restart` is set, the client reconnects
+    /// afterwards, as if the server asked for a restart.
+    fn close_connection(&self, connection_id: u64, restart: bool) -> bool {
+        let mut noise = self.noise.lock().unwrap();
+        let mut socket = self.socket.lock().unwrap();
+        if self.connection_id.load(Ordering::SeqCst) != connection_id {
+            return false;
+        };
+        if restart {
+            self.restart_requested.store(true, Ordering::SeqCst);
+        };
+        socket.close(0);
+        *noise = None;
+        self.cancel_requests();
+        true
+    }
+
+    /// Reconnects if the server asked for a restart, waiting before each attempt as the
+    /// reconnect configuration says. Returns `None` if no restart was requested, or if it
+    /// was cancelled by `disconnect` or the client was dropped before reconnecting.
+    fn reconnect(client: &Weak<Self>) -> Option<(u64, Receiver<Vec<u8>>)> {
+        let mut attempt = 0;
+        loop {
+            let delay = match client.upgrade() {
+                Some(client) if client.restart_requested.load(Ordering::SeqCst) => {
+                    client.reconnect.delay(attempt)
+                }
+                _ => return None,
+            };
+            thread::sleep(delay);
+
+            let client = client.upgrade()?;
+            if !client.restart_requested.load(Ordering::SeqCst) {
+                return None;
+            };
+            match client.open_connection() {
+                Ok(connection) => {
+                    client.restart_requested.store(false, Ordering::SeqCst);
+                    return Some(connection);
+                }
+                Err(err) => log::warn!("failed to reconnect (attempt {}): {err}", attempt + 1),
+            };
+            attempt = attempt.saturating_add(1);
+        }
+    }
+
+    /// Pings the server periodically while the connection is open, emitting
+    /// `KeepAliveTimeout` when a ping fails and `KeepAliveRestored` when pings work again.
+    fn keepalive_loop(client: Weak<Self>, connection_id: u64) {
+        let mut tracker = KeepAliveTracker::new();
+        loop {
+            let interval = match client.upgrade() {
+                Some(client) => client.keepalive.interval(),
+                None => return,
+            };
+            thread::sleep(interval);
+
+            let client = match client.upgrade() {
+                Some(client) => client,
+                None => return,
+            };
+            let is_current = || {
+                client.connection_id.load(Ordering::SeqCst) == connection_id
+                    && client.is_connected()
+            };
+            if !is_current() {
+                return;
+            };
+            let result = client
+                .send_request_timeout(build_keepalive_node(), client.keepalive.response_timeout);
+            // A ping that failed because the connection was closed isn't a timeout.
+            if !is_current() {
+                return;
+            };
+            let event = match result {
+                Ok(_) => tracker.success(),
+                Err(err) => {
+                    log::warn!("keepalive ping failed: {err}");
+                    Some(tracker.failure())
+                }
+            };
+            if let Some(event) = event {
+                client.events.push(event);
+            };
+        }
+    }
+
+    fn decrypt_node(&self, frame: &[u8]) -> Result<Node, RhustAppError> {
+        let data = match self.noise.lock().unwrap().as_mut() {
+            Some(noise) => noise.decrypt_frame(frame)?,
+            None => return Err(new_rhustapp_error("client is not connected", None)),
+        };
+        unmarshal(&data)
+    }
+
+    fn handle_node(self: &Arc<Self>, node: &Node) {
+        if matches!(node.tag.as_str(), "iq" | "ack") && self.receive_response(node) {
+            return;
+        };
+
+        match node.tag.as_str() {
+            "iq" if node.get_optional_child_by_tag(&["pair-device"]).is_some() => {
+                self.handle_pair_device(node)
+            }
+            "iq" if node.get_optional_child_by_tag(&["pair-success"]).is_some() => {
+                self.handle_pair_success(node)
+            }
+            "notification"
+                if node.attr_getter().optional_string("type").as_deref()
+                    == Some("link_code_companion_reg") =>
+            {
+                // Finishing the key exchange sends a request, whose response is received
+                // by this thread.
+                let client = Arc::clone(self);
+                let node = node.clone();
+                thread::spawn(move || client.handle_code_pair_notification(&node));
+            }
+            "notification"
+                if node.attr_getter().optional_string("type").as_deref() == Some("server_sync") =>
+            {
+                self.fetch_app_state_in_background(parse_server_sync_notification(node), false);
+            }
+            "message" => self.handle_message(node),
+            "receipt" => self.handle_receipt(node),
+            "stream:error"
+                if node.attr_getter().optional_string("code").as_deref() == Some("515") =>
+            {
+                // Closing the connection ends its frames, after which the receive thread
+                // reconnects.
+                self.restart_requested.store(true, Ordering::SeqCst);
+                self.socket.lock().unwrap().close(0);
+                self.dispatch_node(node);
+            }
+            "success" => {
+                let client = Arc::downgrade(self);
+                let connection_id = self.connection_id.load(Ordering::SeqCst);
+                thread::spawn(move || Self::keepalive_loop(client, connection_id));
+                self.events.push(RhustAppEventType::Connected);
+            }
+            _ => self.dispatch_node(node),
+        }
+    }
+
+    /// Acknowledges a receipt and emits its `Receipt` event. Retry receipts for sent messages
+    /// also send the message again.
+    fn handle_receipt(self: &Arc<Self>, node: &Node) {
+        let acked = build_receipt_ack_node(node).and_then(|ack| self.send_node(&ack));
+        if let Err(err) = acked {
+            log::warn!("failed to acknowledge receipt: {err}");
+        };
+
+        if node.attr_getter().optional_string("type").as_deref() == Some("retry") {
+            // Resending may need to fetch prekeys, whose response is received by this
+            // thread.
+            let client = Arc::clone(self);
+            let retry = node.clone();
+            thread::spawn(move || {
+                if let Err(err) = client.resend_message(&retry) {
+                    log::warn!("failed to handle retry receipt: {err}");
+                };
+            });
+        };
+        self.dispatch_node(node);
+    }
+
+    /// Emits the event of a node that the client doesn't handle itself, if it has one.
+    fn dispatch_node(&self, node: &Node) {
+        let own_jid = self.device.lock().unwrap().id.clone().unwrap_or_default();
+        if let Some(event) = node_to_event(node, &own_jid) {
+            self.events.push(event);
+        };
+    }
+
+    /// Decrypts an incoming message and emits it as a `Message` event. If it can't be
+    /// decrypted, a retry receipt is sent so that the sender sends it again.
+    fn handle_message(self: &Arc<Self>, node: &Node) {
+        let own_jid = self.device.lock().unwrap().id.clone().unwrap_or_default();
+        let mut info = match MessageInfo::from_node(node, &own_jid) {
+            Ok(info) => info,
+            Err(err) => {
+                log::warn!("failed to parse message: {err}");
+                return;
+            }
+        };
+
+        if let Err(err) = self.update_contact_names(&info) {
+            log::warn!(
+                "failed to save names of {}: {err}",
+                info.source.sender.anonymized()
+            );
+        };
+
+        let decrypted =
+            decrypt_message(&self.device.lock().unwrap(), &*self.store, node, &mut info);
+        match decrypted {
+            Ok(message) => {
+                self.incoming_retries.lock().unwrap().remove(&info.id);
+                if self.delivery_receipts {
+                    let sent = build_delivery_receipt_node(&info.source, &info.id)
+                        .and_then(|receipt| self.send_node(&receipt));
+                    if let Err(err) = sent {
+                        log::warn!("failed to send delivery receipt for {}: {err}", info.id);
+                    };
+                };
+                if let Some(share) = message
+                    .as_ref()
+                    .filter(|_| info.source.is_from_me)
+                    .and_then(|message| message.protocolMessage.appStateSyncKeyShare.as_ref())
+                {
+                    self.handle_app_state_sync_key_share(share);
+                };
+                // Without a message, it only carried a sender key for later group messages.
+                if let Some(message) = message {
+                    self.events
+                        .push(RhustAppEventType::Message(Box::new(Message {
+                            info,
+                            message: Some(Box::new(message)),
+                        })));
+                };
+            }
+            Err(err) => {
+                log::warn!(
+                    "failed to decrypt message {} from {}: {err}",
+                    info.id,
+                    info.source.sender.anonymized()
+                );
+                if let Err(err) = self.send_retry_receipt(node, &info.id) {
+                    log::warn!("failed to send retry receipt for {}: {err}", info.id);
+                };
+            }
+        };
+    }
+
+    /// Saves the push name and the verified business name that a message was sent with, and
+    /// emits `PushNameUpdated` if the push name changed.
+    fn update_contact_names(&self, info: &MessageInfo) -> Result<(), RhustAppError> {
+        if info.source.is_from_me {
+            return Ok(());
+        };
+        let sender = info.source.sender.to_non_ad();
+        if let Some(verified_name) = &info.verified_name {
+            self.store
+                .put_business_name(&sender, verified_name.details.verifiedName())?;
+        };
+        // The server sends "-" when the user hasn't set a push name.
+        if info.push_name.is_empty() || info.push_name == "-" {
+            return Ok(());
+        };
+        if let Some(old_push_name) = self.store.put_push_name(&sender, &info.push_name)? {
+            self.events
+                .push(RhustAppEventType::PushNameUpdated(Box::new(
+                    PushNameUpdated {
+                        jid: sender,
+                        message: info.clone(),
+                        old_push_name,
+                        new_push_name: info.push_name.clone(),
+                    },
+                )));
+        };
+        Ok(())
+    }
+
+    /// Saves the app state sync keys shared by the phone, and syncs the collections that
+    /// weren't synced yet, as their patches may not have been decryptable before.
+    fn handle_app_state_sync_key_share(self: &Arc<Self>, share: &wa_proto::AppStateSyncKeyShare) {
+        for key in &share.keys {
+            let fingerprint = match key.keyData.fingerprint.as_ref() {
+                Some(fingerprint) => fingerprint.write_to_bytes().unwrap_or_default(),
+                None => Vec::new(),
+            };
+            let saved = self.store.put_app_state_sync_key(
+                key.keyId.keyId(),
+                AppStateSyncKey {
+                    data: key.keyData.keyData().to_vec(),
+                    fingerprint,
+                    timestamp: key.keyData.timestamp(),
+                },
+            );
+            if let Err(err) = saved {
+                log::warn!("failed to save app state sync key: {err}");
+            };
+        }
+        self.fetch_app_state_in_background(PatchName::ALL.to_vec(), true);
+    }
+
+    /// Fetches the collections from a new thread, as the responses are received by the
+    /// calling thread. A full sync only fetches the collections that weren't synced yet.
+    fn fetch_app_state_in_background(self: &Arc<Self>, names: Vec<PatchName>, full_sync: bool) {
+        let client = Arc::clone(self);
+        thread::spawn(move || {
+            for name in names {
+                if let Err(err) = client.fetch_app_state(name, full_sync, full_sync) {
+                    log::warn!("failed to sync app state {name}: {err}");
+                };
+            }
+        });
+    }
+
+    /// Asks the sender of a message that couldn't be decrypted to send it again, with a new
+    /// prekey that it can start a new session from.
+    fn send_retry_receipt(&self, node: &Node, id: &str) -> Result<(), RhustAppError> {
+        let retry_count = {
+            let mut retries = self.incoming_retries.lock().unwrap();
+            let count = retries.entry(id.to_string()).or_insert(0);
+            *count += 1;
+            *count
+        };
+        if retry_count > MAX_RETRY_COUNT {
+            return Err(new_rhustapp_error(
+                "not asking for the message again",
+                Some(format!(
+                    "{MAX_RETRY_COUNT} retry receipts were already sent"
+                )),
+            ));
+        };
+
+        let prekey = self
+            .store
+            .get_or_gen_pre_keys(1)?
+            .pop()
+            .ok_or_else(|| new_rhustapp_error("failed to generate prekey", None))?;
+        let prekey_id = prekey
+            .id()
+            .map_err(|err| new_rhustapp_error("failed to read prekey", Some(err.to_string())))?;
+        // The prekey is given to the sender directly instead of through the server.
+        self.store.mark_pre_keys_as_uploaded(prekey_id.into())?;
+
+        let device = self.device.lock().unwrap();
+        let device_identity = device
+            .account
+            .as_ref()
+            .map(build_device_identity_node)
+            .transpose()?;
+        let keys = build_retry_keys_node(
+            public_key_bytes(&device.identity_key.public_key)?,
+            &prekey,
+            &device.signed_pre_key,
+            device_identity,
+        )?;
+        let receipt =
+            build_retry_receipt_node(node, retry_count, device.registration_id, Some(keys))?;
+        drop(device);
+        self.send_node(&receipt)
+    }
+
+    /// Acknowledges the `pair-device` request and emits the QR codes for its refs.
+    fn handle_pair_device(&self, node: &Node) {
+        if let Err(err) = self.send_node(&build_iq_result_node(node)) {
+            log::warn!("failed to acknowledge pair-device request: {err}");
+        };
+
+        let codes = parse_pair_device_refs(node).and_then(|refs| {
+            let device = self.device.lock().unwrap();
+            refs.iter()
+                .map(|qr_ref| make_qr_data(&device, qr_ref))
+                .collect::<Result<Vec<String>, RhustAppError>>()
+        });
+        match codes {
+            Ok(codes) => self.events.push(RhustAppEventType::QR(QR { codes })),
+            Err(err) => log::warn!("failed to handle pair-device request: {err}"),
+        };
+    }
+
+    /// Finishes the key exchange of the pairing started by `pair_phone` once the user has
+    /// entered the code on the phone, which is then completed with `pair-success`.
+    fn handle_code_pair_notification(&self, node: &Node) {
+        let finish = match self.phone_linking.lock().unwrap().as_ref() {
+            Some(linking) => linking.companion_finish(&self.device.lock().unwrap(), node),
+            None => Err(new_rhustapp_error(
+                "received code pairing notification without a pending pairing",
+                None,
+            )),
+        };
+
+        let result = finish.and_then(|(adv_secret, finish)| {
+            self.device.lock().unwrap().adv_secret_key = adv_secret;
+            self.send_request(finish)
+        });
+        if let Err(err) = result {
+            log::error!("failed to handle code pairing notification: {err}");
+        };
+    }
+
+    /// Finishes pairing once the phone has scanned the QR code, emitting `PairSuccess` or
+    /// `PairError`. The client is disconnected if pairing fails.
+    fn handle_pair_success(&self, node: &Node) {
+        let info = match PairSuccessInfo::from_node(node) {
+            Ok(info) => info,
+            Err(err) => {
+                log::warn!("failed to handle pair-success: {err}");
+                return;
+            }
+        };
+
+        let event = match self.complete_pairing(&info) {
+            Ok(()) => RhustAppEventType::PairSuccess(PairSuccess {
+                id: info.jid,
+                business_name: info.business_name,
+                platform: info.platform,
+            }),
+            Err(error) => {
+                log::error!("failed to pair device: {error}");
+                self.disconnect();
+                RhustAppEventType::PairError(PairError {
+                    id: info.jid,
+                    business_name: info.business_name,
+                    platform: info.platform,
+                    error,
+                })
+            }
+        };
+        self.events.push(event);
+    }
+
+    /// Verifies and signs the device identity sent by the phone, stores it in the device and
+    /// confirms the pairing to the server. Verification failures are reported to the server.
+    fn complete_pairing(&self, info: &PairSuccessInfo) -> Result<(), RhustAppError> {
+        let result = finish_pairing(&self.device.lock().unwrap(), info);
+        let (account, response) = match result {
+            Ok(result) => result,
+            Err(err) => {
+                if let Err(send_err) =
+                    self.send_node(&build_pair_error_node(&info.request_id, &err))
+                {
+                    log::warn!("failed to send pair error: {send_err}");
+                };
+                return Err(err);
+            }
+        };
+
+        let saved = {
+            let mut device = self.device.lock().unwrap();
+            device.id = Some(info.jid.clone());
+            device.account = Some(account);
+            device.business_name = info.business_name.clone();
+            device.platform = info.platform.clone();
+            self.store.save_device(&device)
+        };
+        if let Err(err) = saved {
+            self.forget_pairing();
+            if let Err(send_err) = self.send_node(&build_pair_error_node(&info.request_id, &err)) {
+                log::warn!("failed to send pair error: {send_err}");
+            };
+            return Err(err);
+        };
+
+        self.send_node(&response).map_err(|err| {
+            self.forget_pairing();
+            new_rhustapp_error("failed to send pairing confirmation", Some(err.to_string()))
+        })
+    }
+
+    /// Clears the account of a pairing that couldn't be completed.
+    fn forget_pairing(&self) {
+        let mut device = self.device.lock().unwrap();
+        device.id = None;
+        device.account = None;
+    }
+}
+
+impl Drop for Client {
+    /// Closes the event queue, as no more events will be pushed to it. This also stops the
+    /// thread that passes the events to the handlers.
+    fn drop(&mut self) {
+        self.events.close();
+    }
+}
+
+/// Removes the device that is sending from a list of devices to encrypt for.
+fn without_own_device(devices: Vec<JID>, own_id: &JID) -> Vec<JID> {
+    devices
+        .into_iter()
+        .filter(|device| device.user != own_id.user || device.device != own_id.device)
+        .collect()
+}
+
+fn marshal_message(message: &wa_proto::Message) -> Result<Vec<u8>, RhustAppError> {
+    message
+        .write_to_bytes()
+        .map_err(|err| new_rhustapp_error("failed to marshal message", Some(err.to_string())))
+}
+
+fn account(device: &Device) -> Result<&wa_proto::ADVSignedDeviceIdentity, RhustAppError> {
+    device
+        .account
+        .as_ref()
+        .ok_or_else(|| new_rhustapp_error("device has no account", None))
+}
+
+fn push_child(node: &mut Node, child: Node) {
+    match &mut node.content {
+        NodeContentType::ListOfNodes(children) => children.push(child),
+        content => *content = NodeContentType::ListOfNodes(vec![child]),
+    };
+}
+
+/// The result of a function run by `run_blocking`, and the waker of the task awaiting it.
+struct BlockingState<T> {
+    result: Option<thread::Result<T>>,
+    waker: Option<Waker>,
+}
+
+/// A future that resolves to the result of a function run on its own thread.
+struct Blocking<T> {
+    state: Arc<Mutex<BlockingState<T>>>,
+}
+
+impl<T> Future for Blocking<T> {
+    type Output = T;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<T> {
+        let mut state = self.state.lock().unwrap();
+        match state.result.take() {
+            Some(Ok(result)) => Poll::Ready(result),
+            // The panic is passed on to the task awaiting the result.
+            Some(Err(payload)) => panic::resume_unwind(payload),
+            None => {
+                state.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+/// Runs a blocking function on a new thread, returning a future that resolves to its result
+/// and wakes the awaiting task when it's done.
+fn run_blocking<T: Send + 'static>(f: impl FnOnce() -> T + Send + 'static) -> Blocking<T> {
+    let state = Arc::new(Mutex::new(BlockingState {
+        result: None,
+        waker: None,
+    }));
+    let shared = Arc::clone(&state);
+    thread::spawn(move || {
+        let result = panic::catch_unwind(AssertUnwindSafe(f));
+        let mut state = shared.lock().unwrap();
+        state.result = Some(result);
+        if let Some(waker) = state.waker.take() {
+            waker.wake();
+        }
+    });
+    Blocking { state }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::TcpListener, sync::mpsc, time::Instant};
+
+    use std::str::FromStr;
+
+    use libsignal_protocol::PreKeyRecord;
+
+    use crate::{
+        binary::{proto::syncd_mutation::SyncdOperation, Attrs},
+        encryption::encrypt_for_device,
+        encryption::{decrypt_enc_node, decrypt_group_message, process_sender_key_distribution},
+        reconnect::Jitter,
+        request::InfoQueryType,
+        socket::FRAME_MAX_SIZE,
+        store::sqlite::SqliteStore,
+        testing::{
+            block_on, build_app_state_patch, encrypt_app_state_mutation, message_node,
+            pair_success_node, prekey_bundle, prekey_bundle_node, put_app_state_key, serve,
+            serve_connections, usync_devices_node, FakeServer,
+        },
+        types::{
+            events::{Archive, ReceiptType, StreamError},
+            SERVER_JID,
+        },
+    };
+
+    use super::*;
+
+    /// Reads until the client closes the connection.
+    fn wait_for_close(mut server: FakeServer) {
+        while server.receive_node().is_some() {}
+    }
+
+    /// Responds to a request with the given children.
+    fn respond(server: &mut FakeServer, request: &Node, children: Vec<Node>) {
+        let mut response = build_iq_result_node(request);
+        response.content = NodeContentType::ListOfNodes(children);
+        server.send_node(&response);
+    }
+
+    fn message_ack(message: &Node) -> Node {
+        Node {
+            tag: "ack".to_string(),
+            attrs: Attrs::from([
+                (
+                    "class".to_string(),
+                    AttributeTypes::String("message".to_string()),
+                ),
+                (
+                    "id".to_string(),
+                    AttributeTypes::String(message.attr_getter().string("id").unwrap()),
+                ),
+                (
+                    "t".to_string(),
+                    AttributeTypes::String("1700000000".to_string()),
+                ),
+            ]),
+            content: NodeContentType::None,
+        }
+    }
+
+    /// Returns a client connected as a paired device to a server running `handler`.
+    fn paired_client(
+        own_id: &JID,
+        handler: impl FnOnce(FakeServer) + Send + 'static,
+    ) -> (Arc<Client>, thread::JoinHandle<()>) {
+        let (url, server) = serve(handler);
+        let mut device = Device::new();
+        device.id = Some(own_id.clone());
+        device.account = Some(wa_proto::ADVSignedDeviceIdentity::new());
+        let client = Arc::new(
+            Client::new()
+                .with_socket(FrameSocket::new().with_url(&url))
+                .with_device(device),
+        );
+        client.connect().unwrap();
+        (client, server)
+    }
+
+    /// A device that messages are sent to, with its own keys and store.
+    struct Peer {
+        jid: JID,
+        device: Device,
+        store: MemoryStore,
+        prekey: PreKeyRecord,
+    }
+
+    impl Peer {
+        fn new(jid: JID) -> Self {
+            let store = MemoryStore::new();
+            let prekey = store.get_or_gen_pre_keys(1).unwrap().remove(0);
+            Self {
+                jid,
+                device: Device::new(),
+                store,
+                prekey,
+            }
+        }
+
+        fn bundle_node(&self) -> Node {
+            prekey_bundle_node(&self.jid, &self.device, &self.prekey)
+        }
+
+        /// Decrypts the `<enc>` of a sent message that is addressed to this device.
+        fn decrypt(&self, message: &Node, sender: &JID) -> wa_proto::Message {
+            let to = message
+                .get_optional_child_by_tag(&["participants"])
+                .unwrap()
+                .get_children_by_tag("to")
+                .unwrap()
+                .into_iter()
+                .find(|to| to.attr_getter().jid("jid").unwrap() == self.jid)
+                .unwrap();
+            let enc = to.get_optional_child_by_tag(&["enc"]).unwrap();
+            let plaintext = decrypt_enc_node(&self.device, &self.store, sender, &enc).unwrap();
+            wa_proto::Message::parse_from_bytes(&plaintext).unwrap()
+        }
+    }
+
+    fn text_message(text: &str) -> wa_proto::Message {
+        wa_proto::Message {
+            conversation: Some(text.to_string()),
+            ..Default::default()
+        }
+    }
+
+    fn pair_device_node(refs: &[&str]) -> Node {
+        let refs = refs
+            .iter()
+            .map(|qr_ref| Node {
+                tag: "ref".to_string(),
+                attrs: Attrs::new(),
+                content: NodeContentType::ByteArray(qr_ref.as_bytes().to_vec()),
+            })
+            .collect();
+        Node {
+            tag: "iq".to_string(),
+            attrs: Attrs::from([
+                (
+                    "id".to_string(),
+                    AttributeTypes::String("pair-device-1".to_string()),
+                ),
+                (
+                    "type".to_string(),
+                    AttributeTypes::String("set".to_string()),
+                ),
+            ]),
+            content: NodeContentType::ListOfNodes(vec![Node {
+                tag: "pair-device".to_string(),
+                attrs: Attrs::new(),
+                content: NodeContentType::ListOfNodes(refs),
+            }]),
+        }
+    }
+
+    #[test]
+    fn test_connect_disconnect() {
+        let (url, server) = serve(|server| {
+            // The device isn't paired, so it registers.
+            assert!(server.client_payload.devicePairingData.is_some());
+            wait_for_close(server);
+        });
+        let client = Arc::new(Client::new().with_socket(FrameSocket::new().with_url(&url)));
+        assert!(!client.is_connected());
+
+        client.connect().unwrap();
+        assert!(client.is_connected());
+        assert!(client.wait_for_connection(Duration::ZERO));
+        assert!(client.connect().is_err());
+
+        client.disconnect();
+        assert!(!client.is_connected());
+        assert!(!client.wait_for_connection(Duration::ZERO));
+        assert!(client.send_node(&Node::default()).is_err());
+        server.join().unwrap();
+        // Disconnecting on purpose doesn't emit Disconnected.
+        assert!(client.events().is_empty());
+
+        // Disconnecting again is a no-op.
+        client.disconnect();
+    }
+
+    #[test]
+    fn test_connect_disconnect_async() {
+        let (url, server) = serve(wait_for_close);
+        let client = Arc::new(Client::new().with_socket(FrameSocket::new().with_url(&url)));
+        assert!(!block_on(client.wait_for_connection_async(Duration::ZERO)));
+
+        block_on(client.connect_async()).unwrap();
+        assert!(block_on(
+            client.wait_for_connection_async(Duration::from_secs(5))
+        ));
+        assert!(block_on(client.connect_async()).is_err());
+
+        block_on(client.disconnect_async());
+        assert!(!client.is_connected());
+        server.join().unwrap();
+        assert!(client.events().is_empty());
+    }
+
+    #[test]
+    fn test_server_closes_connection() {
+        let (url, server) = serve(drop);
+        let client = Arc::new(Client::new().with_socket(FrameSocket::new().with_url(&url)));
+        client.connect().unwrap();
+        server.join().unwrap();
+
+        let started = Instant::now();
+        while client.is_connected() {
+            assert!(started.elapsed() < Duration::from_secs(5));
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert!(client.send_node(&Node::default()).is_err());
+        assert!(matches!(
+            client.events().pop(),
+            Some(RhustAppEventType::Disconnected)
+        ));
+    }
+
+    #[test]
+    fn test_reconnect_on_stream_restart() {
+        let (url, server) = serve_connections(2, |index, mut server| {
+            // The device logs in on both connections.
+            assert!(server.client_payload.devicePairingData.is_none());
+            match index {
+                0 => server.send_node(&Node {
+                    tag: "stream:error".to_string(),
+                    attrs: Attrs::from([(
+                        "code".to_string(),
+                        AttributeTypes::String("515".to_string()),
+                    )]),
+                    content: NodeContentType::None,
+                }),
+                _ => server.send_node(&Node {
+                    tag: "success".to_string(),
+                    ..Default::default()
+                }),
+            };
+            wait_for_close(server);
+        });
+        let mut device = Device::new();
+        device.id = Some(JID::new_ad("919876543210", 0, 12));
+        device.account = Some(wa_proto::ADVSignedDeviceIdentity::new());
+        let client = Arc::new(
+            Client::new()
+                .with_socket(FrameSocket::new().with_url(&url))
+                .with_device(device)
+                .with_reconnect_config(ReconnectConfig {
+                    base_delay: Duration::from_millis(10),
+                    max_delay: Duration::from_millis(50),
+                    jitter: Jitter::Equal,
+                }),
+        );
+        client.connect().unwrap();
+        let events = client.events();
+
+        assert!(matches!(
+            events.pop(),
+            Some(RhustAppEventType::StreamRestartRequired)
+        ));
+        assert!(matches!(events.pop(), Some(RhustAppEventType::Connected)));
+        assert!(client.is_connected());
+
+        client.disconnect();
+        server.join().unwrap();
+        assert!(events.is_empty());
+    }
+
+    #[test]
+    fn test_reconnect_on_read_timeout() {
+        let (url, server) = serve_connections(2, |index, mut server| {
+            // The first connection stalls without sending anything.
+            if index == 1 {
+                server.send_node(&Node {
+                    tag: "success".to_string(),
+                    ..Default::default()
+                });
+            };
+            wait_for_close(server);
+        });
+        let mut device = Device::new();
+        device.id = Some(JID::new_ad("919876543210", 0, 12));
+        device.account = Some(wa_proto::ADVSignedDeviceIdentity::new());
+        let client = Arc::new(
+            Client::new()
+                .with_socket(
+                    FrameSocket::new()
+                        .with_url(&url)
+                        .with_read_timeout(Duration::from_millis(500)),
+                )
+                .with_device(device)
+                .with_reconnect_config(ReconnectConfig {
+                    base_delay: Duration::from_millis(10),
+                    max_delay: Duration::from_millis(50),
+                    jitter: Jitter::Equal,
+                }),
+        );
+        client.connect().unwrap();
+        let events = client.events();
+
+        assert!(matches!(events.pop(), Some(RhustAppEventType::Connected)));
+        assert!(client.is_connected());
+
+        client.disconnect();
+        server.join().unwrap();
+        assert!(events.is_empty());
+    }
+
+    #[test]
+    fn test_send_retry() {
+        let (sender, received) = mpsc::channel();
+        let (release, released) = mpsc::channel::<()>();
+        let released = Mutex::new(released);
+        let (url, server) = serve_connections(2, move |index, mut server| {
+            while let Some(node) = server.receive_node() {
+                sender.send((index, node.tag.clone())).unwrap();
+                // Keeps the first connection open after "first", so that the client only
+                // notices that it's broken when writing to it.
+                if node.tag == "first" {
+                    released.lock().unwrap().recv().unwrap();
+                }
+            }
+        });
+        let client = Arc::new(
+            Client::new()
+                .with_socket(FrameSocket::new().with_url(&url))
+                .with_send_retry(true),
+        );
+        client.connect().unwrap();
+        let node = |tag: &str| Node {
+            tag: tag.to_string(),
+            ..Default::default()
+        };
+        let timeout = Duration::from_secs(5);
+
+        // Frames that are too large aren't retried on a new connection.
+        let mut large = node("large");
+        large.content = NodeContentType::ByteArray(vec![0; FRAME_MAX_SIZE]);
+        let err = client.send_node(&large).unwrap_err();
+        assert_eq!(*err.kind(), ErrorKind::Socket(SocketError::FrameTooLarge));
+        client.send_node(&node("first")).unwrap();
+        assert_eq!(received.recv_timeout(timeout).unwrap(), (0, "first".into()));
+
+        client.socket.lock().unwrap().break_connection();
+        client.send_node(&node("second")).unwrap();
+        assert_eq!(
+            received.recv_timeout(timeout).unwrap(),
+            (1, "second".into())
+        );
+        assert!(client.is_connected());
+
+        release.send(()).unwrap();
+        client.disconnect();
+        server.join().unwrap();
+        // The connection was replaced, not lost.
+        assert!(client.events().is_empty());
+    }
+
+    #[test]
+    fn test_connect_failure() {
+        // Bind and drop a listener to get a port that nothing listens on.
+        let port = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let client =
+            Arc::new(Client::new().with_socket(
+                FrameSocket::new().with_url(&format!("ws://127.0.0.1:{port}/ws/chat")),
+            ));
+
+        assert!(client.connect().is_err());
+        assert!(!client.is_connected());
+    }
+
+    #[test]
+    fn test_pairing() {
+        let (sender, receiver) = mpsc::channel();
+        let (url, server) = serve(move |mut server| {
+            server.send_node(&pair_device_node(&["2@first", "2@second"]));
+            let ack = server.receive_node().unwrap();
+            let mut ag = ack.attr_getter();
+            assert_eq!(ag.string("id").unwrap(), "pair-device-1");
+            assert_eq!(ag.string("type").unwrap(), "result");
+
+            // The phone scans the QR code.
+            server.send_node(&receiver.recv().unwrap());
+            let response = server.receive_node().unwrap();
+            let mut ag = response.attr_getter();
+            assert_eq!(ag.string("id").unwrap(), "pair-1");
+            assert_eq!(ag.string("type").unwrap(), "result");
+            assert!(response
+                .get_optional_child_by_tag(&["pair-device-sign", "device-identity"])
+                .is_some());
+            wait_for_close(server);
+        });
+        let store = Arc::new(SqliteStore::open_in_memory().unwrap());
+        let client = Arc::new(
+            Client::new()
+                .with_socket(FrameSocket::new().with_url(&url))
+                .with_store(store.clone())
+                .unwrap(),
+        );
+        client.connect().unwrap();
+        let events = client.events();
+
+        let codes = match events.pop() {
+            Some(RhustAppEventType::QR(qr)) => qr.codes,
+            _ => panic!("expected a QR event"),
+        };
+        {
+            let device = client.device.lock().unwrap();
+            assert_eq!(
+                codes,
+                vec![
+                    make_qr_data(&device, "2@first").unwrap(),
+                    make_qr_data(&device, "2@second").unwrap()
+                ]
+            );
+            sender
+                .send(pair_success_node(&device, &device.adv_secret_key))
+                .unwrap();
+        }
+
+        match events.pop() {
+            Some(RhustAppEventType::PairSuccess(success)) => {
+                assert_eq!(success.id, JID::new_ad("919876543210", 0, 12));
+                assert_eq!(success.platform, "android");
+            }
+            _ => panic!("expected a PairSuccess event"),
+        };
+        {
+            let device = client.device.lock().unwrap();
+            assert_eq!(device.id, Some(JID::new_ad("919876543210", 0, 12)));
+            assert!(device.account.is_some());
+        }
+        let saved = store.load_device().unwrap().unwrap();
+        assert_eq!(saved.id, Some(JID::new_ad("919876543210", 0, 12)));
+
+        client.disconnect();
+        server.join().unwrap();
+
+        // A new client connects as the saved device.
+        let client = Client::new().with_store(store).unwrap();
+        assert_eq!(
+            client.device.lock().unwrap().id,
+            Some(JID::new_ad("919876543210", 0, 12))
+        );
+    }
+
+    #[test]
+    fn test_pairing_error() {
+        let (url, server) = serve(|mut server| {
+            // The phone signs with a different adv secret than the one of the QR code.
+            server.send_node(&pair_success_node(&Device::new(), b"another secret"));
+            let response = server.receive_node().unwrap();
+            assert_eq!(response.attr_getter().string("type").unwrap(), "error");
+            let error = response.get_optional_child_by_tag(&["error"]).unwrap();
+            assert_eq!(error.attr_getter().string("code").unwrap(), "401");
+            wait_for_close(server);
+        });
+        let client = Arc::new(Client::new().with_socket(FrameSocket::new().with_url(&url)));
+        client.connect().unwrap();
+
+        match client.events().pop() {
+            Some(RhustAppEventType::PairError(pair_error)) => {
+                assert_eq!(pair_error.id, JID::new_ad("919876543210", 0, 12));
+                assert_eq!(
+                    *pair_error.error.kind(),
+                    ErrorKind::Iq(Box::new(crate::IqError {
+                        code: 401,
+                        text: "not-authorized".to_string(),
+                        condition: None,
+                    }))
+                );
+            }
+            _ => panic!("expected a PairError event"),
+        };
+        // The client disconnects after a failed pairing.
+        server.join().unwrap();
+        assert!(!client.is_connected());
+        assert!(client.device.lock().unwrap().id.is_none());
+    }
+
+    #[test]
+    fn test_pair_phone() {
+        let (url, server) = serve(|mut server| {
+            let hello = server.receive_node().unwrap();
+            let registration = hello
+                .get_optional_child_by_tag(&["link_code_companion_reg"])
+                .unwrap();
+            let mut ag = registration.attr_getter();
+            assert_eq!(ag.string("stage").unwrap(), "companion_hello");
+            assert_eq!(
+                ag.jid("jid").unwrap(),
+                JID::new("919876543210", DEFAULT_USER_SERVER)
+            );
+            let mut response = build_iq_result_node(&hello);
+            response.content = NodeContentType::ListOfNodes(vec![Node {
+                tag: "link_code_companion_reg".to_string(),
+                attrs: Attrs::new(),
+                content: NodeContentType::ListOfNodes(vec![Node {
+                    tag: "link_code_pairing_ref".to_string(),
+                    attrs: Attrs::new(),
+                    content: NodeContentType::ByteArray(b"pairing-ref".to_vec()),
+                }]),
+            }]);
+            server.send_node(&response);
+
+            // The second request is rejected.
+            let hello = server.receive_node().unwrap();
+            let mut response = build_iq_result_node(&hello);
+            response.attrs.insert(
+                "type".to_string(),
+                AttributeTypes::String("error".to_string()),
+            );
+            response.content = NodeContentType::ListOfNodes(vec![Node {
+                tag: "error".to_string(),
+                attrs: Attrs::from([
+                    (
+                        "code".to_string(),
+                        AttributeTypes::String("400".to_string()),
+                    ),
+                    (
+                        "text".to_string(),
+                        AttributeTypes::String("bad-request".to_string()),
+                    ),
+                ]),
+                content: NodeContentType::None,
+            }]);
+            server.send_node(&response);
+            wait_for_close(server);
+        });
+        let client = Arc::new(Client::new().with_socket(FrameSocket::new().with_url(&url)));
+        client.connect().unwrap();
+
+        let code = client.pair_phone("+91 98765 43210", true).unwrap();
+        {
+            let linking = client.phone_linking.lock().unwrap();
+            let linking = linking.as_ref().unwrap();
+            assert_eq!(code, linking.display_code());
+            assert_eq!(linking.pairing_ref, b"pairing-ref");
+        }
+
+        let err = client.pair_phone("919876543210", false).unwrap_err();
+        match err.kind() {
+            ErrorKind::Iq(iq_error) => assert_eq!(iq_error.code, 400),
+            kind => panic!("unexpected error kind {kind:?}"),
+        };
+
+        client.disconnect();
+        server.join().unwrap();
+        assert!(client.response_waiters.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_send_message() {
+        let own_id = JID::new_ad("919876543210", 0, 12);
+        let own_phone = Peer::new(JID::new_ad("919876543210", 0, 0));
+        let recipient = Peer::new(JID::new_ad("911234567890", 0, 0));
+        let devices = vec![recipient.jid.clone(), own_phone.jid.clone(), own_id.clone()];
+        let bundles = vec![recipient.bundle_node(), own_phone.bundle_node()];
+        let (sender, receiver) = mpsc::channel();
+        let (client, server) = paired_client(&own_id, move |mut server| {
+            let mut prekey_requests = 0;
+            for _ in 0..3 {
+                let usync = server.receive_node().unwrap();
+                assert_eq!(usync.attr_getter().string("xmlns").unwrap(), "usync");
+                respond(&mut server, &usync, vec![usync_devices_node(&devices)]);
+
+                let mut request = server.receive_node().unwrap();
+                if request.attr_getter().string("xmlns").as_deref() == Some("encrypt") {
+                    prekey_requests += 1;
+                    // Only the devices without a session, and not our own device.
+                    let users = request
+                        .get_optional_child_by_tag(&["key"])
+                        .unwrap()
+                        .get_children_by_tag("user")
+                        .unwrap();
+                    assert_eq!(users.len(), 2);
+                    let list = Node {
+                        tag: "list".to_string(),
+                        attrs: Attrs::new(),
+                        content: NodeContentType::ListOfNodes(bundles.clone()),
+                    };
+                    respond(&mut server, &request, vec![list]);
+                    request = server.receive_node().unwrap();
+                };
+
+                assert_eq!(request.tag, "message");
+                server.send_node(&message_ack(&request));
+                sender.send(request).unwrap();
+            }
+            // The second message reuses the sessions started for the first one.
+            assert_eq!(prekey_requests, 1);
+            wait_for_close(server);
+        });
+        let to = JID::from_str("911234567890@s.whatsapp.net").unwrap();
+
+        let response = client.send_message(&to, &text_message("hello")).unwrap();
+        let message = receiver.recv().unwrap();
+        let mut ag = message.attr_getter();
+        assert_eq!(ag.string("id").unwrap(), response.id);
+        assert_eq!(ag.string("type").unwrap(), "text");
+        assert_eq!(ag.jid("to").unwrap(), to);
+        assert_eq!(response.timestamp.unix_timestamp(), 1700000000);
+        assert!(response.server_id.is_none());
+        // The first message starts the sessions, so it carries the device identity.
+        assert!(message
+            .get_optional_child_by_tag(&["device-identity"])
+            .is_some());
+
+        let received = recipient.decrypt(&message, &own_id);
+        assert_eq!(received.conversation(), "hello");
+        let device_sent = own_phone.decrypt(&message, &own_id).deviceSentMessage;
+        assert_eq!(device_sent.destinationJid(), to.to_string());
+        assert_eq!(device_sent.message.conversation(), "hello");
+
+        let second = client.send_message(&to, &text_message("again")).unwrap();
+        assert_ne!(second.id, response.id);
+        let message = receiver.recv().unwrap();
+        assert_eq!(recipient.decrypt(&message, &own_id).conversation(), "again");
+
+        assert!(client.revoke_message(&to, "", true, None).is_err());
+        client.revoke_message(&to, &second.id, true, None).unwrap();
+        let message = receiver.recv().unwrap();
+        assert_eq!(message.attr_getter().string("edit").unwrap(), "7");
+        let revoke = recipient.decrypt(&message, &own_id).protocolMessage;
+        assert_eq!(revoke.key.id(), second.id);
+        assert!(revoke.key.fromMe());
+
+        client.disconnect();
+        server.join().unwrap();
+        assert!(client.response_waiters.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_send_group_message() {
+        let own_id = JID::new_ad("919876543210", 0, 12);
+        let group = JID::from_str("120363000000000000@g.us").unwrap();
+        let participant = Peer::new(JID::new_ad("911234567890", 0, 0));
+        let devices = vec![participant.jid.clone(), own_id.clone()];
+        let bundles = vec![participant.bundle_node()];
+        let (sender, receiver) = mpsc::channel();
+        let (client, server) = paired_client(&own_id, move |mut server| {
+            let info = server.receive_node().unwrap();
+            assert_eq!(info.attr_getter().string("xmlns").unwrap(), "w:g2");
+            let participants = ["919876543210@s.whatsapp.net", "911234567890@s.whatsapp.net"]
+                .iter()
+                .map(|jid| Node {
+                    tag: "participant".to_string(),
+                    attrs: Attrs::from([(
+                        "jid".to_string(),
+                        AttributeTypes::JID(JID::from_str(jid).unwrap()),
+                    )]),
+                    content: NodeContentType::None,
+                })
+                .collect();
+            let group_node = Node {
+                tag: "group".to_string(),
+                attrs: Attrs::new(),
+                content: NodeContentType::ListOfNodes(participants),
+            };
+            respond(&mut server, &info, vec![group_node]);
+
+            let usync = server.receive_node().unwrap();
+            respond(&mut server, &usync, vec![usync_devices_node(&devices)]);
+            let prekeys = server.receive_node().unwrap();
+            let list = Node {
+                tag: "list".to_string(),
+                attrs: Attrs::new(),
+                content: NodeContentType::ListOfNodes(bundles),
+            };
+            respond(&mut server, &prekeys, vec![list]);
+
+            let message = server.receive_node().unwrap();
+            server.send_node(&message_ack(&message));
+            sender.send(message).unwrap();
+            wait_for_close(server);
+        });
+
+        let response = client
+            .send_message(&group, &text_message("hello group"))
+            .unwrap();
+        let message = receiver.recv().unwrap();
+        let mut ag = message.attr_getter();
+        assert_eq!(ag.string("id").unwrap(), response.id);
+        assert_eq!(ag.jid("to").unwrap(), group);
+        assert_eq!(
+            ag.string("phash").unwrap(),
+            participant_list_hash(&[participant.jid.clone(), own_id.clone()])
+        );
+
+        // The participant gets the sender key first, with which it decrypts the message.
+        let distribution = participant
+            .decrypt(&message, &own_id)
+            .senderKeyDistributionMessage;
+        assert_eq!(distribution.groupId(), group.to_string());
+        process_sender_key_distribution(
+            &participant.store,
+            &group,
+            &own_id,
+            distribution.axolotlSenderKeyDistributionMessage(),
+        )
+        .unwrap();
+        let skmsg = message.get_optional_child_by_tag(&["enc"]).unwrap();
+        let plaintext = decrypt_group_message(&participant.store, &group, &own_id, &skmsg).unwrap();
+        let received = wa_proto::Message::parse_from_bytes(&plaintext).unwrap();
+        assert_eq!(received.conversation(), "hello group");
+
+        client.disconnect();
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn test_resend_on_retry_receipt() {
+        let own_id = JID::new_ad("919876543210", 0, 12);
+        let recipient = Peer::new(JID::new_ad("911234567890", 0, 0));
+        // The recipient lost its session, and asks again with new keys.
+        let reinstalled = Peer::new(recipient.jid.clone());
+        let devices = vec![recipient.jid.clone(), own_id.clone()];
+        let bundles = vec![recipient.bundle_node()];
+        let keys = build_retry_keys_node(
+            public_key_bytes(&reinstalled.device.identity_key.public_key).unwrap(),
+            &reinstalled.prekey,
+            &reinstalled.device.signed_pre_key,
+            None,
+        )
+        .unwrap();
+        let registration_id = reinstalled.device.registration_id;
+        let peer_jid = recipient.jid.clone();
+        let (sender, receiver) = mpsc::channel();
+        let (client, server) = paired_client(&own_id, move |mut server| {
+            let usync = server.receive_node().unwrap();
+            respond(&mut server, &usync, vec![usync_devices_node(&devices)]);
+            let prekeys = server.receive_node().unwrap();
+            let list = Node {
+                tag: "list".to_string(),
+                attrs: Attrs::new(),
+                content: NodeContentType::ListOfNodes(bundles),
+            };
+            respond(&mut server, &prekeys, vec![list]);
+            let message = server.receive_node().unwrap();
+            server.send_node(&message_ack(&message));
+
+            let id = message.attr_getter().string("id").unwrap();
+            let mut retry = build_retry_receipt_node(
+                &message_node(&id, &peer_jid, None, vec![]),
+                1,
+                registration_id,
+                Some(keys),
+            )
+            .unwrap();
+            retry.attrs.remove("to");
+            retry
+                .attrs
+                .insert("from".to_string(), AttributeTypes::JID(peer_jid));
+            retry.attrs.insert(
+                "t".to_string(),
+                AttributeTypes::String("1700000001".to_string()),
+            );
+            server.send_node(&retry);
+
+            let ack = server.receive_node().unwrap();
+            assert_eq!(ack.tag, "ack");
+            sender.send(server.receive_node().unwrap()).unwrap();
+            wait_for_close(server);
+        });
+        let to = JID::from_str("911234567890@s.whatsapp.net").unwrap();
+
+        let response = client.send_message(&to, &text_message("hello")).unwrap();
+        let resent = receiver.recv().unwrap();
+        let mut ag = resent.attr_getter();
+        assert_eq!(resent.tag, "message");
+        assert_eq!(ag.string("id").unwrap(), response.id);
+        assert_eq!(ag.jid("to").unwrap(), recipient.jid);
+        assert_eq!(ag.string("device_fanout").unwrap(), "false");
+
+        let enc = resent.get_optional_child_by_tag(&["enc"]).unwrap();
+        assert_eq!(enc.attr_getter().string("count").unwrap(), "1");
+        let plaintext =
+            decrypt_enc_node(&reinstalled.device, &reinstalled.store, &own_id, &enc).unwrap();
+        let received = wa_proto::Message::parse_from_bytes(&plaintext).unwrap();
+        assert_eq!(received.conversation(), "hello");
+
+        client.disconnect();
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn test_send_message_not_paired() {
+        let client = Client::new();
+        let to = JID::from_str("911234567890@s.whatsapp.net").unwrap();
+        assert!(client.send_message(&to, &text_message("hello")).is_err());
+    }
+
+    #[test]
+    fn test_receive_message() {
+        let own_id = JID::new_ad("911234567890", 0, 3);
+        let sender = Peer::new(JID::new_ad("919876543210", 0, 0));
+        let (nodes, receive) = mpsc::channel::<Node>();
+        let (receipts, receipt) = mpsc::channel();
+        let (client, server) = paired_client(&own_id, move |mut server| {
+            for node in receive {
+                server.send_node(&node);
+            }
+            for _ in 0..2 {
+                receipts.send(server.receive_node().unwrap()).unwrap();
+            }
+            wait_for_close(server);
+        });
+
+        let bundle = prekey_bundle(&client.device.lock().unwrap(), &*client.store, &own_id);
+        process_prekey_bundle(&sender.device, &sender.store, &own_id, &bundle).unwrap();
+        let plaintext = marshal_message(&text_message("hello")).unwrap();
+        let (enc, _) =
+            encrypt_for_device(&sender.device, &sender.store, &plaintext, &own_id).unwrap();
+        nodes
+            .send(message_node("3EB0AAAA", &sender.jid, None, vec![enc]))
+            .unwrap();
+        // A message that can't be decrypted is answered with a retry receipt.
+        let garbage = Node {
+            tag: "enc".to_string(),
+            attrs: Attrs::from([(
+                "type".to_string(),
+                AttributeTypes::String("msg".to_string()),
+            )]),
+            content: NodeContentType::ByteArray(vec![1, 2, 3]),
+        };
+        nodes
+            .send(message_node("3EB0BBBB", &sender.jid, None, vec![garbage]))
+            .unwrap();
+        drop(nodes);
+
+        match client.events().pop() {
+            Some(RhustAppEventType::Message(message)) => {
+                assert_eq!(message.info.id, "3EB0AAAA");
+                assert_eq!(message.info.source.sender, sender.jid);
+                assert_eq!(message.message.unwrap().conversation(), "hello");
+            }
+            _ => panic!("expected a Message event"),
+        };
+        // The decrypted message is acknowledged with a delivery receipt.
+        let delivered = receipt.recv().unwrap();
+        assert_eq!(delivered.tag, "receipt");
+        let mut ag = delivered.attr_getter();
+        assert_eq!(ag.string("id").unwrap(), "3EB0AAAA");
+        assert_eq!(ag.jid("to").unwrap(), sender.jid.to_non_ad());
+        assert!(ag.optional_string("type").is_none());
+
+        let retry = receipt.recv().unwrap();
+        assert_eq!(retry.tag, "receipt");
+        let mut ag = retry.attr_getter();
+        assert_eq!(ag.string("id").unwrap(), "3EB0BBBB");
+        assert_eq!(ag.string("type").unwrap(), "retry");
+
+        client.disconnect();
+        server.join().unwrap();
+        assert!(client.events().try_pop().is_none());
+    }
+
+    #[test]
+    fn test_server_sync_notification() {
+        let own_id = JID::new_ad("911234567890", 0, 3);
+        let chat = JID::from_str("919876543210@s.whatsapp.net").unwrap();
+        let mut action = wa_proto::SyncActionValue::new();
+        action.timestamp = Some(1_700_000_000_000);
+        action.archiveChatAction.mut_or_insert_default().archived = Some(true);
+        let patch = build_app_state_patch(
+            PatchName::Regular,
+            &mut HashState::default(),
+            vec![encrypt_app_state_mutation(
+                SyncdOperation::SET,
+                &["archive", &chat.to_string()],
+                action,
+            )],
+            |_| None,
+        );
+
+        let notification = Node {
+            tag: "notification".to_string(),
+            attrs: Attrs::from([(
+                "type".to_string(),
+                AttributeTypes::String("server_sync".to_string()),
+            )]),
+            content: NodeContentType::ListOfNodes(vec![Node {
+                tag: "collection".to_string(),
+                attrs: Attrs::from([(
+                    "name".to_string(),
+                    AttributeTypes::String("regular".to_string()),
+                )]),
+                content: NodeContentType::None,
+            }]),
+        };
+        let (start, started) = mpsc::channel();
+        let (sender, receiver) = mpsc::channel();
+        let (client, server) = paired_client(&own_id, move |mut server| {
+            started.recv().unwrap();
+            server.send_node(&notification);
+            let request = server.receive_node().unwrap();
+            sender.send(request.clone()).unwrap();
+            let collection = Node {
+                tag: "collection".to_string(),
+                attrs: Attrs::from([(
+                    "name".to_string(),
+                    AttributeTypes::String("regular".to_string()),
+                )]),
+                content: NodeContentType::ListOfNodes(vec![Node {
+                    tag: "patches".to_string(),
+                    attrs: Attrs::new(),
+                    content: NodeContentType::ListOfNodes(vec![Node {
+                        tag: "patch".to_string(),
+                        attrs: Attrs::new(),
+                        content: NodeContentType::ByteArray(patch.write_to_bytes().unwrap()),
+                    }]),
+                }]),
+            };
+            respond(
+                &mut server,
+                &request,
+                vec![Node {
+                    tag: "sync".to_string(),
+                    attrs: Attrs::new(),
+                    content: NodeContentType::ListOfNodes(vec![collection]),
+                }],
+            );
+            wait_for_close(server);
+        });
+        put_app_state_key(&*client.store);
+        let archives = client.subscribe::<Archive>();
+        start.send(()).unwrap();
+
+        // The collection wasn't synced before, so its snapshot is requested.
+        let request = receiver.recv().unwrap();
+        let collection = request
+            .get_optional_child_by_tag(&["sync", "collection"])
+            .unwrap();
+        let mut ag = collection.attr_getter();
+        assert_eq!(ag.string("name").unwrap(), "regular");
+        assert_eq!(ag.string("return_snapshot").unwrap(), "true");
+
+        let archive = archives.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(archive.jid, chat);
+        assert!(archive.action.archived());
+        assert!(!archive.from_full_sync);
+        assert!(
+            client
+                .store
+                .get_chat_settings(&chat)
+                .unwrap()
+                .unwrap()
+                .archived
+        );
+        assert_eq!(
+            client
+                .store
+                .get_app_state_version("regular")
+                .unwrap()
+                .unwrap()
+                .0,
+            1
+        );
+
+        client.disconnect();
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn test_push_name_updated() {
+        let own_id = JID::new_ad("911234567890", 0, 3);
+        let sender = JID::new_ad("919876543210", 0, 2);
+        let with_name = |id: &str, push_name: &str| {
+            let mut node = message_node(id, &sender, None, Vec::new());
+            node.attrs.insert(
+                "notify".to_string(),
+                AttributeTypes::String(push_name.to_string()),
+            );
+            node
+        };
+        let nodes = vec![
+            with_name("3EB0AAAA", "Alice"),
+            with_name("3EB0BBBB", "Alice"),
+            with_name("3EB0CCCC", "-"),
+            with_name("3EB0DDDD", "Alicia"),
+        ];
+        let (start, started) = mpsc::channel();
+        let (client, server) = paired_client(&own_id, move |mut server| {
+            started.recv().unwrap();
+            for node in &nodes {
+                server.send_node(node);
+            }
+            wait_for_close(server);
+        });
+        let updates = client.subscribe::<PushNameUpdated>();
+        start.send(()).unwrap();
+
+        let update = updates.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(update.jid, sender.to_non_ad());
+        assert_eq!(update.message.id, "3EB0AAAA");
+        assert_eq!(update.old_push_name, "");
+        assert_eq!(update.new_push_name, "Alice");
+        // Messages with the same name or without one don't change it.
+        let update = updates.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(update.message.id, "3EB0DDDD");
+        assert_eq!(update.old_push_name, "Alice");
+        assert_eq!(update.new_push_name, "Alicia");
+
+        let contact = client.get_contact(&sender).unwrap().unwrap();
+        assert_eq!(contact.push_name, "Alicia");
+        let contacts = client.get_all_contacts().unwrap();
+        assert_eq!(contacts.len(), 1);
+        assert_eq!(contacts[0].0, sender.to_non_ad());
+
+        client.disconnect();
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn test_receive_receipt() {
+        let own_id = JID::new_ad("911234567890", 0, 3);
+        let group = JID::from_str("120363000000000000@g.us").unwrap();
+        let reader = JID::new_ad("919876543210", 0, 0);
+        let receipt = Node {
+            tag: "receipt".to_string(),
+            attrs: Attrs::from([
+                ("from".to_string(), AttributeTypes::JID(group.clone())),
+                (
+                    "participant".to_string(),
+                    AttributeTypes::JID(reader.clone()),
+                ),
+                (
+                    "id".to_string(),
+                    AttributeTypes::String("3EB0AAAA".to_string()),
+                ),
+                (
+                    "type".to_string(),
+                    AttributeTypes::String("read".to_string()),
+                ),
+                (
+                    "t".to_string(),
+                    AttributeTypes::String("1700000000".to_string()),
+                ),
+            ]),
+            content: NodeContentType::None,
+        };
+        let (sender, receiver) = mpsc::channel();
+        let (client, server) = paired_client(&own_id, move |mut server| {
+            server.send_node(&receipt);
+            sender.send(server.receive_node().unwrap()).unwrap();
+            wait_for_close(server);
+        });
+        let receipts = client.subscribe::<Receipt>();
+
+        let ack = receiver.recv().unwrap();
+        assert_eq!(ack.tag, "ack");
+        let mut ag = ack.attr_getter();
+        assert_eq!(ag.string("class").unwrap(), "receipt");
+        assert_eq!(ag.string("id").unwrap(), "3EB0AAAA");
+        assert_eq!(ag.jid("to").unwrap(), group);
+
+        let receipt = receipts.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(receipt.message_ids, vec!["3EB0AAAA"]);
+        assert!(matches!(receipt.r#type, ReceiptType::Read));
+        assert_eq!(receipt.source.chat, group);
+        assert_eq!(receipt.source.sender, reader);
+
+        client.disconnect();
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn test_mark_read() {
+        let own_id = JID::new_ad("911234567890", 0, 3);
+        let (sender, receiver) = mpsc::channel();
+        let (client, server) = paired_client(&own_id, move |mut server| {
+            sender.send(server.receive_node().unwrap()).unwrap();
+            wait_for_close(server);
+        });
+
+        let chat = JID::from_str("919876543210@s.whatsapp.net").unwrap();
+        let timestamp = OffsetDateTime::from_unix_timestamp(1700000000).unwrap();
+        client
+            .mark_read(&["3EB0AAAA".to_string()], &chat, &chat, timestamp)
+            .unwrap();
+        let receipt = receiver.recv().unwrap();
+        let mut ag = receipt.attr_getter();
+        assert_eq!(ag.string("id").unwrap(), "3EB0AAAA");
+        assert_eq!(ag.jid("to").unwrap(), chat);
+        assert_eq!(ag.string("type").unwrap(), "read");
+        assert_eq!(ag.string("t").unwrap(), "1700000000");
+
+        assert!(client.mark_read(&[], &chat, &chat, timestamp).is_err());
+        client.disconnect();
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn test_event_handlers() {
+        let client = Client::new();
+        let (sender, handled) = mpsc::channel();
+        let handler = client.add_event_handler(move |event| {
+            let _ = sender.send(matches!(event, RhustAppEventType::Connected));
+        });
+        let stream_errors = client.subscribe::<StreamError>();
+
+        client.events.push(RhustAppEventType::Connected);
+        client
+            .events
+            .push(RhustAppEventType::StreamError(StreamError {
+                code: "503".to_string(),
+            }));
+        let timeout = Duration::from_secs(5);
+        assert!(handled.recv_timeout(timeout).unwrap());
+        assert!(!handled.recv_timeout(timeout).unwrap());
+        assert_eq!(stream_errors.recv_timeout(timeout).unwrap().code, "503");
+
+        assert!(client.remove_event_handler(handler));
+        assert!(!client.remove_event_handler(handler));
+        client
+            .events
+            .push(RhustAppEventType::StreamError(StreamError {
+                code: "500".to_string(),
+            }));
+        assert_eq!(stream_errors.recv_timeout(timeout).unwrap().code, "500");
+        assert!(handled.try_recv().is_err());
+
+        // Dropping the client closes the queue, which ends the subscription.
+        drop(client);
+        assert!(stream_errors.recv_timeout(timeout).is_err());
+    }
+
+    #[test]
+    fn test_keepalive() {
+        let (url, server) = serve(|mut server| {
+            server.send_node(&Node {
+                tag: "success".to_string(),
+                ..Default::default()
+            });
+            // The first two pings time out.
+            let mut pings = 0;
+            while let Some(ping) = server.receive_node() {
+                assert!(ping.get_optional_child_by_tag(&["ping"]).is_some());
+                pings += 1;
+                if pings > 2 {
+                    respond(&mut server, &ping, vec![]);
+                };
+            }
+        });
+        let client = Arc::new(
+            Client::new()
+                .with_socket(FrameSocket::new().with_url(&url))
+                .with_keepalive_config(KeepAliveConfig {
+                    min_interval: Duration::from_millis(20),
+                    max_interval: Duration::from_millis(20),
+                    response_timeout: Duration::from_millis(200),
+                }),
+        );
+        client.connect().unwrap();
+        let events = client.events();
+
+        assert!(matches!(events.pop(), Some(RhustAppEventType::Connected)));
+        for expected in 1..=2 {
+            match events.pop() {
+                Some(RhustAppEventType::KeepAliveTimeout(timeout)) => {
+                    assert_eq!(timeout.error_count, expected)
+                }
+                _ => panic!("expected a KeepAliveTimeout event"),
+            };
+        }
+        assert!(matches!(
+            events.pop(),
+            Some(RhustAppEventType::KeepAliveRestored)
+        ));
+
+        client.disconnect();
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn test_send_iq() {
+        let (url, server) = serve(|mut server| {
+            let first = server.receive_node().unwrap();
+            let mut ag = first.attr_getter();
+            assert_eq!(ag.string("xmlns").unwrap(), "w:p");
+            assert_eq!(ag.string("type").unwrap(), "get");
+            respond(&mut server, &first, vec![]);
+
+            // The second request times out, and the third one is answered.
+            let second = server.receive_node().unwrap();
+            let third = server.receive_node().unwrap();
+            assert_ne!(
+                first.attr_getter().string("id").unwrap(),
+                second.attr_getter().string("id").unwrap()
+            );
+            respond(&mut server, &third, vec![]);
+            wait_for_close(server);
+        });
+        let client = Arc::new(Client::new().with_socket(FrameSocket::new().with_url(&url)));
+        client.connect().unwrap();
+
+        let query = InfoQuery::new("w:p", InfoQueryType::Get, SERVER_JID.clone());
+        let response = client.send_iq(query.clone()).unwrap();
+        assert_eq!(response.attr_getter().string("type").unwrap(), "result");
+
+        let mut short = query.clone();
+        short.timeout = Some(Duration::from_millis(100));
+        assert!(client.send_iq(short).is_err());
+
+        let response = client.send_iq_async(query).recv().unwrap().unwrap();
+        assert_eq!(response.attr_getter().string("type").unwrap(), "result");
+
+        client.disconnect();
+        server.join().unwrap();
+        assert!(client.response_waiters.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_is_on_whatsapp() {
+        let (url, server) = serve(|mut server| {
+            let query = server.receive_node().unwrap();
+            let contact = query
+                .get_optional_child_by_tag(&["usync", "list", "user", "contact"])
+                .unwrap();
+            assert_eq!(
+                contact.content,
+                NodeContentType::ByteArray(b"+911234567890".to_vec())
+            );
+            let user = Node {
+                tag: "user".to_string(),
+                attrs: Attrs::from([(
+                    "jid".to_string(),
+                    AttributeTypes::JID(JID::new("911234567890", DEFAULT_USER_SERVER)),
+                )]),
+                content: NodeContentType::ListOfNodes(vec![Node {
+                    tag: "contact".to_string(),
+                    attrs: Attrs::from([(
+                        "type".to_string(),
+                        AttributeTypes::String("in".to_string()),
+                    )]),
+                    content: contact.content,
+                }]),
+            };
+            let mut usync = usync_devices_node(&[]);
+            usync.content = NodeContentType::ListOfNodes(vec![Node {
+                tag: "list".to_string(),
+                attrs: Attrs::new(),
+                content: NodeContentType::ListOfNodes(vec![user]),
+            }]);
+            respond(&mut server, &query, vec![usync]);
+            wait_for_close(server);
+        });
+        let client = Arc::new(Client::new().with_socket(FrameSocket::new().with_url(&url)));
+        client.connect().unwrap();
+
+        let results = client.is_on_whatsapp(&["911234567890"]).unwrap();
+        assert_eq!(results.len(), 1);
+        assert!(results[0].is_in);
+        assert_eq!(
+            results[0].jid,
+            JID::new("911234567890", DEFAULT_USER_SERVER)
+        );
+
+        client.disconnect();
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn test_upload_reuses_media_conn() {
+        let (url, server) = serve(|mut server| {
+            let query = server.receive_node().unwrap();
+            assert!(query.get_optional_child_by_tag(&["media_conn"]).is_some());
+            respond(
+                &mut server,
+                &query,
+                vec![Node {
+                    tag: "media_conn".to_string(),
+                    attrs: Attrs::from([
+                        (
+                            "auth".to_string(),
+                            AttributeTypes::String("token".to_string()),
+                        ),
+                        ("ttl".to_string(), AttributeTypes::String("300".to_string())),
+                    ]),
+                    content: NodeContentType::ListOfNodes(vec![Node {
+                        tag: "host".to_string(),
+                        attrs: Attrs::from([(
+                            "hostname".to_string(),
+                            AttributeTypes::String("127.0.0.1:1".to_string()),
+                        )]),
+                        content: NodeContentType::None,
+                    }]),
+                }],
+            );
+            // The second upload doesn't fetch the hosts again.
+            assert!(server.receive_node().is_none());
+        });
+        let client = Arc::new(Client::new().with_socket(FrameSocket::new().with_url(&url)));
+        client.connect().unwrap();
+
+        // Nothing listens on the host, so both uploads fail after getting the hosts.
+        for _ in 0..2 {
+            let err = client.upload(b"image data", MediaType::Image).unwrap_err();
+            assert!(err.description.contains("connect to media server"));
+        }
+
+        client.disconnect();
+        server.join().unwrap();
+    }
+}
@@ -0,0 +1,146 @@
+//! `connection_events` contains the parsers for the nodes the server sends about the state of
+//! the connection itself, like `<stream:error>`.
+
+use time::Duration;
+
+use crate::{
+    binary::Node,
+    types::events::{
+        ConnectFailure, ConnectFailureReason, LoggedOut, RhustAppEventType, StreamError,
+        TempBanReason, TemporaryBan,
+    },
+};
+
+/// Converts a `<stream:error>` node into the event that should be emitted for it.
+///
+/// A `StreamRestartRequired` event means that the client should reconnect right away, it
+/// must not be handled as a logout.
+pub fn parse_stream_error(node: &Node) -> RhustAppEventType {
+    let mut ag = node.attr_getter();
+    let code = ag.optional_string("code").unwrap_or_default();
+    let conflict_type = node
+        .get_optional_child_by_tag(&["conflict"])
+        .and_then(|conflict| conflict.attr_getter().optional_string("type"))
+        .unwrap_or_default();
+
+    match (code.as_str(), conflict_type.as_str()) {
+        ("515", _) => RhustAppEventType::StreamRestartRequired,
+        ("401", "device_removed") => RhustAppEventType::LoggedOut(LoggedOut {
+            on_connect: false,
+            reason: ConnectFailureReason::LoggedOut,
+        }),
+        (_, "replaced") => RhustAppEventType::StreamReplaced,
+        _ => RhustAppEventType::StreamError(StreamError { code }),
+    }
+}
+
+/// Converts a `<failure>` node, which the server sends instead of `<success>` when it
+/// rejects the connection, into the event that should be emitted for it.
+pub fn parse_connect_failure(node: &Node) -> RhustAppEventType {
+    let mut ag = node.attr_getter();
+    let reason = ConnectFailureReason::from(ag.optional_i32("reason").unwrap_or_default());
+    let message = ag.optional_string("message").unwrap_or_default();
+
+    match reason {
+        reason if reason.is_logged_out() => RhustAppEventType::LoggedOut(LoggedOut {
+            on_connect: true,
+            reason,
+        }),
+        ConnectFailureReason::TempBanned => RhustAppEventType::TemporaryBan(TemporaryBan {
+            code: TempBanReason::from(ag.optional_i32("code").unwrap_or_default()),
+            expire: Duration::seconds(ag.optional_i32("expire").unwrap_or_default().into()),
+        }),
+        ConnectFailureReason::ClientOutdated => RhustAppEventType::ClientOutdated,
+        reason => RhustAppEventType::ConnectFailure(ConnectFailure { reason, message }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::binary::{AttributeTypes, Attrs, NodeContentType};
+
+    use super::*;
+
+    fn stream_error(code: &str, conflict_type: Option<&str>) -> Node {
+        Node {
+            tag: "stream:error".to_string(),
+            attrs: Attrs::from([("code".to_string(), AttributeTypes::String(code.to_string()))]),
+            content: match conflict_type {
+                Some(t) => NodeContentType::ListOfNodes(vec![Node {
+                    tag: "conflict".to_string(),
+                    attrs: Attrs::from([(
+                        "type".to_string(),
+                        AttributeTypes::String(t.to_string()),
+                    )]),
+                    content: NodeContentType::None,
+                }]),
+                None => NodeContentType::None,
+            },
+        }
+    }
+
+    #[test]
+    fn test_parse_stream_error_restart_required() {
+        assert!(matches!(
+            parse_stream_error(&stream_error("515", None)),
+            RhustAppEventType::StreamRestartRequired
+        ));
+    }
+
+    #[test]
+    fn test_parse_stream_error_other() {
+        assert!(matches!(
+            parse_stream_error(&stream_error("401", Some("device_removed"))),
+            RhustAppEventType::LoggedOut(LoggedOut {
+                on_connect: false,
+                ..
+            })
+        ));
+        assert!(matches!(
+            parse_stream_error(&stream_error("409", Some("replaced"))),
+            RhustAppEventType::StreamReplaced
+        ));
+        assert!(matches!(
+            parse_stream_error(&stream_error("500", None)),
+            RhustAppEventType::StreamError(StreamError { code }) if code == "500"
+        ));
+    }
+
+    #[test]
+    fn test_parse_connect_failure() {
+        let failure = |attrs: &[(&str, &str)]| Node {
+            tag: "failure".to_string(),
+            attrs: attrs
+                .iter()
+                .map(|(key, value)| (key.to_string(), AttributeTypes::String(value.to_string())))
+                .collect(),
+            content: NodeContentType::None,
+        };
+
+        assert!(matches!(
+            parse_connect_failure(&failure(&[("reason", "401")])),
+            RhustAppEventType::LoggedOut(LoggedOut {
+                on_connect: true,
+                reason: ConnectFailureReason::LoggedOut,
+            })
+        ));
+        assert!(matches!(
+            parse_connect_failure(&failure(&[("reason", "402"), ("code", "102"), ("expire", "3600")])),
+            RhustAppEventType::TemporaryBan(TemporaryBan {
+                code: TempBanReason::BlockedByUsers,
+                expire,
+            }) if expire == Duration::hours(1)
+        ));
+        assert!(matches!(
+            parse_connect_failure(&failure(&[("reason", "405")])),
+            RhustAppEventType::ClientOutdated
+        ));
+        assert!(matches!(
+            parse_connect_failure(&failure(&[("reason", "503"), ("message", "try later")])),
+            RhustAppEventType::ConnectFailure(ConnectFailure {
+                reason: ConnectFailureReason::ServiceUnavailable,
+                message,
+            }) if message == "try later"
+        ));
+    }
+}
@@ -0,0 +1,443 @@
+//! `dispatch` contains the demultiplexer that converts the nodes received from the server
+//! into events.
+
+use std::str::FromStr;
+
+use crate::{
+    binary::Node,
+    connection_events::{parse_connect_failure, parse_stream_error},
+    new_rhustapp_error,
+    types::{
+        events::{
+            CallAccept, CallOffer, CallTerminate, ChatPresence, DeviceListUpdate, GroupInfo,
+            Message, PictureChange, PreKeysLow, Presence, PrivacySettingsChange, Receipt,
+            RhustAppEventType,
+        },
+        BasicCallMetadata, CallRemoteMetadata, CallTerminateReason, MessageInfo, JID,
+    },
+    RhustAppError,
+};
+
+/// Converts a node received from the server into the event that should be emitted for it,
+/// based on the tag of the node. `own_jid` is the JID of the current user.
+///
+/// Returns `None` for nodes that don't produce an event (e.g. `<iq>` responses or unknown
+/// notifications) and for nodes that can't be parsed, which are logged.
+///
+/// Messages are emitted with only their `MessageInfo`, decrypting the content is left to
+/// the caller.
+pub fn node_to_event(node: &Node, own_jid: &JID) -> Option<RhustAppEventType> {
+    let event = match node.tag.as_str() {
+        "message" => MessageInfo::from_node(node, own_jid).map(|info| {
+            Some(RhustAppEventType::Message(Box::new(Message {
+                info,
+                message: None,
+            })))
+        }),
+        "receipt" => Receipt::from_node(node, own_jid).map(|r| Some(RhustAppEventType::Receipt(r))),
+        "presence" => Presence::from_node(node).map(|p| Some(RhustAppEventType::Presence(p))),
+        "chatstate" => {
+            ChatPresence::from_node(node, own_jid).map(|p| Some(RhustAppEventType::ChatPresence(p)))
+        }
+        "notification" => notification_to_event(node),
+        "failure" => Ok(Some(parse_connect_failure(node))),
+        "stream:error" => Ok(Some(parse_stream_error(node))),
+        "call" => call_to_event(node),
+        _ => Ok(None),
+    };
+
+    match event {
+        Ok(event) => event,
+        Err(err) => {
+            log::warn!("failed to convert <{}> into an event: {err}", node.tag);
+            None
+        }
+    }
+}
+
+fn notification_to_event(node: &Node) -> Result<Option<RhustAppEventType>, RhustAppError> {
+    let notification_type = node
+        .attr_getter()
+        .optional_string("type")
+        .unwrap_or_default();
+
+    match notification_type.as_str() {
+        "devices" => Ok(Some(RhustAppEventType::DeviceListUpdate(
+            DeviceListUpdate::from_node(node)?,
+        ))),
+        "privacy" => Ok(Some(RhustAppEventType::PrivacySettingsChange(
+            PrivacySettingsChange::from_node(node)?,
+        ))),
+        // Encrypt notifications without a count are about identity changes.
+        "encrypt" if node.get_optional_child_by_tag(&["count"]).is_some() => Ok(Some(
+            RhustAppEventType::PreKeysLow(PreKeysLow::from_node(node)?),
+        )),
+        "w:gp2" => Ok(Some(RhustAppEventType::GroupInfo(GroupInfo::from_node(
+            node,
+        )?))),
+        "picture" => Ok(Some(RhustAppEventType::PictureChange(
+            PictureChange::from_node(node)?,
+        ))),
+        _ => Ok(None),
+    }
+}
+
+/// Converts a `<call>` node, which has one child describing what happened to the call.
+fn call_to_event(node: &Node) -> Result<Option<RhustAppEventType>, RhustAppError> {
+    let child = node
+        .get_children()
+        .and_then(|children| children.into_iter().next())
+        .ok_or_else(|| new_rhustapp_error("missing child in call", None))?;
+
+    let mut ag = node.attr_getter();
+    let mut cag = child.attr_getter();
+    let from = ag.jid("from");
+    let timestamp = ag.unix_time("t");
+    let call_creator = cag.jid("call-creator");
+    let call_id = cag.string("call-id");
+    if let Some(err) = ag.error().or_else(|| cag.error()) {
+        return Err(new_rhustapp_error(
+            "failed to parse call",
+            Some(err.to_string()),
+        ));
+    };
+
+    let metadata = BasicCallMetadata {
+        from: from.unwrap(),
+        timestamp: timestamp.unwrap(),
+        call_creator: call_creator.unwrap(),
+        call_id: call_id.unwrap(),
+    };
+    let remote = CallRemoteMetadata {
+        remote_platform: ag.optional_string("platform").unwrap_or_default(),
+        remote_version: ag.optional_string("version").unwrap_or_default(),
+    };
+
+    match child.tag.as_str() {
+        "offer" => Ok(Some(RhustAppEventType::CallOffer(CallOffer {
+            metadata,
+            remote,
+        }))),
+        "accept" => Ok(Some(RhustAppEventType::CallAccept(CallAccept {
+            metadata,
+            remote,
+        }))),
+        "terminate" => Ok(Some(RhustAppEventType::CallTerminate(CallTerminate {
+            metadata,
+            reason: CallTerminateReason::from_str(
+                &cag.optional_string("reason").unwrap_or_default(),
+            )?,
+        }))),
+        _ => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        binary::NodeContentType,
+        testing::node,
+        types::{
+            events::ConnectFailureReason, ChatPresenceMedia, PrivacySetting, PrivacySettingType,
+        },
+    };
+
+    use super::*;
+
+    fn own_jid() -> JID {
+        JID::new_ad("911111111111", 0, 1)
+    }
+
+    #[test]
+    fn test_node_to_event_message() {
+        let message = node(
+            "message",
+            &[
+                ("from", "919876543210@s.whatsapp.net"),
+                ("id", "3EB0ABCDEF"),
+                ("t", "1677913600"),
+                ("type", "text"),
+            ],
+            NodeContentType::ListOfNodes(vec![node(
+                "enc",
+                &[("type", "msg"), ("v", "2")],
+                NodeContentType::None,
+            )]),
+        );
+
+        match node_to_event(&message, &own_jid()) {
+            Some(RhustAppEventType::Message(event)) => {
+                assert_eq!(event.info.id, "3EB0ABCDEF");
+                assert_eq!(event.info.source.sender.user, "919876543210");
+                assert!(event.message.is_none());
+            }
+            _ => panic!("expected a message event"),
+        }
+    }
+
+    #[test]
+    fn test_node_to_event_receipt() {
+        let receipt = node(
+            "receipt",
+            &[
+                ("from", "919876543210@s.whatsapp.net"),
+                ("id", "3EB0ABCDEF"),
+                ("t", "1677913600"),
+                ("type", "read"),
+            ],
+            NodeContentType::None,
+        );
+
+        assert!(matches!(
+            node_to_event(&receipt, &own_jid()),
+            Some(RhustAppEventType::Receipt(Receipt { message_ids, .. })) if message_ids == vec!["3EB0ABCDEF"]
+        ));
+    }
+
+    #[test]
+    fn test_node_to_event_presence() {
+        let presence = node(
+            "presence",
+            &[
+                ("from", "919876543210@s.whatsapp.net"),
+                ("type", "unavailable"),
+                ("last", "1677913600"),
+            ],
+            NodeContentType::None,
+        );
+        match node_to_event(&presence, &own_jid()) {
+            Some(RhustAppEventType::Presence(presence)) => {
+                assert!(presence.unavailable);
+                assert_eq!(presence.last_seen.unwrap().unix_timestamp(), 1677913600);
+            }
+            _ => panic!("expected a presence event"),
+        }
+
+        let hidden = node(
+            "presence",
+            &[
+                ("from", "919876543210@s.whatsapp.net"),
+                ("type", "unavailable"),
+                ("last", "deny"),
+            ],
+            NodeContentType::None,
+        );
+        assert!(matches!(
+            node_to_event(&hidden, &own_jid()),
+            Some(RhustAppEventType::Presence(Presence {
+                last_seen: None,
+                ..
+            }))
+        ));
+    }
+
+    #[test]
+    fn test_node_to_event_chat_presence() {
+        let chatstate = node(
+            "chatstate",
+            &[("from", "919876543210@s.whatsapp.net")],
+            NodeContentType::ListOfNodes(vec![node(
+                "composing",
+                &[("media", "audio")],
+                NodeContentType::None,
+            )]),
+        );
+
+        assert!(matches!(
+            node_to_event(&chatstate, &own_jid()),
+            Some(RhustAppEventType::ChatPresence(ChatPresence {
+                state: crate::types::ChatPresence::Composing,
+                media: ChatPresenceMedia::Audio,
+                ..
+            }))
+        ));
+    }
+
+    #[test]
+    fn test_node_to_event_group_notification() {
+        let notification = node(
+            "notification",
+            &[
+                ("from", "120363000000000000@g.us"),
+                ("participant", "919876543210@s.whatsapp.net"),
+                ("t", "1677913600"),
+                ("type", "w:gp2"),
+            ],
+            NodeContentType::ListOfNodes(vec![
+                node(
+                    "add",
+                    &[],
+                    NodeContentType::ListOfNodes(vec![
+                        node(
+                            "participant",
+                            &[("jid", "911234567890@s.whatsapp.net")],
+                            NodeContentType::None,
+                        ),
+                        node(
+                            "participant",
+                            &[("jid", "910987654321@s.whatsapp.net")],
+                            NodeContentType::None,
+                        ),
+                    ]),
+                ),
+                node("locked", &[], NodeContentType::None),
+                node(
+                    "subject",
+                    &[
+                        ("subject", "Weekend plans"),
+                        ("s_t", "1677913600"),
+                        ("s_o", "919876543210@s.whatsapp.net"),
+                    ],
+                    NodeContentType::None,
+                ),
+            ]),
+        );
+
+        match node_to_event(&notification, &own_jid()) {
+            Some(RhustAppEventType::GroupInfo(info)) => {
+                assert_eq!(info.jid.to_string(), "120363000000000000@g.us");
+                assert_eq!(
+                    info.join
+                        .iter()
+                        .map(|jid| jid.user.as_str())
+                        .collect::<Vec<&str>>(),
+                    vec!["911234567890", "910987654321"]
+                );
+                assert!(info.locked.unwrap().is_locked);
+                assert_eq!(info.name.unwrap().name, "Weekend plans");
+                assert!(info.leave.is_empty());
+            }
+            _ => panic!("expected a group info event"),
+        }
+    }
+
+    #[test]
+    fn test_node_to_event_privacy_notification() {
+        let category = |name: &str, value: &str| {
+            node(
+                "category",
+                &[("name", name), ("value", value)],
+                NodeContentType::None,
+            )
+        };
+        let notification = node(
+            "notification",
+            &[("type", "privacy")],
+            NodeContentType::ListOfNodes(vec![node(
+                "privacy",
+                &[],
+                NodeContentType::ListOfNodes(vec![
+                    category("last", "contacts"),
+                    category("readreceipts", "none"),
+                ]),
+            )]),
+        );
+
+        match node_to_event(&notification, &own_jid()) {
+            Some(RhustAppEventType::PrivacySettingsChange(change)) => {
+                assert_eq!(change.changes.len(), 2);
+                assert!(matches!(
+                    change.changes[0].setting,
+                    PrivacySettingType::LastSeen
+                ));
+                assert!(matches!(change.changes[0].value, PrivacySetting::Contacts));
+                assert!(matches!(
+                    change.changes[1].setting,
+                    PrivacySettingType::ReadReceipts
+                ));
+                assert!(matches!(change.changes[1].value, PrivacySetting::None));
+            }
+            _ => panic!("expected a privacy settings change event"),
+        }
+    }
+
+    #[test]
+    fn test_node_to_event_connection() {
+        let failure = node("failure", &[("reason", "401")], NodeContentType::None);
+        assert!(matches!(
+            node_to_event(&failure, &own_jid()),
+            Some(RhustAppEventType::LoggedOut(_))
+        ));
+
+        let stream_error = node("stream:error", &[("code", "515")], NodeContentType::None);
+        assert!(matches!(
+            node_to_event(&stream_error, &own_jid()),
+            Some(RhustAppEventType::StreamRestartRequired)
+        ));
+
+        let failure = node("failure", &[("reason", "409")], NodeContentType::None);
+        assert!(matches!(
+            node_to_event(&failure, &own_jid()),
+            Some(RhustAppEventType::ConnectFailure(failure))
+                if matches!(failure.reason, ConnectFailureReason::BadUserAgent)
+        ));
+    }
+
+    #[test]
+    fn test_node_to_event_call() {
+        let call = |child: Node| {
+            node(
+                "call",
+                &[
+                    ("from", "919876543210@s.whatsapp.net"),
+                    ("t", "1677913600"),
+                    ("platform", "android"),
+                    ("version", "2.23.4.76"),
+                ],
+                NodeContentType::ListOfNodes(vec![child]),
+            )
+        };
+        let call_attrs = [
+            ("call-id", "ABCDEF0123456789"),
+            ("call-creator", "919876543210@s.whatsapp.net"),
+        ];
+
+        match node_to_event(
+            &call(node("offer", &call_attrs, NodeContentType::None)),
+            &own_jid(),
+        ) {
+            Some(RhustAppEventType::CallOffer(offer)) => {
+                assert_eq!(offer.metadata.call_id, "ABCDEF0123456789");
+                assert_eq!(offer.remote.remote_platform, "android");
+            }
+            _ => panic!("expected a call offer event"),
+        }
+
+        let mut terminate_attrs = call_attrs.to_vec();
+        terminate_attrs.push(("reason", "busy"));
+        assert!(matches!(
+            node_to_event(
+                &call(node("terminate", &terminate_attrs, NodeContentType::None)),
+                &own_jid()
+            ),
+            Some(RhustAppEventType::CallTerminate(CallTerminate {
+                reason: CallTerminateReason::Busy,
+                ..
+            }))
+        ));
+    }
+
+    #[test]
+    fn test_node_to_event_ignored() {
+        let iq = node(
+            "iq",
+            &[("id", "1"), ("type", "result")],
+            NodeContentType::None,
+        );
+        assert!(node_to_event(&iq, &own_jid()).is_none());
+
+        let unknown_notification = node(
+            "notification",
+            &[("from", "s.whatsapp.net"), ("type", "server_sync")],
+            NodeContentType::None,
+        );
+        assert!(node_to_event(&unknown_notification, &own_jid()).is_none());
+
+        let broken_receipt = node(
+            "receipt",
+            &[("from", "919876543210@s.whatsapp.net")],
+            NodeContentType::None,
+        );
+        assert!(node_to_event(&broken_receipt, &own_jid()).is_none());
+    }
+}
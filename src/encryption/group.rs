@@ -0,0 +1,536 @@
+use aes::Aes256;
+use block_modes::{block_padding::Pkcs7, BlockMode, Cbc};
+use hkdf::Hkdf;
+use hmac::{Hmac, Mac, NewMac};
+use libsignal_protocol::{KeyPair, PrivateKey, PublicKey};
+use protobuf::{rt::WireType, CodedInputStream, CodedOutputStream, MessageField};
+use rand::{Rng, RngCore};
+use sha2::Sha256;
+
+use crate::{
+    binary::{proto as wa_proto, Node},
+    encryption::session::{enc_node, pad_message, parse_enc_node, signal_error, unpad_plaintext},
+    new_rhustapp_error,
+    store::DeviceStore,
+    types::JID,
+    RhustAppError,
+};
+
+/// The version byte of sender key messages, with the current and the minimum version (3) in
+/// the high and low nibble.
+const SENDER_KEY_VERSION: u8 = 3 << 4 | 3;
+const SIGNATURE_LENGTH: usize = 64;
+/// How many message keys are kept for messages that arrive out of order.
+const MAX_MESSAGE_KEYS: usize = 2000;
+/// How far ahead of the chain a message may be.
+const MAX_FORWARD_JUMPS: u32 = 25_000;
+
+type Aes256Cbc = Cbc<Aes256, Pkcs7>;
+
+/// A protobuf field, as read by `read_fields`. Other wire types are skipped.
+enum Field {
+    Varint(u64),
+    Bytes(Vec<u8>),
+}
+
+/// Reads the varint and length-delimited fields of a protobuf message. The sender key
+/// messages are tiny and not part of the WhatsApp protobuf definitions, so they are encoded
+/// by hand.
+fn read_fields(bytes: &[u8]) -> Result<Vec<(u32, Field)>, RhustAppError> {
+    let to_err = |err: protobuf::Error| {
+        new_rhustapp_error("failed to parse sender key", Some(err.to_string()))
+    };
+    let mut input = CodedInputStream::from_bytes(bytes);
+    let mut fields = Vec::new();
+    while let Some(tag) = input.read_raw_tag_or_eof().map_err(to_err)? {
+        let field = match WireType::new(tag & 7) {
+            Some(WireType::Varint) => Field::Varint(input.read_uint64().map_err(to_err)?),
+            Some(WireType::LengthDelimited) => Field::Bytes(input.read_bytes().map_err(to_err)?),
+            Some(wire_type) => {
+                input.skip_field(wire_type).map_err(to_err)?;
+                continue;
+            }
+            None => {
+                return Err(new_rhustapp_error(
+                    "failed to parse sender key",
+                    Some(format!("invalid tag {tag}")),
+                ))
+            }
+        };
+        fields.push((tag >> 3, field));
+    }
+    Ok(fields)
+}
+
+fn write_fields(
+    write: impl FnOnce(&mut CodedOutputStream) -> protobuf::Result<()>,
+) -> Result<Vec<u8>, RhustAppError> {
+    let mut bytes = Vec::new();
+    let mut output = CodedOutputStream::vec(&mut bytes);
+    write(&mut output)
+        .and_then(|_| output.flush())
+        .map_err(|err| new_rhustapp_error("failed to encode sender key", Some(err.to_string())))?;
+    drop(output);
+    Ok(bytes)
+}
+
+fn missing_field(name: &str) -> RhustAppError {
+    new_rhustapp_error(&format!("sender key is missing {name}"), None)
+}
+
+/// Derives the next chain key (`2`) or the seed of the message key (`1`) from a chain key.
+fn chain_step(chain_key: &[u8], step: u8) -> Vec<u8> {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(chain_key).expect("HMAC-SHA256 should accept any key size");
+    mac.update(&[step]);
+    mac.finalize().into_bytes().to_vec()
+}
+
+/// Returns the cipher of a message from the seed of its message key.
+fn message_cipher(seed: &[u8]) -> Aes256Cbc {
+    let mut derived = [0u8; 48];
+    Hkdf::<Sha256>::new(None, seed)
+        .expand(b"WhisperGroup", &mut derived)
+        .expect("48 bytes is a valid HKDF-SHA256 output length");
+    Aes256Cbc::new_from_slices(&derived[16..], &derived[..16])
+        .expect("the key and IV lengths are valid for AES-256-CBC")
+}
+
+/// The sender key of a user in a group: the chain that the keys of its messages are derived
+/// from, and the key that its messages are signed with.
+struct SenderKeyState {
+    key_id: u32,
+    iteration: u32,
+    chain_key: Vec<u8>,
+    signing_key: PublicKey,
+    /// Only set for our own sender keys.
+    signing_private_key: Option<PrivateKey>,
+    /// The seeds of the message keys that were skipped, by iteration.
+    message_keys: Vec<(u32, Vec<u8>)>,
+}
+
+impl SenderKeyState {
+    fn generate() -> Self {
+        let mut csprng = rand::rngs::OsRng;
+        let signing_key = KeyPair::generate(&mut csprng);
+        let mut chain_key = vec![0u8; 32];
+        csprng.fill_bytes(&mut chain_key);
+
+        Self {
+            key_id: csprng.gen::<u32>() >> 1,
+            iteration: 0,
+            chain_key,
+            signing_key: signing_key.public_key,
+            signing_private_key: Some(signing_key.private_key),
+            message_keys: Vec::new(),
+        }
+    }
+
+    /// Serializes the state for the `DeviceStore`.
+    fn serialize(&self) -> Result<Vec<u8>, RhustAppError> {
+        write_fields(|output| {
+            output.write_uint32(1, self.key_id)?;
+            output.write_uint32(2, self.iteration)?;
+            output.write_bytes(3, &self.chain_key)?;
+            output.write_bytes(4, &self.signing_key.serialize())?;
+            if let Some(private_key) = &self.signing_private_key {
+                output.write_bytes(5, &private_key.serialize())?;
+            };
+            for (iteration, seed) in &self.message_keys {
+                let mut message_key = iteration.to_be_bytes().to_vec();
+                message_key.extend_from_slice(seed);
+                output.write_bytes(6, &message_key)?;
+            }
+            Ok(())
+        })
+    }
+
+    fn deserialize(bytes: &[u8]) -> Result<Self, RhustAppError> {
+        let (mut key_id, mut iteration, mut chain_key, mut signing_key) = (None, 0, None, None);
+        let mut signing_private_key = None;
+        let mut message_keys = Vec::new();
+        for field in read_fields(bytes)? {
+            match field {
+                (1, Field::Varint(value)) => key_id = Some(value as u32),
+                (2, Field::Varint(value)) => iteration = value as u32,
+                (3, Field::Bytes(value)) => chain_key = Some(value),
+                (4, Field::Bytes(value)) => {
+                    signing_key = Some(
+                        PublicKey::deserialize(&value)
+                            .map_err(signal_error("failed to parse sender key"))?,
+                    )
+                }
+                (5, Field::Bytes(value)) => {
+                    signing_private_key = Some(
+                        PrivateKey::deserialize(&value)
+                            .map_err(signal_error("failed to parse sender key"))?,
+                    )
+                }
+                (6, Field::Bytes(value)) if value.len() > 4 => {
+                    let (iteration, seed) = value.split_at(4);
+                    message_keys.push((
+                        u32::from_be_bytes(iteration.try_into().unwrap()),
+                        seed.to_vec(),
+                    ));
+                }
+                _ => {}
+            };
+        }
+
+        Ok(Self {
+            key_id: key_id.ok_or_else(|| missing_field("key ID"))?,
+            iteration,
+            chain_key: chain_key.ok_or_else(|| missing_field("chain key"))?,
+            signing_key: signing_key.ok_or_else(|| missing_field("signing key"))?,
+            signing_private_key,
+            message_keys,
+        })
+    }
+
+    /// Builds the `SenderKeyDistributionMessage` that lets other devices decrypt the
+    /// messages from the current iteration on.
+    fn distribution_message(&self) -> Result<Vec<u8>, RhustAppError> {
+        let mut message = vec![SENDER_KEY_VERSION];
+        message.extend(write_fields(|output| {
+            output.write_uint32(1, self.key_id)?;
+            output.write_uint32(2, self.iteration)?;
+            output.write_bytes(3, &self.chain_key)?;
+            output.write_bytes(4, &self.signing_key.serialize())
+        })?);
+        Ok(message)
+    }
+
+    fn from_distribution_message(message: &[u8]) -> Result<Self, RhustAppError> {
+        match message.split_first() {
+            Some((version, fields)) if version >> 4 == 3 => Self::deserialize(fields),
+            Some((version, _)) => Err(new_rhustapp_error(
+                &format!("unsupported sender key version {}", version >> 4),
+                None,
+            )),
+            None => Err(new_rhustapp_error("empty sender key distribution", None)),
+        }
+    }
+
+    /// Returns the iteration and the seed of the next message key, and moves the chain
+    /// forward.
+    fn next_message_key(&mut self) -> (u32, Vec<u8>) {
+        let key = (self.iteration, chain_step(&self.chain_key, 1));
+        self.chain_key = chain_step(&self.chain_key, 2);
+        self.iteration += 1;
+        key
+    }
+
+    /// Returns the seed of the message key of the iteration, keeping the keys of the
+    /// iterations it skips for messages that arrive later.
+    fn message_key(&mut self, iteration: u32) -> Result<Vec<u8>, RhustAppError> {
+        if iteration < self.iteration {
+            return match self
+                .message_keys
+                .iter()
+                .position(|(skipped, _)| *skipped == iteration)
+            {
+                Some(index) => Ok(self.message_keys.remove(index).1),
+                None => Err(new_rhustapp_error(
+                    &format!("duplicate sender key message with iteration {iteration}"),
+                    None,
+                )),
+            };
+        };
+        if iteration - self.iteration > MAX_FORWARD_JUMPS {
+            return Err(new_rhustapp_error(
+                &format!("sender key message with iteration {iteration} is too far ahead"),
+                None,
+            ));
+        };
+
+        while self.iteration < iteration {
+            let skipped = self.next_message_key();
+            self.message_keys.push(skipped);
+        }
+        if self.message_keys.len() > MAX_MESSAGE_KEYS {
+            self.message_keys
+                .drain(..self.message_keys.len() - MAX_MESSAGE_KEYS);
+        };
+        Ok(self.next_message_key().1)
+    }
+}
+
+fn load_sender_key(
+    store: &dyn DeviceStore,
+    group: &JID,
+    sender: &JID,
+) -> Result<Option<SenderKeyState>, RhustAppError> {
+    store
+        .get_sender_key(&group.to_string(), &sender.signal_address().to_string())?
+        .map(|state| SenderKeyState::deserialize(&state))
+        .transpose()
+}
+
+fn save_sender_key(
+    store: &dyn DeviceStore,
+    group: &JID,
+    sender: &JID,
+    state: &SenderKeyState,
+) -> Result<(), RhustAppError> {
+    store.put_sender_key(
+        &group.to_string(),
+        &sender.signal_address().to_string(),
+        &state.serialize()?,
+    )
+}
+
+/// Encrypts a message for a group with the sender key of our device, creating it on the
+/// first message. Returns the `<enc type="skmsg">` node, and the sender key distribution
+/// message that the participants need to decrypt it, which is sent to each of their devices
+/// with `build_sender_key_distribution_message` and `encrypt_for_devices`.
+pub fn encrypt_group_message(
+    store: &dyn DeviceStore,
+    group: &JID,
+    own_id: &JID,
+    plaintext: &[u8],
+) -> Result<(Node, Vec<u8>), RhustAppError> {
+    let mut state = match load_sender_key(store, group, own_id)? {
+        Some(state) if state.signing_private_key.is_some() => state,
+        _ => SenderKeyState::generate(),
+    };
+    let distribution = state.distribution_message()?;
+
+    let (iteration, seed) = state.next_message_key();
+    let ciphertext = message_cipher(&seed).encrypt_vec(&pad_message(plaintext));
+    let mut message = vec![SENDER_KEY_VERSION];
+    message.extend(write_fields(|output| {
+        output.write_uint32(1, state.key_id)?;
+        output.write_uint32(2, iteration)?;
+        output.write_bytes(3, &ciphertext)
+    })?);
+    let signature = state
+        .signing_private_key
+        .as_ref()
+        .expect("own sender keys have a private signing key")
+        .calculate_signature(&message, &mut rand::rngs::OsRng)
+        .map_err(signal_error("failed to sign sender key message"))?;
+    message.extend_from_slice(&signature);
+
+    save_sender_key(store, group, own_id, &state)?;
+    Ok((enc_node("skmsg", message), distribution))
+}
+
+/// Returns the sender key distribution message of our current sender key in a group, e.g.
+/// to send it again along with a message that a participant couldn't decrypt.
+pub fn own_sender_key_distribution(
+    store: &dyn DeviceStore,
+    group: &JID,
+    own_id: &JID,
+) -> Result<Vec<u8>, RhustAppError> {
+    match load_sender_key(store, group, own_id)? {
+        Some(state) if state.signing_private_key.is_some() => state.distribution_message(),
+        _ => {
+            let state = SenderKeyState::generate();
+            save_sender_key(store, group, own_id, &state)?;
+            state.distribution_message()
+        }
+    }
+}
+
+/// Builds the message that carries our sender key distribution to the devices of the
+/// participants of a group.
+pub fn build_sender_key_distribution_message(
+    group: &JID,
+    distribution: Vec<u8>,
+) -> wa_proto::Message {
+    wa_proto::Message {
+        senderKeyDistributionMessage: MessageField::some(wa_proto::SenderKeyDistributionMessage {
+            groupId: Some(group.to_string()),
+            axolotlSenderKeyDistributionMessage: Some(distribution),
+            ..Default::default()
+        }),
+        ..Default::default()
+    }
+}
+
+/// Saves the sender key that a participant distributed for a group. A sender key that is
+/// already known is kept, so that the message keys it has moved past aren't derived again.
+pub fn process_sender_key_distribution(
+    store: &dyn DeviceStore,
+    group: &JID,
+    sender: &JID,
+    distribution: &[u8],
+) -> Result<(), RhustAppError> {
+    let state = SenderKeyState::from_distribution_message(distribution)?;
+    if let Some(saved) = load_sender_key(store, group, sender)? {
+        if saved.key_id == state.key_id {
+            return Ok(());
+        };
+    };
+    save_sender_key(store, group, sender, &state)
+}
+
+/// Decrypts an `<enc type="skmsg">` node sent by a participant of a group, returning the
+/// plaintext.
+pub fn decrypt_group_message(
+    store: &dyn DeviceStore,
+    group: &JID,
+    sender: &JID,
+    enc: &Node,
+) -> Result<Vec<u8>, RhustAppError> {
+    let (enc_type, version, message) = parse_enc_node(enc)?;
+    if enc_type != "skmsg" {
+        return Err(new_rhustapp_error(
+            &format!("unexpected enc type {enc_type} for group message"),
+            None,
+        ));
+    };
+    let mut state = load_sender_key(store, group, sender)?.ok_or_else(|| {
+        new_rhustapp_error(
+            &format!("no sender key for {} in {group}", sender.anonymized()),
+            None,
+        )
+    })?;
+
+    if message.len() < 1 + SIGNATURE_LENGTH || message[0] >> 4 != 3 {
+        return Err(new_rhustapp_error("invalid sender key message", None));
+    };
+    let (signed, signature) = message.split_at(message.len() - SIGNATURE_LENGTH);
+    if !state
+        .signing_key
+        .verify_signature(signed, signature)
+        .map_err(signal_error("failed to verify sender key message"))?
+    {
+        return Err(new_rhustapp_error(
+            "invalid sender key message signature",
+            None,
+        ));
+    };
+
+    let (mut key_id, mut iteration, mut ciphertext) = (None, None, None);
+    for field in read_fields(&signed[1..])? {
+        match field {
+            (1, Field::Varint(value)) => key_id = Some(value as u32),
+            (2, Field::Varint(value)) => iteration = Some(value as u32),
+            (3, Field::Bytes(value)) => ciphertext = Some(value),
+            _ => {}
+        };
+    }
+    if key_id != Some(state.key_id) {
+        return Err(new_rhustapp_error(
+            &format!("no sender key with ID {key_id:?}"),
+            None,
+        ));
+    };
+
+    let seed = state.message_key(iteration.ok_or_else(|| missing_field("iteration"))?)?;
+    let padded = message_cipher(&seed)
+        .decrypt_vec(&ciphertext.ok_or_else(|| missing_field("ciphertext"))?)
+        .map_err(|err| {
+            new_rhustapp_error("failed to decrypt group message", Some(err.to_string()))
+        })?;
+    save_sender_key(store, group, sender, &state)?;
+
+    unpad_plaintext(&version, padded)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use protobuf::Message;
+
+    use crate::{binary::NodeContentType, store::memory::MemoryStore};
+
+    use super::*;
+
+    fn jids() -> (JID, JID) {
+        (
+            JID::from_str("1234-5678@g.us").unwrap(),
+            JID::from_str("1111.0:1@s.whatsapp.net").unwrap(),
+        )
+    }
+
+    #[test]
+    fn test_group_message() {
+        let (group, alice) = jids();
+        let alice_store = MemoryStore::new();
+        let bob_store = MemoryStore::new();
+
+        let (first, distribution) =
+            encrypt_group_message(&alice_store, &group, &alice, b"first").unwrap();
+        assert_eq!(first.attr_getter().string("type").unwrap(), "skmsg");
+        assert!(decrypt_group_message(&bob_store, &group, &alice, &first).is_err());
+
+        let message = build_sender_key_distribution_message(&group, distribution.clone());
+        let message =
+            wa_proto::Message::parse_from_bytes(&message.write_to_bytes().unwrap()).unwrap();
+        let received = message.senderKeyDistributionMessage.unwrap();
+        assert_eq!(received.groupId(), group.to_string());
+        process_sender_key_distribution(
+            &bob_store,
+            &group,
+            &alice,
+            received.axolotlSenderKeyDistributionMessage(),
+        )
+        .unwrap();
+
+        // The same sender key is reused, messages may arrive out of order but only once.
+        let (second, second_distribution) =
+            encrypt_group_message(&alice_store, &group, &alice, b"second").unwrap();
+        let (third, _) = encrypt_group_message(&alice_store, &group, &alice, b"third").unwrap();
+        assert_eq!(
+            SenderKeyState::from_distribution_message(&second_distribution)
+                .unwrap()
+                .key_id,
+            SenderKeyState::from_distribution_message(&distribution)
+                .unwrap()
+                .key_id
+        );
+        assert_eq!(
+            decrypt_group_message(&bob_store, &group, &alice, &third).unwrap(),
+            b"third"
+        );
+        assert_eq!(
+            decrypt_group_message(&bob_store, &group, &alice, &first).unwrap(),
+            b"first"
+        );
+        assert_eq!(
+            decrypt_group_message(&bob_store, &group, &alice, &second).unwrap(),
+            b"second"
+        );
+        assert!(decrypt_group_message(&bob_store, &group, &alice, &second).is_err());
+
+        // Distributing the same key again doesn't reset the chain.
+        process_sender_key_distribution(&bob_store, &group, &alice, &distribution).unwrap();
+        assert!(decrypt_group_message(&bob_store, &group, &alice, &third).is_err());
+    }
+
+    #[test]
+    fn test_group_message_signature() {
+        let (group, alice) = jids();
+        let alice_store = MemoryStore::new();
+        let bob_store = MemoryStore::new();
+        let (mut enc, distribution) =
+            encrypt_group_message(&alice_store, &group, &alice, b"hello").unwrap();
+        process_sender_key_distribution(&bob_store, &group, &alice, &distribution).unwrap();
+
+        if let NodeContentType::ByteArray(message) = &mut enc.content {
+            let last = message.len() - 1;
+            message[last] ^= 1;
+        };
+        assert!(decrypt_group_message(&bob_store, &group, &alice, &enc).is_err());
+    }
+
+    #[test]
+    fn test_skipped_message_keys() {
+        let mut state = SenderKeyState::generate();
+        let mut receiver =
+            SenderKeyState::from_distribution_message(&state.distribution_message().unwrap())
+                .unwrap();
+        let keys = (0..5).map(|_| state.next_message_key()).collect::<Vec<_>>();
+
+        assert_eq!(receiver.message_key(3).unwrap(), keys[3].1);
+        assert_eq!(receiver.message_keys.len(), 3);
+        let mut receiver = SenderKeyState::deserialize(&receiver.serialize().unwrap()).unwrap();
+        assert_eq!(receiver.message_key(1).unwrap(), keys[1].1);
+        assert_eq!(receiver.message_key(4).unwrap(), keys[4].1);
+        assert!(receiver.message_key(1).is_err());
+        assert!(receiver.message_key(5 + MAX_FORWARD_JUMPS + 1).is_err());
+    }
+}
@@ -0,0 +1,11 @@
+//! `encryption` contains the Signal sessions with other devices: starting them from prekey
+//! bundles, encrypting messages for a list of devices and decrypting the `<enc>` nodes that
+//! they send. Group messages are encrypted once with the sender key of the group, which is
+//! distributed to the participants through their sessions. The sessions and keys are kept in
+//! a `DeviceStore`.
+
+mod group;
+pub use group::*;
+
+mod session;
+pub use session::*;
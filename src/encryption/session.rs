@@ -0,0 +1,628 @@
+use std::{
+    fmt,
+    future::Future,
+    pin::pin,
+    task::{Context as TaskContext, Poll, Waker},
+};
+
+use async_trait::async_trait;
+use libsignal_protocol::{
+    message_decrypt_prekey, message_decrypt_signal, message_encrypt, Context, Direction,
+    IdentityKey, IdentityKeyPair, IdentityKeyStore, PreKeyBundle, PreKeyId, PreKeyRecord,
+    PreKeySignalMessage, PreKeyStore, ProtocolAddress, SessionRecord, SessionStore, SignalMessage,
+    SignalProtocolError, SignedPreKeyId, SignedPreKeyRecord, SignedPreKeyStore,
+};
+use rand::Rng;
+
+use crate::{
+    binary::{AttributeTypes, Attrs, Node, NodeContentType},
+    new_rhustapp_error,
+    send::build_participant_node,
+    store::{Device, DeviceStore},
+    types::JID,
+    RhustAppError,
+};
+
+/// The version of the `<enc>` nodes, in which the plaintext is padded.
+const ENC_VERSION: &str = "2";
+
+/// Runs a future of libsignal with a single poll. The stores are synchronous and never wait
+/// on anything, so the futures are ready on the first poll; one that isn't would never be
+/// woken, so it fails instead of spinning.
+fn block_on<T>(
+    future: impl Future<Output = Result<T, SignalProtocolError>>,
+) -> Result<T, SignalProtocolError> {
+    let future = pin!(future);
+    match future.poll(&mut TaskContext::from_waker(Waker::noop())) {
+        Poll::Ready(output) => output,
+        Poll::Pending => Err(SignalProtocolError::InvalidState(
+            "block_on",
+            "libsignal future is still pending after the first poll".to_string(),
+        )),
+    }
+}
+
+/// A `DeviceStore` error returned to libsignal.
+#[derive(Debug)]
+struct StoreError(RhustAppError);
+
+impl fmt::Display for StoreError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for StoreError {}
+
+fn store_error(method: &'static str) -> impl Fn(RhustAppError) -> SignalProtocolError {
+    move |err| SignalProtocolError::ApplicationCallbackError(method, Box::new(StoreError(err)))
+}
+
+pub(super) fn signal_error(message: &str) -> impl Fn(SignalProtocolError) -> RhustAppError + '_ {
+    move |err| new_rhustapp_error(message, Some(err.to_string()))
+}
+
+/// Implements the stores of libsignal with a `DeviceStore` and the keys of our device.
+#[derive(Clone, Copy)]
+struct SignalStore<'a> {
+    device: &'a Device,
+    store: &'a dyn DeviceStore,
+}
+
+#[async_trait(?Send)]
+impl IdentityKeyStore for SignalStore<'_> {
+    async fn get_identity_key_pair(
+        &self,
+        _: Context,
+    ) -> Result<IdentityKeyPair, SignalProtocolError> {
+        Ok(IdentityKeyPair::new(
+            IdentityKey::new(self.device.identity_key.public_key),
+            self.device.identity_key.private_key,
+        ))
+    }
+
+    async fn get_local_registration_id(&self, _: Context) -> Result<u32, SignalProtocolError> {
+        Ok(self.device.registration_id)
+    }
+
+    async fn save_identity(
+        &mut self,
+        address: &ProtocolAddress,
+        identity: &IdentityKey,
+        _: Context,
+    ) -> Result<bool, SignalProtocolError> {
+        let key: [u8; 32] = identity
+            .public_key()
+            .public_key_bytes()?
+            .try_into()
+            .map_err(|_| SignalProtocolError::BadKeyLength(identity.public_key().key_type(), 0))?;
+        let address = address.to_string();
+        let previous = self
+            .store
+            .get_identity(&address)
+            .map_err(store_error("save_identity"))?;
+        self.store
+            .put_identity(&address, key)
+            .map_err(store_error("save_identity"))?;
+        Ok(previous.is_some_and(|previous| previous != key))
+    }
+
+    async fn is_trusted_identity(
+        &self,
+        address: &ProtocolAddress,
+        identity: &IdentityKey,
+        _: Direction,
+        _: Context,
+    ) -> Result<bool, SignalProtocolError> {
+        let key = identity.public_key().public_key_bytes()?;
+        match key.try_into() {
+            Ok(key) => self
+                .store
+                .is_trusted_identity(&address.to_string(), key)
+                .map_err(store_error("is_trusted_identity")),
+            Err(_) => Ok(false),
+        }
+    }
+
+    async fn get_identity(
+        &self,
+        address: &ProtocolAddress,
+        _: Context,
+    ) -> Result<Option<IdentityKey>, SignalProtocolError> {
+        self.store
+            .get_identity(&address.to_string())
+            .map_err(store_error("get_identity"))?
+            .map(|key| {
+                Ok(IdentityKey::new(
+                    libsignal_protocol::PublicKey::from_djb_public_key_bytes(&key)?,
+                ))
+            })
+            .transpose()
+    }
+}
+
+#[async_trait(?Send)]
+impl PreKeyStore for SignalStore<'_> {
+    async fn get_pre_key(
+        &self,
+        prekey_id: PreKeyId,
+        _: Context,
+    ) -> Result<PreKeyRecord, SignalProtocolError> {
+        self.store
+            .get_pre_key(prekey_id.into())
+            .map_err(store_error("get_pre_key"))?
+            .ok_or(SignalProtocolError::InvalidPreKeyId)
+    }
+
+    async fn save_pre_key(
+        &mut self,
+        _: PreKeyId,
+        _: &PreKeyRecord,
+        _: Context,
+    ) -> Result<(), SignalProtocolError> {
+        Err(SignalProtocolError::InvalidArgument(
+            "prekeys are generated by the DeviceStore".to_string(),
+        ))
+    }
+
+    async fn remove_pre_key(
+        &mut self,
+        prekey_id: PreKeyId,
+        _: Context,
+    ) -> Result<(), SignalProtocolError> {
+        self.store
+            .remove_pre_key(prekey_id.into())
+            .map_err(store_error("remove_pre_key"))
+    }
+}
+
+#[async_trait(?Send)]
+impl SignedPreKeyStore for SignalStore<'_> {
+    async fn get_signed_pre_key(
+        &self,
+        signed_prekey_id: SignedPreKeyId,
+        _: Context,
+    ) -> Result<SignedPreKeyRecord, SignalProtocolError> {
+        if self.device.signed_pre_key.id()? != signed_prekey_id {
+            return Err(SignalProtocolError::InvalidSignedPreKeyId);
+        };
+        Ok(self.device.signed_pre_key.clone())
+    }
+
+    async fn save_signed_pre_key(
+        &mut self,
+        _: SignedPreKeyId,
+        _: &SignedPreKeyRecord,
+        _: Context,
+    ) -> Result<(), SignalProtocolError> {
+        Err(SignalProtocolError::InvalidArgument(
+            "the signed prekey is part of the Device".to_string(),
+        ))
+    }
+}
+
+#[async_trait(?Send)]
+impl SessionStore for SignalStore<'_> {
+    async fn load_session(
+        &self,
+        address: &ProtocolAddress,
+        _: Context,
+    ) -> Result<Option<SessionRecord>, SignalProtocolError> {
+        self.store
+            .get_session(&address.to_string())
+            .map_err(store_error("load_session"))?
+            .map(|session| SessionRecord::deserialize(&session))
+            .transpose()
+    }
+
+    async fn store_session(
+        &mut self,
+        address: &ProtocolAddress,
+        record: &SessionRecord,
+        _: Context,
+    ) -> Result<(), SignalProtocolError> {
+        self.store
+            .put_session(&address.to_string(), &record.serialize()?)
+            .map_err(store_error("store_session"))
+    }
+}
+
+/// Builds an `<enc>` node of the given type (`pkmsg`, `msg` or `skmsg`).
+pub(super) fn enc_node(enc_type: &str, ciphertext: Vec<u8>) -> Node {
+    Node {
+        tag: "enc".to_string(),
+        attrs: Attrs::from([
+            (
+                "v".to_string(),
+                AttributeTypes::String(ENC_VERSION.to_string()),
+            ),
+            (
+                "type".to_string(),
+                AttributeTypes::String(enc_type.to_string()),
+            ),
+        ]),
+        content: NodeContentType::ByteArray(ciphertext),
+    }
+}
+
+/// Pads the plaintext with 1 to 16 bytes, each holding the length of the padding.
+pub(super) fn pad_message(plaintext: &[u8]) -> Vec<u8> {
+    let padding = rand::thread_rng().gen_range(1, 17);
+    let mut padded = Vec::with_capacity(plaintext.len() + padding as usize);
+    padded.extend_from_slice(plaintext);
+    padded.resize(plaintext.len() + padding as usize, padding);
+    padded
+}
+
+/// Returns the type, the version and the ciphertext of an `<enc>` node.
+pub(super) fn parse_enc_node(enc: &Node) -> Result<(String, String, &[u8]), RhustAppError> {
+    let mut ag = enc.attr_getter();
+    let enc_type = ag.string("type");
+    let version = ag.optional_string("v").unwrap_or_default();
+    if let Some(err) = ag.error() {
+        return Err(err);
+    };
+    match &enc.content {
+        NodeContentType::ByteArray(bytes) => Ok((enc_type.unwrap(), version, bytes.as_slice())),
+        content => Err(new_rhustapp_error(
+            &format!("unexpected enc content: {content:?}"),
+            None,
+        )),
+    }
+}
+
+/// Removes the padding from a decrypted plaintext. Version 3 `<enc>` nodes aren't padded.
+pub(super) fn unpad_plaintext(version: &str, padded: Vec<u8>) -> Result<Vec<u8>, RhustAppError> {
+    if version == "3" {
+        return Ok(padded);
+    };
+    unpad_message(&padded).map(<[u8]>::to_vec)
+}
+
+/// Removes the padding added by `pad_message`.
+fn unpad_message(padded: &[u8]) -> Result<&[u8], RhustAppError> {
+    let padding = match padded.last() {
+        Some(padding) if *padding > 0 && usize::from(*padding) <= padded.len() => *padding,
+        _ => return Err(new_rhustapp_error("invalid message padding", None)),
+    };
+    let (plaintext, padding_bytes) = padded.split_at(padded.len() - usize::from(padding));
+    if padding_bytes.iter().any(|byte| *byte != padding) {
+        return Err(new_rhustapp_error("invalid message padding", None));
+    };
+    Ok(plaintext)
+}
+
+/// Starts a session with the device from its prekey bundle, fetched with
+/// `prekeys::build_get_prekeys_node` or sent in a retry receipt.
+///
+/// Like in `decrypt_enc_node`, a changed identity key means that the device reinstalled
+/// WhatsApp, so the old identity is forgotten instead of rejecting the bundle.
+pub fn process_prekey_bundle(
+    device: &Device,
+    store: &dyn DeviceStore,
+    jid: &JID,
+    bundle: &PreKeyBundle,
+) -> Result<(), RhustAppError> {
+    let address = jid.signal_address();
+    let process = || {
+        let mut sessions = SignalStore { device, store };
+        let mut identities = sessions;
+        block_on(libsignal_protocol::process_prekey_bundle(
+            &address,
+            &mut sessions,
+            &mut identities,
+            bundle,
+            &mut rand::rngs::OsRng,
+            None,
+        ))
+    };
+
+    match process() {
+        Err(SignalProtocolError::UntrustedIdentity(_)) => {
+            log::warn!(
+                "identity of {} changed, forgetting the old one",
+                jid.anonymized()
+            );
+            store.delete_identity(&address.to_string())?;
+            process()
+        }
+        result => result,
+    }
+    .map_err(signal_error("failed to process prekey bundle"))
+}
+
+/// Returns the devices that there's no session with yet. Their prekey bundles have to be
+/// fetched and processed before encrypting for them.
+pub fn devices_without_session(
+    store: &dyn DeviceStore,
+    devices: &[JID],
+) -> Result<Vec<JID>, RhustAppError> {
+    let mut missing = Vec::new();
+    for jid in devices {
+        if !store.has_session(&jid.signal_address().to_string())? {
+            missing.push(jid.clone());
+        };
+    }
+    Ok(missing)
+}
+
+/// Encrypts the plaintext for a device that there's a session with. Returns the `<enc>` node
+/// and whether it's a `pkmsg`, which starts the session on the other side.
+pub fn encrypt_for_device(
+    device: &Device,
+    store: &dyn DeviceStore,
+    plaintext: &[u8],
+    jid: &JID,
+) -> Result<(Node, bool), RhustAppError> {
+    let mut sessions = SignalStore { device, store };
+    let mut identities = sessions;
+    let ciphertext = block_on(message_encrypt(
+        &pad_message(plaintext),
+        &jid.signal_address(),
+        &mut sessions,
+        &mut identities,
+        None,
+    ))
+    .map_err(signal_error("failed to encrypt message"))?;
+
+    let is_prekey = matches!(
+        ciphertext,
+        libsignal_protocol::CiphertextMessage::PreKeySignalMessage(_)
+    );
+    let enc_type = if is_prekey { "pkmsg" } else { "msg" };
+    let enc = enc_node(enc_type, ciphertext.serialize().to_vec());
+    Ok((enc, is_prekey))
+}
+
+/// Encrypts the plaintext for each of the devices, returning a `<to>` node with the `<enc>`
+/// of each device, and whether any of them is a `pkmsg`, in which case the message has to
+/// include the `<device-identity>` of our device. Devices that the plaintext couldn't be
+/// encrypted for, e.g. because there's no session with them, are left out.
+pub fn encrypt_for_devices(
+    device: &Device,
+    store: &dyn DeviceStore,
+    plaintext: &[u8],
+    devices: &[JID],
+) -> (Vec<Node>, bool) {
+    let mut participants = Vec::with_capacity(devices.len());
+    let mut include_identity = false;
+    for jid in devices {
+        match encrypt_for_device(device, store, plaintext, jid) {
+            Ok((enc, is_prekey)) => {
+                include_identity |= is_prekey;
+                participants.push(build_participant_node(jid, enc));
+            }
+            Err(err) => log::warn!("failed to encrypt for {}: {err}", jid.anonymized()),
+        };
+    }
+    (participants, include_identity)
+}
+
+/// Decrypts an `<enc type="pkmsg">` or `<enc type="msg">` node sent by the device, returning
+/// the plaintext.
+///
+/// If the sender reinstalled WhatsApp, its identity key changed and a `pkmsg` is rejected as
+/// untrusted. In that case the old identity is forgotten and the message decrypted again,
+/// as the phone does.
+pub fn decrypt_enc_node(
+    device: &Device,
+    store: &dyn DeviceStore,
+    sender: &JID,
+    enc: &Node,
+) -> Result<Vec<u8>, RhustAppError> {
+    let (enc_type, version, ciphertext) = parse_enc_node(enc)?;
+
+    let address = sender.signal_address();
+    let decrypt = || -> Result<Vec<u8>, SignalProtocolError> {
+        let mut sessions = SignalStore { device, store };
+        let mut identities = sessions;
+        match enc_type.as_str() {
+            "pkmsg" => {
+                let mut pre_keys = sessions;
+                let mut signed_pre_keys = sessions;
+                block_on(message_decrypt_prekey(
+                    &PreKeySignalMessage::try_from(ciphertext)?,
+                    &address,
+                    &mut sessions,
+                    &mut identities,
+                    &mut pre_keys,
+                    &mut signed_pre_keys,
+                    &mut rand::rngs::OsRng,
+                    None,
+                ))
+            }
+            "msg" => block_on(message_decrypt_signal(
+                &SignalMessage::try_from(ciphertext)?,
+                &address,
+                &mut sessions,
+                &mut identities,
+                &mut rand::rngs::OsRng,
+                None,
+            )),
+            _ => Err(SignalProtocolError::InvalidArgument(format!(
+                "unsupported enc type {enc_type}"
+            ))),
+        }
+    };
+
+    let padded = match decrypt() {
+        Err(SignalProtocolError::UntrustedIdentity(_)) if enc_type == "pkmsg" => {
+            log::warn!(
+                "identity of {} changed, forgetting the old one",
+                sender.anonymized()
+            );
+            store.delete_identity(&address.to_string())?;
+            decrypt()
+        }
+        result => result,
+    }
+    .map_err(signal_error("failed to decrypt message"))?;
+
+    unpad_plaintext(&version, padded)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use crate::{store::memory::MemoryStore, testing::prekey_bundle};
+
+    use super::*;
+
+    fn enc_content(node: &Node) -> &Node {
+        match &node.content {
+            NodeContentType::ListOfNodes(children) => &children[0],
+            content => panic!("unexpected content {content:?}"),
+        }
+    }
+
+    #[test]
+    fn test_block_on() {
+        assert_eq!(block_on(async { Ok(5) }).unwrap(), 5);
+        assert!(matches!(
+            block_on(std::future::pending::<Result<(), SignalProtocolError>>()),
+            Err(SignalProtocolError::InvalidState("block_on", _))
+        ));
+    }
+
+    #[test]
+    fn test_padding() {
+        for length in [0, 1, 100] {
+            let plaintext = vec![7; length];
+            let padded = pad_message(&plaintext);
+            assert!((length + 1..=length + 16).contains(&padded.len()));
+            assert_eq!(unpad_message(&padded).unwrap(), plaintext);
+        }
+        assert!(unpad_message(&[]).is_err());
+        assert!(unpad_message(&[1, 2, 3]).is_err());
+        assert!(unpad_message(&[1, 2, 2]).is_ok());
+        assert!(unpad_message(&[1, 3, 2]).is_err());
+    }
+
+    #[test]
+    fn test_session() {
+        let alice = Device::new();
+        let alice_store = MemoryStore::new();
+        let alice_jid = JID::from_str("1111.0:1@s.whatsapp.net").unwrap();
+        let bob = Device::new();
+        let bob_store = MemoryStore::new();
+        let bob_jid = JID::from_str("2222.0:3@s.whatsapp.net").unwrap();
+
+        assert_eq!(
+            devices_without_session(&alice_store, std::slice::from_ref(&bob_jid)).unwrap(),
+            vec![bob_jid.clone()]
+        );
+        let (participants, include_identity) = encrypt_for_devices(
+            &alice,
+            &alice_store,
+            b"hello",
+            std::slice::from_ref(&bob_jid),
+        );
+        assert!(participants.is_empty());
+        assert!(!include_identity);
+
+        let bundle = prekey_bundle(&bob, &bob_store, &bob_jid);
+        process_prekey_bundle(&alice, &alice_store, &bob_jid, &bundle).unwrap();
+        assert!(
+            devices_without_session(&alice_store, std::slice::from_ref(&bob_jid))
+                .unwrap()
+                .is_empty()
+        );
+
+        let (participants, include_identity) = encrypt_for_devices(
+            &alice,
+            &alice_store,
+            b"hello",
+            std::slice::from_ref(&bob_jid),
+        );
+        assert_eq!(participants.len(), 1);
+        assert_eq!(participants[0].attr_getter().jid("jid").unwrap(), bob_jid);
+        assert!(include_identity);
+        let enc = enc_content(&participants[0]);
+        assert_eq!(enc.attr_getter().string("type").unwrap(), "pkmsg");
+        assert_eq!(enc.attr_getter().string("v").unwrap(), "2");
+
+        let plaintext = decrypt_enc_node(&bob, &bob_store, &alice_jid, enc).unwrap();
+        assert_eq!(plaintext, b"hello");
+        assert!(bob_store.get_pre_key(1).unwrap().is_none());
+
+        // Once Bob replies, both sides send normal messages.
+        let (reply, is_prekey) = encrypt_for_device(&bob, &bob_store, b"hi", &alice_jid).unwrap();
+        assert!(!is_prekey);
+        assert_eq!(reply.attr_getter().string("type").unwrap(), "msg");
+        let plaintext = decrypt_enc_node(&alice, &alice_store, &bob_jid, &reply).unwrap();
+        assert_eq!(plaintext, b"hi");
+
+        let (enc, is_prekey) =
+            encrypt_for_device(&alice, &alice_store, b"again", &bob_jid).unwrap();
+        assert!(!is_prekey);
+        let plaintext = decrypt_enc_node(&bob, &bob_store, &alice_jid, &enc).unwrap();
+        assert_eq!(plaintext, b"again");
+    }
+
+    #[test]
+    fn test_changed_identity() {
+        let bob = Device::new();
+        let bob_store = MemoryStore::new();
+        let bob_jid = JID::from_str("2222.0:3@s.whatsapp.net").unwrap();
+        let alice_jid = JID::from_str("1111.0:1@s.whatsapp.net").unwrap();
+
+        // Alice reinstalls WhatsApp, and starts a new session with new keys.
+        for _ in 0..2 {
+            let alice = Device::new();
+            let alice_store = MemoryStore::new();
+            let bundle = prekey_bundle(&bob, &bob_store, &bob_jid);
+            process_prekey_bundle(&alice, &alice_store, &bob_jid, &bundle).unwrap();
+            let (enc, _) = encrypt_for_device(&alice, &alice_store, b"hello", &bob_jid).unwrap();
+
+            let plaintext = decrypt_enc_node(&bob, &bob_store, &alice_jid, &enc).unwrap();
+            assert_eq!(plaintext, b"hello");
+        }
+
+        // Bob reinstalls too, and Alice starts a new session from his new bundle.
+        let alice = Device::new();
+        let alice_store = MemoryStore::new();
+        for _ in 0..2 {
+            let bob = Device::new();
+            let bob_store = MemoryStore::new();
+            let bundle = prekey_bundle(&bob, &bob_store, &bob_jid);
+            process_prekey_bundle(&alice, &alice_store, &bob_jid, &bundle).unwrap();
+            let (enc, _) = encrypt_for_device(&alice, &alice_store, b"hi", &bob_jid).unwrap();
+            let plaintext = decrypt_enc_node(&bob, &bob_store, &alice_jid, &enc).unwrap();
+            assert_eq!(plaintext, b"hi");
+        }
+    }
+
+    #[test]
+    fn test_decrypt_invalid() {
+        let device = Device::new();
+        let store = MemoryStore::new();
+        let sender = JID::from_str("1111.0:1@s.whatsapp.net").unwrap();
+        let enc = |enc_type: &str, content: NodeContentType| Node {
+            tag: "enc".to_string(),
+            attrs: Attrs::from([(
+                "type".to_string(),
+                AttributeTypes::String(enc_type.to_string()),
+            )]),
+            content,
+        };
+
+        assert!(decrypt_enc_node(
+            &device,
+            &store,
+            &sender,
+            &enc("msg", NodeContentType::ByteArray(vec![1, 2, 3]))
+        )
+        .is_err());
+        assert!(decrypt_enc_node(
+            &device,
+            &store,
+            &sender,
+            &enc("skmsg", NodeContentType::ByteArray(vec![1, 2, 3]))
+        )
+        .is_err());
+        assert!(
+            decrypt_enc_node(&device, &store, &sender, &enc("msg", NodeContentType::None)).is_err()
+        );
+    }
+}
@@ -1,16 +1,58 @@
 use core::panic::Location;
 use std::fmt;
 
+use crate::socket::SocketError;
+
+/// The kind of a `RhustAppError`, for the errors that callers may want to handle
+/// specifically.
+#[derive(Clone, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub enum ErrorKind {
+    #[default]
+    Other,
+    /// The server responded to an `<iq>` with an `<error>`.
+    Iq(Box<IqError>),
+    /// The websocket failed, e.g. because nothing was received within the read timeout.
+    Socket(SocketError),
+    /// A media server responded with a non-2xx HTTP status, e.g. 404 for expired media.
+    Http(u16),
+}
+
+/// The `<error>` returned by the server in response to an `<iq>`.
+#[derive(Clone, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct IqError {
+    /// The `code` attribute, e.g. 404 or 429.
+    pub code: u16,
+    /// The `text` attribute, e.g. "item-not-found" or "rate-overlimit".
+    pub text: String,
+    /// The tag of the first child of the `<error>`, e.g. "conflict", if it has one.
+    pub condition: Option<String>,
+}
+
 #[derive(Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 pub struct RhustAppError {
     pub description: String,
     pub error: Option<String>,
     pub location: String,
+    kind: ErrorKind,
 }
 
 impl RhustAppError {
     const ERROR_SPACE_WIDTH: usize = 4;
 
+    /// Returns the kind of the error.
+    pub fn kind(&self) -> &ErrorKind {
+        &self.kind
+    }
+
+    /// Sets the kind of the error.
+    pub fn with_kind(mut self, kind: ErrorKind) -> Self {
+        self.kind = kind;
+        self
+    }
+
     pub fn to_string(&self) -> String {
         match &self.error {
             Some(err) => format!(
@@ -52,6 +94,7 @@ impl fmt::Debug for RhustAppError {
             .field("description", &self.description)
             .field("error", &self.error)
             .field("location", &self.location)
+            .field("kind", &self.kind)
             .finish()
     }
 }
@@ -69,11 +112,13 @@ pub fn new_rhustapp_error(description: &str, err: Option<String>) -> RhustAppErr
             description: description.to_string(),
             error: Some(err),
             location: Location::caller().to_string(),
+            kind: ErrorKind::Other,
         },
         None => RhustAppError {
             description: description.to_string(),
             error: None,
             location: Location::caller().to_string(),
+            kind: ErrorKind::Other,
         },
     }
 }
@@ -0,0 +1,187 @@
+//! `event_handlers` contains the registry of the functions that events are passed to, see
+//! `Client::add_event_handler` and `Client::subscribe`.
+
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc, Mutex,
+};
+
+use crate::types::events::{
+    Archive, CallAccept, CallOffer, CallTerminate, ChatPresence, ConnectFailure, Contact,
+    DeleteForMe, DeviceListUpdate, EventsDropped, GroupInfo, KeepAliveTimeout, LoggedOut, Message,
+    Mute, PairError, PairSuccess, PictureChange, Pin, PreKeysLow, Presence, PrivacySettingsChange,
+    PushNameUpdated, Receipt, RhustAppEventType, Star, StreamError, TemporaryBan, QR,
+};
+
+/// Identifies a registered event handler, so that it can be removed again.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct HandlerId(u64);
+
+/// The data of one of the variants of `RhustAppEventType`, which can be subscribed to with
+/// `Client::subscribe`. Variants without data (e.g. `Connected`) can only be received
+/// through a handler.
+pub trait EventKind: Sized + Send + 'static {
+    /// Returns the data of the event if it's of this kind.
+    fn from_event(event: &RhustAppEventType) -> Option<Self>;
+}
+
+macro_rules! impl_event_kind {
+    ($($variant:ident),* $(,)?) => {
+        $(
+            impl EventKind for $variant {
+                fn from_event(event: &RhustAppEventType) -> Option<Self> {
+                    match event {
+                        RhustAppEventType::$variant(data) => Some(data.clone()),
+                        _ => None,
+                    }
+                }
+            }
+        )*
+    };
+}
+
+impl_event_kind!(
+    QR,
+    PairSuccess,
+    PairError,
+    KeepAliveTimeout,
+    LoggedOut,
+    TemporaryBan,
+    PrivacySettingsChange,
+    CallTerminate,
+    Receipt,
+    StreamError,
+    EventsDropped,
+    DeviceListUpdate,
+    PreKeysLow,
+    Presence,
+    ChatPresence,
+    GroupInfo,
+    CallOffer,
+    CallAccept,
+    ConnectFailure,
+    PictureChange,
+    Contact,
+    Pin,
+    Mute,
+    Archive,
+    Star,
+    DeleteForMe,
+);
+
+impl EventKind for Message {
+    fn from_event(event: &RhustAppEventType) -> Option<Self> {
+        match event {
+            RhustAppEventType::Message(message) => Some(Message::clone(message)),
+            _ => None,
+        }
+    }
+}
+
+impl EventKind for PushNameUpdated {
+    fn from_event(event: &RhustAppEventType) -> Option<Self> {
+        match event {
+            RhustAppEventType::PushNameUpdated(update) => Some(PushNameUpdated::clone(update)),
+            _ => None,
+        }
+    }
+}
+
+/// A registered handler. It returns false once it should be removed, e.g. because the
+/// receiver of a subscription was dropped.
+pub(crate) type Handler = Arc<dyn Fn(&RhustAppEventType) -> bool + Send + Sync>;
+
+/// The event handlers of a client, which are called in the order they were added.
+#[derive(Default)]
+pub(crate) struct EventHandlers {
+    next_id: AtomicU64,
+    handlers: Mutex<Vec<(HandlerId, Handler)>>,
+}
+
+impl EventHandlers {
+    pub(crate) fn add(&self, handler: Handler) -> HandlerId {
+        let id = HandlerId(self.next_id.fetch_add(1, Ordering::SeqCst));
+        self.handlers.lock().unwrap().push((id, handler));
+        id
+    }
+
+    /// Removes a handler, returning false if it was already removed.
+    pub(crate) fn remove(&self, id: HandlerId) -> bool {
+        let mut handlers = self.handlers.lock().unwrap();
+        let count = handlers.len();
+        handlers.retain(|(handler_id, _)| *handler_id != id);
+        handlers.len() != count
+    }
+
+    /// Passes the event to every handler. The handlers are called without holding the lock,
+    /// so that they can add and remove handlers themselves.
+    pub(crate) fn dispatch(&self, event: &RhustAppEventType) {
+        let handlers = self.handlers.lock().unwrap().clone();
+        for (id, handler) in handlers {
+            if !handler(event) {
+                self.remove(id);
+            };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc;
+
+    use super::*;
+
+    fn stream_error(code: &str) -> RhustAppEventType {
+        RhustAppEventType::StreamError(StreamError {
+            code: code.to_string(),
+        })
+    }
+
+    #[test]
+    fn test_dispatch() {
+        let handlers = EventHandlers::default();
+        let (sender, receiver) = mpsc::channel();
+        let first = {
+            let sender = sender.clone();
+            handlers.add(Arc::new(move |event| {
+                sender.send(("first", event.clone())).unwrap();
+                true
+            }))
+        };
+        handlers.add(Arc::new(move |event| {
+            sender.send(("second", event.clone())).unwrap();
+            // Only interested in the first event.
+            false
+        }));
+
+        handlers.dispatch(&stream_error("1"));
+        handlers.dispatch(&RhustAppEventType::Connected);
+        assert!(handlers.remove(first));
+        assert!(!handlers.remove(first));
+        handlers.dispatch(&stream_error("2"));
+
+        let received = receiver
+            .try_iter()
+            .map(|(handler, event)| (handler, StreamError::from_event(&event)))
+            .map(|(handler, error)| (handler, error.map(|error| error.code)))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            received,
+            vec![
+                ("first", Some("1".to_string())),
+                ("second", Some("1".to_string())),
+                ("first", None),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_event_kind() {
+        assert!(QR::from_event(&stream_error("1")).is_none());
+        assert!(StreamError::from_event(&RhustAppEventType::Connected).is_none());
+        assert_eq!(
+            StreamError::from_event(&stream_error("1")).unwrap().code,
+            "1"
+        );
+    }
+}
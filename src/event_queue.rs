@@ -0,0 +1,213 @@
+//! `event_queue` contains the bounded queue that events are delivered through, so that an
+//! application that is slow to consume events can't make the client use unbounded memory.
+
+use std::{
+    collections::VecDeque,
+    sync::{Condvar, Mutex},
+};
+
+use crate::types::events::{EventsDropped, RhustAppEventType};
+
+/// What to do when an event is pushed to a full `EventQueue`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OverflowPolicy {
+    /// Block the pushing thread until the application consumes an event.
+    Block,
+    /// Drop the oldest queued event to make space. The consumer is told how many events
+    /// were dropped with an `EventsDropped` event.
+    DropOldest,
+}
+
+/// The configuration of an `EventQueue`.
+#[derive(Clone, Debug)]
+pub struct EventQueueConfig {
+    /// The maximum number of events that can be queued.
+    pub capacity: usize,
+    pub policy: OverflowPolicy,
+}
+
+impl Default for EventQueueConfig {
+    fn default() -> Self {
+        Self {
+            capacity: 1024,
+            policy: OverflowPolicy::Block,
+        }
+    }
+}
+
+struct QueueState {
+    events: VecDeque<RhustAppEventType>,
+    /// The number of events dropped since the consumer was last told about it.
+    dropped: usize,
+    closed: bool,
+}
+
+/// A bounded, thread-safe queue of events.
+pub struct EventQueue {
+    config: EventQueueConfig,
+    state: Mutex<QueueState>,
+    /// Notified when an event is pushed or the queue is closed.
+    not_empty: Condvar,
+    /// Notified when an event is popped or the queue is closed.
+    not_full: Condvar,
+}
+
+impl EventQueue {
+    pub fn new(config: EventQueueConfig) -> Self {
+        let capacity = config.capacity.max(1);
+        Self {
+            config: EventQueueConfig { capacity, ..config },
+            state: Mutex::new(QueueState {
+                events: VecDeque::with_capacity(capacity),
+                dropped: 0,
+                closed: false,
+            }),
+            not_empty: Condvar::new(),
+            not_full: Condvar::new(),
+        }
+    }
+
+    /// Pushes an event to the queue, applying the overflow policy if the queue is full.
+    /// Events pushed after the queue was closed are discarded.
+    pub fn push(&self, event: RhustAppEventType) {
+        let mut state = self.state.lock().unwrap();
+
+        while state.events.len() >= self.config.capacity && !state.closed {
+            match self.config.policy {
+                OverflowPolicy::Block => {
+                    state = self.not_full.wait(state).unwrap();
+                }
+                OverflowPolicy::DropOldest => {
+                    state.events.pop_front();
+                    state.dropped += 1;
+                    log::warn!("event queue is full, dropped the oldest event");
+                }
+            }
+        }
+        if state.closed {
+            return;
+        };
+
+        state.events.push_back(event);
+        self.not_empty.notify_one();
+    }
+
+    /// Pops the next event, blocking until there is one. Returns `None` once the queue is
+    /// closed and empty.
+    pub fn pop(&self) -> Option<RhustAppEventType> {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            if let Some(event) = self.take_next(&mut state) {
+                return Some(event);
+            };
+            if state.closed {
+                return None;
+            };
+            state = self.not_empty.wait(state).unwrap();
+        }
+    }
+
+    /// Pops the next event if there is one, without blocking.
+    pub fn try_pop(&self) -> Option<RhustAppEventType> {
+        let mut state = self.state.lock().unwrap();
+        self.take_next(&mut state)
+    }
+
+    /// Closes the queue, waking up any blocked threads.
+    pub fn close(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.closed = true;
+        self.not_empty.notify_all();
+        self.not_full.notify_all();
+    }
+
+    /// Returns the number of queued events.
+    pub fn len(&self) -> usize {
+        self.state.lock().unwrap().events.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn take_next(&self, state: &mut QueueState) -> Option<RhustAppEventType> {
+        if state.dropped > 0 {
+            let count = state.dropped;
+            state.dropped = 0;
+            return Some(RhustAppEventType::EventsDropped(EventsDropped { count }));
+        };
+
+        let event = state.events.pop_front();
+        if event.is_some() {
+            self.not_full.notify_one();
+        };
+        event
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, thread, time::Duration};
+
+    use crate::types::events::StreamError;
+
+    use super::*;
+
+    fn event(code: &str) -> RhustAppEventType {
+        RhustAppEventType::StreamError(StreamError {
+            code: code.to_string(),
+        })
+    }
+
+    fn code(event: RhustAppEventType) -> String {
+        match event {
+            RhustAppEventType::StreamError(StreamError { code }) => code,
+            _ => panic!("unexpected event"),
+        }
+    }
+
+    #[test]
+    fn test_drop_oldest_keeps_newest_events() {
+        let queue = EventQueue::new(EventQueueConfig {
+            capacity: 3,
+            policy: OverflowPolicy::DropOldest,
+        });
+        for i in 1..=5 {
+            queue.push(event(&i.to_string()));
+        }
+        assert_eq!(queue.len(), 3);
+
+        assert!(matches!(
+            queue.try_pop(),
+            Some(RhustAppEventType::EventsDropped(EventsDropped { count: 2 }))
+        ));
+        let codes = (0..3)
+            .map(|_| code(queue.try_pop().unwrap()))
+            .collect::<Vec<String>>();
+        assert_eq!(codes, vec!["3", "4", "5"]);
+        assert!(queue.try_pop().is_none());
+    }
+
+    #[test]
+    fn test_block_waits_for_consumer() {
+        let queue = Arc::new(EventQueue::new(EventQueueConfig {
+            capacity: 1,
+            policy: OverflowPolicy::Block,
+        }));
+        queue.push(event("1"));
+
+        let producer = {
+            let queue = Arc::clone(&queue);
+            thread::spawn(move || queue.push(event("2")))
+        };
+        thread::sleep(Duration::from_millis(50));
+        assert_eq!(queue.len(), 1);
+
+        assert_eq!(code(queue.pop().unwrap()), "1");
+        producer.join().unwrap();
+        assert_eq!(code(queue.pop().unwrap()), "2");
+
+        queue.close();
+        assert!(queue.pop().is_none());
+    }
+}
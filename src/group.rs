@@ -0,0 +1,279 @@
+//! `group` contains the builders for the `w:g2` queries that fetch the info of groups and
+//! change their settings.
+
+use crate::{
+    binary::{AttributeTypes, Attrs, Node, NodeContentType},
+    new_rhustapp_error,
+    send::generate_message_id,
+    types::{GroupInfo, GroupParticipant, GROUP_SERVER, JID},
+    RhustAppError,
+};
+
+/// The maximum length of a group subject in characters. The server may send a different
+/// limit in its props, see `ServerProps::max_subject_length`.
+pub const MAX_GROUP_SUBJECT_LENGTH: usize = 100;
+
+/// The maximum length of a group description in characters.
+pub const MAX_GROUP_DESCRIPTION_LENGTH: usize = 2048;
+
+/// Builds the `<iq xmlns="w:g2" type="set">` stanza that changes the subject (name) of a
+/// group. The `id` of the `<iq>` is not set here, it is assigned when the query is sent.
+pub fn build_set_group_subject_node(group: &JID, subject: &str) -> Result<Node, RhustAppError> {
+    validate_group(group)?;
+    if subject.trim().is_empty() {
+        return Err(new_rhustapp_error(
+            "failed to build group subject change",
+            Some("subject is empty".to_string()),
+        ));
+    };
+    validate_length("subject", subject, MAX_GROUP_SUBJECT_LENGTH)?;
+
+    Ok(build_group_iq(
+        group,
+        "set",
+        Node {
+            tag: "subject".to_string(),
+            attrs: Attrs::new(),
+            content: NodeContentType::ByteArray(subject.as_bytes().to_vec()),
+        },
+    ))
+}
+
+/// Builds the `<iq xmlns="w:g2" type="set">` stanza that changes the description (topic) of
+/// a group. An empty description deletes the current one.
+///
+/// `prev_id` is the ID of the current description (`GroupTopic::topic_id`), the server
+/// rejects the change if it doesn't match.
+pub fn build_set_group_description_node(
+    group: &JID,
+    description: &str,
+    prev_id: Option<&str>,
+) -> Result<Node, RhustAppError> {
+    validate_group(group)?;
+    validate_length("description", description, MAX_GROUP_DESCRIPTION_LENGTH)?;
+
+    let mut attrs = Attrs::from([(
+        "id".to_string(),
+        AttributeTypes::String(generate_message_id()),
+    )]);
+    if let Some(prev_id) = prev_id.filter(|prev_id| !prev_id.is_empty()) {
+        attrs.insert(
+            "prev".to_string(),
+            AttributeTypes::String(prev_id.to_string()),
+        );
+    };
+
+    let content = if description.is_empty() {
+        attrs.insert(
+            "delete".to_string(),
+            AttributeTypes::String("true".to_string()),
+        );
+        NodeContentType::None
+    } else {
+        NodeContentType::ListOfNodes(vec![Node {
+            tag: "body".to_string(),
+            attrs: Attrs::new(),
+            content: NodeContentType::ByteArray(description.as_bytes().to_vec()),
+        }])
+    };
+
+    Ok(build_group_iq(
+        group,
+        "set",
+        Node {
+            tag: "description".to_string(),
+            attrs,
+            content,
+        },
+    ))
+}
+
+/// Builds the `<iq xmlns="w:g2" type="get">` query that fetches the info of a group,
+/// including its participants.
+pub fn build_get_group_info_node(group: &JID) -> Result<Node, RhustAppError> {
+    validate_group(group)?;
+    Ok(build_group_iq(
+        group,
+        "get",
+        Node {
+            tag: "query".to_string(),
+            attrs: Attrs::from([(
+                "request".to_string(),
+                AttributeTypes::String("interactive".to_string()),
+            )]),
+            content: NodeContentType::None,
+        },
+    ))
+}
+
+/// Parses the `<group>` in the response to `build_get_group_info_node`.
+pub fn parse_group_info(response: &Node) -> Result<GroupInfo, RhustAppError> {
+    let group = response
+        .get_optional_child_by_tag(&["group"])
+        .ok_or_else(|| new_rhustapp_error("didn't find <group> in group info response", None))?;
+    GroupInfo::from_node(&group)
+}
+
+/// Parses the participants out of the response to `build_get_group_info_node`.
+pub fn parse_group_participants(response: &Node) -> Result<Vec<GroupParticipant>, RhustAppError> {
+    response
+        .get_optional_child_by_tag(&["group"])
+        .ok_or_else(|| new_rhustapp_error("didn't find <group> in group info response", None))?
+        .get_children_by_tag("participant")
+        .unwrap_or_default()
+        .iter()
+        .map(GroupParticipant::from_node)
+        .collect()
+}
+
+fn build_group_iq(group: &JID, iq_type: &str, content: Node) -> Node {
+    Node {
+        tag: "iq".to_string(),
+        attrs: Attrs::from([
+            (
+                "xmlns".to_string(),
+                AttributeTypes::String("w:g2".to_string()),
+            ),
+            (
+                "type".to_string(),
+                AttributeTypes::String(iq_type.to_string()),
+            ),
+            ("to".to_string(), AttributeTypes::JID(group.clone())),
+        ]),
+        content: NodeContentType::ListOfNodes(vec![content]),
+    }
+}
+
+fn validate_group(group: &JID) -> Result<(), RhustAppError> {
+    if group.server.eq(GROUP_SERVER) && !group.user.is_empty() {
+        return Ok(());
+    };
+    Err(new_rhustapp_error(
+        &format!("{group} is not a group JID"),
+        None,
+    ))
+}
+
+fn validate_length(field: &str, value: &str, max_length: usize) -> Result<(), RhustAppError> {
+    let length = value.chars().count();
+    if length > max_length {
+        return Err(new_rhustapp_error(
+            &format!("group {field} is too long"),
+            Some(format!("{length} characters, the maximum is {max_length}")),
+        ));
+    };
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use super::*;
+
+    fn group() -> JID {
+        JID::from_str("120363000000000000@g.us").unwrap()
+    }
+
+    #[test]
+    fn test_build_set_group_subject_node() {
+        let node = build_set_group_subject_node(&group(), "Weekend plans").unwrap();
+        assert_eq!(node.tag, "iq");
+        let mut ag = node.attr_getter();
+        assert_eq!(ag.string("xmlns").unwrap(), "w:g2");
+        assert_eq!(ag.string("type").unwrap(), "set");
+        assert_eq!(ag.jid("to").unwrap(), group());
+
+        let subject = node.get_optional_child_by_tag(&["subject"]).unwrap();
+        assert!(matches!(
+            subject.content,
+            NodeContentType::ByteArray(bytes) if bytes == b"Weekend plans"
+        ));
+
+        assert!(build_set_group_subject_node(&group(), &"a".repeat(101)).is_err());
+        assert!(build_set_group_subject_node(&group(), " ").is_err());
+        let user = JID::from_str("919876543210@s.whatsapp.net").unwrap();
+        assert!(build_set_group_subject_node(&user, "Weekend plans").is_err());
+    }
+
+    #[test]
+    fn test_build_set_group_description_node() {
+        let node = build_set_group_description_node(&group(), "Plans for the weekend", Some("ABC"))
+            .unwrap();
+        assert_eq!(node.attr_getter().string("xmlns").unwrap(), "w:g2");
+
+        let description = node.get_optional_child_by_tag(&["description"]).unwrap();
+        let mut ag = description.attr_getter();
+        assert!(ag.string("id").unwrap().starts_with("3EB0"));
+        assert_eq!(ag.string("prev").unwrap(), "ABC");
+        assert!(ag.optional_string("delete").is_none());
+        let body = description.get_optional_child_by_tag(&["body"]).unwrap();
+        assert!(matches!(
+            body.content,
+            NodeContentType::ByteArray(bytes) if bytes == b"Plans for the weekend"
+        ));
+
+        let deleted = build_set_group_description_node(&group(), "", None).unwrap();
+        let description = deleted.get_optional_child_by_tag(&["description"]).unwrap();
+        let mut ag = description.attr_getter();
+        assert_eq!(ag.string("delete").unwrap(), "true");
+        assert!(ag.optional_string("prev").is_none());
+        assert!(matches!(description.content, NodeContentType::None));
+
+        assert!(build_set_group_description_node(&group(), &"a".repeat(2049), None).is_err());
+    }
+
+    #[test]
+    fn test_group_info() {
+        let node = build_get_group_info_node(&group()).unwrap();
+        let mut ag = node.attr_getter();
+        assert_eq!(ag.string("xmlns").unwrap(), "w:g2");
+        assert_eq!(ag.string("type").unwrap(), "get");
+        assert_eq!(ag.jid("to").unwrap(), group());
+        let query = node.get_optional_child_by_tag(&["query"]).unwrap();
+        assert_eq!(
+            query.attr_getter().string("request").unwrap(),
+            "interactive"
+        );
+        let user = JID::from_str("919876543210@s.whatsapp.net").unwrap();
+        assert!(build_get_group_info_node(&user).is_err());
+
+        let participant = |jid: &str, participant_type: Option<&str>| {
+            let mut attrs = Attrs::from([(
+                "jid".to_string(),
+                AttributeTypes::JID(JID::from_str(jid).unwrap()),
+            )]);
+            if let Some(participant_type) = participant_type {
+                attrs.insert(
+                    "type".to_string(),
+                    AttributeTypes::String(participant_type.to_string()),
+                );
+            };
+            Node {
+                tag: "participant".to_string(),
+                attrs,
+                content: NodeContentType::None,
+            }
+        };
+        let response = Node {
+            tag: "iq".to_string(),
+            attrs: Attrs::new(),
+            content: NodeContentType::ListOfNodes(vec![Node {
+                tag: "group".to_string(),
+                attrs: Attrs::new(),
+                content: NodeContentType::ListOfNodes(vec![
+                    participant("919876543210@s.whatsapp.net", Some("superadmin")),
+                    participant("911234567890@s.whatsapp.net", None),
+                ]),
+            }]),
+        };
+        let participants = parse_group_participants(&response).unwrap();
+        assert_eq!(participants.len(), 2);
+        assert_eq!(participants[0].jid, user);
+        assert!(participants[0].is_super_admin);
+        assert!(!participants[1].is_admin);
+
+        assert!(parse_group_participants(&Node::default()).is_err());
+        assert!(parse_group_info(&Node::default()).is_err());
+    }
+}
@@ -0,0 +1,150 @@
+//! `keepalive` contains the pings that are sent periodically to check that the connection
+//! is still alive, and the tracking of their failures.
+
+use std::time::Duration;
+
+use rand::Rng;
+use time::OffsetDateTime;
+
+use crate::{
+    binary::{AttributeTypes, Attrs, Node, NodeContentType},
+    types::{
+        events::{KeepAliveTimeout, RhustAppEventType},
+        SERVER_JID,
+    },
+};
+
+/// The configuration of the keepalive pings.
+#[derive(Clone, Debug)]
+pub struct KeepAliveConfig {
+    /// The minimum time between two pings. The time before each ping is picked randomly
+    /// between `min_interval` and `max_interval`.
+    pub min_interval: Duration,
+    pub max_interval: Duration,
+    /// How long to wait for the response to a ping before it counts as failed.
+    pub response_timeout: Duration,
+}
+
+impl Default for KeepAliveConfig {
+    fn default() -> Self {
+        Self {
+            min_interval: Duration::from_secs(20),
+            max_interval: Duration::from_secs(30),
+            response_timeout: Duration::from_secs(10),
+        }
+    }
+}
+
+impl KeepAliveConfig {
+    /// Returns how long to wait before the next ping.
+    pub fn interval(&self) -> Duration {
+        let min = self.min_interval.as_millis() as u64;
+        let max = (self.max_interval.as_millis() as u64).max(min);
+        Duration::from_millis(rand::thread_rng().gen_range(min, max + 1))
+    }
+}
+
+/// Builds the `<iq xmlns="w:p" type="get">` ping. The `id` of the `<iq>` is not set here, it
+/// is assigned when the ping is sent.
+pub fn build_keepalive_node() -> Node {
+    Node {
+        tag: "iq".to_string(),
+        attrs: Attrs::from([
+            (
+                "xmlns".to_string(),
+                AttributeTypes::String("w:p".to_string()),
+            ),
+            (
+                "type".to_string(),
+                AttributeTypes::String("get".to_string()),
+            ),
+            ("to".to_string(), AttributeTypes::JID(SERVER_JID.clone())),
+        ]),
+        content: NodeContentType::ListOfNodes(vec![Node {
+            tag: "ping".to_string(),
+            attrs: Attrs::new(),
+            content: NodeContentType::None,
+        }]),
+    }
+}
+
+/// Tracks the results of the pings of a connection, and returns the events to emit for
+/// them: `KeepAliveTimeout` for every failed ping, and `KeepAliveRestored` for the first
+/// successful ping after failures.
+pub(crate) struct KeepAliveTracker {
+    error_count: i32,
+    last_success: OffsetDateTime,
+}
+
+impl KeepAliveTracker {
+    pub(crate) fn new() -> Self {
+        Self {
+            error_count: 0,
+            last_success: OffsetDateTime::now_utc(),
+        }
+    }
+
+    pub(crate) fn success(&mut self) -> Option<RhustAppEventType> {
+        self.last_success = OffsetDateTime::now_utc();
+        if self.error_count == 0 {
+            return None;
+        };
+        self.error_count = 0;
+        Some(RhustAppEventType::KeepAliveRestored)
+    }
+
+    pub(crate) fn failure(&mut self) -> RhustAppEventType {
+        self.error_count += 1;
+        RhustAppEventType::KeepAliveTimeout(KeepAliveTimeout {
+            error_count: self.error_count,
+            last_success: self.last_success,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_interval() {
+        let config = KeepAliveConfig::default();
+        for _ in 0..100 {
+            let interval = config.interval();
+            assert!(interval >= config.min_interval && interval <= config.max_interval);
+        }
+
+        let fixed = KeepAliveConfig {
+            min_interval: Duration::from_secs(5),
+            max_interval: Duration::from_secs(1),
+            ..config
+        };
+        assert_eq!(fixed.interval(), Duration::from_secs(5));
+    }
+
+    #[test]
+    fn test_tracker() {
+        let mut tracker = KeepAliveTracker::new();
+        assert!(tracker.success().is_none());
+        let last_success = tracker.last_success;
+
+        for expected in 1..=2 {
+            match tracker.failure() {
+                RhustAppEventType::KeepAliveTimeout(timeout) => {
+                    assert_eq!(timeout.error_count, expected);
+                    assert_eq!(timeout.last_success, last_success);
+                }
+                _ => panic!("expected a KeepAliveTimeout event"),
+            };
+        }
+        assert!(matches!(
+            tracker.success(),
+            Some(RhustAppEventType::KeepAliveRestored)
+        ));
+        assert!(tracker.success().is_none());
+        assert!(matches!(
+            tracker.failure(),
+            RhustAppEventType::KeepAliveTimeout(KeepAliveTimeout { error_count: 1, .. })
+        ));
+    }
+}
@@ -1,8 +1,53 @@
+pub mod appstate;
+
 pub mod binary;
 
+pub mod client;
+
+pub mod connection_events;
+
+pub mod dispatch;
+
+pub mod encryption;
+
+pub mod event_handlers;
+
+pub mod event_queue;
+
 mod error;
 pub use error::*;
 
+pub mod group;
+
+pub mod keepalive;
+
+pub mod media;
+
+pub mod message;
+
+pub mod newsletter;
+
+pub mod pair;
+
+pub mod prekeys;
+
+pub mod presence;
+
+pub mod receipt;
+
+pub mod reconnect;
+
+pub mod request;
+
+pub mod send;
+
 pub mod socket;
 
+pub mod store;
+
+#[cfg(test)]
+mod testing;
+
 pub mod types;
+
+pub mod usync;
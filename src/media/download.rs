@@ -0,0 +1,327 @@
+use block_modes::BlockMode;
+use sha2::{Digest, Sha256};
+use url::Url;
+
+use crate::{binary::proto as wa_proto, new_rhustapp_error, RhustAppError};
+
+use super::{expand_media_key, http, media_mac, Aes256Cbc, MediaType, MEDIA_MAC_LENGTH};
+
+/// A message with an attachment that can be downloaded with `Client::download`.
+pub trait DownloadableMessage {
+    fn media_type(&self) -> MediaType;
+    /// The full URL of the attachment, if the message has one.
+    fn url(&self) -> Option<&str>;
+    /// The path of the attachment on the media hosts.
+    fn direct_path(&self) -> &str;
+    fn media_key(&self) -> &[u8];
+    fn file_sha256(&self) -> &[u8];
+    fn file_enc_sha256(&self) -> &[u8];
+    /// The size of the decrypted attachment, or 0 if the message doesn't say. It limits how
+    /// much is downloaded.
+    fn file_length(&self) -> u64 {
+        0
+    }
+}
+
+macro_rules! downloadable_message {
+    ($message:ty, $media_type:expr) => {
+        impl DownloadableMessage for $message {
+            fn media_type(&self) -> MediaType {
+                $media_type
+            }
+            fn url(&self) -> Option<&str> {
+                self.url.as_deref()
+            }
+            fn direct_path(&self) -> &str {
+                self.directPath()
+            }
+            fn media_key(&self) -> &[u8] {
+                self.mediaKey()
+            }
+            fn file_sha256(&self) -> &[u8] {
+                self.fileSha256()
+            }
+            fn file_enc_sha256(&self) -> &[u8] {
+                self.fileEncSha256()
+            }
+            fn file_length(&self) -> u64 {
+                self.fileLength()
+            }
+        }
+    };
+}
+
+downloadable_message!(wa_proto::ImageMessage, MediaType::Image);
+downloadable_message!(wa_proto::VideoMessage, MediaType::Video);
+downloadable_message!(wa_proto::AudioMessage, MediaType::Audio);
+downloadable_message!(wa_proto::DocumentMessage, MediaType::Document);
+// Stickers are encrypted like images.
+downloadable_message!(wa_proto::StickerMessage, MediaType::Image);
+
+/// The history sync blobs only have a direct path.
+impl DownloadableMessage for wa_proto::HistorySyncNotification {
+    fn media_type(&self) -> MediaType {
+        MediaType::History
+    }
+    fn url(&self) -> Option<&str> {
+        None
+    }
+    fn direct_path(&self) -> &str {
+        self.directPath()
+    }
+    fn media_key(&self) -> &[u8] {
+        self.mediaKey()
+    }
+    fn file_sha256(&self) -> &[u8] {
+        self.fileSha256()
+    }
+    fn file_enc_sha256(&self) -> &[u8] {
+        self.fileEncSha256()
+    }
+    fn file_length(&self) -> u64 {
+        self.fileLength()
+    }
+}
+
+/// The app state snapshots and patches that are too large to be sent inline.
+impl DownloadableMessage for wa_proto::ExternalBlobReference {
+    fn media_type(&self) -> MediaType {
+        MediaType::AppState
+    }
+    fn url(&self) -> Option<&str> {
+        None
+    }
+    fn direct_path(&self) -> &str {
+        self.directPath()
+    }
+    fn media_key(&self) -> &[u8] {
+        self.mediaKey()
+    }
+    fn file_sha256(&self) -> &[u8] {
+        self.fileSha256()
+    }
+    fn file_enc_sha256(&self) -> &[u8] {
+        self.fileEncSha256()
+    }
+    fn file_length(&self) -> u64 {
+        self.fileSizeBytes()
+    }
+}
+
+/// The size of the largest attachment that is downloaded, which is also the limit for
+/// messages that don't say how large their attachment is.
+const MAX_MEDIA_SIZE: usize = 2 * 1024 * 1024 * 1024;
+
+/// Returns how large the encrypted attachment of the message can be: its file length padded
+/// to the next AES block, plus the MAC.
+fn max_encrypted_size(message: &dyn DownloadableMessage) -> usize {
+    match usize::try_from(message.file_length()) {
+        Ok(length) if length > 0 && length < MAX_MEDIA_SIZE => {
+            (length / 16 + 1) * 16 + MEDIA_MAC_LENGTH
+        }
+        _ => MAX_MEDIA_SIZE,
+    }
+}
+
+/// Downloads and decrypts the attachment of a message from its URL, which must be `https`
+/// as it comes from the sender of the message.
+pub fn download_from_url(
+    url: &str,
+    message: &dyn DownloadableMessage,
+) -> Result<Vec<u8>, RhustAppError> {
+    let scheme = Url::parse(url)
+        .map_err(|err| new_rhustapp_error("failed to parse media URL", Some(err.to_string())))?
+        .scheme()
+        .to_string();
+    if scheme != "https" {
+        return Err(new_rhustapp_error(
+            &format!("unsupported media URL scheme {scheme}"),
+            Some("only https is allowed".to_string()),
+        ));
+    };
+    fetch(url, message)
+}
+
+fn fetch(url: &str, message: &dyn DownloadableMessage) -> Result<Vec<u8>, RhustAppError> {
+    let data = http::request(
+        "GET",
+        url,
+        &[
+            ("Origin", "https://web.whatsapp.com"),
+            ("Referer", "https://web.whatsapp.com/"),
+        ],
+        &[],
+        max_encrypted_size(message),
+    )?
+    .into_body()?;
+    decrypt_media(&data, message)
+}
+
+/// Downloads and decrypts the attachment of a message from its direct path on the first of
+/// the media hosts that has it.
+pub fn download_from_hosts(
+    hosts: &[String],
+    message: &dyn DownloadableMessage,
+) -> Result<Vec<u8>, RhustAppError> {
+    download_from_hosts_with_scheme(hosts, message, "https")
+}
+
+fn download_from_hosts_with_scheme(
+    hosts: &[String],
+    message: &dyn DownloadableMessage,
+    scheme: &str,
+) -> Result<Vec<u8>, RhustAppError> {
+    if message.direct_path().is_empty() {
+        return Err(new_rhustapp_error(
+            "message has no direct path to download from",
+            None,
+        ));
+    };
+
+    let mut last_err = None;
+    for host in hosts {
+        let url = format!("{scheme}://{host}{}", message.direct_path());
+        match fetch(&url, message) {
+            Ok(plaintext) => return Ok(plaintext),
+            Err(err) => {
+                log::warn!("failed to download media from {host}: {err}");
+                last_err = Some(err);
+            }
+        };
+    }
+    Err(last_err.unwrap_or_else(|| new_rhustapp_error("no media hosts to download from", None)))
+}
+
+/// Checks the hash and the MAC of a downloaded attachment, and decrypts it with the media
+/// key of its message.
+pub fn decrypt_media(
+    data: &[u8],
+    message: &dyn DownloadableMessage,
+) -> Result<Vec<u8>, RhustAppError> {
+    if data.len() <= MEDIA_MAC_LENGTH {
+        return Err(new_rhustapp_error(
+            "downloaded media is too short",
+            Some(format!("{} bytes", data.len())),
+        ));
+    };
+    if !message.file_enc_sha256().is_empty()
+        && Sha256::digest(data).as_slice() != message.file_enc_sha256()
+    {
+        return Err(new_rhustapp_error(
+            "hash of downloaded media doesn't match",
+            None,
+        ));
+    };
+
+    let keys = expand_media_key(message.media_key(), message.media_type());
+    let (ciphertext, mac) = data.split_at(data.len() - MEDIA_MAC_LENGTH);
+    if media_mac(&keys, ciphertext) != mac {
+        return Err(new_rhustapp_error(
+            "MAC of downloaded media doesn't match",
+            None,
+        ));
+    };
+
+    let plaintext = Aes256Cbc::new_from_slices(&keys.cipher_key, &keys.iv)
+        .map_err(|err| new_rhustapp_error("invalid media key", Some(err.to_string())))?
+        .decrypt_vec(ciphertext)
+        .map_err(|err| new_rhustapp_error("failed to decrypt media", Some(err.to_string())))?;
+    if !message.file_sha256().is_empty()
+        && Sha256::digest(&plaintext).as_slice() != message.file_sha256()
+    {
+        return Err(new_rhustapp_error(
+            "hash of decrypted media doesn't match",
+            None,
+        ));
+    };
+    Ok(plaintext)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{media::encrypt_media, testing::serve_http, ErrorKind};
+
+    use super::*;
+
+    fn image_message(plaintext: &[u8]) -> (wa_proto::ImageMessage, Vec<u8>) {
+        let encrypted = encrypt_media(plaintext, MediaType::Image);
+        let mut message = wa_proto::ImageMessage::new();
+        message.directPath = Some("/v/t62/abc.enc".to_string());
+        message.mediaKey = Some(encrypted.media_key);
+        message.fileSha256 = Some(encrypted.file_sha256);
+        message.fileEncSha256 = Some(encrypted.file_enc_sha256);
+        message.fileLength = Some(plaintext.len() as u64);
+        (message, encrypted.data)
+    }
+
+    #[test]
+    fn test_decrypt_media() {
+        let (message, data) = image_message(b"image data");
+        assert_eq!(decrypt_media(&data, &message).unwrap(), b"image data");
+
+        let mut tampered = data.clone();
+        tampered[0] ^= 1;
+        let err = decrypt_media(&tampered, &message).unwrap_err();
+        assert!(err.description.contains("hash of downloaded media"));
+
+        // Without the hashes, the MAC still catches the tampering.
+        let mut without_hashes = message.clone();
+        without_hashes.fileEncSha256 = None;
+        without_hashes.fileSha256 = None;
+        let err = decrypt_media(&tampered, &without_hashes).unwrap_err();
+        assert!(err.description.contains("MAC"));
+
+        // The keys are expanded for the type of the message.
+        let mut document = wa_proto::DocumentMessage::new();
+        document.mediaKey = message.mediaKey.clone();
+        assert!(decrypt_media(&data, &document).is_err());
+
+        assert!(decrypt_media(&data[..MEDIA_MAC_LENGTH], &message).is_err());
+    }
+
+    #[test]
+    fn test_download_from_hosts() {
+        let (message, data) = image_message(b"image data");
+        let (host, server) = serve_http(move |head, _| {
+            assert!(head.starts_with("GET /v/t62/abc.enc HTTP/1.1"));
+            (200, data)
+        });
+
+        let plaintext = download_from_hosts_with_scheme(&[host], &message, "http").unwrap();
+        server.join().unwrap();
+        assert_eq!(plaintext, b"image data");
+    }
+
+    #[test]
+    fn test_download_from_url_expired() {
+        let (message, _) = image_message(b"image data");
+        let (host, server) = serve_http(|_, _| (404, Vec::new()));
+
+        let err = fetch(&format!("http://{host}/d/f/abc.enc"), &message).unwrap_err();
+        server.join().unwrap();
+        assert_eq!(*err.kind(), ErrorKind::Http(404));
+    }
+
+    #[test]
+    fn test_download_from_url_not_https() {
+        let (message, _) = image_message(b"image data");
+        let err = download_from_url("http://mmg.whatsapp.net/d/f/abc.enc", &message).unwrap_err();
+        assert!(err.description.contains("scheme http"));
+    }
+
+    #[test]
+    fn test_download_too_large() {
+        let (mut message, data) = image_message(&[1; 40]);
+        // The attachment is larger than the message says.
+        message.fileLength = Some(4);
+        assert_eq!(max_encrypted_size(&message), 16 + MEDIA_MAC_LENGTH);
+        let (host, server) = serve_http(move |_, _| (200, data));
+
+        let err = fetch(&format!("http://{host}/d/f/abc.enc"), &message).unwrap_err();
+        server.join().unwrap();
+        assert!(err.description.contains("too large"));
+
+        message.fileLength = None;
+        assert_eq!(max_encrypted_size(&message), MAX_MEDIA_SIZE);
+    }
+}
@@ -0,0 +1,227 @@
+//! A minimal HTTP/1.1 client for the media servers, which is all that transferring
+//! attachments needs: one request per connection, over TLS for `https` URLs.
+
+use std::{
+    io::{Read, Write},
+    net::TcpStream,
+    time::Duration,
+};
+
+use native_tls::TlsConnector;
+use url::Url;
+
+use crate::{new_rhustapp_error, ErrorKind, RhustAppError};
+
+/// How long to wait for the media server to accept or send data.
+const HTTP_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// How much of a response may be used by the status line, the headers and the sizes of the
+/// chunks, on top of the body.
+const MAX_HEAD_SIZE: usize = 64 * 1024;
+
+pub(crate) struct HttpResponse {
+    pub status: u16,
+    pub body: Vec<u8>,
+}
+
+impl HttpResponse {
+    /// Returns the body, or an error of kind `ErrorKind::Http` if the status isn't 2xx.
+    pub fn into_body(self) -> Result<Vec<u8>, RhustAppError> {
+        if (200..300).contains(&self.status) {
+            return Ok(self.body);
+        };
+        Err(new_rhustapp_error(
+            &format!("media server returned HTTP {}", self.status),
+            Some(String::from_utf8_lossy(&self.body).to_string()),
+        )
+        .with_kind(ErrorKind::Http(self.status)))
+    }
+}
+
+/// Sends a request and reads the whole response, whose body may be at most `max_body_size`
+/// bytes long.
+pub(crate) fn request(
+    method: &str,
+    url: &str,
+    headers: &[(&str, &str)],
+    body: &[u8],
+    max_body_size: usize,
+) -> Result<HttpResponse, RhustAppError> {
+    let url = Url::parse(url)
+        .map_err(|err| new_rhustapp_error("failed to parse media URL", Some(err.to_string())))?;
+    let host = url
+        .host_str()
+        .ok_or_else(|| new_rhustapp_error("media URL has no host", None))?;
+    let port = url
+        .port_or_known_default()
+        .ok_or_else(|| new_rhustapp_error("media URL has no port", None))?;
+    let target = match url.query() {
+        Some(query) => format!("{}?{query}", url.path()),
+        None => url.path().to_string(),
+    };
+
+    let mut head = format!(
+        "{method} {target} HTTP/1.1\r\nHost: {host}\r\nContent-Length: {}\r\nConnection: close\r\n",
+        body.len()
+    );
+    for (name, value) in headers {
+        head.push_str(&format!("{name}: {value}\r\n"));
+    }
+    head.push_str("\r\n");
+    let mut data = head.into_bytes();
+    data.extend_from_slice(body);
+
+    let stream = TcpStream::connect((host, port)).map_err(|err| {
+        new_rhustapp_error("failed to connect to media server", Some(err.to_string()))
+    })?;
+    for result in [
+        stream.set_read_timeout(Some(HTTP_TIMEOUT)),
+        stream.set_write_timeout(Some(HTTP_TIMEOUT)),
+    ] {
+        result.map_err(|err| {
+            new_rhustapp_error("failed to set media socket timeout", Some(err.to_string()))
+        })?;
+    }
+
+    let response = match url.scheme() {
+        "https" => {
+            let connector = TlsConnector::new().map_err(|err| {
+                new_rhustapp_error("failed to build TLS connector", Some(err.to_string()))
+            })?;
+            let stream = connector.connect(host, stream).map_err(|err| {
+                new_rhustapp_error(
+                    "TLS handshake with media server failed",
+                    Some(err.to_string()),
+                )
+            })?;
+            exchange(stream, &data, max_body_size)?
+        }
+        "http" => exchange(stream, &data, max_body_size)?,
+        scheme => {
+            return Err(new_rhustapp_error(
+                &format!("unsupported media URL scheme {scheme}"),
+                None,
+            ))
+        }
+    };
+    let response = parse_response(&response)?;
+    if response.body.len() > max_body_size {
+        return Err(response_too_large(max_body_size));
+    };
+    Ok(response)
+}
+
+fn response_too_large(max_body_size: usize) -> RhustAppError {
+    new_rhustapp_error(
+        "response of media server is too large",
+        Some(format!("expected at most {max_body_size} bytes")),
+    )
+}
+
+fn exchange(
+    mut stream: impl Read + Write,
+    request: &[u8],
+    max_body_size: usize,
+) -> Result<Vec<u8>, RhustAppError> {
+    stream.write_all(request).map_err(|err| {
+        new_rhustapp_error(
+            "failed to send request to media server",
+            Some(err.to_string()),
+        )
+    })?;
+    // One byte more than allowed is read to tell if the response is too large.
+    let max_size = max_body_size.saturating_add(MAX_HEAD_SIZE);
+    let mut response = Vec::new();
+    stream
+        .take(max_size as u64 + 1)
+        .read_to_end(&mut response)
+        .map_err(|err| {
+            new_rhustapp_error(
+                "failed to read response of media server",
+                Some(err.to_string()),
+            )
+        })?;
+    if response.len() > max_size {
+        return Err(response_too_large(max_body_size));
+    };
+    Ok(response)
+}
+
+fn parse_response(response: &[u8]) -> Result<HttpResponse, RhustAppError> {
+    let invalid = || new_rhustapp_error("invalid HTTP response from media server", None);
+
+    let head_end = response
+        .windows(4)
+        .position(|window| window == b"\r\n\r\n")
+        .ok_or_else(invalid)?;
+    let head = String::from_utf8_lossy(&response[..head_end]);
+    let mut body = &response[head_end + 4..];
+
+    let mut lines = head.split("\r\n");
+    let status = lines
+        .next()
+        .and_then(|line| line.split(' ').nth(1))
+        .and_then(|status| status.parse::<u16>().ok())
+        .ok_or_else(invalid)?;
+
+    let mut chunked = false;
+    for line in lines {
+        let (name, value) = line.split_once(':').ok_or_else(invalid)?;
+        let value = value.trim();
+        if name.eq_ignore_ascii_case("transfer-encoding") {
+            chunked = value.eq_ignore_ascii_case("chunked");
+        } else if name.eq_ignore_ascii_case("content-length") {
+            let length = value.parse::<usize>().map_err(|_| invalid())?;
+            body = body.get(..length).ok_or_else(invalid)?;
+        };
+    }
+
+    let body = match chunked {
+        true => decode_chunked(body).ok_or_else(invalid)?,
+        false => body.to_vec(),
+    };
+    Ok(HttpResponse { status, body })
+}
+
+/// Joins the chunks of a body sent with `Transfer-Encoding: chunked`.
+fn decode_chunked(mut data: &[u8]) -> Option<Vec<u8>> {
+    let mut body = Vec::new();
+    loop {
+        let line_end = data.windows(2).position(|window| window == b"\r\n")?;
+        let size = String::from_utf8_lossy(&data[..line_end]);
+        // Chunk extensions are ignored.
+        let size = size.split(';').next()?.trim();
+        let size = usize::from_str_radix(size, 16).ok()?;
+        data = &data[line_end + 2..];
+        if size == 0 {
+            return Some(body);
+        };
+        body.extend_from_slice(data.get(..size)?);
+        data = data.get(size + 2..)?;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_response() {
+        let response =
+            parse_response(b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nhello, and more").unwrap();
+        assert_eq!(response.status, 200);
+        assert_eq!(response.body, b"hello");
+
+        let response = parse_response(
+            b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhello\r\n7;ext=1\r\n, world\r\n0\r\n\r\n",
+        )
+        .unwrap();
+        assert_eq!(response.body, b"hello, world");
+
+        let response = parse_response(b"HTTP/1.1 404 Not Found\r\n\r\n").unwrap();
+        let err = response.into_body().unwrap_err();
+        assert_eq!(*err.kind(), ErrorKind::Http(404));
+
+        assert!(parse_response(b"not http").is_err());
+    }
+}
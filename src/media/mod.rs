@@ -0,0 +1,299 @@
+//! `media` contains the media attachments of messages (images, videos, documents...): their
+//! encryption with keys expanded from a random media key, which is sent in the message, and
+//! their transfer to and from the WhatsApp media servers, whose hosts are fetched with a
+//! `<media_conn>` query. Downloaded attachments are checked against the hashes in their
+//! message before and after being decrypted.
+
+mod download;
+pub use download::*;
+
+mod http;
+
+mod upload;
+pub use upload::*;
+
+use std::time::{Duration, Instant};
+
+use aes::Aes256;
+use block_modes::{block_padding::Pkcs7, BlockMode, Cbc};
+use hkdf::Hkdf;
+use hmac::{Hmac, Mac, NewMac};
+use rand::RngCore;
+use sha2::{Digest, Sha256};
+
+use crate::{
+    binary::{Node, NodeContentType},
+    new_rhustapp_error,
+    request::{InfoQuery, InfoQueryType},
+    types::SERVER_JID,
+    RhustAppError,
+};
+
+type Aes256Cbc = Cbc<Aes256, Pkcs7>;
+
+/// The length of the truncated HMAC-SHA256 appended to encrypted media.
+const MEDIA_MAC_LENGTH: usize = 10;
+
+/// The type of a media attachment, which determines the keys that its media key is expanded
+/// into and where it is uploaded.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MediaType {
+    Image,
+    Video,
+    Audio,
+    Document,
+    /// The history sync blobs sent by the phone after pairing.
+    History,
+    /// The app state snapshots and patches.
+    AppState,
+    /// The thumbnails of link previews.
+    LinkThumbnail,
+}
+
+impl MediaType {
+    /// Returns the HKDF info that the media key is expanded with.
+    pub fn app_info(&self) -> &'static str {
+        match self {
+            Self::Image => "WhatsApp Image Keys",
+            Self::Video => "WhatsApp Video Keys",
+            Self::Audio => "WhatsApp Audio Keys",
+            Self::Document => "WhatsApp Document Keys",
+            Self::History => "WhatsApp History Keys",
+            Self::AppState => "WhatsApp App State Keys",
+            Self::LinkThumbnail => "WhatsApp Link Thumbnail Keys",
+        }
+    }
+
+    /// Returns the type used in the paths of the media servers.
+    pub fn mms_type(&self) -> &'static str {
+        match self {
+            Self::Image => "image",
+            Self::Video => "video",
+            Self::Audio => "audio",
+            Self::Document => "document",
+            Self::History => "md-msg-hist",
+            Self::AppState => "md-app-state",
+            Self::LinkThumbnail => "thumbnail-link",
+        }
+    }
+}
+
+/// The keys expanded from a media key.
+pub struct MediaKeys {
+    pub iv: Vec<u8>,
+    pub cipher_key: Vec<u8>,
+    pub mac_key: Vec<u8>,
+    pub ref_key: Vec<u8>,
+}
+
+/// Expands the 32-byte media key of an attachment with HKDF-SHA256 into the keys it is
+/// encrypted and authenticated with.
+pub fn expand_media_key(media_key: &[u8], media_type: MediaType) -> MediaKeys {
+    let mut expanded = [0u8; 112];
+    Hkdf::<Sha256>::new(None, media_key)
+        .expand(media_type.app_info().as_bytes(), &mut expanded)
+        .expect("112 bytes is a valid HKDF-SHA256 output length");
+
+    MediaKeys {
+        iv: expanded[..16].to_vec(),
+        cipher_key: expanded[16..48].to_vec(),
+        mac_key: expanded[48..80].to_vec(),
+        ref_key: expanded[80..].to_vec(),
+    }
+}
+
+/// An attachment encrypted with a new media key, ready to be uploaded.
+pub struct EncryptedMedia {
+    pub media_key: Vec<u8>,
+    /// The AES-256-CBC ciphertext followed by the first 10 bytes of its HMAC-SHA256.
+    pub data: Vec<u8>,
+    /// The SHA-256 of the plaintext.
+    pub file_sha256: Vec<u8>,
+    /// The SHA-256 of `data`.
+    pub file_enc_sha256: Vec<u8>,
+}
+
+/// Encrypts an attachment with a new random media key.
+pub fn encrypt_media(plaintext: &[u8], media_type: MediaType) -> EncryptedMedia {
+    let mut media_key = vec![0u8; 32];
+    rand::rngs::OsRng.fill_bytes(&mut media_key);
+    let keys = expand_media_key(&media_key, media_type);
+
+    let mut data = Aes256Cbc::new_from_slices(&keys.cipher_key, &keys.iv)
+        .expect("the key and IV lengths are valid for AES-256-CBC")
+        .encrypt_vec(plaintext);
+    let mac = media_mac(&keys, &data);
+    data.extend_from_slice(&mac);
+
+    EncryptedMedia {
+        media_key,
+        file_sha256: Sha256::digest(plaintext).to_vec(),
+        file_enc_sha256: Sha256::digest(&data).to_vec(),
+        data,
+    }
+}
+
+/// Returns the truncated HMAC-SHA256 of the IV and the ciphertext of an attachment.
+fn media_mac(keys: &MediaKeys, ciphertext: &[u8]) -> [u8; MEDIA_MAC_LENGTH] {
+    let mut mac = Hmac::<Sha256>::new_from_slice(&keys.mac_key)
+        .expect("HMAC-SHA256 should accept any key size");
+    mac.update(&keys.iv);
+    mac.update(ciphertext);
+    let mut truncated = [0u8; MEDIA_MAC_LENGTH];
+    truncated.copy_from_slice(&mac.finalize().into_bytes()[..MEDIA_MAC_LENGTH]);
+    truncated
+}
+
+/// The media servers that attachments are transferred with, and the token that authorizes
+/// the uploads.
+#[derive(Clone, Debug)]
+pub struct MediaConn {
+    pub auth: String,
+    /// How long the hosts and token can be used for.
+    pub ttl: Duration,
+    pub hosts: Vec<String>,
+    pub fetched_at: Instant,
+}
+
+impl MediaConn {
+    pub fn is_expired(&self) -> bool {
+        self.fetched_at.elapsed() >= self.ttl
+    }
+}
+
+/// Builds the query that fetches the current `MediaConn`.
+pub fn media_conn_query() -> InfoQuery {
+    let mut query = InfoQuery::new("w:m", InfoQueryType::Set, SERVER_JID.clone());
+    query.content = NodeContentType::ListOfNodes(vec![Node {
+        tag: "media_conn".to_string(),
+        ..Default::default()
+    }]);
+    query
+}
+
+/// Parses the response to `media_conn_query`.
+pub fn parse_media_conn(response: &Node) -> Result<MediaConn, RhustAppError> {
+    let media_conn = response
+        .get_optional_child_by_tag(&["media_conn"])
+        .ok_or_else(|| new_rhustapp_error("didn't find <media_conn> in response", None))?;
+
+    let mut ag = media_conn.attr_getter();
+    let auth = ag.string("auth");
+    let ttl = ag.u64("ttl");
+    if let Some(err) = ag.error() {
+        return Err(err);
+    };
+
+    let hosts = media_conn
+        .get_children_by_tag("host")
+        .unwrap_or_default()
+        .iter()
+        .filter_map(|host| host.attr_getter().optional_string("hostname"))
+        .collect::<Vec<String>>();
+    if hosts.is_empty() {
+        return Err(new_rhustapp_error(
+            "didn't find any media hosts in response",
+            None,
+        ));
+    };
+
+    Ok(MediaConn {
+        auth: auth.unwrap(),
+        ttl: Duration::from_secs(ttl.unwrap()),
+        hosts,
+        fetched_at: Instant::now(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::binary::{AttributeTypes, Attrs};
+
+    use super::*;
+
+    #[test]
+    fn test_expand_media_key() {
+        let keys = expand_media_key(&[7u8; 32], MediaType::Image);
+        assert_eq!(keys.iv.len(), 16);
+        assert_eq!(keys.cipher_key.len(), 32);
+        assert_eq!(keys.mac_key.len(), 32);
+        assert_eq!(keys.ref_key.len(), 32);
+
+        // The keys depend on the type of the media.
+        let document_keys = expand_media_key(&[7u8; 32], MediaType::Document);
+        assert_ne!(keys.cipher_key, document_keys.cipher_key);
+    }
+
+    #[test]
+    fn test_encrypt_media() {
+        let plaintext = b"not really an image";
+        let encrypted = encrypt_media(plaintext, MediaType::Image);
+        assert_eq!(encrypted.media_key.len(), 32);
+        // One block of padded ciphertext and the MAC.
+        assert_eq!(encrypted.data.len(), 32 + MEDIA_MAC_LENGTH);
+        assert_eq!(encrypted.file_sha256, Sha256::digest(plaintext).to_vec());
+        assert_eq!(
+            encrypted.file_enc_sha256,
+            Sha256::digest(&encrypted.data).to_vec()
+        );
+
+        let keys = expand_media_key(&encrypted.media_key, MediaType::Image);
+        let (ciphertext, mac) = encrypted.data.split_at(32);
+        assert_eq!(media_mac(&keys, ciphertext), mac);
+        let decrypted = Aes256Cbc::new_from_slices(&keys.cipher_key, &keys.iv)
+            .unwrap()
+            .decrypt_vec(ciphertext)
+            .unwrap();
+        assert_eq!(decrypted, plaintext);
+    }
+
+    #[test]
+    fn test_parse_media_conn() {
+        let host = |hostname: &str| Node {
+            tag: "host".to_string(),
+            attrs: Attrs::from([(
+                "hostname".to_string(),
+                AttributeTypes::String(hostname.to_string()),
+            )]),
+            content: NodeContentType::None,
+        };
+        let response = Node {
+            tag: "iq".to_string(),
+            attrs: Attrs::new(),
+            content: NodeContentType::ListOfNodes(vec![Node {
+                tag: "media_conn".to_string(),
+                attrs: Attrs::from([
+                    (
+                        "auth".to_string(),
+                        AttributeTypes::String("token".to_string()),
+                    ),
+                    ("ttl".to_string(), AttributeTypes::String("300".to_string())),
+                ]),
+                content: NodeContentType::ListOfNodes(vec![
+                    host("mmg.whatsapp.net"),
+                    host("media-bom1-1.cdn.whatsapp.net"),
+                ]),
+            }]),
+        };
+
+        let media_conn = parse_media_conn(&response).unwrap();
+        assert_eq!(media_conn.auth, "token");
+        assert_eq!(media_conn.ttl, Duration::from_secs(300));
+        assert_eq!(
+            media_conn.hosts,
+            vec!["mmg.whatsapp.net", "media-bom1-1.cdn.whatsapp.net"]
+        );
+        assert!(!media_conn.is_expired());
+
+        assert!(parse_media_conn(&Node::default()).is_err());
+    }
+
+    #[test]
+    fn test_media_conn_query() {
+        let node = media_conn_query().to_node("1");
+        let mut ag = node.attr_getter();
+        assert_eq!(ag.string("xmlns").unwrap(), "w:m");
+        assert_eq!(ag.string("type").unwrap(), "set");
+        assert!(node.get_optional_child_by_tag(&["media_conn"]).is_some());
+    }
+}
@@ -0,0 +1,178 @@
+use url::Url;
+
+use crate::{new_rhustapp_error, RhustAppError};
+
+use super::{encrypt_media, http, MediaConn, MediaType};
+
+/// The details of an uploaded attachment, which are set in the media message (e.g. an
+/// `ImageMessage`) that it is sent with.
+#[derive(Clone, Debug)]
+pub struct UploadResponse {
+    pub url: String,
+    pub direct_path: String,
+    pub media_key: Vec<u8>,
+    pub file_enc_sha256: Vec<u8>,
+    pub file_sha256: Vec<u8>,
+    pub file_length: u64,
+}
+
+/// The most that is read of the JSON that the media server responds to an upload with.
+const MAX_UPLOAD_RESPONSE_SIZE: usize = 64 * 1024;
+
+/// Encrypts an attachment with a new media key and uploads it to the first of the media
+/// hosts that accepts it.
+pub fn upload(
+    media_conn: &MediaConn,
+    plaintext: &[u8],
+    media_type: MediaType,
+) -> Result<UploadResponse, RhustAppError> {
+    upload_with_scheme(media_conn, plaintext, media_type, "https")
+}
+
+fn upload_with_scheme(
+    media_conn: &MediaConn,
+    plaintext: &[u8],
+    media_type: MediaType,
+    scheme: &str,
+) -> Result<UploadResponse, RhustAppError> {
+    let encrypted = encrypt_media(plaintext, media_type);
+    let token = base64::encode_config(&encrypted.file_enc_sha256, base64::URL_SAFE);
+
+    let mut last_err = None;
+    for host in &media_conn.hosts {
+        let url = upload_url(scheme, host, media_type, &media_conn.auth, &token)?;
+        let uploaded = http::request(
+            "POST",
+            &url,
+            &[
+                ("Origin", "https://web.whatsapp.com"),
+                ("Referer", "https://web.whatsapp.com/"),
+            ],
+            &encrypted.data,
+            MAX_UPLOAD_RESPONSE_SIZE,
+        )
+        .and_then(|response| response.into_body())
+        .and_then(|body| parse_upload_response(&body));
+
+        match uploaded {
+            Ok((url, direct_path)) => {
+                return Ok(UploadResponse {
+                    url,
+                    direct_path,
+                    media_key: encrypted.media_key,
+                    file_enc_sha256: encrypted.file_enc_sha256,
+                    file_sha256: encrypted.file_sha256,
+                    file_length: plaintext.len() as u64,
+                })
+            }
+            Err(err) => {
+                log::warn!("failed to upload media to {host}: {err}");
+                last_err = Some(err);
+            }
+        };
+    }
+    Err(last_err.unwrap_or_else(|| new_rhustapp_error("no media hosts to upload to", None)))
+}
+
+fn upload_url(
+    scheme: &str,
+    host: &str,
+    media_type: MediaType,
+    auth: &str,
+    token: &str,
+) -> Result<String, RhustAppError> {
+    let mut url = Url::parse(&format!(
+        "{scheme}://{host}/mms/{}/{token}",
+        media_type.mms_type()
+    ))
+    .map_err(|err| new_rhustapp_error("failed to build upload URL", Some(err.to_string())))?;
+    url.query_pairs_mut()
+        .append_pair("auth", auth)
+        .append_pair("token", token);
+    Ok(url.to_string())
+}
+
+/// Parses the `url` and `direct_path` out of the JSON response to an upload.
+fn parse_upload_response(body: &[u8]) -> Result<(String, String), RhustAppError> {
+    let response: serde_json::Value = serde_json::from_slice(body).map_err(|err| {
+        new_rhustapp_error("failed to parse upload response", Some(err.to_string()))
+    })?;
+    let field = |name: &str| {
+        response[name]
+            .as_str()
+            .map(|value| value.to_string())
+            .ok_or_else(|| new_rhustapp_error(&format!("missing {name} in upload response"), None))
+    };
+    Ok((field("url")?, field("direct_path")?))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use sha2::{Digest, Sha256};
+
+    use crate::testing::serve_http;
+
+    use super::*;
+
+    fn media_conn(hosts: Vec<String>) -> MediaConn {
+        MediaConn {
+            auth: "auth/token=".to_string(),
+            ttl: Duration::from_secs(300),
+            hosts,
+            fetched_at: Instant::now(),
+        }
+    }
+
+    #[test]
+    fn test_upload() {
+        let (host, server) = serve_http(|head, body| {
+            let target = head.split(' ').nth(1).unwrap().to_string();
+            assert!(head.starts_with("POST /mms/image/"));
+            assert!(target.contains("auth=auth%2Ftoken%3D"));
+            let token = base64::encode_config(Sha256::digest(&body), base64::URL_SAFE);
+            assert!(target.contains(&format!("/{token}?")));
+            (
+                200,
+                br#"{"url":"https://mmg.whatsapp.net/d/f/abc.enc","direct_path":"/v/t62/abc.enc"}"#
+                    .to_vec(),
+            )
+        });
+
+        // The first host refuses the connection, so the upload moves on to the next one.
+        let unreachable = {
+            let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+            listener.local_addr().unwrap().to_string()
+        };
+        let conn = media_conn(vec![unreachable, host]);
+        let response = upload_with_scheme(&conn, b"image data", MediaType::Image, "http").unwrap();
+        server.join().unwrap();
+
+        assert_eq!(response.url, "https://mmg.whatsapp.net/d/f/abc.enc");
+        assert_eq!(response.direct_path, "/v/t62/abc.enc");
+        assert_eq!(response.media_key.len(), 32);
+        assert_eq!(response.file_sha256, Sha256::digest(b"image data").to_vec());
+        assert_eq!(response.file_length, 10);
+    }
+
+    #[test]
+    fn test_upload_error() {
+        let (host, server) = serve_http(|_, _| (500, b"server error".to_vec()));
+        let err = upload_with_scheme(
+            &media_conn(vec![host]),
+            b"data",
+            MediaType::Document,
+            "http",
+        )
+        .unwrap_err();
+        server.join().unwrap();
+        assert_eq!(*err.kind(), crate::ErrorKind::Http(500));
+    }
+
+    #[test]
+    fn test_parse_upload_response() {
+        assert!(parse_upload_response(br#"{"url":"https://example.com"}"#).is_err());
+        assert!(parse_upload_response(b"not json").is_err());
+    }
+}
@@ -0,0 +1,265 @@
+//! `message` contains the helpers for handling incoming `<message>` stanzas.
+
+use protobuf::Message;
+
+use crate::{
+    binary::{proto as wa_proto, Node, NodeContentType},
+    encryption::{decrypt_enc_node, decrypt_group_message, process_sender_key_distribution},
+    new_rhustapp_error,
+    store::{Device, DeviceStore},
+    types::{DeviceSentMeta, MessageInfo},
+    RhustAppError,
+};
+
+/// Returns the ciphertext of the `<enc type="skmsg">` child of a group `<message>` stanza,
+/// i.e. the message encrypted with the sender's sender key.
+///
+/// This is not the sender key distribution: that one is inside the plaintext of the
+/// pairwise encrypted `pkmsg` or `msg` child, and must be processed before the ciphertext
+/// returned here can be decrypted.
+///
+/// Returns `None` if the node has no `skmsg` child or its content is not a byte array.
+pub fn extract_skmsg_ciphertext(node: &Node) -> Option<Vec<u8>> {
+    node.get_children_by_tag("enc")
+        .unwrap_or_default()
+        .into_iter()
+        .find(|child| {
+            child
+                .attr_getter()
+                .optional_string("type")
+                .is_some_and(|enc_type| enc_type.eq("skmsg"))
+        })
+        .and_then(|enc| match &enc.content {
+            NodeContentType::ByteArray(bytes) => Some(bytes.to_vec()),
+            _ => None,
+        })
+}
+
+/// Decrypts the `<enc>` children of an incoming `<message>` stanza, whose metadata was
+/// parsed into `info`, and returns the message they carry.
+///
+/// The sender key distributions that come along with group messages are saved, so that the
+/// `skmsg` payload after them can be decrypted. Returns `None` if the stanza only carried a
+/// sender key. Messages sent by another one of the user's own devices are unwrapped from
+/// their `deviceSentMessage`, whose metadata is put in `info.device_sent_meta`.
+pub fn decrypt_message(
+    device: &Device,
+    store: &dyn DeviceStore,
+    node: &Node,
+    info: &mut MessageInfo,
+) -> Result<Option<wa_proto::Message>, RhustAppError> {
+    let sender = &info.source.sender;
+    let mut decrypted = None;
+    for enc in node.get_children_by_tag("enc").unwrap_or_default() {
+        let enc_type = enc
+            .attr_getter()
+            .optional_string("type")
+            .unwrap_or_default();
+        let plaintext = match enc_type.as_str() {
+            "pkmsg" | "msg" => decrypt_enc_node(device, store, sender, &enc)?,
+            "skmsg" => decrypt_group_message(store, &info.source.chat, sender, &enc)?,
+            _ => {
+                log::warn!("ignoring unsupported enc type {enc_type:?} in {}", info.id);
+                continue;
+            }
+        };
+        let mut message = wa_proto::Message::parse_from_bytes(&plaintext).map_err(|err| {
+            new_rhustapp_error("failed to unmarshal message", Some(err.to_string()))
+        })?;
+
+        if let Some(distribution) = message.senderKeyDistributionMessage.take() {
+            process_sender_key_distribution(
+                store,
+                &info.source.chat,
+                sender,
+                distribution.axolotlSenderKeyDistributionMessage(),
+            )?;
+        };
+        let mut content = message.clone();
+        content.messageContextInfo.clear();
+        if content != wa_proto::Message::default() {
+            decrypted = Some(message);
+        };
+    }
+
+    let message = match decrypted {
+        Some(message) => message,
+        None => return Ok(None),
+    };
+    match DeviceSentMeta::from_message(&message) {
+        Some(meta) => {
+            info.device_sent_meta = Some(meta);
+            Ok(message
+                .deviceSentMessage
+                .into_option()
+                .and_then(|device_sent| device_sent.message.into_option()))
+        }
+        None => Ok(Some(message)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use crate::{
+        binary::{AttributeTypes, Attrs},
+        encryption::{
+            build_sender_key_distribution_message, encrypt_for_device, encrypt_group_message,
+            process_prekey_bundle,
+        },
+        send::build_device_sent_message,
+        store::memory::MemoryStore,
+        testing::{message_node, prekey_bundle},
+        types::JID,
+    };
+
+    use super::*;
+
+    fn enc_node(enc_type: &str, content: Vec<u8>) -> Node {
+        Node {
+            tag: "enc".to_string(),
+            attrs: Attrs::from([
+                ("v".to_string(), AttributeTypes::String("2".to_string())),
+                (
+                    "type".to_string(),
+                    AttributeTypes::String(enc_type.to_string()),
+                ),
+            ]),
+            content: NodeContentType::ByteArray(content),
+        }
+    }
+
+    fn group_message(children: Vec<Node>) -> Node {
+        Node {
+            tag: "message".to_string(),
+            attrs: Attrs::from([
+                (
+                    "from".to_string(),
+                    AttributeTypes::JID(JID::from_str("120363000000000000@g.us").unwrap()),
+                ),
+                (
+                    "participant".to_string(),
+                    AttributeTypes::JID(JID::from_str("919876543210@s.whatsapp.net").unwrap()),
+                ),
+                (
+                    "id".to_string(),
+                    AttributeTypes::String("3EB0ABCDEF".to_string()),
+                ),
+            ]),
+            content: NodeContentType::ListOfNodes(children),
+        }
+    }
+
+    #[test]
+    fn test_extract_skmsg_ciphertext() {
+        let node = group_message(vec![
+            enc_node("pkmsg", vec![1, 2, 3]),
+            enc_node("skmsg", vec![4, 5, 6]),
+        ]);
+        assert_eq!(extract_skmsg_ciphertext(&node), Some(vec![4, 5, 6]));
+    }
+
+    #[test]
+    fn test_extract_skmsg_ciphertext_missing() {
+        let node = group_message(vec![enc_node("msg", vec![1, 2, 3])]);
+        assert_eq!(extract_skmsg_ciphertext(&node), None);
+    }
+
+    fn text_message(text: &str) -> Vec<u8> {
+        wa_proto::Message {
+            conversation: Some(text.to_string()),
+            ..Default::default()
+        }
+        .write_to_bytes()
+        .unwrap()
+    }
+
+    /// Returns a device, its store and its JID.
+    fn device(jid: &str) -> (Device, MemoryStore, JID) {
+        (
+            Device::new(),
+            MemoryStore::new(),
+            JID::from_str(jid).unwrap(),
+        )
+    }
+
+    #[test]
+    fn test_decrypt_message_group() {
+        let (own, own_store, own_jid) = device("911234567890.0:1@s.whatsapp.net");
+        let (alice, alice_store, alice_jid) = device("919876543210.0:2@s.whatsapp.net");
+        let group = JID::from_str("120363000000000000@g.us").unwrap();
+        let bundle = prekey_bundle(&own, &own_store, &own_jid);
+        process_prekey_bundle(&alice, &alice_store, &own_jid, &bundle).unwrap();
+
+        let (skmsg, distribution) =
+            encrypt_group_message(&alice_store, &group, &alice_jid, &text_message("hi")).unwrap();
+        let distribution = build_sender_key_distribution_message(&group, distribution)
+            .write_to_bytes()
+            .unwrap();
+        let (pkmsg, _) = encrypt_for_device(&alice, &alice_store, &distribution, &own_jid).unwrap();
+        let node = message_node("1", &group, Some(&alice_jid), vec![pkmsg, skmsg]);
+        let mut info = MessageInfo::from_node(&node, &own_jid).unwrap();
+        let message = decrypt_message(&own, &own_store, &node, &mut info)
+            .unwrap()
+            .unwrap();
+        assert_eq!(message.conversation(), "hi");
+        assert!(info.device_sent_meta.is_none());
+
+        // A stanza that only carries the sender key has no message.
+        let (pkmsg, _) = encrypt_for_device(&alice, &alice_store, &distribution, &own_jid).unwrap();
+        let node = message_node("2", &group, Some(&alice_jid), vec![pkmsg]);
+        let mut info = MessageInfo::from_node(&node, &own_jid).unwrap();
+        assert!(decrypt_message(&own, &own_store, &node, &mut info)
+            .unwrap()
+            .is_none());
+
+        let (skmsg, _) =
+            encrypt_group_message(&alice_store, &group, &alice_jid, &text_message("again"))
+                .unwrap();
+        let node = message_node("3", &group, Some(&alice_jid), vec![skmsg]);
+        let mut info = MessageInfo::from_node(&node, &own_jid).unwrap();
+        let message = decrypt_message(&own, &own_store, &node, &mut info)
+            .unwrap()
+            .unwrap();
+        assert_eq!(message.conversation(), "again");
+    }
+
+    #[test]
+    fn test_decrypt_message_device_sent() {
+        let (own, own_store, own_jid) = device("919876543210.0:1@s.whatsapp.net");
+        let (phone, phone_store, phone_jid) = device("919876543210.0:0@s.whatsapp.net");
+        let bundle = prekey_bundle(&own, &own_store, &own_jid);
+        process_prekey_bundle(&phone, &phone_store, &own_jid, &bundle).unwrap();
+
+        let to = JID::from_str("911234567890@s.whatsapp.net").unwrap();
+        let message = wa_proto::Message {
+            conversation: Some("sent from the phone".to_string()),
+            ..Default::default()
+        };
+        let plaintext = build_device_sent_message(&to, &message)
+            .write_to_bytes()
+            .unwrap();
+        let (pkmsg, _) = encrypt_for_device(&phone, &phone_store, &plaintext, &own_jid).unwrap();
+        let node = message_node("1", &phone_jid, None, vec![pkmsg]);
+
+        let mut info = MessageInfo::from_node(&node, &own_jid).unwrap();
+        let decrypted = decrypt_message(&own, &own_store, &node, &mut info)
+            .unwrap()
+            .unwrap();
+        assert_eq!(decrypted, message);
+        assert_eq!(
+            info.device_sent_meta.unwrap().destination_jid,
+            "911234567890@s.whatsapp.net"
+        );
+    }
+
+    #[test]
+    fn test_decrypt_message_invalid() {
+        let (own, own_store, own_jid) = device("911234567890.0:1@s.whatsapp.net");
+        let sender = JID::from_str("919876543210@s.whatsapp.net").unwrap();
+        let node = message_node("1", &sender, None, vec![enc_node("msg", vec![1, 2, 3])]);
+        let mut info = MessageInfo::from_node(&node, &own_jid).unwrap();
+        assert!(decrypt_message(&own, &own_store, &node, &mut info).is_err());
+    }
+}
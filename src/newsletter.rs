@@ -0,0 +1,72 @@
+//! `newsletter` contains the builders for the queries about newsletters (channels).
+
+use crate::{
+    binary::{AttributeTypes, Attrs, Node, NodeContentType},
+    new_rhustapp_error,
+    types::{JID, NEWSLETTER_SERVER, SERVER_JID},
+    RhustAppError,
+};
+
+/// Builds the `<iq xmlns="newsletter" type="get">` query that fetches the metadata of a
+/// newsletter. The response contains a `<newsletter>` node, which can be parsed with
+/// `NewsletterInfo::from_node`. The `id` of the `<iq>` is not set here, it is assigned when
+/// the query is sent.
+pub fn build_newsletter_metadata_query(jid: &JID) -> Result<Node, RhustAppError> {
+    if !jid.server.eq(NEWSLETTER_SERVER) || jid.user.is_empty() {
+        return Err(new_rhustapp_error(
+            &format!("{jid} is not a newsletter JID"),
+            None,
+        ));
+    };
+
+    Ok(Node {
+        tag: "iq".to_string(),
+        attrs: Attrs::from([
+            (
+                "xmlns".to_string(),
+                AttributeTypes::String("newsletter".to_string()),
+            ),
+            (
+                "type".to_string(),
+                AttributeTypes::String("get".to_string()),
+            ),
+            ("to".to_string(), AttributeTypes::JID(SERVER_JID.clone())),
+        ]),
+        content: NodeContentType::ListOfNodes(vec![Node {
+            tag: "newsletter".to_string(),
+            attrs: Attrs::from([("jid".to_string(), AttributeTypes::JID(jid.clone()))]),
+            content: NodeContentType::ListOfNodes(vec![Node {
+                tag: "metadata".to_string(),
+                attrs: Attrs::new(),
+                content: NodeContentType::None,
+            }]),
+        }]),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use super::*;
+
+    #[test]
+    fn test_build_newsletter_metadata_query() {
+        let jid = JID::from_str("120363000000000000@newsletter").unwrap();
+        let node = build_newsletter_metadata_query(&jid).unwrap();
+        assert_eq!(node.tag, "iq");
+        let mut ag = node.attr_getter();
+        assert_eq!(ag.string("xmlns").unwrap(), "newsletter");
+        assert_eq!(ag.string("type").unwrap(), "get");
+        assert_eq!(ag.jid("to").unwrap(), *SERVER_JID);
+
+        let newsletter = node.get_optional_child_by_tag(&["newsletter"]).unwrap();
+        assert_eq!(newsletter.attr_getter().jid("jid").unwrap(), jid);
+        assert!(newsletter
+            .get_optional_child_by_tag(&["metadata"])
+            .is_some());
+
+        let group = JID::from_str("120363000000000000@g.us").unwrap();
+        assert!(build_newsletter_metadata_query(&group).is_err());
+    }
+}
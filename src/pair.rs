@@ -0,0 +1,952 @@
+//! `pair` contains the helpers used while pairing this client as a companion device, and
+//! for unpairing it again.
+
+use aes::Aes256;
+use aes_gcm::{
+    aead::{Aead, NewAead},
+    Aes256Gcm,
+};
+use ctr::{
+    cipher::{generic_array::GenericArray, NewCipher, StreamCipher},
+    Ctr128BE,
+};
+use hkdf::Hkdf;
+use hmac::{Hmac, Mac, NewMac};
+use libsignal_protocol::{KeyPair, PublicKey};
+use pbkdf2::pbkdf2;
+use protobuf::Message;
+use rand::RngCore;
+use sha2::Sha256;
+
+use crate::{
+    binary::{proto as wa_proto, AttributeTypes, Attrs, Node, NodeContentType},
+    new_rhustapp_error,
+    store::{public_key_bytes, Device},
+    types::{DEFAULT_USER_SERVER, JID, SERVER_JID},
+    ErrorKind, IqError, RhustAppError,
+};
+
+/// The prefix of the message that the phone signs with its account key.
+pub(crate) const ADV_ACCOUNT_SIGNATURE_PREFIX: [u8; 2] = [6, 0];
+/// The prefix of the message that this device signs with its identity key.
+const ADV_DEVICE_SIGNATURE_PREFIX: [u8; 2] = [6, 1];
+
+/// The alphabet of the pairing codes, which leaves out 0, I, O and U.
+const LINKING_CODE_ALPHABET: &[u8; 32] = b"123456789ABCDEFGHJKLMNPQRSTVWXYZ";
+/// The number of PBKDF2 rounds of the key derived from the pairing code.
+const LINKING_CODE_ROUNDS: u32 = 2 << 16;
+/// The length of an ephemeral key wrapped with the pairing code: the salt, the IV and the
+/// encrypted key.
+const WRAPPED_EPHEMERAL_KEY_LENGTH: usize = 32 + 16 + 32;
+
+/// The platform of this client shown on the phone when pairing with a code (Chrome).
+pub const PAIR_CLIENT_PLATFORM_ID: u8 = 1;
+/// The name of this client shown on the phone when pairing with a code, which must be
+/// formatted as `Browser (OS)`.
+pub const PAIR_CLIENT_DISPLAY_NAME: &str = "Chrome (Linux)";
+
+/// Computes the HMAC-SHA256 of the device identity details using the adv secret key.
+///
+/// The phone signs the `ADVSignedDeviceIdentity` details with the adv secret that was shared
+/// through the QR code, so the result must match the HMAC sent in `pair-success`.
+pub fn compute_adv_sign(adv_secret: &[u8], device_details: &[u8]) -> Vec<u8> {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(adv_secret).expect("HMAC-SHA256 should accept any key size");
+    mac.update(device_details);
+    mac.finalize().into_bytes().to_vec()
+}
+
+/// Verifies the HMAC of the device identity details in constant time.
+pub fn verify_adv(adv_secret: &[u8], device_details: &[u8], expected_mac: &[u8]) -> bool {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(adv_secret).expect("HMAC-SHA256 should accept any key size");
+    mac.update(device_details);
+    mac.verify(expected_mac).is_ok()
+}
+
+/// Collects the QR code refs of a `<pair-device>` node, or of the `<iq>` containing it, in
+/// the order they were sent. Each ref is used for one QR code, the next one is shown when
+/// the previous one expires. Children other than `<ref>` are skipped.
+pub fn parse_pair_device_refs(node: &Node) -> Result<Vec<String>, RhustAppError> {
+    let pair_device = if node.tag.eq("pair-device") {
+        node.to_owned()
+    } else {
+        node.get_optional_child_by_tag(&["pair-device"])
+            .ok_or_else(|| new_rhustapp_error("didn't find <pair-device> in node", None))?
+    };
+
+    let mut refs = Vec::new();
+    for child in pair_device.get_children().unwrap_or_default() {
+        if !child.tag.eq("ref") {
+            log::warn!("unexpected child <{}> in pair-device node", child.tag);
+            continue;
+        };
+        let qr_ref = match child.content {
+            NodeContentType::ByteArray(bytes) => String::from_utf8(bytes).map_err(|err| {
+                new_rhustapp_error("pair-device ref is not valid UTF-8", Some(err.to_string()))
+            })?,
+            NodeContentType::String(qr_ref) => qr_ref,
+            content => {
+                return Err(new_rhustapp_error(
+                    &format!("unexpected pair-device ref content: {content:?}"),
+                    None,
+                ))
+            }
+        };
+        refs.push(qr_ref);
+    }
+
+    Ok(refs)
+}
+
+/// Builds the string that is shown as a QR code for the given ref: the ref, followed by the
+/// noise key, the identity key and the adv secret of the device, all base64-encoded.
+pub fn make_qr_data(device: &Device, qr_ref: &str) -> Result<String, RhustAppError> {
+    Ok([
+        qr_ref.to_string(),
+        base64::encode(public_key_bytes(&device.noise_key.public_key)?),
+        base64::encode(public_key_bytes(&device.identity_key.public_key)?),
+        base64::encode(device.adv_secret_key),
+    ]
+    .join(","))
+}
+
+/// The contents of the `<iq>` containing `<pair-success>`, which the server sends once the
+/// phone has scanned the QR code.
+pub struct PairSuccessInfo {
+    /// The id of the `<iq>`, which the response must have.
+    pub request_id: String,
+    /// The JID that was assigned to this device.
+    pub jid: JID,
+    pub business_name: String,
+    pub platform: String,
+    /// The serialized `ADVSignedDeviceIdentityHMAC` sent by the phone.
+    pub device_identity: Vec<u8>,
+}
+
+impl PairSuccessInfo {
+    pub fn from_node(node: &Node) -> Result<Self, RhustAppError> {
+        let pair_success = node
+            .get_optional_child_by_tag(&["pair-success"])
+            .ok_or_else(|| new_rhustapp_error("didn't find <pair-success> in node", None))?;
+        let child = |tag: &str| {
+            pair_success
+                .get_optional_child_by_tag(&[tag])
+                .ok_or_else(|| {
+                    new_rhustapp_error(&format!("didn't find <{tag}> in pair-success"), None)
+                })
+        };
+
+        let device_identity = match child("device-identity")?.content {
+            NodeContentType::ByteArray(bytes) => bytes,
+            content => {
+                return Err(new_rhustapp_error(
+                    &format!("unexpected device-identity content: {content:?}"),
+                    None,
+                ))
+            }
+        };
+        let device = child("device")?;
+        let platform = child("platform")?;
+
+        let mut ag = node.attr_getter();
+        let mut dag = device.attr_getter();
+        let mut pag = platform.attr_getter();
+        let request_id = ag.string("id");
+        let jid = dag.jid("jid");
+        let platform = pag.string("name");
+        ag.merge(dag);
+        ag.merge(pag);
+        if let Some(err) = ag.error() {
+            return Err(new_rhustapp_error(
+                "failed to parse pair-success",
+                Some(err.to_string()),
+            ));
+        };
+
+        Ok(Self {
+            request_id: request_id.unwrap(),
+            jid: jid.unwrap(),
+            business_name: pair_success
+                .get_optional_child_by_tag(&["biz"])
+                .and_then(|biz| biz.attr_getter().optional_string("name"))
+                .unwrap_or_default(),
+            platform: platform.unwrap(),
+            device_identity,
+        })
+    }
+}
+
+/// Returns an error that is reported back to the server with the given error code and text,
+/// see `build_pair_error_node`.
+fn pair_failure(code: u16, text: &str, message: &str, details: Option<String>) -> RhustAppError {
+    new_rhustapp_error(message, details).with_kind(ErrorKind::Iq(Box::new(IqError {
+        code,
+        text: text.to_string(),
+        condition: None,
+    })))
+}
+
+/// Finishes pairing: verifies that the device identity sent in `pair-success` was signed by
+/// the phone with the adv secret and its account key, and signs it with the identity key of
+/// this device.
+///
+/// Returns the signed device identity, which should be stored as the `account` of the
+/// device, and the `<iq>` response that must be sent to the server. If this fails, the
+/// `<iq>` built by `build_pair_error_node` must be sent instead.
+pub fn finish_pairing(
+    device: &Device,
+    info: &PairSuccessInfo,
+) -> Result<(wa_proto::ADVSignedDeviceIdentity, Node), RhustAppError> {
+    let internal_error = |message: &str, err: protobuf::Error| {
+        pair_failure(500, "internal-error", message, Some(err.to_string()))
+    };
+
+    let container = wa_proto::ADVSignedDeviceIdentityHMAC::parse_from_bytes(&info.device_identity)
+        .map_err(|err| internal_error("failed to parse device identity container", err))?;
+    if !verify_adv(
+        &device.adv_secret_key,
+        container.details(),
+        container.hmac(),
+    ) {
+        return Err(pair_failure(
+            401,
+            "not-authorized",
+            "invalid device identity HMAC in pair success message",
+            None,
+        ));
+    };
+
+    let mut identity = wa_proto::ADVSignedDeviceIdentity::parse_from_bytes(container.details())
+        .map_err(|err| internal_error("failed to parse signed device identity", err))?;
+    let identity_key = public_key_bytes(&device.identity_key.public_key)?;
+    if !verify_account_signature(&identity, identity_key) {
+        return Err(pair_failure(
+            401,
+            "not-authorized",
+            "invalid device signature in pair success message",
+            None,
+        ));
+    };
+
+    let message = [
+        &ADV_DEVICE_SIGNATURE_PREFIX[..],
+        identity.details(),
+        identity_key,
+        identity.accountSignatureKey(),
+    ]
+    .concat();
+    let signature = device
+        .identity_key
+        .private_key
+        .calculate_signature(&message, &mut rand::rngs::OsRng)
+        .map_err(|err| {
+            new_rhustapp_error("failed to sign device identity", Some(err.to_string()))
+        })?;
+    identity.deviceSignature = Some(signature.to_vec());
+
+    let details = wa_proto::ADVDeviceIdentity::parse_from_bytes(identity.details())
+        .map_err(|err| internal_error("failed to parse device identity details", err))?;
+    identity.accountSignatureKey = None;
+    let self_signed = identity
+        .write_to_bytes()
+        .map_err(|err| internal_error("failed to marshal self-signed device identity", err))?;
+
+    let response = Node {
+        tag: "iq".to_string(),
+        attrs: pair_response_attrs(&info.request_id, "result"),
+        content: NodeContentType::ListOfNodes(vec![Node {
+            tag: "pair-device-sign".to_string(),
+            attrs: Attrs::new(),
+            content: NodeContentType::ListOfNodes(vec![Node {
+                tag: "device-identity".to_string(),
+                attrs: Attrs::from([(
+                    "key-index".to_string(),
+                    AttributeTypes::String(details.keyIndex().to_string()),
+                )]),
+                content: NodeContentType::ByteArray(self_signed),
+            }]),
+        }]),
+    };
+
+    Ok((identity, response))
+}
+
+/// Checks the signature that the phone made over the device identity with its account key.
+fn verify_account_signature(
+    identity: &wa_proto::ADVSignedDeviceIdentity,
+    identity_key: &[u8],
+) -> bool {
+    if identity.accountSignatureKey().len() != 32 || identity.accountSignature().len() != 64 {
+        return false;
+    };
+    let message = [
+        &ADV_ACCOUNT_SIGNATURE_PREFIX[..],
+        identity.details(),
+        identity_key,
+    ]
+    .concat();
+
+    PublicKey::from_djb_public_key_bytes(identity.accountSignatureKey())
+        .and_then(|key| key.verify_signature(&message, identity.accountSignature()))
+        .unwrap_or(false)
+}
+
+fn pair_response_attrs(request_id: &str, response_type: &str) -> Attrs {
+    Attrs::from([
+        (
+            "id".to_string(),
+            AttributeTypes::String(request_id.to_string()),
+        ),
+        (
+            "type".to_string(),
+            AttributeTypes::String(response_type.to_string()),
+        ),
+        ("to".to_string(), AttributeTypes::JID(SERVER_JID.clone())),
+    ])
+}
+
+/// Builds the `<iq type="error">` response that is sent when `finish_pairing` fails. The
+/// error code is taken from the error, falling back to 500 (internal-error).
+pub fn build_pair_error_node(request_id: &str, err: &RhustAppError) -> Node {
+    let (code, text) = match err.kind() {
+        ErrorKind::Iq(iq_error) => (iq_error.code, iq_error.text.clone()),
+        _ => (500, "internal-error".to_string()),
+    };
+
+    Node {
+        tag: "iq".to_string(),
+        attrs: pair_response_attrs(request_id, "error"),
+        content: NodeContentType::ListOfNodes(vec![Node {
+            tag: "error".to_string(),
+            attrs: Attrs::from([
+                ("code".to_string(), AttributeTypes::String(code.to_string())),
+                ("text".to_string(), AttributeTypes::String(text)),
+            ]),
+            content: NodeContentType::None,
+        }]),
+    }
+}
+
+/// Encodes the random bytes of a pairing code with `LINKING_CODE_ALPHABET`, 5 bits per
+/// character.
+fn encode_linking_code(bytes: &[u8; 5]) -> String {
+    let value = bytes
+        .iter()
+        .fold(0u64, |value, byte| (value << 8) | u64::from(*byte));
+    (0..8)
+        .rev()
+        .map(|i| LINKING_CODE_ALPHABET[((value >> (i * 5)) & 0x1F) as usize] as char)
+        .collect()
+}
+
+/// Encrypts (or decrypts, which is the same in CTR mode) an ephemeral key with the key
+/// derived from the pairing code.
+fn apply_linking_code_cipher(linking_code: &str, salt: &[u8], iv: &[u8], data: &mut [u8]) {
+    let mut key = [0u8; 32];
+    pbkdf2::<Hmac<Sha256>>(linking_code.as_bytes(), salt, LINKING_CODE_ROUNDS, &mut key);
+    Ctr128BE::<Aes256>::new(GenericArray::from_slice(&key), GenericArray::from_slice(iv))
+        .apply_keystream(data);
+}
+
+/// Wraps an ephemeral public key with the pairing code, so that only the other side of the
+/// pairing, which knows the code, can use it.
+fn wrap_ephemeral_key(linking_code: &str, key: &[u8]) -> Vec<u8> {
+    let mut salt = [0u8; 32];
+    let mut iv = [0u8; 16];
+    rand::thread_rng().fill_bytes(&mut salt);
+    rand::thread_rng().fill_bytes(&mut iv);
+
+    let mut encrypted = key.to_vec();
+    apply_linking_code_cipher(linking_code, &salt, &iv, &mut encrypted);
+    [&salt[..], &iv, &encrypted].concat()
+}
+
+/// Unwraps an ephemeral public key wrapped with `wrap_ephemeral_key`.
+fn unwrap_ephemeral_key(linking_code: &str, wrapped: &[u8]) -> Result<Vec<u8>, RhustAppError> {
+    if wrapped.len() != WRAPPED_EPHEMERAL_KEY_LENGTH {
+        return Err(new_rhustapp_error(
+            "failed to unwrap ephemeral key",
+            Some(format!(
+                "expected {WRAPPED_EPHEMERAL_KEY_LENGTH} bytes, got {}",
+                wrapped.len()
+            )),
+        ));
+    };
+
+    let mut key = wrapped[48..].to_vec();
+    apply_linking_code_cipher(linking_code, &wrapped[..32], &wrapped[32..48], &mut key);
+    Ok(key)
+}
+
+fn hkdf_sha256(key: &[u8], salt: Option<&[u8]>, info: &[u8]) -> [u8; 32] {
+    let mut output = [0u8; 32];
+    Hkdf::<Sha256>::new(salt, key)
+        .expand(info, &mut output)
+        .expect("32 bytes is a valid HKDF-SHA256 output length");
+    output
+}
+
+fn calculate_agreement(key_pair: &KeyPair, public_key: &[u8]) -> Result<Box<[u8]>, RhustAppError> {
+    PublicKey::from_djb_public_key_bytes(public_key)
+        .and_then(|public_key| key_pair.private_key.calculate_agreement(&public_key))
+        .map_err(|err| {
+            new_rhustapp_error("failed to calculate shared secret", Some(err.to_string()))
+        })
+}
+
+/// Returns the byte content of the child of the node with the given tag.
+fn child_bytes(node: &Node, tag: &str) -> Result<Vec<u8>, RhustAppError> {
+    match node
+        .get_optional_child_by_tag(&[tag])
+        .map(|child| child.content)
+    {
+        Some(NodeContentType::ByteArray(bytes)) => Ok(bytes),
+        Some(content) => Err(new_rhustapp_error(
+            &format!("unexpected {tag} content: {content:?}"),
+            None,
+        )),
+        None => Err(new_rhustapp_error(
+            &format!("didn't find <{tag}> in <{}>", node.tag),
+            None,
+        )),
+    }
+}
+
+fn bytes_node(tag: &str, bytes: Vec<u8>) -> Node {
+    Node {
+        tag: tag.to_string(),
+        attrs: Attrs::new(),
+        content: NodeContentType::ByteArray(bytes),
+    }
+}
+
+/// Builds the `<iq xmlns="md" type="set">` containing a `<link_code_companion_reg>` of the
+/// given stage. The `id` of the `<iq>` is assigned when the query is sent.
+fn build_link_code_companion_reg_node(
+    jid: &JID,
+    stage: &str,
+    mut attrs: Attrs,
+    children: Vec<Node>,
+) -> Node {
+    attrs.insert("jid".to_string(), AttributeTypes::JID(jid.clone()));
+    attrs.insert(
+        "stage".to_string(),
+        AttributeTypes::String(stage.to_string()),
+    );
+
+    Node {
+        tag: "iq".to_string(),
+        attrs: Attrs::from([
+            (
+                "xmlns".to_string(),
+                AttributeTypes::String("md".to_string()),
+            ),
+            (
+                "type".to_string(),
+                AttributeTypes::String("set".to_string()),
+            ),
+            ("to".to_string(), AttributeTypes::JID(SERVER_JID.clone())),
+        ]),
+        content: NodeContentType::ListOfNodes(vec![Node {
+            tag: "link_code_companion_reg".to_string(),
+            attrs,
+            content: NodeContentType::ListOfNodes(children),
+        }]),
+    }
+}
+
+/// The state of pairing with a phone number instead of a QR code: the user enters the
+/// pairing code on the phone, which then sends its own ephemeral key wrapped with the code
+/// in a `link_code_companion_reg` notification, see `companion_finish`. The pairing is then
+/// completed with `pair-success` like the QR code flow, using the adv secret derived here.
+pub struct PhoneLinking {
+    /// The JID of the phone number that is being linked.
+    pub jid: JID,
+    /// The ephemeral key pair of this device, whose public key is wrapped with the code.
+    pub key_pair: KeyPair,
+    /// The pairing code, without the dash.
+    pub linking_code: String,
+    /// The ref that the server assigned to the pairing, see `parse_pairing_ref`.
+    pub pairing_ref: Vec<u8>,
+}
+
+impl PhoneLinking {
+    /// Starts linking the given phone number, ignoring anything in it but digits: generates
+    /// the ephemeral key pair and the pairing code.
+    pub fn new(phone: &str) -> Self {
+        let phone = phone
+            .chars()
+            .filter(char::is_ascii_digit)
+            .collect::<String>();
+        let mut code = [0u8; 5];
+        rand::thread_rng().fill_bytes(&mut code);
+
+        Self {
+            jid: JID::new(&phone, DEFAULT_USER_SERVER),
+            key_pair: KeyPair::generate(&mut rand::rngs::OsRng),
+            linking_code: encode_linking_code(&code),
+            pairing_ref: Vec::new(),
+        }
+    }
+
+    /// Returns the pairing code to show to the user, formatted as `XXXX-XXXX`.
+    pub fn display_code(&self) -> String {
+        format!("{}-{}", &self.linking_code[..4], &self.linking_code[4..])
+    }
+
+    /// Builds the `companion_hello` query, which registers the wrapped ephemeral key and the
+    /// noise key of the device with the server. The response contains the pairing ref, see
+    /// `parse_pairing_ref`.
+    ///
+    /// If `show_push_notification` is true, the phone shows a notification prompting the
+    /// user to enter the code.
+    pub fn build_companion_hello_node(
+        &self,
+        device: &Device,
+        show_push_notification: bool,
+    ) -> Result<Node, RhustAppError> {
+        let ephemeral_key = public_key_bytes(&self.key_pair.public_key)?;
+        let string_node = |tag: &str, value: String| Node {
+            tag: tag.to_string(),
+            attrs: Attrs::new(),
+            content: NodeContentType::String(value),
+        };
+
+        Ok(build_link_code_companion_reg_node(
+            &self.jid,
+            "companion_hello",
+            Attrs::from([(
+                "should_show_push_notification".to_string(),
+                AttributeTypes::String(show_push_notification.to_string()),
+            )]),
+            vec![
+                bytes_node(
+                    "link_code_pairing_wrapped_companion_ephemeral_pub",
+                    wrap_ephemeral_key(&self.linking_code, ephemeral_key),
+                ),
+                bytes_node(
+                    "companion_server_auth_key_pub",
+                    public_key_bytes(&device.noise_key.public_key)?.to_vec(),
+                ),
+                string_node("companion_platform_id", PAIR_CLIENT_PLATFORM_ID.to_string()),
+                string_node(
+                    "companion_platform_display",
+                    PAIR_CLIENT_DISPLAY_NAME.to_string(),
+                ),
+                bytes_node("link_code_pairing_nonce", vec![0]),
+            ],
+        ))
+    }
+
+    /// Finishes the key exchange once the user has entered the code on the phone, given the
+    /// `link_code_companion_reg` notification sent by the phone.
+    ///
+    /// Returns the adv secret, which replaces the one of the device as it is used to verify
+    /// the following `pair-success`, and the `companion_finish` query that must be sent to
+    /// the server. It contains the identity keys of both devices and the randomness of the
+    /// adv secret, encrypted with the shared secret of the ephemeral keys.
+    pub fn companion_finish(
+        &self,
+        device: &Device,
+        notification: &Node,
+    ) -> Result<([u8; 32], Node), RhustAppError> {
+        let registration = notification
+            .get_optional_child_by_tag(&["link_code_companion_reg"])
+            .ok_or_else(|| {
+                new_rhustapp_error("didn't find <link_code_companion_reg> in node", None)
+            })?;
+        let pairing_ref = child_bytes(&registration, "link_code_pairing_ref")?;
+        if pairing_ref != self.pairing_ref {
+            return Err(new_rhustapp_error(
+                "pairing ref mismatch in code pairing notification",
+                None,
+            ));
+        };
+        let primary_ephemeral = unwrap_ephemeral_key(
+            &self.linking_code,
+            &child_bytes(
+                &registration,
+                "link_code_pairing_wrapped_primary_ephemeral_pub",
+            )?,
+        )?;
+        let primary_identity = child_bytes(&registration, "primary_identity_pub")?;
+
+        let ephemeral_secret = calculate_agreement(&self.key_pair, &primary_ephemeral)?;
+        let identity_secret = calculate_agreement(&device.identity_key, &primary_identity)?;
+        let mut adv_secret_random = [0u8; 32];
+        let mut key_bundle_salt = [0u8; 32];
+        let mut key_bundle_nonce = [0u8; 12];
+        rand::thread_rng().fill_bytes(&mut adv_secret_random);
+        rand::thread_rng().fill_bytes(&mut key_bundle_salt);
+        rand::thread_rng().fill_bytes(&mut key_bundle_nonce);
+
+        let identity_key = public_key_bytes(&device.identity_key.public_key)?;
+        let key_bundle_key = hkdf_sha256(
+            &ephemeral_secret,
+            Some(&key_bundle_salt),
+            b"link_code_pairing_key_bundle_encryption_key",
+        );
+        let key_bundle = [identity_key, &primary_identity, &adv_secret_random].concat();
+        let encrypted_key_bundle = Aes256Gcm::new(GenericArray::from_slice(&key_bundle_key))
+            .encrypt(GenericArray::from_slice(&key_bundle_nonce), &key_bundle[..])
+            .map_err(|err| {
+                new_rhustapp_error("failed to encrypt key bundle", Some(err.to_string()))
+            })?;
+
+        let adv_secret = hkdf_sha256(
+            &[&ephemeral_secret[..], &identity_secret, &adv_secret_random].concat(),
+            None,
+            b"adv_secret",
+        );
+
+        let finish = build_link_code_companion_reg_node(
+            &self.jid,
+            "companion_finish",
+            Attrs::new(),
+            vec![
+                bytes_node(
+                    "link_code_pairing_wrapped_key_bundle",
+                    [
+                        &key_bundle_salt[..],
+                        &key_bundle_nonce,
+                        &encrypted_key_bundle,
+                    ]
+                    .concat(),
+                ),
+                bytes_node("companion_identity_public", identity_key.to_vec()),
+                bytes_node("link_code_pairing_ref", pairing_ref),
+            ],
+        );
+        Ok((adv_secret, finish))
+    }
+}
+
+/// Parses the pairing ref from the response to the `companion_hello` query.
+pub fn parse_pairing_ref(response: &Node) -> Result<Vec<u8>, RhustAppError> {
+    let registration = response
+        .get_optional_child_by_tag(&["link_code_companion_reg"])
+        .ok_or_else(|| {
+            new_rhustapp_error(
+                "didn't find <link_code_companion_reg> in code link registration response",
+                None,
+            )
+        })?;
+    child_bytes(&registration, "link_code_pairing_ref")
+}
+
+/// Builds the `<iq xmlns="md" type="set">` stanza that logs out this client by removing
+/// `own_jid` from the companion devices of the user. The `id` of the `<iq>` is not set
+/// here, it is assigned when the query is sent.
+///
+/// The `LoggedOut` event is only emitted when the logout is initiated by the server or by
+/// another device, so it should NOT be emitted after sending this stanza.
+pub fn build_logout_node(own_jid: &JID) -> Node {
+    Node {
+        tag: "iq".to_string(),
+        attrs: Attrs::from([
+            (
+                "xmlns".to_string(),
+                AttributeTypes::String("md".to_string()),
+            ),
+            (
+                "type".to_string(),
+                AttributeTypes::String("set".to_string()),
+            ),
+            ("to".to_string(), AttributeTypes::JID(SERVER_JID.clone())),
+        ]),
+        content: NodeContentType::ListOfNodes(vec![Node {
+            tag: "remove-companion-device".to_string(),
+            attrs: Attrs::from([
+                ("jid".to_string(), AttributeTypes::JID(own_jid.clone())),
+                (
+                    "reason".to_string(),
+                    AttributeTypes::String("user_initiated".to_string()),
+                ),
+            ]),
+            content: NodeContentType::None,
+        }]),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::pair_success_node;
+
+    // Test case 2 from RFC 4231.
+    const ADV_SECRET: &[u8] = b"Jefe";
+    const DEVICE_DETAILS: &[u8] = b"what do ya want for nothing?";
+    const EXPECTED_MAC: &str = "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843";
+
+    #[test]
+    fn test_compute_adv_sign() {
+        let mac = compute_adv_sign(ADV_SECRET, DEVICE_DETAILS);
+        assert_eq!(hex::encode(mac), EXPECTED_MAC);
+    }
+
+    #[test]
+    fn test_verify_adv() {
+        let mac = hex::decode(EXPECTED_MAC).unwrap();
+        assert!(verify_adv(ADV_SECRET, DEVICE_DETAILS, &mac));
+        assert!(!verify_adv(ADV_SECRET, b"tampered details", &mac));
+        assert!(!verify_adv(b"wrong secret", DEVICE_DETAILS, &mac));
+    }
+
+    #[test]
+    fn test_build_logout_node() {
+        let own_jid = JID::new_ad("919876543210", 0, 12);
+        let node = build_logout_node(&own_jid);
+        assert_eq!(node.tag, "iq");
+        let mut ag = node.attr_getter();
+        assert_eq!(ag.string("xmlns").unwrap(), "md");
+        assert_eq!(ag.string("type").unwrap(), "set");
+        assert_eq!(ag.jid("to").unwrap(), *SERVER_JID);
+        assert!(ag.optional_string("id").is_none());
+
+        let children = node.get_children().unwrap();
+        assert_eq!(children.len(), 1);
+        let remove = &children[0];
+        assert_eq!(remove.tag, "remove-companion-device");
+        let mut ag = remove.attr_getter();
+        assert_eq!(ag.jid("jid").unwrap(), own_jid);
+        assert_eq!(ag.string("reason").unwrap(), "user_initiated");
+        assert!(matches!(remove.content, NodeContentType::None));
+    }
+
+    #[test]
+    fn test_parse_pair_device_refs() {
+        let qr_ref = |content: NodeContentType| Node {
+            tag: "ref".to_string(),
+            attrs: Attrs::new(),
+            content,
+        };
+        let pair_device = Node {
+            tag: "pair-device".to_string(),
+            attrs: Attrs::new(),
+            content: NodeContentType::ListOfNodes(vec![
+                qr_ref(NodeContentType::ByteArray(b"2@first".to_vec())),
+                qr_ref(NodeContentType::ByteArray(b"2@second".to_vec())),
+                qr_ref(NodeContentType::String("2@third".to_string())),
+            ]),
+        };
+        let expected = vec!["2@first", "2@second", "2@third"];
+        assert_eq!(parse_pair_device_refs(&pair_device).unwrap(), expected);
+
+        let iq = Node {
+            tag: "iq".to_string(),
+            attrs: Attrs::from([(
+                "type".to_string(),
+                AttributeTypes::String("set".to_string()),
+            )]),
+            content: NodeContentType::ListOfNodes(vec![pair_device]),
+        };
+        assert_eq!(parse_pair_device_refs(&iq).unwrap(), expected);
+
+        let empty_iq = Node {
+            tag: "iq".to_string(),
+            ..Default::default()
+        };
+        assert!(parse_pair_device_refs(&empty_iq).is_err());
+        let bad_ref = Node {
+            tag: "pair-device".to_string(),
+            attrs: Attrs::new(),
+            content: NodeContentType::ListOfNodes(vec![qr_ref(NodeContentType::ByteArray(vec![
+                0xFF,
+            ]))]),
+        };
+        assert!(parse_pair_device_refs(&bad_ref).is_err());
+    }
+
+    #[test]
+    fn test_make_qr_data() {
+        let device = Device::new();
+        let qr = make_qr_data(&device, "2@ref").unwrap();
+        let parts = qr.split(',').collect::<Vec<&str>>();
+        assert_eq!(parts.len(), 4);
+        assert_eq!(parts[0], "2@ref");
+        assert_eq!(
+            base64::decode(parts[1]).unwrap(),
+            public_key_bytes(&device.noise_key.public_key).unwrap()
+        );
+        assert_eq!(
+            base64::decode(parts[2]).unwrap(),
+            public_key_bytes(&device.identity_key.public_key).unwrap()
+        );
+        assert_eq!(base64::decode(parts[3]).unwrap(), device.adv_secret_key);
+    }
+
+    #[test]
+    fn test_finish_pairing() {
+        let device = Device::new();
+        let node = pair_success_node(&device, &device.adv_secret_key);
+        let info = PairSuccessInfo::from_node(&node).unwrap();
+        assert_eq!(info.request_id, "pair-1");
+        assert_eq!(info.jid, JID::new_ad("919876543210", 0, 12));
+        assert_eq!(info.platform, "android");
+        assert_eq!(info.business_name, "");
+
+        let (account, response) = finish_pairing(&device, &info).unwrap();
+        assert!(account.accountSignatureKey.is_none());
+        let mut ag = response.attr_getter();
+        assert_eq!(ag.string("id").unwrap(), "pair-1");
+        assert_eq!(ag.string("type").unwrap(), "result");
+
+        let identity = response
+            .get_optional_child_by_tag(&["pair-device-sign", "device-identity"])
+            .unwrap();
+        assert_eq!(identity.attr_getter().string("key-index").unwrap(), "3");
+        let signed = match identity.content {
+            NodeContentType::ByteArray(bytes) => {
+                wa_proto::ADVSignedDeviceIdentity::parse_from_bytes(&bytes).unwrap()
+            }
+            _ => panic!("device-identity content is not a byte array"),
+        };
+        assert_eq!(signed, account);
+        assert_eq!(signed.deviceSignature().len(), 64);
+    }
+
+    #[test]
+    fn test_finish_pairing_wrong_adv_secret() {
+        let device = Device::new();
+        let node = pair_success_node(&device, b"another secret");
+        let info = PairSuccessInfo::from_node(&node).unwrap();
+
+        let err = finish_pairing(&device, &info).unwrap_err();
+        let response = build_pair_error_node(&info.request_id, &err);
+        assert_eq!(response.attr_getter().string("type").unwrap(), "error");
+        let error = response.get_optional_child_by_tag(&["error"]).unwrap();
+        let mut ag = error.attr_getter();
+        assert_eq!(ag.string("code").unwrap(), "401");
+        assert_eq!(ag.string("text").unwrap(), "not-authorized");
+    }
+
+    #[test]
+    fn test_encode_linking_code() {
+        assert_eq!(encode_linking_code(&[0; 5]), "11111111");
+        assert_eq!(encode_linking_code(&[0, 0, 0, 0, 1]), "11111112");
+        assert_eq!(encode_linking_code(&[0xFF; 5]), "ZZZZZZZZ");
+        assert_eq!(
+            encode_linking_code(&[0x08, 0x42, 0x10, 0x84, 0x21]),
+            "22222222"
+        );
+    }
+
+    #[test]
+    fn test_code_pairing() {
+        let device = Device::new();
+        let mut linking = PhoneLinking::new("+91 98765-43210");
+        assert_eq!(linking.jid, JID::new("919876543210", DEFAULT_USER_SERVER));
+        let code = linking.display_code();
+        assert_eq!(code.len(), 9);
+        assert_eq!(code.replace('-', ""), linking.linking_code);
+        assert!(linking
+            .linking_code
+            .bytes()
+            .all(|c| LINKING_CODE_ALPHABET.contains(&c)));
+
+        let hello = linking.build_companion_hello_node(&device, true).unwrap();
+        let registration = hello
+            .get_optional_child_by_tag(&["link_code_companion_reg"])
+            .unwrap();
+        let mut ag = registration.attr_getter();
+        assert_eq!(ag.string("stage").unwrap(), "companion_hello");
+        assert_eq!(ag.string("should_show_push_notification").unwrap(), "true");
+        assert_eq!(
+            child_bytes(&registration, "companion_server_auth_key_pub").unwrap(),
+            public_key_bytes(&device.noise_key.public_key).unwrap()
+        );
+        // The phone unwraps the ephemeral key with the code that the user entered.
+        let companion_ephemeral = unwrap_ephemeral_key(
+            &linking.linking_code,
+            &child_bytes(
+                &registration,
+                "link_code_pairing_wrapped_companion_ephemeral_pub",
+            )
+            .unwrap(),
+        )
+        .unwrap();
+        assert_eq!(
+            companion_ephemeral,
+            public_key_bytes(&linking.key_pair.public_key).unwrap()
+        );
+
+        let response = build_link_code_companion_reg_node(
+            &linking.jid,
+            "companion_hello",
+            Attrs::new(),
+            vec![bytes_node("link_code_pairing_ref", b"pairing-ref".to_vec())],
+        );
+        linking.pairing_ref = parse_pairing_ref(&response).unwrap();
+        assert_eq!(linking.pairing_ref, b"pairing-ref");
+
+        let mut csprng = rand::rngs::OsRng;
+        let primary_ephemeral = KeyPair::generate(&mut csprng);
+        let primary_identity = KeyPair::generate(&mut csprng);
+        let notification = Node {
+            tag: "notification".to_string(),
+            attrs: Attrs::from([(
+                "type".to_string(),
+                AttributeTypes::String("link_code_companion_reg".to_string()),
+            )]),
+            content: NodeContentType::ListOfNodes(vec![Node {
+                tag: "link_code_companion_reg".to_string(),
+                attrs: Attrs::new(),
+                content: NodeContentType::ListOfNodes(vec![
+                    bytes_node("link_code_pairing_ref", b"pairing-ref".to_vec()),
+                    bytes_node(
+                        "link_code_pairing_wrapped_primary_ephemeral_pub",
+                        wrap_ephemeral_key(
+                            &linking.linking_code,
+                            public_key_bytes(&primary_ephemeral.public_key).unwrap(),
+                        ),
+                    ),
+                    bytes_node(
+                        "primary_identity_pub",
+                        public_key_bytes(&primary_identity.public_key)
+                            .unwrap()
+                            .to_vec(),
+                    ),
+                ]),
+            }]),
+        };
+        let (adv_secret, finish) = linking.companion_finish(&device, &notification).unwrap();
+
+        // The phone decrypts the key bundle and derives the same adv secret.
+        let registration = finish
+            .get_optional_child_by_tag(&["link_code_companion_reg"])
+            .unwrap();
+        assert_eq!(
+            registration.attr_getter().string("stage").unwrap(),
+            "companion_finish"
+        );
+        let wrapped = child_bytes(&registration, "link_code_pairing_wrapped_key_bundle").unwrap();
+        let ephemeral_secret =
+            calculate_agreement(&primary_ephemeral, &companion_ephemeral).unwrap();
+        let key = hkdf_sha256(
+            &ephemeral_secret,
+            Some(&wrapped[..32]),
+            b"link_code_pairing_key_bundle_encryption_key",
+        );
+        let key_bundle = Aes256Gcm::new(GenericArray::from_slice(&key))
+            .decrypt(GenericArray::from_slice(&wrapped[32..44]), &wrapped[44..])
+            .unwrap();
+        let identity_key = public_key_bytes(&device.identity_key.public_key).unwrap();
+        assert_eq!(&key_bundle[..32], identity_key);
+        assert_eq!(
+            &key_bundle[32..64],
+            public_key_bytes(&primary_identity.public_key).unwrap()
+        );
+        let identity_secret = calculate_agreement(&primary_identity, identity_key).unwrap();
+        let expected = hkdf_sha256(
+            &[&ephemeral_secret[..], &identity_secret, &key_bundle[64..]].concat(),
+            None,
+            b"adv_secret",
+        );
+        assert_eq!(adv_secret, expected);
+
+        // A notification for another pairing is rejected.
+        linking.pairing_ref = b"other-ref".to_vec();
+        assert!(linking.companion_finish(&device, &notification).is_err());
+    }
+}
@@ -0,0 +1,542 @@
+//! `prekeys` contains the builders for uploading Signal prekeys to the server, and for
+//! fetching the prekey bundles of other devices to start sessions with them.
+
+use libsignal_protocol::{IdentityKey, PreKeyBundle, PreKeyRecord, PublicKey, SignedPreKeyRecord};
+
+use crate::{
+    binary::{AttributeTypes, Attrs, Node, NodeContentType},
+    new_rhustapp_error,
+    request::iq_error_from_node,
+    types::{JID, SERVER_JID},
+    RhustAppError,
+};
+
+/// The type byte of Curve25519 (DJB) keys, sent in the `<type>` node.
+pub const DJB_TYPE: u8 = 5;
+
+/// Builds the `<iq xmlns="encrypt" type="set">` stanza that uploads the identity key, the
+/// signed prekey and a batch of one-time prekeys. The `id` of the `<iq>` is not set here,
+/// it is assigned when the query is sent.
+pub fn build_set_prekeys_node(
+    registration_id: u32,
+    identity_key: &[u8],
+    signed_prekey: &SignedPreKeyRecord,
+    prekeys: &[PreKeyRecord],
+) -> Result<Node, RhustAppError> {
+    if identity_key.is_empty() {
+        return Err(new_rhustapp_error(
+            "failed to build prekey upload",
+            Some("identity key is empty".to_string()),
+        ));
+    };
+    if prekeys.is_empty() {
+        return Err(new_rhustapp_error(
+            "failed to build prekey upload",
+            Some("no prekeys to upload".to_string()),
+        ));
+    };
+
+    let map_signal_err = |err: libsignal_protocol::SignalProtocolError| {
+        new_rhustapp_error("failed to read prekey", Some(err.to_string()))
+    };
+
+    let mut keys = Vec::with_capacity(prekeys.len());
+    for prekey in prekeys {
+        keys.push(prekey_node(
+            "key",
+            prekey.id().map_err(map_signal_err)?.into(),
+            &prekey.public_key().map_err(map_signal_err)?,
+            None,
+        )?);
+    }
+    let skey = prekey_node(
+        "skey",
+        signed_prekey.id().map_err(map_signal_err)?.into(),
+        &signed_prekey.public_key().map_err(map_signal_err)?,
+        Some(signed_prekey.signature().map_err(map_signal_err)?),
+    )?;
+
+    Ok(Node {
+        tag: "iq".to_string(),
+        attrs: Attrs::from([
+            (
+                "xmlns".to_string(),
+                AttributeTypes::String("encrypt".to_string()),
+            ),
+            (
+                "type".to_string(),
+                AttributeTypes::String("set".to_string()),
+            ),
+            ("to".to_string(), AttributeTypes::JID(SERVER_JID.clone())),
+        ]),
+        content: NodeContentType::ListOfNodes(vec![
+            bytes_node("registration", registration_id.to_be_bytes().to_vec()),
+            bytes_node("type", vec![DJB_TYPE]),
+            bytes_node("identity", identity_key.to_vec()),
+            Node {
+                tag: "list".to_string(),
+                attrs: Attrs::new(),
+                content: NodeContentType::ListOfNodes(keys),
+            },
+            skey,
+        ]),
+    })
+}
+
+/// Builds the `<iq xmlns="encrypt" type="get">` query that fetches the prekey bundles of the
+/// given devices. The `id` of the `<iq>` is not set here, it is assigned when the query is
+/// sent.
+pub fn build_get_prekeys_node(devices: &[JID]) -> Node {
+    let users = devices
+        .iter()
+        .map(|jid| Node {
+            tag: "user".to_string(),
+            attrs: Attrs::from([("jid".to_string(), AttributeTypes::JID(jid.clone()))]),
+            content: NodeContentType::None,
+        })
+        .collect();
+
+    Node {
+        tag: "iq".to_string(),
+        attrs: Attrs::from([
+            (
+                "xmlns".to_string(),
+                AttributeTypes::String("encrypt".to_string()),
+            ),
+            (
+                "type".to_string(),
+                AttributeTypes::String("get".to_string()),
+            ),
+            ("to".to_string(), AttributeTypes::JID(SERVER_JID.clone())),
+        ]),
+        content: NodeContentType::ListOfNodes(vec![Node {
+            tag: "key".to_string(),
+            attrs: Attrs::new(),
+            content: NodeContentType::ListOfNodes(users),
+        }]),
+    }
+}
+
+/// Builds the `<keys>` node sent in retry receipts, with a new one-time prekey and the
+/// signed prekey, so that the sender can start a new session to send the message again.
+/// The device identity is included if the device is paired.
+pub fn build_retry_keys_node(
+    identity_key: &[u8],
+    prekey: &PreKeyRecord,
+    signed_prekey: &SignedPreKeyRecord,
+    device_identity: Option<Node>,
+) -> Result<Node, RhustAppError> {
+    let map_signal_err = |err: libsignal_protocol::SignalProtocolError| {
+        new_rhustapp_error("failed to read prekey", Some(err.to_string()))
+    };
+
+    let mut children = vec![
+        bytes_node("type", vec![DJB_TYPE]),
+        bytes_node("identity", identity_key.to_vec()),
+        prekey_node(
+            "key",
+            prekey.id().map_err(map_signal_err)?.into(),
+            &prekey.public_key().map_err(map_signal_err)?,
+            None,
+        )?,
+        prekey_node(
+            "skey",
+            signed_prekey.id().map_err(map_signal_err)?.into(),
+            &signed_prekey.public_key().map_err(map_signal_err)?,
+            Some(signed_prekey.signature().map_err(map_signal_err)?),
+        )?,
+    ];
+    children.extend(device_identity);
+
+    Ok(Node {
+        tag: "keys".to_string(),
+        attrs: Attrs::new(),
+        content: NodeContentType::ListOfNodes(children),
+    })
+}
+
+/// Parses the prekey bundle that a device sent in the `<keys>` of a retry receipt, along
+/// with its `<registration>`.
+pub fn parse_retry_prekey_bundle(jid: &JID, receipt: &Node) -> Result<PreKeyBundle, RhustAppError> {
+    let keys = receipt
+        .get_optional_child_by_tag(&["keys"])
+        .ok_or_else(|| new_rhustapp_error("didn't find <keys> in retry receipt", None))?;
+    let registration = receipt
+        .get_optional_child_by_tag(&["registration"])
+        .ok_or_else(|| new_rhustapp_error("didn't find <registration> in retry receipt", None))?;
+
+    // The bundle is laid out like the <user> of a prekey response.
+    let mut children = keys.get_children().unwrap_or_default();
+    children.push(registration);
+    prekey_bundle_from_node(
+        jid,
+        &Node {
+            tag: "keys".to_string(),
+            attrs: Attrs::new(),
+            content: NodeContentType::ListOfNodes(children),
+        },
+    )
+}
+
+/// The prekey bundle of a device, or the error returned for it.
+pub type PreKeyBundleResult = (JID, Result<PreKeyBundle, RhustAppError>);
+
+/// Parses the response to `build_get_prekeys_node`. Each device gets its own result, as the
+/// server may return an `<error>` for some of them, e.g. when they have run out of prekeys.
+pub fn parse_prekey_bundles(response: &Node) -> Result<Vec<PreKeyBundleResult>, RhustAppError> {
+    let users = response
+        .get_optional_child_by_tag(&["list"])
+        .ok_or_else(|| new_rhustapp_error("didn't find <list> in prekey response", None))?
+        .get_children_by_tag("user")
+        .unwrap_or_default();
+
+    users
+        .iter()
+        .map(|user| {
+            let mut ag = user.attr_getter();
+            let jid = ag.jid("jid");
+            if let Some(err) = ag.error() {
+                return Err(err);
+            };
+            let jid = jid.unwrap();
+            let bundle = match iq_error_from_node(user) {
+                Some(err) => Err(err),
+                None => prekey_bundle_from_node(&jid, user),
+            };
+            Ok((jid, bundle))
+        })
+        .collect()
+}
+
+/// Parses the `<user>` node of a single device in a prekey response. The one-time prekey is
+/// optional, the signed prekey is not.
+fn prekey_bundle_from_node(jid: &JID, user: &Node) -> Result<PreKeyBundle, RhustAppError> {
+    let map_signal_err = |err: libsignal_protocol::SignalProtocolError| {
+        new_rhustapp_error("failed to parse prekey bundle", Some(err.to_string()))
+    };
+
+    let registration_id: [u8; 4] = child_bytes(user, "registration")?
+        .try_into()
+        .map_err(|_| new_rhustapp_error("invalid registration ID length", None))?;
+    let key_type = child_bytes(user, "type")?;
+    if key_type != [DJB_TYPE] {
+        return Err(new_rhustapp_error(
+            &format!("unexpected prekey type {key_type:?}"),
+            None,
+        ));
+    };
+    let identity_key = IdentityKey::new(
+        PublicKey::from_djb_public_key_bytes(&child_bytes(user, "identity")?)
+            .map_err(map_signal_err)?,
+    );
+
+    let pre_key = match user.get_optional_child_by_tag(&["key"]) {
+        Some(key) => {
+            let (id, public_key) = prekey_from_node(&key)?;
+            Some((id.into(), public_key))
+        }
+        None => None,
+    };
+    let skey = user
+        .get_optional_child_by_tag(&["skey"])
+        .ok_or_else(|| new_rhustapp_error("didn't find <skey> in prekey bundle", None))?;
+    let (signed_pre_key_id, signed_pre_key) = prekey_from_node(&skey)?;
+
+    PreKeyBundle::new(
+        u32::from_be_bytes(registration_id),
+        u32::from(jid.device.unwrap_or(0)).into(),
+        pre_key,
+        signed_pre_key_id.into(),
+        signed_pre_key,
+        child_bytes(&skey, "signature")?,
+        identity_key,
+    )
+    .map_err(map_signal_err)
+}
+
+/// Parses the ID and the public key of a `<key>` or `<skey>` node.
+fn prekey_from_node(node: &Node) -> Result<(u32, PublicKey), RhustAppError> {
+    let id = child_bytes(node, "id")?;
+    if id.len() != 3 {
+        return Err(new_rhustapp_error(
+            &format!("invalid prekey ID length {}", id.len()),
+            None,
+        ));
+    };
+    let public_key = PublicKey::from_djb_public_key_bytes(&child_bytes(node, "value")?)
+        .map_err(|err| new_rhustapp_error("failed to parse prekey", Some(err.to_string())))?;
+
+    Ok((u32::from_be_bytes([0, id[0], id[1], id[2]]), public_key))
+}
+
+/// Builds a `<key>` or `<skey>` node. The ID is sent as 3 big-endian bytes and the public
+/// key without the type prefix.
+fn prekey_node(
+    tag: &str,
+    id: u32,
+    public_key: &PublicKey,
+    signature: Option<Vec<u8>>,
+) -> Result<Node, RhustAppError> {
+    if id >= 1 << 24 {
+        return Err(new_rhustapp_error(
+            &format!("prekey ID {id} doesn't fit in 3 bytes"),
+            None,
+        ));
+    };
+    let public_key = public_key
+        .public_key_bytes()
+        .map_err(|err| new_rhustapp_error("failed to read public key", Some(err.to_string())))?;
+
+    let mut children = vec![
+        bytes_node("id", id.to_be_bytes()[1..].to_vec()),
+        bytes_node("value", public_key.to_vec()),
+    ];
+    if let Some(signature) = signature {
+        children.push(bytes_node("signature", signature));
+    };
+
+    Ok(Node {
+        tag: tag.to_string(),
+        attrs: Attrs::new(),
+        content: NodeContentType::ListOfNodes(children),
+    })
+}
+
+fn bytes_node(tag: &str, bytes: Vec<u8>) -> Node {
+    Node {
+        tag: tag.to_string(),
+        attrs: Attrs::new(),
+        content: NodeContentType::ByteArray(bytes),
+    }
+}
+
+fn child_bytes(node: &Node, tag: &str) -> Result<Vec<u8>, RhustAppError> {
+    match node
+        .get_optional_child_by_tag(&[tag])
+        .map(|child| child.content)
+    {
+        Some(NodeContentType::ByteArray(bytes)) => Ok(bytes),
+        Some(content) => Err(new_rhustapp_error(
+            &format!("unexpected {tag} content: {content:?}"),
+            None,
+        )),
+        None => Err(new_rhustapp_error(
+            &format!("didn't find <{tag}> in <{}>", node.tag),
+            None,
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use libsignal_protocol::KeyPair;
+
+    use crate::{
+        store::{public_key_bytes, Device},
+        testing::prekey_bundle_node,
+    };
+
+    use super::*;
+
+    fn content_bytes(node: &Node, tag: &str) -> Vec<u8> {
+        match node.get_optional_child_by_tag(&[tag]).unwrap().content {
+            NodeContentType::ByteArray(bytes) => bytes,
+            _ => panic!("<{tag}> content is not a byte array"),
+        }
+    }
+
+    #[test]
+    fn test_build_set_prekeys_node() {
+        let mut csprng = rand::rngs::OsRng;
+        let identity = KeyPair::generate(&mut csprng);
+        let signed_prekey =
+            SignedPreKeyRecord::new(1.into(), 0, &KeyPair::generate(&mut csprng), &[7; 64]);
+        let prekeys = (1..=3)
+            .map(|id| PreKeyRecord::new(id.into(), &KeyPair::generate(&mut csprng)))
+            .collect::<Vec<PreKeyRecord>>();
+        let identity_key = identity.public_key.public_key_bytes().unwrap();
+
+        let node =
+            build_set_prekeys_node(0x01020304, identity_key, &signed_prekey, &prekeys).unwrap();
+
+        assert_eq!(node.tag, "iq");
+        let mut ag = node.attr_getter();
+        assert_eq!(ag.string("xmlns").unwrap(), "encrypt");
+        assert_eq!(ag.string("type").unwrap(), "set");
+        assert_eq!(ag.jid("to").unwrap(), *SERVER_JID);
+
+        let tags = node
+            .get_children()
+            .unwrap()
+            .iter()
+            .map(|child| child.tag.to_string())
+            .collect::<Vec<String>>();
+        assert_eq!(
+            tags,
+            vec!["registration", "type", "identity", "list", "skey"]
+        );
+        assert_eq!(content_bytes(&node, "registration"), vec![1, 2, 3, 4]);
+        assert_eq!(content_bytes(&node, "type"), vec![DJB_TYPE]);
+        assert_eq!(content_bytes(&node, "identity"), identity_key.to_vec());
+
+        let keys = node
+            .get_optional_child_by_tag(&["list"])
+            .unwrap()
+            .get_children_by_tag("key")
+            .unwrap();
+        assert_eq!(keys.len(), 3);
+        for (i, key) in keys.iter().enumerate() {
+            assert_eq!(content_bytes(key, "id"), vec![0, 0, i as u8 + 1]);
+            assert_eq!(content_bytes(key, "value").len(), 32);
+            assert!(key.get_optional_child_by_tag(&["signature"]).is_none());
+        }
+
+        let skey = node.get_optional_child_by_tag(&["skey"]).unwrap();
+        assert_eq!(content_bytes(&skey, "id"), vec![0, 0, 1]);
+        assert_eq!(content_bytes(&skey, "signature"), vec![7; 64]);
+    }
+
+    #[test]
+    fn test_build_set_prekeys_node_empty() {
+        let mut csprng = rand::rngs::OsRng;
+        let signed_prekey =
+            SignedPreKeyRecord::new(1.into(), 0, &KeyPair::generate(&mut csprng), &[7; 64]);
+
+        assert!(build_set_prekeys_node(1, &[1; 32], &signed_prekey, &[]).is_err());
+        assert!(build_set_prekeys_node(
+            1,
+            &[],
+            &signed_prekey,
+            &[PreKeyRecord::new(1.into(), &KeyPair::generate(&mut csprng))]
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_build_get_prekeys_node() {
+        let jids = [
+            JID::from_str("1111.0:1@s.whatsapp.net").unwrap(),
+            JID::from_str("2222@s.whatsapp.net").unwrap(),
+        ];
+        let node = build_get_prekeys_node(&jids);
+
+        let mut ag = node.attr_getter();
+        assert_eq!(ag.string("xmlns").unwrap(), "encrypt");
+        assert_eq!(ag.string("type").unwrap(), "get");
+        let users = node
+            .get_optional_child_by_tag(&["key"])
+            .unwrap()
+            .get_children_by_tag("user")
+            .unwrap();
+        let users = users
+            .iter()
+            .map(|user| user.attr_getter().jid("jid").unwrap())
+            .collect::<Vec<JID>>();
+        assert_eq!(users, jids);
+    }
+
+    #[test]
+    fn test_parse_prekey_bundles() {
+        let device = Device::new();
+        let prekey = PreKeyRecord::new(0x010203.into(), &KeyPair::generate(&mut rand::rngs::OsRng));
+        let jid = JID::from_str("1111.0:3@s.whatsapp.net").unwrap();
+        let failed_jid = JID::from_str("2222@s.whatsapp.net").unwrap();
+        let response = Node {
+            tag: "iq".to_string(),
+            attrs: Attrs::new(),
+            content: NodeContentType::ListOfNodes(vec![Node {
+                tag: "list".to_string(),
+                attrs: Attrs::new(),
+                content: NodeContentType::ListOfNodes(vec![
+                    prekey_bundle_node(&jid, &device, &prekey),
+                    Node {
+                        tag: "user".to_string(),
+                        attrs: Attrs::from([(
+                            "jid".to_string(),
+                            AttributeTypes::JID(failed_jid.clone()),
+                        )]),
+                        content: NodeContentType::ListOfNodes(vec![Node {
+                            tag: "error".to_string(),
+                            attrs: Attrs::from([
+                                (
+                                    "code".to_string(),
+                                    AttributeTypes::String("406".to_string()),
+                                ),
+                                (
+                                    "text".to_string(),
+                                    AttributeTypes::String("not-acceptable".to_string()),
+                                ),
+                            ]),
+                            content: NodeContentType::None,
+                        }]),
+                    },
+                ]),
+            }]),
+        };
+
+        let mut bundles = parse_prekey_bundles(&response).unwrap();
+        assert_eq!(bundles.len(), 2);
+        let (failed, err) = bundles.pop().unwrap();
+        assert_eq!(failed, failed_jid);
+        match err.unwrap_err().kind() {
+            crate::ErrorKind::Iq(err) => assert_eq!(err.code, 406),
+            kind => panic!("unexpected error kind {kind:?}"),
+        };
+
+        let (parsed, bundle) = bundles.pop().unwrap();
+        assert_eq!(parsed, jid);
+        let bundle = bundle.unwrap();
+        assert_eq!(bundle.registration_id().unwrap(), device.registration_id);
+        assert_eq!(u32::from(bundle.device_id().unwrap()), 3);
+        assert_eq!(bundle.pre_key_id().unwrap(), Some(0x010203.into()));
+        assert_eq!(
+            bundle.pre_key_public().unwrap(),
+            Some(prekey.public_key().unwrap())
+        );
+        assert_eq!(
+            bundle.signed_pre_key_id().unwrap(),
+            device.signed_pre_key.id().unwrap()
+        );
+        assert_eq!(
+            public_key_bytes(bundle.identity_key().unwrap().public_key()).unwrap(),
+            public_key_bytes(&device.identity_key.public_key).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_retry_keys_round_trip() {
+        let device = Device::new();
+        let prekey = PreKeyRecord::new(42.into(), &KeyPair::generate(&mut rand::rngs::OsRng));
+        let identity_key = public_key_bytes(&device.identity_key.public_key).unwrap();
+        let keys =
+            build_retry_keys_node(identity_key, &prekey, &device.signed_pre_key, None).unwrap();
+        let tags = keys
+            .get_children()
+            .unwrap()
+            .iter()
+            .map(|child| child.tag.to_string())
+            .collect::<Vec<String>>();
+        assert_eq!(tags, vec!["type", "identity", "key", "skey"]);
+
+        let receipt = Node {
+            tag: "receipt".to_string(),
+            attrs: Attrs::new(),
+            content: NodeContentType::ListOfNodes(vec![
+                bytes_node(
+                    "registration",
+                    device.registration_id.to_be_bytes().to_vec(),
+                ),
+                keys,
+            ]),
+        };
+        let jid = JID::from_str("1111.0:2@s.whatsapp.net").unwrap();
+        let bundle = parse_retry_prekey_bundle(&jid, &receipt).unwrap();
+        assert_eq!(bundle.registration_id().unwrap(), device.registration_id);
+        assert_eq!(u32::from(bundle.device_id().unwrap()), 2);
+        assert_eq!(bundle.pre_key_id().unwrap(), Some(42.into()));
+
+        assert!(parse_retry_prekey_bundle(&jid, &Node::default()).is_err());
+    }
+}
@@ -0,0 +1,172 @@
+//! `presence` contains the builders for the presence stanzas, and the debouncing of the
+//! outgoing chat presence (typing indicator).
+
+use std::time::{Duration, Instant};
+
+use crate::{
+    binary::{AttributeTypes, Attrs, Node, NodeContentType},
+    types::{ChatPresence, ChatPresenceMedia, JID},
+};
+
+/// Builds the `<chatstate>` stanza that tells `chat` whether the user is typing
+/// (`Composing`) or stopped typing (`Paused`).
+pub fn build_chat_presence_node(
+    own_jid: &JID,
+    chat: &JID,
+    state: ChatPresence,
+    media: ChatPresenceMedia,
+) -> Node {
+    let mut child_attrs = Attrs::new();
+    if let (ChatPresence::Composing, ChatPresenceMedia::Audio) = (&state, &media) {
+        child_attrs.insert(
+            "media".to_string(),
+            AttributeTypes::String(media.to_string()),
+        );
+    };
+
+    Node {
+        tag: "chatstate".to_string(),
+        attrs: Attrs::from([
+            ("from".to_string(), AttributeTypes::JID(own_jid.to_non_ad())),
+            ("to".to_string(), AttributeTypes::JID(chat.clone())),
+        ]),
+        content: NodeContentType::ListOfNodes(vec![Node {
+            tag: state.to_string(),
+            attrs: child_attrs,
+            content: NodeContentType::None,
+        }]),
+    }
+}
+
+/// Rate-limits the outgoing `composing` chat presence of a single chat, so that typing
+/// doesn't send a stanza for every keystroke.
+///
+/// At most one `composing` node is produced per `interval`. Once the user hasn't typed for
+/// `interval`, `poll` produces a trailing `paused` node.
+pub struct ChatPresenceDebouncer {
+    own_jid: JID,
+    chat: JID,
+    interval: Duration,
+    /// When the last `composing` node was produced, `None` if the user is not typing.
+    last_composing: Option<Instant>,
+    /// When the user last typed.
+    last_typing: Option<Instant>,
+}
+
+impl ChatPresenceDebouncer {
+    pub fn new(own_jid: &JID, chat: &JID, interval: Duration) -> Self {
+        Self {
+            own_jid: own_jid.clone(),
+            chat: chat.clone(),
+            interval,
+            last_composing: None,
+            last_typing: None,
+        }
+    }
+
+    /// Must be called whenever the user types. Returns the `composing` node if one should be
+    /// sent now.
+    pub fn on_typing(&mut self, now: Instant) -> Option<Node> {
+        self.last_typing = Some(now);
+        match self.last_composing {
+            Some(last) if now.saturating_duration_since(last) < self.interval => None,
+            _ => {
+                self.last_composing = Some(now);
+                Some(self.node(ChatPresence::Composing))
+            }
+        }
+    }
+
+    /// Must be called periodically. Returns the trailing `paused` node if the user was
+    /// typing, but hasn't typed for `interval`.
+    pub fn poll(&mut self, now: Instant) -> Option<Node> {
+        self.last_composing?;
+        match self.last_typing {
+            Some(last) if now.saturating_duration_since(last) < self.interval => None,
+            _ => {
+                self.last_composing = None;
+                self.last_typing = None;
+                Some(self.node(ChatPresence::Paused))
+            }
+        }
+    }
+
+    /// Returns when `poll` should be called next, if a trailing `paused` is pending.
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.last_composing?;
+        self.last_typing.map(|last| last + self.interval)
+    }
+
+    fn node(&self, state: ChatPresence) -> Node {
+        build_chat_presence_node(&self.own_jid, &self.chat, state, ChatPresenceMedia::Text)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use super::*;
+
+    fn debouncer() -> ChatPresenceDebouncer {
+        ChatPresenceDebouncer::new(
+            &JID::from_str("911234567890@s.whatsapp.net").unwrap(),
+            &JID::from_str("919876543210@s.whatsapp.net").unwrap(),
+            Duration::from_secs(3),
+        )
+    }
+
+    fn state(node: &Node) -> String {
+        node.get_children().unwrap()[0].tag.to_string()
+    }
+
+    #[test]
+    fn test_rapid_typing() {
+        let mut debouncer = debouncer();
+        let start = Instant::now();
+
+        // A keystroke every 100ms for 10 seconds.
+        let mut composing = Vec::new();
+        for i in 0..100 {
+            let now = start + Duration::from_millis(i * 100);
+            if let Some(node) = debouncer.on_typing(now) {
+                assert_eq!(state(&node), "composing");
+                composing.push(now);
+            };
+            assert!(debouncer.poll(now).is_none());
+        }
+
+        assert_eq!(composing.len(), 4);
+        for pair in composing.windows(2) {
+            assert!(pair[1] - pair[0] >= Duration::from_secs(3));
+        }
+    }
+
+    #[test]
+    fn test_trailing_paused() {
+        let mut debouncer = debouncer();
+        let start = Instant::now();
+
+        let node = debouncer.on_typing(start).unwrap();
+        assert_eq!(node.tag, "chatstate");
+        assert_eq!(
+            node.attr_getter().jid("to").unwrap().to_string(),
+            "919876543210@s.whatsapp.net"
+        );
+        assert!(debouncer
+            .on_typing(start + Duration::from_secs(1))
+            .is_none());
+
+        assert_eq!(
+            debouncer.next_deadline(),
+            Some(start + Duration::from_secs(4))
+        );
+        assert!(debouncer.poll(start + Duration::from_secs(3)).is_none());
+        let paused = debouncer.poll(start + Duration::from_secs(4)).unwrap();
+        assert_eq!(state(&paused), "paused");
+
+        // Only a single paused node is sent.
+        assert!(debouncer.poll(start + Duration::from_secs(10)).is_none());
+        assert!(debouncer.next_deadline().is_none());
+    }
+}
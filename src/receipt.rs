@@ -0,0 +1,584 @@
+//! `receipt` contains the builders for the receipts that are sent for incoming messages, the
+//! parsing of the retry receipts received for sent ones, and the acks that tell the server
+//! that a receipt was received. The other receipts are parsed into `events::Receipt`.
+
+use time::OffsetDateTime;
+
+use crate::{
+    binary::{AttributeTypes, Attrs, Node, NodeContentType},
+    new_rhustapp_error,
+    types::{
+        events::ReceiptType, AddressingMode, MessageID, MessageSource, BROADCAST_SERVER,
+        GROUP_SERVER, JID,
+    },
+    RhustAppError,
+};
+
+/// How many times a message is asked for again with a retry receipt, and sent again in
+/// response to them, before giving up.
+pub const MAX_RETRY_COUNT: u32 = 5;
+
+/// Builds the `<receipt>` stanza of the given type for the given messages, which must all be
+/// from the same source.
+///
+/// The receipt is addressed to `MessageSource::reply_recipient`, so receipts for incoming
+/// broadcast list messages go back to the owner of the list. In groups (and status
+/// broadcasts), the sender is put in the `participant` attribute.
+pub fn build_receipt_node(
+    source: &MessageSource,
+    message_ids: &[String],
+    receipt_type: ReceiptType,
+) -> Result<Node, RhustAppError> {
+    if message_ids.is_empty() {
+        return Err(new_rhustapp_error(
+            "failed to build receipt",
+            Some("no message ids given".to_string()),
+        ));
+    };
+
+    let mut attrs = Attrs::from([
+        (
+            "id".to_string(),
+            AttributeTypes::String(message_ids[0].to_string()),
+        ),
+        (
+            "to".to_string(),
+            AttributeTypes::JID(source.reply_recipient()),
+        ),
+    ]);
+    let receipt_type = receipt_type.to_string();
+    if !receipt_type.is_empty() {
+        attrs.insert("type".to_string(), AttributeTypes::String(receipt_type));
+    };
+    if source.is_group && !source.is_incoming_broadcast() {
+        attrs.insert(
+            "participant".to_string(),
+            AttributeTypes::JID(source.sender.to_non_ad()),
+        );
+    };
+
+    let content = if message_ids.len() > 1 {
+        NodeContentType::ListOfNodes(vec![Node {
+            tag: "list".to_string(),
+            attrs: Attrs::new(),
+            content: NodeContentType::ListOfNodes(
+                message_ids[1..]
+                    .iter()
+                    .map(|id| Node {
+                        tag: "item".to_string(),
+                        attrs: Attrs::from([(
+                            "id".to_string(),
+                            AttributeTypes::String(id.to_string()),
+                        )]),
+                        content: NodeContentType::None,
+                    })
+                    .collect(),
+            ),
+        }])
+    } else {
+        NodeContentType::None
+    };
+
+    Ok(Node {
+        tag: "receipt".to_string(),
+        attrs,
+        content,
+    })
+}
+
+/// Builds the `<receipt>` stanza that tells the sender of an incoming message that it was
+/// delivered. Messages sent by the current user from another device are acknowledged to
+/// that device with a `sender` receipt instead.
+pub fn build_delivery_receipt_node(
+    source: &MessageSource,
+    message_id: &str,
+) -> Result<Node, RhustAppError> {
+    let ids = [message_id.to_string()];
+    if !source.is_from_me {
+        return build_receipt_node(source, &ids, ReceiptType::Delivered);
+    };
+
+    let mut node = build_receipt_node(source, &ids, ReceiptType::Sender)?;
+    if !source.is_group {
+        node.attrs
+            .insert("to".to_string(), AttributeTypes::JID(source.sender.clone()));
+        node.attrs.insert(
+            "recipient".to_string(),
+            AttributeTypes::JID(source.chat.clone()),
+        );
+    };
+    Ok(node)
+}
+
+/// Builds the `<receipt type="read">` stanza that marks the given messages as read. They
+/// must all be in `chat` and sent by `sender`, which is only needed in groups and broadcast
+/// lists. Read receipts for broadcast list messages are addressed to `sender`, the owner of
+/// the list, like in `MessageSource::reply_recipient`.
+pub fn build_read_receipt_node(
+    message_ids: &[MessageID],
+    chat: &JID,
+    sender: &JID,
+    timestamp: OffsetDateTime,
+) -> Result<Node, RhustAppError> {
+    match chat.is_broadcast_list() {
+        true => sender.is_sendable()?,
+        false => chat.is_sendable()?,
+    };
+    let source = MessageSource {
+        chat: chat.to_non_ad(),
+        sender: sender.clone(),
+        is_from_me: false,
+        is_group: chat.server.eq(GROUP_SERVER) || chat.server.eq(BROADCAST_SERVER),
+        broadcast_list_owner: None,
+        recipient: None,
+        addressing_mode: AddressingMode::Pn,
+        sender_alt: None,
+    };
+
+    let mut node = build_receipt_node(&source, message_ids, ReceiptType::Read)?;
+    node.attrs.insert(
+        "t".to_string(),
+        AttributeTypes::String(timestamp.unix_timestamp().to_string()),
+    );
+    Ok(node)
+}
+
+/// Builds the `<receipt type="read">` stanza that marks a whole chat as read, up to and
+/// including the message with `last_message_id`.
+///
+/// In groups (and status broadcasts), `sender` is the sender of that message and is
+/// required, in direct chats it is ignored.
+pub fn build_mark_chat_read_node(
+    chat: &JID,
+    last_message_id: &str,
+    sender: Option<&JID>,
+) -> Result<Node, RhustAppError> {
+    chat.is_sendable()?;
+    if last_message_id.is_empty() {
+        return Err(new_rhustapp_error(
+            "failed to build chat read receipt",
+            Some("last message id is empty".to_string()),
+        ));
+    };
+
+    let mut attrs = Attrs::from([
+        (
+            "id".to_string(),
+            AttributeTypes::String(last_message_id.to_string()),
+        ),
+        ("to".to_string(), AttributeTypes::JID(chat.to_non_ad())),
+        (
+            "type".to_string(),
+            AttributeTypes::String(ReceiptType::Read.to_string()),
+        ),
+    ]);
+    if chat.server.eq(GROUP_SERVER) || chat.server.eq(BROADCAST_SERVER) {
+        let sender = sender.ok_or_else(|| {
+            new_rhustapp_error(
+                "failed to build chat read receipt",
+                Some(format!("sender of the last message in {chat} is required")),
+            )
+        })?;
+        attrs.insert(
+            "participant".to_string(),
+            AttributeTypes::JID(sender.to_non_ad()),
+        );
+    };
+
+    Ok(Node {
+        tag: "receipt".to_string(),
+        attrs,
+        content: NodeContentType::None,
+    })
+}
+
+/// Builds the `<ack>` that tells the server that a `<receipt>` was received, so that it isn't
+/// delivered again on the next connection.
+pub fn build_receipt_ack_node(receipt: &Node) -> Result<Node, RhustAppError> {
+    let mut ag = receipt.attr_getter();
+    let id = ag.string("id");
+    let from = ag.jid("from");
+    let receipt_type = ag.optional_string("type");
+    let participant = ag.optional_jid("participant");
+    let recipient = ag.optional_jid("recipient");
+    if let Some(err) = ag.error() {
+        return Err(new_rhustapp_error(
+            "failed to build receipt ack",
+            Some(err.to_string()),
+        ));
+    };
+
+    let mut attrs = Attrs::from([
+        (
+            "class".to_string(),
+            AttributeTypes::String("receipt".to_string()),
+        ),
+        ("id".to_string(), AttributeTypes::String(id.unwrap())),
+        ("to".to_string(), AttributeTypes::JID(from.unwrap())),
+    ]);
+    if let Some(receipt_type) = receipt_type {
+        attrs.insert("type".to_string(), AttributeTypes::String(receipt_type));
+    };
+    if let Some(participant) = participant {
+        attrs.insert("participant".to_string(), AttributeTypes::JID(participant));
+    };
+    if let Some(recipient) = recipient {
+        attrs.insert("recipient".to_string(), AttributeTypes::JID(recipient));
+    };
+
+    Ok(Node {
+        tag: "ack".to_string(),
+        attrs,
+        content: NodeContentType::None,
+    })
+}
+
+/// Builds the `<receipt type="retry">` stanza that asks the sender of a `<message>` that
+/// couldn't be decrypted to send it again. `registration_id` is the registration ID of our
+/// device, with which the sender can tell if it has to start a new session, and `keys` the
+/// node built with `build_retry_keys_node` that it can start the session from.
+pub fn build_retry_receipt_node(
+    message: &Node,
+    retry_count: u32,
+    registration_id: u32,
+    keys: Option<Node>,
+) -> Result<Node, RhustAppError> {
+    let mut ag = message.attr_getter();
+    let id = ag.string("id");
+    let from = ag.jid("from");
+    let timestamp = ag.string("t");
+    let participant = ag.optional_jid("participant");
+    let recipient = ag.optional_jid("recipient");
+    if let Some(err) = ag.error() {
+        return Err(new_rhustapp_error(
+            "failed to build retry receipt",
+            Some(err.to_string()),
+        ));
+    };
+    let id = id.unwrap();
+
+    let mut attrs = Attrs::from([
+        ("id".to_string(), AttributeTypes::String(id.clone())),
+        ("to".to_string(), AttributeTypes::JID(from.unwrap())),
+        (
+            "type".to_string(),
+            AttributeTypes::String(ReceiptType::Retry.to_string()),
+        ),
+    ]);
+    if let Some(participant) = participant {
+        attrs.insert("participant".to_string(), AttributeTypes::JID(participant));
+    };
+    if let Some(recipient) = recipient {
+        attrs.insert("recipient".to_string(), AttributeTypes::JID(recipient));
+    };
+
+    let mut children = vec![
+        Node {
+            tag: "retry".to_string(),
+            attrs: Attrs::from([
+                (
+                    "count".to_string(),
+                    AttributeTypes::String(retry_count.to_string()),
+                ),
+                ("id".to_string(), AttributeTypes::String(id)),
+                ("t".to_string(), AttributeTypes::String(timestamp.unwrap())),
+                ("v".to_string(), AttributeTypes::String("1".to_string())),
+            ]),
+            content: NodeContentType::None,
+        },
+        Node {
+            tag: "registration".to_string(),
+            attrs: Attrs::new(),
+            content: NodeContentType::ByteArray(registration_id.to_be_bytes().to_vec()),
+        },
+    ];
+    children.extend(keys);
+
+    Ok(Node {
+        tag: "receipt".to_string(),
+        attrs,
+        content: NodeContentType::ListOfNodes(children),
+    })
+}
+
+/// Parses the ID of the message that a `<receipt type="retry">` asks to send again, and how
+/// many times the receiver has asked for it.
+pub fn parse_retry_receipt(receipt: &Node) -> Result<(String, u32), RhustAppError> {
+    let retry = receipt
+        .get_optional_child_by_tag(&["retry"])
+        .ok_or_else(|| new_rhustapp_error("didn't find <retry> in retry receipt", None))?;
+    let id = receipt.attr_getter().string("id");
+    let mut ag = retry.attr_getter();
+    let count = ag.u64("count");
+    if let Some(err) = ag.error() {
+        return Err(err);
+    };
+    let id = id.ok_or_else(|| new_rhustapp_error("missing id of retry receipt", None))?;
+    let count = u32::try_from(count.unwrap())
+        .map_err(|_| new_rhustapp_error("invalid retry count", None))?;
+    Ok((id, count))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use crate::types::AddressingMode;
+
+    use super::*;
+
+    #[test]
+    fn test_build_receipt_node_incoming_broadcast() {
+        let owner = JID::from_str("919876543210@s.whatsapp.net").unwrap();
+        let source = MessageSource {
+            chat: JID::from_str("1678000000@broadcast").unwrap(),
+            sender: owner.clone(),
+            is_from_me: false,
+            is_group: true,
+            broadcast_list_owner: None,
+            recipient: None,
+            addressing_mode: AddressingMode::Pn,
+            sender_alt: None,
+        };
+
+        let node =
+            build_receipt_node(&source, &["3EB0ABCDEF".to_string()], ReceiptType::Read).unwrap();
+        assert_eq!(node.tag, "receipt");
+        let mut ag = node.attr_getter();
+        assert_eq!(ag.jid("to").unwrap(), owner);
+        assert_eq!(ag.string("type").unwrap(), "read");
+        assert!(ag.optional_jid("participant").is_none());
+    }
+
+    #[test]
+    fn test_build_receipt_node_group_list() {
+        let group = JID::from_str("120363000000000000@g.us").unwrap();
+        let source = MessageSource {
+            chat: group.clone(),
+            sender: JID::new_ad("919876543210", 0, 2),
+            is_from_me: false,
+            is_group: true,
+            broadcast_list_owner: None,
+            recipient: None,
+            addressing_mode: AddressingMode::Pn,
+            sender_alt: None,
+        };
+
+        let node = build_receipt_node(
+            &source,
+            &["ID1".to_string(), "ID2".to_string(), "ID3".to_string()],
+            ReceiptType::Delivered,
+        )
+        .unwrap();
+        let mut ag = node.attr_getter();
+        assert_eq!(ag.jid("to").unwrap(), group);
+        assert_eq!(
+            ag.jid("participant").unwrap().to_string(),
+            "919876543210@s.whatsapp.net"
+        );
+        assert!(ag.optional_string("type").is_none());
+        assert_eq!(ag.string("id").unwrap(), "ID1");
+
+        let items = node
+            .get_optional_child_by_tag(&["list"])
+            .unwrap()
+            .get_children_by_tag("item")
+            .unwrap()
+            .iter()
+            .map(|item| item.attr_getter().string("id").unwrap())
+            .collect::<Vec<String>>();
+        assert_eq!(items, vec!["ID2", "ID3"]);
+    }
+
+    #[test]
+    fn test_build_delivery_receipt_node() {
+        let chat = JID::from_str("911234567890@s.whatsapp.net").unwrap();
+        let own_device = JID::new_ad("919876543210", 0, 0);
+        let mut source = MessageSource {
+            chat: chat.clone(),
+            sender: JID::new_ad("911234567890", 0, 0),
+            is_from_me: false,
+            is_group: false,
+            broadcast_list_owner: None,
+            recipient: None,
+            addressing_mode: AddressingMode::Pn,
+            sender_alt: None,
+        };
+
+        let node = build_delivery_receipt_node(&source, "3EB0ABCDEF").unwrap();
+        let mut ag = node.attr_getter();
+        assert_eq!(ag.string("id").unwrap(), "3EB0ABCDEF");
+        assert_eq!(ag.jid("to").unwrap(), chat);
+        assert!(ag.optional_string("type").is_none());
+
+        // Sent from our phone, so the receipt goes back to it.
+        source.is_from_me = true;
+        source.sender = own_device.clone();
+        let node = build_delivery_receipt_node(&source, "3EB0ABCDEF").unwrap();
+        let mut ag = node.attr_getter();
+        assert_eq!(ag.jid("to").unwrap(), own_device);
+        assert_eq!(ag.jid("recipient").unwrap(), chat);
+        assert_eq!(ag.string("type").unwrap(), "sender");
+    }
+
+    #[test]
+    fn test_build_read_receipt_node() {
+        let timestamp = OffsetDateTime::from_unix_timestamp(1700000000).unwrap();
+        let group = JID::from_str("120363000000000000@g.us").unwrap();
+        let sender = JID::new_ad("919876543210", 0, 2);
+        let ids = ["ID1".to_string(), "ID2".to_string()];
+        let node = build_read_receipt_node(&ids, &group, &sender, timestamp).unwrap();
+        let mut ag = node.attr_getter();
+        assert_eq!(ag.jid("to").unwrap(), group);
+        assert_eq!(ag.string("type").unwrap(), "read");
+        assert_eq!(ag.string("t").unwrap(), "1700000000");
+        assert_eq!(
+            ag.jid("participant").unwrap().to_string(),
+            "919876543210@s.whatsapp.net"
+        );
+        assert!(node.get_optional_child_by_tag(&["list"]).is_some());
+
+        // Broadcast list messages are read receipted to the owner of the list.
+        let broadcast = JID::from_str("1678000000@broadcast").unwrap();
+        let node = build_read_receipt_node(&ids[..1], &broadcast, &sender, timestamp).unwrap();
+        let mut ag = node.attr_getter();
+        assert_eq!(
+            ag.jid("to").unwrap().to_string(),
+            "919876543210@s.whatsapp.net"
+        );
+        assert!(ag.optional_jid("participant").is_none());
+
+        let dm = JID::from_str("919876543210@s.whatsapp.net").unwrap();
+        let node = build_read_receipt_node(&ids[..1], &dm, &sender, timestamp).unwrap();
+        assert!(node.attr_getter().optional_jid("participant").is_none());
+
+        assert!(build_read_receipt_node(&[], &dm, &sender, timestamp).is_err());
+    }
+
+    #[test]
+    fn test_build_mark_chat_read_node_dm() {
+        let chat = JID::from_str("919876543210@s.whatsapp.net").unwrap();
+        let node = build_mark_chat_read_node(&chat, "3EB0ABCDEF", Some(&chat)).unwrap();
+        assert_eq!(node.tag, "receipt");
+        let mut ag = node.attr_getter();
+        assert_eq!(ag.string("id").unwrap(), "3EB0ABCDEF");
+        assert_eq!(ag.jid("to").unwrap(), chat);
+        assert_eq!(ag.string("type").unwrap(), "read");
+        assert!(ag.optional_jid("participant").is_none());
+        assert!(matches!(node.content, NodeContentType::None));
+
+        assert!(build_mark_chat_read_node(&chat, "", None).is_err());
+        let server_only = JID::from_str("s.whatsapp.net").unwrap();
+        assert!(build_mark_chat_read_node(&server_only, "3EB0ABCDEF", None).is_err());
+    }
+
+    #[test]
+    fn test_build_mark_chat_read_node_group() {
+        let group = JID::from_str("120363000000000000@g.us").unwrap();
+        let sender = JID::new_ad("919876543210", 0, 2);
+        let node = build_mark_chat_read_node(&group, "3EB0ABCDEF", Some(&sender)).unwrap();
+        let mut ag = node.attr_getter();
+        assert_eq!(ag.jid("to").unwrap(), group);
+        assert_eq!(ag.string("type").unwrap(), "read");
+        assert_eq!(
+            ag.jid("participant").unwrap().to_string(),
+            "919876543210@s.whatsapp.net"
+        );
+
+        assert!(build_mark_chat_read_node(&group, "3EB0ABCDEF", None).is_err());
+    }
+
+    #[test]
+    fn test_build_receipt_ack_node() {
+        let group = JID::from_str("120363000000000000@g.us").unwrap();
+        let reader = JID::new_ad("919876543210", 0, 2);
+        let receipt = Node {
+            tag: "receipt".to_string(),
+            attrs: Attrs::from([
+                ("id".to_string(), AttributeTypes::String("ID1".to_string())),
+                ("from".to_string(), AttributeTypes::JID(group.clone())),
+                (
+                    "participant".to_string(),
+                    AttributeTypes::JID(reader.clone()),
+                ),
+                (
+                    "type".to_string(),
+                    AttributeTypes::String("read".to_string()),
+                ),
+                (
+                    "t".to_string(),
+                    AttributeTypes::String("1700000000".to_string()),
+                ),
+            ]),
+            content: NodeContentType::None,
+        };
+
+        let ack = build_receipt_ack_node(&receipt).unwrap();
+        assert_eq!(ack.tag, "ack");
+        let mut ag = ack.attr_getter();
+        assert_eq!(ag.string("class").unwrap(), "receipt");
+        assert_eq!(ag.string("id").unwrap(), "ID1");
+        assert_eq!(ag.jid("to").unwrap(), group);
+        assert_eq!(ag.jid("participant").unwrap(), reader);
+        assert_eq!(ag.string("type").unwrap(), "read");
+        assert!(ag.optional_string("t").is_none());
+
+        assert!(build_receipt_ack_node(&Node::default()).is_err());
+    }
+
+    #[test]
+    fn test_build_retry_receipt_node() {
+        let group = JID::from_str("120363000000000000@g.us").unwrap();
+        let sender = JID::new_ad("919876543210", 0, 2);
+        let message = Node {
+            tag: "message".to_string(),
+            attrs: Attrs::from([
+                (
+                    "id".to_string(),
+                    AttributeTypes::String("3EB0ABCDEF".to_string()),
+                ),
+                ("from".to_string(), AttributeTypes::JID(group.clone())),
+                (
+                    "participant".to_string(),
+                    AttributeTypes::JID(sender.clone()),
+                ),
+                (
+                    "t".to_string(),
+                    AttributeTypes::String("1700000000".to_string()),
+                ),
+            ]),
+            content: NodeContentType::None,
+        };
+
+        let keys = Node {
+            tag: "keys".to_string(),
+            ..Default::default()
+        };
+        let node = build_retry_receipt_node(&message, 2, 0x01020304, Some(keys)).unwrap();
+        let mut ag = node.attr_getter();
+        assert_eq!(ag.string("id").unwrap(), "3EB0ABCDEF");
+        assert_eq!(ag.jid("to").unwrap(), group);
+        assert_eq!(ag.jid("participant").unwrap(), sender);
+        assert_eq!(ag.string("type").unwrap(), "retry");
+
+        let retry = node.get_optional_child_by_tag(&["retry"]).unwrap();
+        let mut ag = retry.attr_getter();
+        assert_eq!(ag.string("count").unwrap(), "2");
+        assert_eq!(ag.string("id").unwrap(), "3EB0ABCDEF");
+        assert_eq!(ag.string("t").unwrap(), "1700000000");
+        let registration = node.get_optional_child_by_tag(&["registration"]).unwrap();
+        assert!(matches!(
+            registration.content,
+            NodeContentType::ByteArray(bytes) if bytes == [1, 2, 3, 4]
+        ));
+
+        assert!(node.get_optional_child_by_tag(&["keys"]).is_some());
+        assert_eq!(
+            parse_retry_receipt(&node).unwrap(),
+            ("3EB0ABCDEF".to_string(), 2)
+        );
+
+        assert!(build_retry_receipt_node(&Node::default(), 1, 1, None).is_err());
+        assert!(parse_retry_receipt(&Node::default()).is_err());
+    }
+}
@@ -0,0 +1,115 @@
+//! `reconnect` contains the delay schedule used for reconnecting after the connection is
+//! lost, which is set with `Client::with_reconnect_config`.
+
+use std::time::Duration;
+
+use rand::Rng;
+
+/// How much randomness is added to the reconnect delays, so that many clients that lost
+/// their connection at the same time don't all reconnect at the same time.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Jitter {
+    /// The delays are exactly the exponential schedule.
+    None,
+    /// The delay is picked uniformly between zero and the exponential delay.
+    Full,
+    /// The delay is half of the exponential delay, plus a uniformly picked part of the
+    /// other half.
+    Equal,
+}
+
+/// The configuration of the exponential backoff between reconnect attempts.
+#[derive(Clone, Debug)]
+pub struct ReconnectConfig {
+    /// The delay before the first reconnect attempt, which is doubled on every attempt.
+    pub base_delay: Duration,
+    /// The maximum delay between attempts, applied before the jitter.
+    pub max_delay: Duration,
+    pub jitter: Jitter,
+}
+
+impl Default for ReconnectConfig {
+    fn default() -> Self {
+        Self {
+            base_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(120),
+            jitter: Jitter::Full,
+        }
+    }
+}
+
+impl ReconnectConfig {
+    /// Returns the delay before the given reconnect attempt, starting from 0.
+    pub fn delay(&self, attempt: u32) -> Duration {
+        self.delay_with_rng(attempt, &mut rand::thread_rng())
+    }
+
+    /// Same as `delay`, but with the given random number generator.
+    pub fn delay_with_rng<R: Rng + ?Sized>(&self, attempt: u32, rng: &mut R) -> Duration {
+        let delay = self.capped_delay(attempt);
+        let millis = delay.as_millis() as u64;
+        match self.jitter {
+            Jitter::None => delay,
+            Jitter::Full => Duration::from_millis(rng.gen_range(0, millis + 1)),
+            Jitter::Equal => {
+                let half = millis / 2;
+                Duration::from_millis(half + rng.gen_range(0, millis - half + 1))
+            }
+        }
+    }
+
+    /// Returns the exponential delay before the given attempt, capped at `max_delay`.
+    fn capped_delay(&self, attempt: u32) -> Duration {
+        let factor = 2u32.checked_pow(attempt).unwrap_or(u32::MAX);
+        self.base_delay
+            .checked_mul(factor)
+            .unwrap_or(self.max_delay)
+            .min(self.max_delay)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{rngs::StdRng, SeedableRng};
+
+    use super::*;
+
+    fn config(jitter: Jitter) -> ReconnectConfig {
+        ReconnectConfig {
+            base_delay: Duration::from_millis(500),
+            max_delay: Duration::from_secs(30),
+            jitter,
+        }
+    }
+
+    #[test]
+    fn test_delay_without_jitter() {
+        let config = config(Jitter::None);
+        let delays = (0..8)
+            .map(|attempt| config.delay(attempt).as_millis())
+            .collect::<Vec<u128>>();
+        assert_eq!(
+            delays,
+            vec![500, 1000, 2000, 4000, 8000, 16000, 30000, 30000]
+        );
+        assert_eq!(config.delay(100), Duration::from_secs(30));
+    }
+
+    #[test]
+    fn test_delay_jitter_bounds() {
+        let mut rng = StdRng::seed_from_u64(42);
+        let full = config(Jitter::Full);
+        let equal = config(Jitter::Equal);
+
+        for attempt in 0..40 {
+            let max = full.capped_delay(attempt);
+            for _ in 0..50 {
+                assert!(full.delay_with_rng(attempt, &mut rng) <= max);
+
+                let delay = equal.delay_with_rng(attempt, &mut rng);
+                assert!(delay >= max / 2);
+                assert!(delay <= max);
+            }
+        }
+    }
+}
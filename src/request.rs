@@ -0,0 +1,259 @@
+//! `request` contains the helpers for the `<iq>` request-response queries.
+
+use std::time::Duration;
+
+use crate::{
+    binary::{AttributeTypes, Attrs, Node, NodeContentType},
+    new_rhustapp_error,
+    types::JID,
+    ErrorKind, IqError, RhustAppError,
+};
+
+/// The type of an `<iq>` request.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum InfoQueryType {
+    Get,
+    Set,
+}
+
+impl InfoQueryType {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Get => "get",
+            Self::Set => "set",
+        }
+    }
+}
+
+/// An `<iq>` request to send with `Client::send_iq`, which assigns its id.
+#[derive(Clone, Debug)]
+pub struct InfoQuery {
+    /// The `xmlns` of the request, like `usync` or `w:g2`.
+    pub namespace: String,
+    pub iq_type: InfoQueryType,
+    pub to: JID,
+    /// The `target` of the request, for the queries that are sent to the server about
+    /// another entity.
+    pub target: Option<JID>,
+    pub content: NodeContentType,
+    /// How long to wait for the response, `REQUEST_TIMEOUT` if unset.
+    pub timeout: Option<Duration>,
+}
+
+impl InfoQuery {
+    /// Creates a request without content, target and timeout.
+    pub fn new(namespace: &str, iq_type: InfoQueryType, to: JID) -> Self {
+        Self {
+            namespace: namespace.to_string(),
+            iq_type,
+            to,
+            target: None,
+            content: NodeContentType::None,
+            timeout: None,
+        }
+    }
+
+    /// Builds the `<iq>` node of the request with the given id.
+    pub fn to_node(&self, id: &str) -> Node {
+        let mut attrs = Attrs::from([
+            ("id".to_string(), AttributeTypes::String(id.to_string())),
+            (
+                "xmlns".to_string(),
+                AttributeTypes::String(self.namespace.clone()),
+            ),
+            (
+                "type".to_string(),
+                AttributeTypes::String(self.iq_type.as_str().to_string()),
+            ),
+            ("to".to_string(), AttributeTypes::JID(self.to.clone())),
+        ]);
+        if let Some(target) = &self.target {
+            attrs.insert("target".to_string(), AttributeTypes::JID(target.clone()));
+        };
+
+        Node {
+            tag: "iq".to_string(),
+            attrs,
+            content: self.content.clone(),
+        }
+    }
+}
+
+/// Parses the `<error code="..." text="...">` child of an `<iq>` response into an error of
+/// kind `ErrorKind::Iq`. Returns `None` if the response has no `<error>` child.
+pub fn iq_error_from_node(node: &Node) -> Option<RhustAppError> {
+    let error = node.get_optional_child_by_tag(&["error"])?;
+
+    let mut ag = error.attr_getter();
+    let code = ag
+        .optional_string("code")
+        .and_then(|code| code.parse::<u16>().ok())
+        .unwrap_or_default();
+    let text = ag.optional_string("text").unwrap_or_default();
+    let condition = error
+        .get_children()
+        .and_then(|children| children.first().map(|child| child.tag.to_string()));
+
+    let mut details = format!("{code}: {text}");
+    if let Some(condition) = &condition {
+        details = format!("{details} ({condition})");
+    };
+
+    Some(
+        new_rhustapp_error("server returned error in iq response", Some(details)).with_kind(
+            ErrorKind::Iq(Box::new(IqError {
+                code,
+                text,
+                condition,
+            })),
+        ),
+    )
+}
+
+/// Builds the empty `<iq type="result">` that acknowledges an `<iq>` request sent by the
+/// server, with the same `id` and sent back to its `from`.
+pub fn build_iq_result_node(request: &Node) -> Node {
+    let mut attrs = Attrs::from([(
+        "type".to_string(),
+        AttributeTypes::String("result".to_string()),
+    )]);
+    for (from, to) in [("id", "id"), ("from", "to")] {
+        if let Some(value) = request.attrs.get(from) {
+            attrs.insert(to.to_string(), value.clone());
+        };
+    }
+
+    Node {
+        tag: "iq".to_string(),
+        attrs,
+        content: NodeContentType::None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn iq_error(code: &str, text: &str, children: Vec<Node>) -> Node {
+        Node {
+            tag: "iq".to_string(),
+            attrs: Attrs::from([
+                ("id".to_string(), AttributeTypes::String("1".to_string())),
+                (
+                    "type".to_string(),
+                    AttributeTypes::String("error".to_string()),
+                ),
+            ]),
+            content: NodeContentType::ListOfNodes(vec![Node {
+                tag: "error".to_string(),
+                attrs: Attrs::from([
+                    ("code".to_string(), AttributeTypes::String(code.to_string())),
+                    ("text".to_string(), AttributeTypes::String(text.to_string())),
+                ]),
+                content: NodeContentType::ListOfNodes(children),
+            }]),
+        }
+    }
+
+    #[test]
+    fn test_iq_error_not_found() {
+        let err = iq_error_from_node(&iq_error("404", "item-not-found", vec![])).unwrap();
+        assert_eq!(
+            *err.kind(),
+            ErrorKind::Iq(Box::new(IqError {
+                code: 404,
+                text: "item-not-found".to_string(),
+                condition: None,
+            }))
+        );
+    }
+
+    #[test]
+    fn test_iq_error_rate_overlimit() {
+        let node = iq_error(
+            "429",
+            "rate-overlimit",
+            vec![Node {
+                tag: "rate-overlimit".to_string(),
+                ..Default::default()
+            }],
+        );
+        let err = iq_error_from_node(&node).unwrap();
+        assert_eq!(
+            *err.kind(),
+            ErrorKind::Iq(Box::new(IqError {
+                code: 429,
+                text: "rate-overlimit".to_string(),
+                condition: Some("rate-overlimit".to_string()),
+            }))
+        );
+        assert!(err.to_string().contains("429: rate-overlimit"));
+    }
+
+    #[test]
+    fn test_iq_without_error() {
+        let node = Node {
+            tag: "iq".to_string(),
+            attrs: Attrs::from([(
+                "type".to_string(),
+                AttributeTypes::String("result".to_string()),
+            )]),
+            content: NodeContentType::None,
+        };
+        assert!(iq_error_from_node(&node).is_none());
+    }
+
+    #[test]
+    fn test_build_iq_result_node() {
+        let request = Node {
+            tag: "iq".to_string(),
+            attrs: Attrs::from([
+                ("id".to_string(), AttributeTypes::String("42".to_string())),
+                (
+                    "type".to_string(),
+                    AttributeTypes::String("set".to_string()),
+                ),
+                (
+                    "from".to_string(),
+                    AttributeTypes::JID(crate::types::SERVER_JID.clone()),
+                ),
+            ]),
+            content: NodeContentType::None,
+        };
+
+        let result = build_iq_result_node(&request);
+        assert_eq!(result.tag, "iq");
+        let mut ag = result.attr_getter();
+        assert_eq!(ag.string("id").unwrap(), "42");
+        assert_eq!(ag.string("type").unwrap(), "result");
+        assert_eq!(ag.jid("to").unwrap(), *crate::types::SERVER_JID);
+        assert!(ag.optional_string("from").is_none());
+    }
+
+    #[test]
+    fn test_info_query_to_node() {
+        let target = JID::new("123", crate::types::GROUP_SERVER);
+        let mut query =
+            InfoQuery::new("w:g2", InfoQueryType::Set, crate::types::SERVER_JID.clone());
+        query.target = Some(target.clone());
+        query.content = NodeContentType::ListOfNodes(vec![Node {
+            tag: "query".to_string(),
+            ..Default::default()
+        }]);
+
+        let node = query.to_node("7");
+        assert_eq!(node.tag, "iq");
+        let mut ag = node.attr_getter();
+        assert_eq!(ag.string("id").unwrap(), "7");
+        assert_eq!(ag.string("xmlns").unwrap(), "w:g2");
+        assert_eq!(ag.string("type").unwrap(), "set");
+        assert_eq!(ag.jid("to").unwrap(), *crate::types::SERVER_JID);
+        assert_eq!(ag.jid("target").unwrap(), target);
+        assert!(node.get_optional_child_by_tag(&["query"]).is_some());
+
+        let node = InfoQuery::new("w:p", InfoQueryType::Get, crate::types::SERVER_JID.clone())
+            .to_node("8");
+        assert!(!node.attrs.contains_key("target"));
+        assert_eq!(node.content, NodeContentType::None);
+    }
+}
@@ -0,0 +1,618 @@
+//! `send` contains the builders for the stanzas used to send messages, and the cache of the
+//! recently sent ones that are sent again when a recipient can't decrypt them.
+
+use std::collections::VecDeque;
+
+use protobuf::{EnumOrUnknown, Message, MessageField};
+use rand::RngCore;
+use sha2::{Digest, Sha256};
+use time::OffsetDateTime;
+
+use crate::{
+    binary::{proto as wa_proto, AttributeTypes, Attrs, Node, NodeContentType},
+    new_rhustapp_error,
+    types::{MessageEditType, JID},
+    RhustAppError,
+};
+
+/// The response of the server to a sent message.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SendResponse {
+    /// The ID of the message, which is needed e.g. to revoke it.
+    pub id: String,
+    /// When the server received the message.
+    pub timestamp: OffsetDateTime,
+    /// The ID assigned by the server, only set for messages sent to newsletters.
+    pub server_id: Option<u64>,
+}
+
+/// Generates a random message ID in the same format as the official clients.
+pub fn generate_message_id() -> String {
+    let mut id = [0u8; 8];
+    rand::thread_rng().fill_bytes(&mut id);
+    format!("3EB0{}", hex::encode_upper(id))
+}
+
+/// Builds the `<message>` stanza that is sent to `to`.
+///
+/// `enc_children` are the per-device nodes built with `build_participant_node`. With
+/// multi-device, every message is encrypted separately for each device of the recipient (and
+/// for the user's own other devices, see `build_dm_participants`), so they are wrapped in a
+/// `<participants>` node.
+pub fn build_message_node(to: &JID, id: &str, message_type: &str, enc_children: Vec<Node>) -> Node {
+    Node {
+        tag: "message".to_string(),
+        attrs: Attrs::from([
+            ("id".to_string(), AttributeTypes::String(id.to_string())),
+            (
+                "type".to_string(),
+                AttributeTypes::String(message_type.to_string()),
+            ),
+            ("to".to_string(), AttributeTypes::JID(to.clone())),
+        ]),
+        content: NodeContentType::ListOfNodes(vec![Node {
+            tag: "participants".to_string(),
+            attrs: Attrs::new(),
+            content: NodeContentType::ListOfNodes(enc_children),
+        }]),
+    }
+}
+
+/// Encrypts a direct message for the devices of the recipient and the user's own other
+/// devices, and returns their `<to>` nodes for `build_message_node`. The own devices get the
+/// message wrapped in a `DeviceSentMessage`, so that they show it in the chat with `to`.
+///
+/// `encrypt` encrypts a serialized message for some of the devices and returns their `<to>`
+/// nodes, leaving out the devices that it fails for.
+pub fn build_dm_participants(
+    to: &JID,
+    own_id: &JID,
+    message: &wa_proto::Message,
+    devices: &[JID],
+    mut encrypt: impl FnMut(&[u8], &[JID]) -> Vec<Node>,
+) -> Result<Vec<Node>, RhustAppError> {
+    let marshal = |message: &wa_proto::Message| {
+        message
+            .write_to_bytes()
+            .map_err(|err| new_rhustapp_error("failed to marshal message", Some(err.to_string())))
+    };
+    let (own_devices, recipient_devices): (Vec<JID>, Vec<JID>) = devices
+        .iter()
+        .cloned()
+        .partition(|device| device.user == own_id.user);
+
+    let mut participants = encrypt(&marshal(message)?, &recipient_devices);
+    if !own_devices.is_empty() {
+        let device_sent = marshal(&build_device_sent_message(to, message))?;
+        participants.extend(encrypt(&device_sent, &own_devices));
+    };
+    Ok(participants)
+}
+
+/// Builds the `<message>` stanza that sends a message again to the single device that asked
+/// for it with a retry receipt. `enc` is encrypted for that device only, so the message
+/// isn't fanned out to the other devices of a direct chat.
+pub fn build_resent_message_node(
+    receipt: &Node,
+    id: &str,
+    message_type: &str,
+    is_group: bool,
+    enc: Node,
+) -> Result<Node, RhustAppError> {
+    let mut attrs = Attrs::from([
+        ("id".to_string(), AttributeTypes::String(id.to_string())),
+        (
+            "type".to_string(),
+            AttributeTypes::String(message_type.to_string()),
+        ),
+    ]);
+    for (from, to) in [
+        ("from", "to"),
+        ("participant", "participant"),
+        ("recipient", "recipient"),
+    ] {
+        if let Some(value) = receipt.attrs.get(from) {
+            attrs.insert(to.to_string(), value.clone());
+        };
+    }
+    if !attrs.contains_key("to") {
+        return Err(new_rhustapp_error("retry receipt has no sender", None));
+    };
+    if !is_group {
+        attrs.insert(
+            "device_fanout".to_string(),
+            AttributeTypes::String("false".to_string()),
+        );
+    };
+
+    Ok(Node {
+        tag: "message".to_string(),
+        attrs,
+        content: NodeContentType::ListOfNodes(vec![enc]),
+    })
+}
+
+/// Returns the `type` attribute of the `<message>` stanza that carries the message, which
+/// the server uses e.g. to decide which push notification to show.
+pub fn message_type(message: &wa_proto::Message) -> &'static str {
+    if message.reactionMessage.is_some() || message.encReactionMessage.is_some() {
+        "reaction"
+    } else if message.pollCreationMessage.is_some()
+        || message.pollCreationMessageV2.is_some()
+        || message.pollCreationMessageV3.is_some()
+        || message.pollUpdateMessage.is_some()
+    {
+        "poll"
+    } else if message.imageMessage.is_some()
+        || message.videoMessage.is_some()
+        || message.audioMessage.is_some()
+        || message.documentMessage.is_some()
+        || message.stickerMessage.is_some()
+        || message.contactMessage.is_some()
+        || message.contactsArrayMessage.is_some()
+        || message.locationMessage.is_some()
+        || message.liveLocationMessage.is_some()
+    {
+        "media"
+    } else {
+        "text"
+    }
+}
+
+/// Wraps a message sent to `to` in the `DeviceSentMessage` that is sent to the user's own
+/// other devices, so that they show it in the chat with `to`.
+pub fn build_device_sent_message(to: &JID, message: &wa_proto::Message) -> wa_proto::Message {
+    wa_proto::Message {
+        deviceSentMessage: MessageField::some(wa_proto::DeviceSentMessage {
+            destinationJid: Some(to.to_string()),
+            message: MessageField::some(message.clone()),
+            ..Default::default()
+        }),
+        ..Default::default()
+    }
+}
+
+/// Builds the `<device-identity>` node with the serialized account of the device, which has
+/// to be included in messages that start a session (i.e. contain a `pkmsg`) so that the
+/// recipient can verify that the device belongs to the user.
+pub fn build_device_identity_node(
+    account: &wa_proto::ADVSignedDeviceIdentity,
+) -> Result<Node, RhustAppError> {
+    let account = account.write_to_bytes().map_err(|err| {
+        new_rhustapp_error("failed to marshal device identity", Some(err.to_string()))
+    })?;
+    Ok(Node {
+        tag: "device-identity".to_string(),
+        attrs: Attrs::new(),
+        content: NodeContentType::ByteArray(account),
+    })
+}
+
+/// Parses the `<ack class="message">` that the server responds to a sent `<message>` with.
+/// An ack with an `error` attribute means the server rejected the message.
+pub fn parse_message_ack(ack: &Node) -> Result<SendResponse, RhustAppError> {
+    ack.expect_tag("ack")?;
+
+    let mut ag = ack.attr_getter();
+    let id = ag.string("id");
+    let timestamp = ag.optional_unix_time("t");
+    let server_id = ag.optional_u64_radix("server_id", 10);
+    let error = ag.optional_string("error");
+    if let Some(err) = ag.error() {
+        return Err(new_rhustapp_error(
+            "failed to parse message ack",
+            Some(err.to_string()),
+        ));
+    };
+    if let Some(error) = error {
+        return Err(new_rhustapp_error(
+            "server rejected the message",
+            Some(format!("error code {error}")),
+        ));
+    };
+
+    Ok(SendResponse {
+        id: id.unwrap(),
+        timestamp: timestamp.unwrap_or_else(OffsetDateTime::now_utc),
+        server_id,
+    })
+}
+
+/// Wraps the `<enc>` node encrypted for a single device in a `<to jid="...">` node.
+pub fn build_participant_node(device: &JID, enc: Node) -> Node {
+    Node {
+        tag: "to".to_string(),
+        attrs: Attrs::from([("jid".to_string(), AttributeTypes::JID(device.clone()))]),
+        content: NodeContentType::ListOfNodes(vec![enc]),
+    }
+}
+
+/// Computes the participant list hash (`phash`) of the given devices: the first 6 bytes of
+/// the SHA-256 of their sorted AD strings, base64-encoded with a "2:" version prefix.
+///
+/// It's sent in the `phash` attribute of group messages, so that the server can tell if the
+/// sender's device list is outdated. The devices include the sending device, which isn't
+/// among the `<participants>` of the message.
+pub fn participant_list_hash(devices: &[JID]) -> String {
+    let mut ad_strings = devices.iter().map(JID::ad_string).collect::<Vec<String>>();
+    ad_strings.sort();
+    let hash = Sha256::digest(ad_strings.concat().as_bytes());
+    format!(
+        "2:{}",
+        base64::encode_config(&hash[..6], base64::STANDARD_NO_PAD)
+    )
+}
+
+/// Builds the protocol message that revokes (deletes for everyone) a previously sent
+/// message.
+///
+/// `participant` is the sender of the revoked message, which is only needed when revoking
+/// someone else's message in a group as an admin.
+pub fn build_revoke(
+    chat: &JID,
+    message_id: &str,
+    from_me: bool,
+    participant: Option<&JID>,
+) -> wa_proto::Message {
+    let mut key = wa_proto::MessageKey::new();
+    key.remoteJid = Some(chat.to_string());
+    key.fromMe = Some(from_me);
+    key.id = Some(message_id.to_string());
+    if !from_me {
+        key.participant = participant.map(|jid| jid.to_non_ad().to_string());
+    };
+
+    let mut protocol_message = wa_proto::ProtocolMessage::new();
+    protocol_message.type_ = Some(EnumOrUnknown::new(wa_proto::protocol_message::Type::REVOKE));
+    protocol_message.key = MessageField::some(key);
+
+    let mut message = wa_proto::Message::new();
+    message.protocolMessage = MessageField::some(protocol_message);
+    message
+}
+
+/// Returns the `edit` attribute of the `<message>` stanza that carries the message, which
+/// is only set for revokes (see `build_revoke`).
+pub fn edit_attribute(message: &wa_proto::Message) -> Option<MessageEditType> {
+    let protocol_message = message.protocolMessage.as_ref()?;
+    if protocol_message.type_() != wa_proto::protocol_message::Type::REVOKE {
+        return None;
+    };
+    match protocol_message.key.fromMe() {
+        true => Some(MessageEditType::SenderRevoke),
+        false => Some(MessageEditType::AdminRevoke),
+    }
+}
+
+/// How many sent messages are kept to answer retry receipts.
+pub const RECENT_MESSAGES_SIZE: usize = 256;
+
+/// The most recently sent messages, by chat and ID. The oldest message is dropped once
+/// there are `RECENT_MESSAGES_SIZE` of them.
+#[derive(Default)]
+pub struct RecentMessages {
+    messages: VecDeque<(JID, String, wa_proto::Message)>,
+}
+
+impl RecentMessages {
+    pub fn add(&mut self, chat: &JID, id: &str, message: &wa_proto::Message) {
+        if self.messages.len() >= RECENT_MESSAGES_SIZE {
+            self.messages.pop_front();
+        };
+        self.messages
+            .push_back((chat.to_non_ad(), id.to_string(), message.clone()));
+    }
+
+    pub fn get(&self, chat: &JID, id: &str) -> Option<&wa_proto::Message> {
+        let chat = chat.to_non_ad();
+        self.messages
+            .iter()
+            .rev()
+            .find(|(message_chat, message_id, _)| *message_chat == chat && message_id == id)
+            .map(|(_, _, message)| message)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use super::*;
+
+    fn enc_node(enc_type: &str) -> Node {
+        Node {
+            tag: "enc".to_string(),
+            attrs: Attrs::from([
+                ("v".to_string(), AttributeTypes::String("2".to_string())),
+                (
+                    "type".to_string(),
+                    AttributeTypes::String(enc_type.to_string()),
+                ),
+            ]),
+            content: NodeContentType::ByteArray(vec![1, 2, 3]),
+        }
+    }
+
+    /// Stands in for the encryption of a message for the devices, with the plaintext as
+    /// the content of the `<enc>` nodes.
+    fn fake_encrypt(plaintext: &[u8], devices: &[JID]) -> Vec<Node> {
+        devices
+            .iter()
+            .map(|device| {
+                let mut enc = enc_node("pkmsg");
+                enc.content = NodeContentType::ByteArray(plaintext.to_vec());
+                build_participant_node(device, enc)
+            })
+            .collect()
+    }
+
+    /// Returns the devices of the `<to>` nodes and the messages that were encrypted for them.
+    fn participant_messages(participants: Vec<Node>) -> Vec<(JID, wa_proto::Message)> {
+        participants
+            .into_iter()
+            .map(|to| {
+                let enc = to.get_optional_child_by_tag(&["enc"]).unwrap();
+                let plaintext = match enc.content {
+                    NodeContentType::ByteArray(bytes) => bytes,
+                    _ => panic!("enc content is not a byte array"),
+                };
+                (
+                    to.attr_getter().jid("jid").unwrap(),
+                    wa_proto::Message::parse_from_bytes(&plaintext).unwrap(),
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_build_message_node_dm() {
+        let to = JID::from_str("919876543210@s.whatsapp.net").unwrap();
+        let device = JID::new_ad("919876543210", 0, 1);
+        let node = build_message_node(
+            &to,
+            "3EB0ABCDEF",
+            "text",
+            vec![build_participant_node(&device, enc_node("pkmsg"))],
+        );
+
+        assert_eq!(node.tag, "message");
+        let mut ag = node.attr_getter();
+        assert_eq!(ag.string("id").unwrap(), "3EB0ABCDEF");
+        assert_eq!(ag.string("type").unwrap(), "text");
+        assert_eq!(ag.jid("to").unwrap(), to);
+
+        let participants = node
+            .get_optional_child_by_tag(&["participants"])
+            .unwrap()
+            .get_children_by_tag("to")
+            .unwrap();
+        assert_eq!(participants.len(), 1);
+        assert_eq!(participants[0].attr_getter().jid("jid").unwrap(), device);
+        assert!(participants[0]
+            .get_optional_child_by_tag(&["enc"])
+            .is_some());
+    }
+
+    #[test]
+    fn test_build_dm_participants() {
+        let to = JID::from_str("919876543210@s.whatsapp.net").unwrap();
+        let own_id = JID::new_ad("911234567890", 0, 1);
+        let devices = [
+            JID::new_ad("919876543210", 0, 0),
+            JID::new_ad("911234567890", 0, 0),
+            JID::new_ad("919876543210", 0, 2),
+        ];
+        let mut message = wa_proto::Message::new();
+        message.set_conversation("hello".to_string());
+        let participants =
+            build_dm_participants(&to, &own_id, &message, &devices, fake_encrypt).unwrap();
+
+        // The recipient's devices get the message, and the phone of the user gets it
+        // wrapped in a device sent message.
+        let participants = participant_messages(participants);
+        assert_eq!(
+            participants,
+            vec![
+                (devices[0].clone(), message.clone()),
+                (devices[2].clone(), message.clone()),
+                (devices[1].clone(), build_device_sent_message(&to, &message)),
+            ]
+        );
+        let device_sent = participants[2].1.deviceSentMessage.as_ref().unwrap();
+        assert_eq!(device_sent.destinationJid(), "919876543210@s.whatsapp.net");
+        assert_eq!(device_sent.message.conversation(), "hello");
+    }
+
+    #[test]
+    fn test_build_revoke_group() {
+        let group = JID::from_str("120363000000000000@g.us").unwrap();
+        let sender = JID::from_str("919876543210@s.whatsapp.net").unwrap();
+        let message = build_revoke(&group, "3EB0ABCDEF", false, Some(&sender));
+        assert!(matches!(
+            edit_attribute(&message),
+            Some(MessageEditType::AdminRevoke)
+        ));
+
+        let protocol_message = message.protocolMessage.unwrap();
+        assert_eq!(
+            protocol_message.type_.unwrap().enum_value().unwrap(),
+            wa_proto::protocol_message::Type::REVOKE
+        );
+        let key = protocol_message.key.unwrap();
+        assert_eq!(key.remoteJid.unwrap(), "120363000000000000@g.us");
+        assert_eq!(key.id.unwrap(), "3EB0ABCDEF");
+        assert!(!key.fromMe.unwrap());
+        assert_eq!(key.participant.unwrap(), "919876543210@s.whatsapp.net");
+    }
+
+    #[test]
+    fn test_edit_attribute() {
+        let chat = JID::from_str("919876543210@s.whatsapp.net").unwrap();
+        let revoke = build_revoke(&chat, "3EB0ABCDEF", true, None);
+        assert!(matches!(
+            edit_attribute(&revoke),
+            Some(MessageEditType::SenderRevoke)
+        ));
+
+        let mut message = wa_proto::Message::new();
+        message.set_conversation("hello".to_string());
+        assert!(edit_attribute(&message).is_none());
+    }
+
+    #[test]
+    fn test_build_message_node_group() {
+        let group = JID::from_str("120363000000000000@g.us").unwrap();
+        let devices = [
+            JID::new_ad("919876543210", 0, 0),
+            JID::new_ad("911234567890", 0, 3),
+        ];
+        let node = build_message_node(
+            &group,
+            "3EB0ABCDEF",
+            "text",
+            devices
+                .iter()
+                .map(|device| build_participant_node(device, enc_node("pkmsg")))
+                .collect(),
+        );
+
+        assert_eq!(node.attr_getter().jid("to").unwrap(), group);
+        let participants = node
+            .get_optional_child_by_tag(&["participants"])
+            .unwrap()
+            .get_children_by_tag("to")
+            .unwrap();
+        assert_eq!(
+            participants
+                .iter()
+                .map(|to| to.attr_getter().jid("jid").unwrap())
+                .collect::<Vec<JID>>(),
+            devices.to_vec()
+        );
+        for to in participants {
+            let enc = to.get_optional_child_by_tag(&["enc"]).unwrap();
+            assert_eq!(enc.attr_getter().string("type").unwrap(), "pkmsg");
+        }
+    }
+
+    #[test]
+    fn test_participant_list_hash() {
+        let devices = [
+            JID::from_str("919876543210@s.whatsapp.net").unwrap(),
+            JID::new_ad("919876543210", 0, 3),
+            JID::new_ad("911234567890", 0, 2),
+        ];
+
+        // The hash of the sorted AD strings, so the order of the devices doesn't matter.
+        let phash = participant_list_hash(&devices);
+        assert_eq!(phash, "2:QApOz3nc");
+        let mut reversed = devices.to_vec();
+        reversed.reverse();
+        assert_eq!(participant_list_hash(&reversed), phash);
+    }
+
+    #[test]
+    fn test_parse_message_ack() {
+        let ack = |attrs: &[(&str, &str)]| Node {
+            tag: "ack".to_string(),
+            attrs: attrs
+                .iter()
+                .map(|(key, value)| (key.to_string(), AttributeTypes::String(value.to_string())))
+                .collect(),
+            content: NodeContentType::None,
+        };
+
+        let response = parse_message_ack(&ack(&[
+            ("class", "message"),
+            ("id", "3EB0ABCDEF"),
+            ("t", "1700000000"),
+            ("server_id", "105"),
+        ]))
+        .unwrap();
+        assert_eq!(response.id, "3EB0ABCDEF");
+        assert_eq!(response.timestamp.unix_timestamp(), 1700000000);
+        assert_eq!(response.server_id, Some(105));
+
+        assert!(parse_message_ack(&ack(&[("id", "3EB0ABCDEF"), ("error", "479")])).is_err());
+        assert!(parse_message_ack(&ack(&[("t", "1700000000")])).is_err());
+    }
+
+    #[test]
+    fn test_message_type() {
+        let mut message = wa_proto::Message::new();
+        message.conversation = Some("hello".to_string());
+        assert_eq!(message_type(&message), "text");
+
+        message.imageMessage = MessageField::some(wa_proto::ImageMessage::new());
+        assert_eq!(message_type(&message), "media");
+
+        let mut reaction = wa_proto::Message::new();
+        reaction.reactionMessage = MessageField::some(wa_proto::ReactionMessage::new());
+        assert_eq!(message_type(&reaction), "reaction");
+    }
+
+    #[test]
+    fn test_recent_messages() {
+        let chat = JID::new("919876543210", crate::types::DEFAULT_USER_SERVER);
+        let mut message = wa_proto::Message::new();
+        message.set_conversation("hello".to_string());
+
+        let mut recent = RecentMessages::default();
+        recent.add(&chat, "first", &message);
+        assert_eq!(
+            recent
+                .get(&JID::new_ad("919876543210", 0, 3), "first")
+                .unwrap()
+                .conversation(),
+            "hello"
+        );
+        assert!(recent.get(&chat, "other").is_none());
+
+        for i in 0..RECENT_MESSAGES_SIZE {
+            recent.add(&chat, &i.to_string(), &message);
+        }
+        assert!(recent.get(&chat, "first").is_none());
+        assert!(recent.get(&chat, "0").is_some());
+    }
+
+    #[test]
+    fn test_build_resent_message_node() {
+        let group = JID::new("120363000000000000", crate::types::GROUP_SERVER);
+        let participant = JID::new_ad("919876543210", 0, 2);
+        let receipt = Node {
+            tag: "receipt".to_string(),
+            attrs: Attrs::from([
+                ("from".to_string(), AttributeTypes::JID(group.clone())),
+                (
+                    "participant".to_string(),
+                    AttributeTypes::JID(participant.clone()),
+                ),
+            ]),
+            content: NodeContentType::None,
+        };
+        let enc = Node {
+            tag: "enc".to_string(),
+            ..Default::default()
+        };
+
+        let node =
+            build_resent_message_node(&receipt, "3EB0AA", "text", true, enc.clone()).unwrap();
+        let mut ag = node.attr_getter();
+        assert_eq!(ag.jid("to").unwrap(), group);
+        assert_eq!(ag.jid("participant").unwrap(), participant);
+        assert_eq!(ag.string("id").unwrap(), "3EB0AA");
+        assert!(ag.optional_string("device_fanout").is_none());
+        assert!(node.get_optional_child_by_tag(&["enc"]).is_some());
+
+        let receipt = Node {
+            tag: "receipt".to_string(),
+            attrs: Attrs::from([("from".to_string(), AttributeTypes::JID(participant.clone()))]),
+            content: NodeContentType::None,
+        };
+        let node =
+            build_resent_message_node(&receipt, "3EB0AA", "text", false, enc.clone()).unwrap();
+        assert_eq!(node.attr_getter().string("device_fanout").unwrap(), "false");
+
+        assert!(build_resent_message_node(&Node::default(), "3EB0AA", "text", false, enc).is_err());
+    }
+}
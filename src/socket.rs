@@ -2,14 +2,42 @@
 //! by WhatsApp.
 
 use std::{
-    net::TcpStream,
-    sync::{Arc, Mutex},
+    io,
+    net::{Shutdown, TcpStream},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc::{self, Receiver, RecvTimeoutError, Sender},
+        Arc, Mutex, MutexGuard,
+    },
     thread,
+    time::Duration,
 };
 
-use tungstenite::{http::Uri, stream::MaybeTlsStream, WebSocket};
+use aes_gcm::{
+    aead::{Aead, NewAead, Payload},
+    Aes256Gcm, Key, Nonce,
+};
+use hkdf::Hkdf;
+use libsignal_protocol::{KeyPair, PrivateKey, PublicKey};
+use native_tls::{Certificate, TlsConnector};
+use protobuf::{Message, MessageField};
+use sha2::{Digest, Sha256};
+use tungstenite::{
+    http::{HeaderName, HeaderValue, Uri},
+    protocol::{frame::coding::CloseCode, CloseFrame},
+    stream::MaybeTlsStream,
+    Connector, WebSocket,
+};
 
-use crate::{binary::token, new_rhustapp_error, RhustAppError};
+use crate::{
+    binary::{
+        proto as wa_proto,
+        proto::cert_chain::noise_certificate::Details as NoiseCertificateDetails, token,
+    },
+    new_rhustapp_error,
+    store::public_key_bytes,
+    ErrorKind, RhustAppError,
+};
 
 /// It is the Origin header for all WhatsApp websocket connection.
 pub const ORIGIN: &str = "https://web.whatsapp.com";
@@ -26,10 +54,21 @@ pub fn get_wa_header() -> [u8; 4] {
 pub const FRAME_MAX_SIZE: usize = 2 << 23;
 pub const FRAME_LENGTH_SIZE: usize = 3;
 
+/// How long the read pump waits for data before giving writers a chance to lock the
+/// websocket.
+const READ_PUMP_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 pub enum SocketError {
     FrameTooLarge,
     SocketClosed,
     SocketAlreadyOpen,
+    /// Nothing was received within the read timeout, see `FrameSocket::with_read_timeout`.
+    ReadTimeout,
+    /// Writing to the websocket failed because of a transient connection problem, e.g. the
+    /// connection was reset. Sending again after reconnecting may succeed.
+    WriteFailed,
 }
 
 impl SocketError {
@@ -38,35 +77,261 @@ impl SocketError {
             Self::FrameTooLarge => String::from("frame is too large"),
             Self::SocketClosed => String::from("frame socket is closed"),
             Self::SocketAlreadyOpen => String::from("frame socket is already open"),
+            Self::ReadTimeout => String::from("timed out waiting for data"),
+            Self::WriteFailed => String::from("failed to write to frame socket"),
+        }
+    }
+}
+
+/// The state of the connection of a `FrameSocket`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ConnectionState {
+    /// The websocket is being connected.
+    Connecting,
+    /// The websocket is connected, but the Noise handshake hasn't been completed yet.
+    Handshaking,
+    /// The Noise handshake has been completed, frames can be sent.
+    Connected,
+    /// The websocket is being closed.
+    Closing,
+    /// The websocket is not connected. This is also the initial state.
+    Closed,
+}
+
+/// A callback that is called with the old and the new state whenever the connection state
+/// of a `FrameSocket` changes.
+pub type StateChangeHandler = Box<dyn Fn(ConnectionState, ConnectionState) + Send>;
+
+type WebSocketConnection = WebSocket<MaybeTlsStream<TcpStream>>;
+
+/// The websocket connection, shared between the `FrameSocket` and its read pump.
+struct SharedConnection {
+    websocket: Mutex<WebSocketConnection>,
+    /// The number of threads waiting to write. The read pump lets them go first, as it
+    /// would otherwise lock the websocket again right after every read.
+    waiting_writers: AtomicUsize,
+}
+
+impl SharedConnection {
+    fn lock_for_write(&self) -> MutexGuard<'_, WebSocketConnection> {
+        self.waiting_writers.fetch_add(1, Ordering::SeqCst);
+        let websocket = self.websocket.lock().unwrap();
+        self.waiting_writers.fetch_sub(1, Ordering::SeqCst);
+        websocket
+    }
+}
+
+/// Returns the TCP stream under the websocket.
+fn tcp_stream(websocket: &WebSocketConnection) -> Option<&TcpStream> {
+    match websocket.get_ref() {
+        MaybeTlsStream::Plain(stream) => Some(stream),
+        MaybeTlsStream::NativeTls(stream) => Some(stream.get_ref()),
+        _ => None,
+    }
+}
+
+/// Splits the data received over the websocket into frames, which are prefixed with their
+/// 3-byte big-endian length. A websocket message can contain multiple frames, and a frame
+/// can be split over multiple messages.
+#[derive(Default)]
+struct FrameReader {
+    buffer: Vec<u8>,
+}
+
+impl FrameReader {
+    /// Adds the received data to the buffer and returns the frames that are now complete.
+    fn process(&mut self, data: &[u8]) -> Result<Vec<Vec<u8>>, RhustAppError> {
+        self.buffer.extend_from_slice(data);
+
+        let mut frames = Vec::new();
+        while self.buffer.len() >= FRAME_LENGTH_SIZE {
+            let length = (usize::from(self.buffer[0]) << 16)
+                | (usize::from(self.buffer[1]) << 8)
+                | usize::from(self.buffer[2]);
+            if length >= FRAME_MAX_SIZE {
+                return Err(new_rhustapp_error(
+                    "failed to read frame",
+                    Some(SocketError::FrameTooLarge.to_string()),
+                )
+                .with_kind(ErrorKind::Socket(SocketError::FrameTooLarge)));
+            };
+            if self.buffer.len() < FRAME_LENGTH_SIZE + length {
+                break;
+            };
+
+            frames.push(self.buffer[FRAME_LENGTH_SIZE..FRAME_LENGTH_SIZE + length].to_vec());
+            self.buffer.drain(..FRAME_LENGTH_SIZE + length);
         }
+
+        Ok(frames)
     }
 }
 
 pub struct FrameSocket {
-    connection: Option<WebSocket<MaybeTlsStream<TcpStream>>>,
+    connection: Option<Arc<SharedConnection>>,
+    /// The frames received by the read pump of the current connection, see `frames`.
+    frames: Option<Receiver<Vec<u8>>>,
+    state: ConnectionState,
+    on_state_change: Option<StateChangeHandler>,
+    /// The header that is sent before the first frame of every connection.
     pub header: Option<[u8; 4]>,
+    /// Whether the header was sent on the current connection.
+    header_sent: bool,
+    url: String,
+    /// The extra headers of the websocket handshake request, see `with_header`.
+    headers: Vec<(String, String)>,
+    /// The TLS connector to use instead of the default one, which trusts the system roots.
+    tls_connector: Option<TlsConnector>,
+    /// How long `read_data` waits for a frame, see `with_read_timeout`.
+    read_timeout: Option<Duration>,
     lock: Arc<Mutex<u8>>,
-    incoming_length: usize,
-    received_length: usize,
 }
 
 impl FrameSocket {
     pub fn new() -> Self {
         Self {
             connection: None,
+            frames: None,
+            state: ConnectionState::Closed,
+            on_state_change: None,
             header: Some(get_wa_header()),
+            header_sent: false,
+            url: URL.to_string(),
+            headers: Vec::new(),
+            tls_connector: None,
+            read_timeout: None,
             lock: Arc::new(Mutex::new(0)),
-            incoming_length: 0,
-            received_length: 0,
         }
     }
 
+    /// Sets the websocket URL to connect to instead of `URL`, e.g. to go through a proxy.
+    pub fn with_url(mut self, url: &str) -> Self {
+        self.url = url.to_string();
+        self
+    }
+
+    /// Adds a header to the websocket handshake request. If the header is one of the
+    /// default headers (e.g. `Origin`) or was already added, its value is replaced.
+    pub fn with_header(mut self, name: &str, value: &str) -> Self {
+        self.headers
+            .retain(|(existing, _)| !existing.eq_ignore_ascii_case(name));
+        self.headers.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    /// Sets the TLS connector used for the websocket connection. By default, the system
+    /// roots are trusted.
+    pub fn with_tls_connector(mut self, connector: TlsConnector) -> Self {
+        self.tls_connector = Some(connector);
+        self
+    }
+
+    /// Pins the trusted certificates: only server certificates issued by (or equal to) one
+    /// of the given PEM-encoded certificates are accepted, and the system roots are not
+    /// trusted.
+    pub fn with_pinned_certificates(
+        self,
+        pem_certificates: &[&[u8]],
+    ) -> Result<Self, RhustAppError> {
+        let mut builder = TlsConnector::builder();
+        builder.disable_built_in_roots(true);
+        for pem in pem_certificates {
+            let certificate = Certificate::from_pem(pem).map_err(|err| {
+                new_rhustapp_error("failed to parse pinned certificate", Some(err.to_string()))
+            })?;
+            builder.add_root_certificate(certificate);
+        }
+        let connector = builder.build().map_err(|err| {
+            new_rhustapp_error("failed to build TLS connector", Some(err.to_string()))
+        })?;
+
+        Ok(self.with_tls_connector(connector))
+    }
+
+    /// Sets the read timeout. If no frame is received for this long, `read_data` fails with
+    /// an error of kind `ErrorKind::Socket(ReadTimeout)` instead of blocking forever, and
+    /// `Client` closes the connection and reconnects. By default, reads never time out.
+    ///
+    /// The server only sends data when there is something to deliver, so the timeout
+    /// should be longer than the keepalive interval, whose pings it responds to.
+    pub fn with_read_timeout(mut self, timeout: Duration) -> Self {
+        self.read_timeout = Some(timeout);
+        self
+    }
+
+    /// Returns the read timeout, see `with_read_timeout`.
+    pub fn read_timeout(&self) -> Option<Duration> {
+        self.read_timeout
+    }
+
+    /// Sets the callback that is called whenever the connection state changes.
+    pub fn with_state_change_handler(mut self, handler: StateChangeHandler) -> Self {
+        self.on_state_change = Some(handler);
+        self
+    }
+
     pub fn is_connected(&self) -> bool {
         self.connection.is_some()
     }
 
+    /// Returns the current state of the connection.
+    pub fn state(&self) -> ConnectionState {
+        self.state
+    }
+
+    fn set_state(&mut self, state: ConnectionState) {
+        let old_state = self.state;
+        if old_state == state {
+            return;
+        };
+        self.state = state;
+        if let Some(handler) = &self.on_state_change {
+            handler(old_state, state);
+        };
+    }
+
+    /// Marks the Noise handshake as completed, moving the state from `Handshaking` to
+    /// `Connected`.
+    pub fn handshake_complete(&mut self) -> Result<(), RhustAppError> {
+        if self.state != ConnectionState::Handshaking {
+            return Err(new_rhustapp_error(
+                &format!("can't complete handshake in state {:?}", self.state),
+                None,
+            ));
+        };
+        self.set_state(ConnectionState::Connected);
+        Ok(())
+    }
+
+    /// Closes the websocket. If `code` is positive, a close frame with that code is sent
+    /// first.
     pub fn close(&mut self, code: i32) {
-        todo!()
+        let connection = match self.connection.take() {
+            Some(connection) => connection,
+            None => return,
+        };
+        self.frames = None;
+        self.set_state(ConnectionState::Closing);
+
+        let mut websocket = connection.lock_for_write();
+        if code > 0 {
+            let frame = CloseFrame {
+                code: CloseCode::from(code as u16),
+                reason: "".into(),
+            };
+            if let Err(err) = websocket.close(Some(frame)) {
+                log::warn!("error sending close frame: {err}");
+            } else if let Err(err) = websocket.write_pending() {
+                log::warn!("error flushing close frame: {err}");
+            };
+        };
+        // The read pump holds on to the connection, shutting down the stream stops it.
+        if let Some(stream) = tcp_stream(&websocket) {
+            let _ = stream.shutdown(Shutdown::Both);
+        };
+
+        drop(websocket);
+        self.set_state(ConnectionState::Closed);
     }
 
     pub fn connect(&mut self) -> Result<(), RhustAppError> {
@@ -83,23 +348,214 @@ impl FrameSocket {
             ));
         };
 
-        let ws_request = Self::build_connnection_request().map_err(|err| {
-            new_rhustapp_error(
-                "failed to build websocket connection request",
-                Some(err.to_string()),
+        let ws_request =
+            Self::build_connnection_request(&self.url, &self.headers).map_err(|err| {
+                new_rhustapp_error(
+                    "failed to build websocket connection request",
+                    Some(err.to_string()),
+                )
+            })?;
+
+        self.set_state(ConnectionState::Connecting);
+        let socket = match self
+            .open_websocket(ws_request)
+            .and_then(Self::set_poll_interval)
+        {
+            Ok(socket) => socket,
+            Err(err) => {
+                self.set_state(ConnectionState::Closed);
+                return Err(err);
+            }
+        };
+        let connection = Arc::new(SharedConnection {
+            websocket: Mutex::new(socket),
+            waiting_writers: AtomicUsize::new(0),
+        });
+        let (sender, receiver) = mpsc::channel();
+        {
+            let connection = Arc::clone(&connection);
+            thread::spawn(move || Self::read_pump(connection, sender));
+        }
+        self.connection = Some(connection);
+        self.frames = Some(receiver);
+        self.header_sent = false;
+        self.set_state(ConnectionState::Handshaking);
+
+        Ok(())
+    }
+
+    fn open_websocket(
+        &self,
+        ws_request: tungstenite::http::Request<()>,
+    ) -> Result<WebSocket<MaybeTlsStream<TcpStream>>, RhustAppError> {
+        let socket = match &self.tls_connector {
+            Some(connector) => {
+                let uri = ws_request.uri();
+                let address = (
+                    uri.host().unwrap_or_default(),
+                    uri.port_u16().unwrap_or(443),
+                );
+                let stream = TcpStream::connect(address).map_err(|err| {
+                    new_rhustapp_error("failed to connect to websocket", Some(err.to_string()))
+                })?;
+                tungstenite::client_tls_with_config(
+                    ws_request,
+                    stream,
+                    None,
+                    Some(Connector::NativeTls(connector.clone())),
+                )
+                .map_err(|err| {
+                    new_rhustapp_error("failed to connect to websocket", Some(err.to_string()))
+                })?
+                .0
+            }
+            None => {
+                tungstenite::connect(ws_request)
+                    .map_err(|err| {
+                        new_rhustapp_error("failed to connect to websocket", Some(err.to_string()))
+                    })?
+                    .0
+            }
+        };
+
+        Ok(socket)
+    }
+
+    /// Sets the read timeout of the underlying `TcpStream`, so that the read pump
+    /// periodically unlocks the websocket.
+    fn set_poll_interval(
+        socket: WebSocketConnection,
+    ) -> Result<WebSocketConnection, RhustAppError> {
+        if let Some(stream) = tcp_stream(&socket) {
+            stream
+                .set_read_timeout(Some(READ_PUMP_POLL_INTERVAL))
+                .map_err(|err| {
+                    new_rhustapp_error(
+                        "failed to set websocket read timeout",
+                        Some(err.to_string()),
+                    )
+                })?;
+        };
+        Ok(socket)
+    }
+
+    /// Returns the channel that the frames received over the current connection are
+    /// delivered through, e.g. to receive them in another thread. The channel is
+    /// disconnected when the connection is closed.
+    ///
+    /// Returns `None` if the socket isn't connected or the channel was already taken.
+    /// `read_data` can't be used after the channel is taken.
+    pub fn frames(&mut self) -> Option<Receiver<Vec<u8>>> {
+        self.frames.take()
+    }
+
+    /// Receives the next frame, blocking until one is received or the read timeout (see
+    /// `with_read_timeout`) passes.
+    pub fn read_data(&mut self) -> Result<Vec<u8>, RhustAppError> {
+        match self.frames.as_ref() {
+            Some(frames) => Self::receive_frame(frames, self.read_timeout),
+            None => Err(new_rhustapp_error(
+                "failed to read data",
+                Some(SocketError::SocketClosed.to_string()),
             )
-        })?;
+            .with_kind(ErrorKind::Socket(SocketError::SocketClosed))),
+        }
+    }
 
-        let (socket, _) = tungstenite::connect(ws_request).map_err(|err| {
-            new_rhustapp_error("failed to connect to websocket", Some(err.to_string()))
-        })?;
-        self.connection = Some(socket);
+    /// Receives the next frame from the channel returned by `frames`, blocking until one is
+    /// received or `timeout` passes, like `read_data`.
+    pub fn receive_frame(
+        frames: &Receiver<Vec<u8>>,
+        timeout: Option<Duration>,
+    ) -> Result<Vec<u8>, RhustAppError> {
+        let frame = match timeout {
+            Some(timeout) => frames.recv_timeout(timeout),
+            None => frames.recv().map_err(|_| RecvTimeoutError::Disconnected),
+        };
+        match frame {
+            Ok(frame) => Ok(frame),
+            Err(RecvTimeoutError::Timeout) => Err(new_rhustapp_error(
+                "failed to read data",
+                Some(SocketError::ReadTimeout.to_string()),
+            )
+            .with_kind(ErrorKind::Socket(SocketError::ReadTimeout))),
+            Err(RecvTimeoutError::Disconnected) => Err(new_rhustapp_error(
+                "failed to read data",
+                Some(SocketError::SocketClosed.to_string()),
+            )
+            .with_kind(ErrorKind::Socket(SocketError::SocketClosed))),
+        }
+    }
+
+    /// Sends a frame, prefixed with its 3-byte big-endian length. The header is sent
+    /// along with the first frame of every connection.
+    ///
+    /// Failed sends aren't retried here, as the frames after the handshake can't be sent on
+    /// a new connection, see `Client::with_send_retry` instead.
+    pub fn send_frame(&mut self, data: &[u8]) -> Result<(), RhustAppError> {
+        if data.len() >= FRAME_MAX_SIZE {
+            return Err(new_rhustapp_error(
+                "failed to send frame",
+                Some(SocketError::FrameTooLarge.to_string()),
+            )
+            .with_kind(ErrorKind::Socket(SocketError::FrameTooLarge)));
+        };
+
+        let header = match &self.header {
+            Some(header) if !self.header_sent => &header[..],
+            _ => &[],
+        };
+        let length = data.len().to_be_bytes();
+        let frame = [header, &length[length.len() - FRAME_LENGTH_SIZE..], data].concat();
 
+        self.write_data(&frame)?;
+        self.header_sent = true;
         Ok(())
     }
 
-    fn build_connnection_request() -> Result<tungstenite::http::Request<()>, RhustAppError> {
-        let ws_uri = URL.parse::<Uri>().map_err(|err| {
+    /// Shuts down the writing half of the connection, so that the next write fails like it
+    /// would on a broken connection.
+    #[cfg(test)]
+    pub(crate) fn break_connection(&self) {
+        let websocket = self.connection.as_ref().unwrap().lock_for_write();
+        tcp_stream(&websocket)
+            .unwrap()
+            .shutdown(Shutdown::Write)
+            .unwrap();
+    }
+
+    fn write_data(&mut self, data: &[u8]) -> Result<(), RhustAppError> {
+        let connection = self.connection.as_ref().ok_or_else(|| {
+            new_rhustapp_error(
+                "failed to send data",
+                Some(SocketError::SocketClosed.to_string()),
+            )
+        })?;
+
+        connection
+            .lock_for_write()
+            .write_message(tungstenite::Message::Binary(data.to_vec()))
+            .map_err(|err| {
+                let transient = matches!(
+                    err,
+                    tungstenite::Error::Io(_)
+                        | tungstenite::Error::ConnectionClosed
+                        | tungstenite::Error::AlreadyClosed
+                );
+                let err = new_rhustapp_error("failed to send data", Some(err.to_string()));
+                if transient {
+                    err.with_kind(ErrorKind::Socket(SocketError::WriteFailed))
+                } else {
+                    err
+                }
+            })
+    }
+
+    fn build_connnection_request(
+        url: &str,
+        headers: &[(String, String)],
+    ) -> Result<tungstenite::http::Request<()>, RhustAppError> {
+        let ws_uri = url.parse::<Uri>().map_err(|err| {
             new_rhustapp_error("failed to parse URL into Uri", Some(err.to_string()))
         })?;
 
@@ -109,7 +565,7 @@ impl FrameSocket {
             .map(|idx| authority.split_at(idx + 1).1)
             .unwrap_or_else(|| authority);
 
-        let ws_request = tungstenite::http::Request::builder()
+        let mut ws_request = tungstenite::http::Request::builder()
             .method("GET")
             .header("Host", host)
             .header("Connection", "Upgrade")
@@ -129,8 +585,825 @@ impl FrameSocket {
                 )
             })?;
 
+        for (name, value) in headers {
+            let name = HeaderName::from_bytes(name.as_bytes()).map_err(|err| {
+                new_rhustapp_error(
+                    &format!("invalid websocket header name '{name}'"),
+                    Some(err.to_string()),
+                )
+            })?;
+            let value = HeaderValue::from_str(value).map_err(|err| {
+                new_rhustapp_error(
+                    &format!("invalid value for websocket header '{name}'"),
+                    Some(err.to_string()),
+                )
+            })?;
+            ws_request.headers_mut().insert(name, value);
+        }
+
         Ok(ws_request)
     }
 
-    fn read_pump(&mut self) {}
+    /// Reads from the websocket until the connection is closed, delivering the received
+    /// frames through the channel. It runs in its own thread, which `connect` starts.
+    fn read_pump(connection: Arc<SharedConnection>, frames: Sender<Vec<u8>>) {
+        let mut reader = FrameReader::default();
+        loop {
+            while connection.waiting_writers.load(Ordering::SeqCst) > 0 {
+                thread::yield_now();
+            }
+
+            let message = connection.websocket.lock().unwrap().read_message();
+            let data = match message {
+                Ok(tungstenite::Message::Binary(data)) => data,
+                Ok(tungstenite::Message::Close(_)) => return,
+                Ok(_) => continue,
+                Err(tungstenite::Error::Io(err))
+                    if matches!(
+                        err.kind(),
+                        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                    ) =>
+                {
+                    continue
+                }
+                Err(err) => {
+                    log::debug!("stopping read pump: {err}");
+                    return;
+                }
+            };
+
+            let received = match reader.process(&data) {
+                Ok(received) => received,
+                Err(err) => {
+                    log::warn!("closing connection: {err}");
+                    if let Some(stream) = tcp_stream(&connection.lock_for_write()) {
+                        let _ = stream.shutdown(Shutdown::Both);
+                    };
+                    return;
+                }
+            };
+            for frame in received {
+                // The receiver is dropped when the socket is closed.
+                if frames.send(frame).is_err() {
+                    return;
+                };
+            }
+        }
+    }
+}
+
+impl Drop for FrameSocket {
+    fn drop(&mut self) {
+        self.close(0);
+    }
+}
+
+/// The length of the Curve25519 keys and the SHA-256 hashes used in the Noise handshake.
+const NOISE_KEY_LENGTH: usize = 32;
+
+/// Generates the AES-GCM nonce for the given counter, which is the counter in big-endian
+/// padded with zeroes to 12 bytes.
+fn generate_iv(counter: u32) -> [u8; 12] {
+    let mut iv = [0u8; 12];
+    iv[8..].copy_from_slice(&counter.to_be_bytes());
+    iv
+}
+
+/// Derives the write key and the read key from the data with HKDF-SHA256.
+fn extract_and_expand(salt: &[u8], data: &[u8]) -> ([u8; 32], [u8; 32]) {
+    let mut output = [0u8; 2 * NOISE_KEY_LENGTH];
+    Hkdf::<Sha256>::new(Some(salt), data)
+        .expand(&[], &mut output)
+        .expect("64 bytes is a valid HKDF-SHA256 output length");
+
+    let mut write = [0u8; NOISE_KEY_LENGTH];
+    let mut read = [0u8; NOISE_KEY_LENGTH];
+    write.copy_from_slice(&output[..NOISE_KEY_LENGTH]);
+    read.copy_from_slice(&output[NOISE_KEY_LENGTH..]);
+    (write, read)
+}
+
+fn new_cipher(key: &[u8]) -> Aes256Gcm {
+    Aes256Gcm::new(Key::from_slice(key))
+}
+
+/// The state of the `Noise_XX_25519_AESGCM_SHA256` handshake, which is done over the
+/// `FrameSocket` before anything else is sent. Once the handshake is done, `finish` returns
+/// the `NoiseSocket` that encrypts and decrypts the frames.
+///
+/// The client side of the XX pattern is done with `client_hello`, `process_server_hello`
+/// and `client_finish`, the lower level methods are the Noise primitives they're built on.
+pub struct NoiseHandshake {
+    hash: [u8; NOISE_KEY_LENGTH],
+    salt: [u8; NOISE_KEY_LENGTH],
+    key: Aes256Gcm,
+    counter: u32,
+}
+
+impl NoiseHandshake {
+    /// Starts a handshake with the given pattern (`NOISE_START_PATTERN`), authenticating
+    /// the header that is sent before the first frame as the prologue.
+    pub fn new(pattern: &str, header: &[u8]) -> Self {
+        let mut hash = [0u8; NOISE_KEY_LENGTH];
+        if pattern.len() == NOISE_KEY_LENGTH {
+            hash.copy_from_slice(pattern.as_bytes());
+        } else {
+            hash.copy_from_slice(&Sha256::digest(pattern.as_bytes()));
+        };
+
+        let mut handshake = Self {
+            hash,
+            salt: hash,
+            key: new_cipher(&hash),
+            counter: 0,
+        };
+        handshake.authenticate(header);
+        handshake
+    }
+
+    /// Mixes the data into the handshake hash.
+    pub fn authenticate(&mut self, data: &[u8]) {
+        let mut hasher = Sha256::new();
+        hasher.update(self.hash);
+        hasher.update(data);
+        self.hash.copy_from_slice(&hasher.finalize());
+    }
+
+    fn next_iv(&mut self) -> [u8; 12] {
+        let iv = generate_iv(self.counter);
+        self.counter += 1;
+        iv
+    }
+
+    /// Encrypts the data with the current key, using the handshake hash as the associated
+    /// data, and mixes the ciphertext into the hash.
+    pub fn encrypt(&mut self, plaintext: &[u8]) -> Result<Vec<u8>, RhustAppError> {
+        let iv = self.next_iv();
+        let ciphertext = self
+            .key
+            .encrypt(
+                Nonce::from_slice(&iv),
+                Payload {
+                    msg: plaintext,
+                    aad: &self.hash,
+                },
+            )
+            .map_err(|err| {
+                new_rhustapp_error("failed to encrypt handshake data", Some(err.to_string()))
+            })?;
+        self.authenticate(&ciphertext);
+        Ok(ciphertext)
+    }
+
+    /// Decrypts the data with the current key, using the handshake hash as the associated
+    /// data, and mixes the ciphertext into the hash.
+    pub fn decrypt(&mut self, ciphertext: &[u8]) -> Result<Vec<u8>, RhustAppError> {
+        let iv = self.next_iv();
+        let plaintext = self
+            .key
+            .decrypt(
+                Nonce::from_slice(&iv),
+                Payload {
+                    msg: ciphertext,
+                    aad: &self.hash,
+                },
+            )
+            .map_err(|err| {
+                new_rhustapp_error("failed to decrypt handshake data", Some(err.to_string()))
+            })?;
+        self.authenticate(ciphertext);
+        Ok(plaintext)
+    }
+
+    /// Mixes the Diffie-Hellman shared secret of the keys into the key.
+    pub fn mix_shared_secret_into_key(
+        &mut self,
+        private_key: &PrivateKey,
+        public_key: &PublicKey,
+    ) -> Result<(), RhustAppError> {
+        let secret = private_key.calculate_agreement(public_key).map_err(|err| {
+            new_rhustapp_error("failed to calculate shared secret", Some(err.to_string()))
+        })?;
+        self.mix_into_key(&secret);
+        Ok(())
+    }
+
+    /// Derives a new salt and key from the current salt and the data, resetting the counter.
+    pub fn mix_into_key(&mut self, data: &[u8]) {
+        self.counter = 0;
+        let (write, read) = extract_and_expand(&self.salt, data);
+        self.salt = write;
+        self.key = new_cipher(&read);
+    }
+
+    /// Finishes the handshake, deriving the keys of the `NoiseSocket` from the salt.
+    pub fn finish(self) -> NoiseSocket {
+        let (write, read) = extract_and_expand(&self.salt, &[]);
+        NoiseSocket::new(&write, &read)
+    }
+
+    /// Starts the client side of the handshake with the ephemeral key pair, which must be
+    /// generated for every connection. Returns the handshake and the `ClientHello` message,
+    /// which is the first frame sent to the server.
+    pub fn client_hello(
+        header: &[u8],
+        ephemeral: &KeyPair,
+    ) -> Result<(Self, Vec<u8>), RhustAppError> {
+        let mut handshake = Self::new(NOISE_START_PATTERN, header);
+        let ephemeral_public = public_key_bytes(&ephemeral.public_key)?;
+        handshake.authenticate(ephemeral_public);
+
+        let mut hello = wa_proto::HandshakeClientHello::new();
+        hello.ephemeral = Some(ephemeral_public.to_vec());
+        let mut message = wa_proto::HandshakeMessage::new();
+        message.clientHello = MessageField::some(hello);
+        let data = message.write_to_bytes().map_err(|err| {
+            new_rhustapp_error("failed to marshal handshake message", Some(err.to_string()))
+        })?;
+
+        Ok((handshake, data))
+    }
+
+    /// Processes the `ServerHello` message that the server responds to the `ClientHello`
+    /// with, returning the ephemeral key of the server, which is needed for `client_finish`.
+    ///
+    /// The certificate chain of the server is checked to be issued for its static key. The
+    /// signatures of the certificates aren't verified.
+    pub fn process_server_hello(
+        &mut self,
+        ephemeral: &KeyPair,
+        data: &[u8],
+    ) -> Result<PublicKey, RhustAppError> {
+        let message = wa_proto::HandshakeMessage::parse_from_bytes(data).map_err(|err| {
+            new_rhustapp_error(
+                "failed to unmarshal handshake message",
+                Some(err.to_string()),
+            )
+        })?;
+        let hello = message
+            .serverHello
+            .into_option()
+            .ok_or_else(|| new_rhustapp_error("missing server hello in handshake message", None))?;
+
+        let server_ephemeral = hello.ephemeral.unwrap_or_default();
+        let static_ciphertext = hello.static_.unwrap_or_default();
+        let certificate_ciphertext = hello.payload.unwrap_or_default();
+        if server_ephemeral.len() != NOISE_KEY_LENGTH || static_ciphertext.len() != 48 {
+            return Err(new_rhustapp_error(
+                "failed to process server hello",
+                Some(format!(
+                    "unexpected key lengths: ephemeral {}, static {}",
+                    server_ephemeral.len(),
+                    static_ciphertext.len()
+                )),
+            ));
+        };
+
+        let server_ephemeral =
+            PublicKey::from_djb_public_key_bytes(&server_ephemeral).map_err(|err| {
+                new_rhustapp_error(
+                    "failed to parse server ephemeral key",
+                    Some(err.to_string()),
+                )
+            })?;
+        self.authenticate(public_key_bytes(&server_ephemeral)?);
+        self.mix_shared_secret_into_key(&ephemeral.private_key, &server_ephemeral)?;
+
+        let server_static = self.decrypt(&static_ciphertext)?;
+        let server_static =
+            PublicKey::from_djb_public_key_bytes(&server_static).map_err(|err| {
+                new_rhustapp_error("failed to parse server static key", Some(err.to_string()))
+            })?;
+        self.mix_shared_secret_into_key(&ephemeral.private_key, &server_static)?;
+
+        let certificate = self.decrypt(&certificate_ciphertext)?;
+        verify_server_certificate(&certificate, public_key_bytes(&server_static)?)?;
+
+        Ok(server_ephemeral)
+    }
+
+    /// Builds the `ClientFinish` message, which contains the static noise key of the client
+    /// and the client payload (the login or registration data), both encrypted.
+    pub fn client_finish(
+        &mut self,
+        noise_key: &KeyPair,
+        server_ephemeral: &PublicKey,
+        client_payload: &[u8],
+    ) -> Result<Vec<u8>, RhustAppError> {
+        let encrypted_key = self.encrypt(public_key_bytes(&noise_key.public_key)?)?;
+        self.mix_shared_secret_into_key(&noise_key.private_key, server_ephemeral)?;
+        let encrypted_payload = self.encrypt(client_payload)?;
+
+        let mut finish = wa_proto::HandshakeClientFinish::new();
+        finish.static_ = Some(encrypted_key);
+        finish.payload = Some(encrypted_payload);
+        let mut message = wa_proto::HandshakeMessage::new();
+        message.clientFinish = MessageField::some(finish);
+        message.write_to_bytes().map_err(|err| {
+            new_rhustapp_error("failed to marshal handshake message", Some(err.to_string()))
+        })
+    }
+}
+
+/// Checks that the leaf certificate of the chain is issued by the intermediate one and for
+/// the static key of the server.
+fn verify_server_certificate(data: &[u8], server_static: &[u8]) -> Result<(), RhustAppError> {
+    let parse_err = |err: protobuf::Error| {
+        new_rhustapp_error(
+            "failed to unmarshal server certificate",
+            Some(err.to_string()),
+        )
+    };
+    let chain = wa_proto::CertChain::parse_from_bytes(data).map_err(parse_err)?;
+    let intermediate = NoiseCertificateDetails::parse_from_bytes(
+        chain.intermediate.details.as_deref().unwrap_or_default(),
+    )
+    .map_err(parse_err)?;
+    let leaf = NoiseCertificateDetails::parse_from_bytes(
+        chain.leaf.details.as_deref().unwrap_or_default(),
+    )
+    .map_err(parse_err)?;
+
+    if leaf.issuerSerial() != intermediate.serial() {
+        return Err(new_rhustapp_error(
+            "invalid server certificate",
+            Some("leaf certificate isn't issued by the intermediate certificate".to_string()),
+        ));
+    };
+    if leaf.key() != server_static {
+        return Err(new_rhustapp_error(
+            "invalid server certificate",
+            Some("certificate key doesn't match the server static key".to_string()),
+        ));
+    };
+    Ok(())
+}
+
+/// Encrypts and decrypts the frames after the Noise handshake, see `NoiseHandshake::finish`.
+pub struct NoiseSocket {
+    write_key: Aes256Gcm,
+    read_key: Aes256Gcm,
+    write_counter: u32,
+    read_counter: u32,
+}
+
+impl NoiseSocket {
+    fn new(write_key: &[u8], read_key: &[u8]) -> Self {
+        Self {
+            write_key: new_cipher(write_key),
+            read_key: new_cipher(read_key),
+            write_counter: 0,
+            read_counter: 0,
+        }
+    }
+
+    /// Swaps the keys, turning the socket derived by the client into the one of the server.
+    #[cfg(test)]
+    pub(crate) fn into_responder(self) -> Self {
+        Self {
+            write_key: self.read_key,
+            read_key: self.write_key,
+            write_counter: 0,
+            read_counter: 0,
+        }
+    }
+
+    /// Encrypts a frame to be sent. Frames must be encrypted in the order they are sent.
+    ///
+    /// A frame that would be too large to send fails with `SocketError::FrameTooLarge`
+    /// without using up a counter, so that the following frames can still be decrypted.
+    pub fn encrypt_frame(&mut self, plaintext: &[u8]) -> Result<Vec<u8>, RhustAppError> {
+        let iv = generate_iv(self.write_counter);
+        let ciphertext = self
+            .write_key
+            .encrypt(Nonce::from_slice(&iv), plaintext)
+            .map_err(|err| new_rhustapp_error("failed to encrypt frame", Some(err.to_string())))?;
+        if ciphertext.len() >= FRAME_MAX_SIZE {
+            return Err(new_rhustapp_error(
+                "failed to encrypt frame",
+                Some(SocketError::FrameTooLarge.to_string()),
+            )
+            .with_kind(ErrorKind::Socket(SocketError::FrameTooLarge)));
+        };
+        self.write_counter += 1;
+        Ok(ciphertext)
+    }
+
+    /// Decrypts a received frame. Frames must be decrypted in the order they are received.
+    pub fn decrypt_frame(&mut self, ciphertext: &[u8]) -> Result<Vec<u8>, RhustAppError> {
+        let iv = generate_iv(self.read_counter);
+        let plaintext = self
+            .read_key
+            .decrypt(Nonce::from_slice(&iv), ciphertext)
+            .map_err(|err| new_rhustapp_error("failed to decrypt frame", Some(err.to_string())))?;
+        self.read_counter += 1;
+        Ok(plaintext)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpListener;
+
+    use native_tls::{Identity, TlsAcceptor};
+    use openssl::{
+        asn1::Asn1Time,
+        hash::MessageDigest,
+        pkey::PKey,
+        rsa::Rsa,
+        x509::{extension::SubjectAlternativeName, X509NameBuilder, X509},
+    };
+
+    use super::*;
+    use crate::testing::{certificate_chain, server_hello};
+
+    /// Generates a self-signed certificate for `localhost`, returning the PEM-encoded
+    /// certificate and PKCS #8 private key.
+    fn self_signed_certificate() -> (Vec<u8>, Vec<u8>) {
+        let key = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+
+        let mut name = X509NameBuilder::new().unwrap();
+        name.append_entry_by_text("CN", "localhost").unwrap();
+        let name = name.build();
+
+        let mut builder = X509::builder().unwrap();
+        builder.set_version(2).unwrap();
+        builder.set_subject_name(&name).unwrap();
+        builder.set_issuer_name(&name).unwrap();
+        builder.set_pubkey(&key).unwrap();
+        builder
+            .set_not_before(&Asn1Time::days_from_now(0).unwrap())
+            .unwrap();
+        builder
+            .set_not_after(&Asn1Time::days_from_now(1).unwrap())
+            .unwrap();
+        let san = SubjectAlternativeName::new()
+            .dns("localhost")
+            .build(&builder.x509v3_context(None, None))
+            .unwrap();
+        builder.append_extension(san).unwrap();
+        builder.sign(&key, MessageDigest::sha256()).unwrap();
+
+        (
+            builder.build().to_pem().unwrap(),
+            key.private_key_to_pem_pkcs8().unwrap(),
+        )
+    }
+
+    /// Starts a TLS websocket server for a single connection using the given certificate,
+    /// returning its URL.
+    fn serve_once(certificate: &[u8], key: &[u8]) -> String {
+        let acceptor = TlsAcceptor::new(Identity::from_pkcs8(certificate, key).unwrap()).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            if let Ok(stream) = acceptor.accept(stream) {
+                let _ = tungstenite::accept(stream);
+            };
+        });
+
+        format!("wss://localhost:{port}/ws/chat")
+    }
+
+    #[test]
+    fn test_pinned_certificate_matches() {
+        let (certificate, key) = self_signed_certificate();
+        let url = serve_once(&certificate, &key);
+
+        let mut socket = FrameSocket::new()
+            .with_url(&url)
+            .with_pinned_certificates(&[&certificate])
+            .unwrap();
+        assert!(socket.connect().is_ok());
+        assert!(socket.is_connected());
+    }
+
+    #[test]
+    fn test_wrong_pinned_certificate_fails() {
+        let (certificate, key) = self_signed_certificate();
+        let (other_certificate, _) = self_signed_certificate();
+        let url = serve_once(&certificate, &key);
+
+        let mut socket = FrameSocket::new()
+            .with_url(&url)
+            .with_pinned_certificates(&[&other_certificate])
+            .unwrap();
+        assert!(socket.connect().is_err());
+        assert!(!socket.is_connected());
+    }
+
+    #[test]
+    fn test_invalid_pinned_certificate() {
+        assert!(FrameSocket::new()
+            .with_pinned_certificates(&[b"not a certificate"])
+            .is_err());
+    }
+
+    #[test]
+    fn test_connection_state_transitions() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut websocket = tungstenite::accept(stream).unwrap();
+            // Read until the client closes the connection.
+            while websocket.read_message().is_ok() {}
+        });
+
+        let transitions = Arc::new(Mutex::new(Vec::new()));
+        let mut socket = {
+            let transitions = Arc::clone(&transitions);
+            FrameSocket::new()
+                .with_url(&format!("ws://127.0.0.1:{port}/ws/chat"))
+                .with_state_change_handler(Box::new(move |old, new| {
+                    transitions.lock().unwrap().push((old, new));
+                }))
+        };
+        assert_eq!(socket.state(), ConnectionState::Closed);
+        assert!(socket.handshake_complete().is_err());
+
+        socket.connect().unwrap();
+        assert_eq!(socket.state(), ConnectionState::Handshaking);
+        socket.handshake_complete().unwrap();
+        assert_eq!(socket.state(), ConnectionState::Connected);
+        socket.close(1000);
+        assert_eq!(socket.state(), ConnectionState::Closed);
+        assert!(!socket.is_connected());
+        server.join().unwrap();
+
+        use ConnectionState::*;
+        assert_eq!(
+            *transitions.lock().unwrap(),
+            vec![
+                (Closed, Connecting),
+                (Connecting, Handshaking),
+                (Handshaking, Connected),
+                (Connected, Closing),
+                (Closing, Closed),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_connection_state_failed_connect() {
+        // Bind and drop a listener to get a port that nothing listens on.
+        let port = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+
+        let mut socket = FrameSocket::new().with_url(&format!("ws://127.0.0.1:{port}/ws/chat"));
+        assert!(socket.connect().is_err());
+        assert_eq!(socket.state(), ConnectionState::Closed);
+    }
+
+    #[test]
+    fn test_read_timeout() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut websocket = tungstenite::accept(stream).unwrap();
+            // Stall without sending anything until the client closes the connection.
+            while websocket.read_message().is_ok() {}
+        });
+
+        let mut socket = FrameSocket::new()
+            .with_url(&format!("ws://127.0.0.1:{port}/ws/chat"))
+            .with_read_timeout(Duration::from_millis(100));
+        socket.connect().unwrap();
+
+        let started = std::time::Instant::now();
+        let err = socket.read_data().unwrap_err();
+        assert_eq!(*err.kind(), ErrorKind::Socket(SocketError::ReadTimeout));
+        assert!(started.elapsed() >= Duration::from_millis(100));
+        assert!(started.elapsed() < Duration::from_secs(5));
+
+        socket.close(1000);
+        server.join().unwrap();
+    }
+
+    fn frame(data: &[u8]) -> Vec<u8> {
+        let length = data.len().to_be_bytes();
+        [&length[length.len() - FRAME_LENGTH_SIZE..], data].concat()
+    }
+
+    #[test]
+    fn test_frame_reader() {
+        let mut reader = FrameReader::default();
+        let joined = [frame(b"first"), frame(b""), frame(b"third")].concat();
+        assert_eq!(
+            reader.process(&joined).unwrap(),
+            vec![b"first".to_vec(), vec![], b"third".to_vec()]
+        );
+
+        // A frame split in the middle of the length and of the data.
+        let split = frame(&[9; 300]);
+        assert!(reader.process(&split[..2]).unwrap().is_empty());
+        assert!(reader.process(&split[2..100]).unwrap().is_empty());
+        assert_eq!(reader.process(&split[100..]).unwrap(), vec![vec![9; 300]]);
+        assert!(reader.buffer.is_empty());
+    }
+
+    #[test]
+    fn test_read_pump_frames() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut websocket = tungstenite::accept(stream).unwrap();
+            let data = [frame(b"first"), frame(b"second"), frame(b"third")].concat();
+            for message in [&data[..5], &data[5..14], &data[14..]] {
+                websocket
+                    .write_message(tungstenite::Message::Binary(message.to_vec()))
+                    .unwrap();
+            }
+            websocket.close(None).unwrap();
+            while websocket.read_message().is_ok() {}
+        });
+
+        let mut socket = FrameSocket::new().with_url(&format!("ws://127.0.0.1:{port}/ws/chat"));
+        socket.connect().unwrap();
+        // Sending while the read pump is waiting for data isn't blocked by it.
+        socket.send_frame(b"hello").unwrap();
+        assert_eq!(socket.read_data().unwrap(), b"first");
+
+        let frames = socket.frames().unwrap();
+        assert!(socket.frames().is_none());
+        assert_eq!(
+            *socket.read_data().unwrap_err().kind(),
+            ErrorKind::Socket(SocketError::SocketClosed)
+        );
+        let timeout = Duration::from_secs(5);
+        assert_eq!(frames.recv_timeout(timeout).unwrap(), b"second");
+        assert_eq!(frames.recv_timeout(timeout).unwrap(), b"third");
+        // The channel is disconnected once the server closes the connection.
+        assert_eq!(
+            frames.recv_timeout(timeout),
+            Err(RecvTimeoutError::Disconnected)
+        );
+
+        socket.close(0);
+        server.join().unwrap();
+    }
+
+    /// Starts a websocket server that accepts up to `connections` connections and reports
+    /// the index of the connection and the data of each binary message it receives.
+    fn serve_messages(connections: usize) -> (String, std::sync::mpsc::Receiver<(usize, Vec<u8>)>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let (sender, receiver) = std::sync::mpsc::channel();
+
+        thread::spawn(move || {
+            for index in 0..connections {
+                let (stream, _) = listener.accept().unwrap();
+                let sender = sender.clone();
+                thread::spawn(move || {
+                    let mut websocket = tungstenite::accept(stream).unwrap();
+                    while let Ok(message) = websocket.read_message() {
+                        if let tungstenite::Message::Binary(data) = message {
+                            let _ = sender.send((index, data));
+                        };
+                    }
+                });
+            }
+        });
+
+        (format!("ws://127.0.0.1:{port}/ws/chat"), receiver)
+    }
+
+    #[test]
+    fn test_send_frame() {
+        let (url, received) = serve_messages(2);
+        let mut socket = FrameSocket::new().with_url(&url);
+        let timeout = Duration::from_secs(5);
+
+        socket.connect().unwrap();
+        socket.send_frame(b"first").unwrap();
+        socket.send_frame(b"second").unwrap();
+        assert_eq!(
+            received.recv_timeout(timeout).unwrap(),
+            (0, [&get_wa_header()[..], &[0, 0, 5], b"first"].concat())
+        );
+        assert_eq!(
+            received.recv_timeout(timeout).unwrap(),
+            (0, [&[0, 0, 6][..], b"second"].concat())
+        );
+
+        let err = socket.send_frame(&vec![0; FRAME_MAX_SIZE]).unwrap_err();
+        assert_eq!(*err.kind(), ErrorKind::Socket(SocketError::FrameTooLarge));
+
+        // The header is sent again on a new connection.
+        socket.close(1000);
+        socket.connect().unwrap();
+        socket.send_frame(&[7; 300]).unwrap();
+        assert_eq!(
+            received.recv_timeout(timeout).unwrap(),
+            (1, [&get_wa_header()[..], &[0, 1, 44], &[7; 300]].concat())
+        );
+
+        socket.break_connection();
+        let err = socket.send_frame(b"lost").unwrap_err();
+        assert_eq!(*err.kind(), ErrorKind::Socket(SocketError::WriteFailed));
+    }
+
+    #[test]
+    fn test_connection_request_headers() {
+        let socket = FrameSocket::new()
+            .with_header("Origin", "https://proxy.example.com")
+            .with_header("X-Test", "first")
+            .with_header("x-test", "second");
+        let request = FrameSocket::build_connnection_request(URL, &socket.headers).unwrap();
+
+        let headers = request.headers();
+        assert_eq!(headers["Origin"], "https://proxy.example.com");
+        assert_eq!(headers.get_all("Origin").iter().count(), 1);
+        assert_eq!(headers["X-Test"], "second");
+        assert_eq!(headers["Host"], "web.whatsapp.com");
+        assert_eq!(headers["Upgrade"], "websocket");
+
+        let default = FrameSocket::build_connnection_request(URL, &[]).unwrap();
+        assert_eq!(default.headers()["Origin"], ORIGIN);
+
+        let invalid = [("Bad Header".to_string(), "value".to_string())];
+        assert!(FrameSocket::build_connnection_request(URL, &invalid).is_err());
+    }
+
+    #[test]
+    fn test_noise_handshake() {
+        let mut csprng = rand::rngs::OsRng;
+        let client_ephemeral = KeyPair::generate(&mut csprng);
+        let noise_key = KeyPair::generate(&mut csprng);
+        let server_ephemeral = KeyPair::generate(&mut csprng);
+        let server_static = KeyPair::generate(&mut csprng);
+
+        let (mut client, hello) =
+            NoiseHandshake::client_hello(&get_wa_header(), &client_ephemeral).unwrap();
+        let certificate =
+            certificate_chain(server_static.public_key.public_key_bytes().unwrap(), 1);
+        let (mut server, _, response) =
+            server_hello(&hello, &server_ephemeral, &server_static, &certificate);
+
+        let received_ephemeral = client
+            .process_server_hello(&client_ephemeral, &response)
+            .unwrap();
+        let finish = client
+            .client_finish(&noise_key, &received_ephemeral, b"client payload")
+            .unwrap();
+
+        // The server decrypts the client's static key and payload.
+        let finish = wa_proto::HandshakeMessage::parse_from_bytes(&finish).unwrap();
+        let client_static = server.decrypt(finish.clientFinish.static_()).unwrap();
+        assert_eq!(
+            client_static,
+            noise_key.public_key.public_key_bytes().unwrap()
+        );
+        server
+            .mix_shared_secret_into_key(
+                &server_ephemeral.private_key,
+                &PublicKey::from_djb_public_key_bytes(&client_static).unwrap(),
+            )
+            .unwrap();
+        assert_eq!(
+            server.decrypt(finish.clientFinish.payload()).unwrap(),
+            b"client payload"
+        );
+
+        // Both sides derive the same keys, the server reads with the client's write key.
+        let mut client = client.finish();
+        let mut server = server.finish().into_responder();
+        for frame in [b"first".as_slice(), b"second".as_slice()] {
+            let encrypted = client.encrypt_frame(frame).unwrap();
+            assert_eq!(server.decrypt_frame(&encrypted).unwrap(), frame);
+        }
+        let encrypted = server.encrypt_frame(b"reply").unwrap();
+        assert_eq!(client.decrypt_frame(&encrypted).unwrap(), b"reply");
+        // A replayed frame can't be decrypted, as the counter has moved on.
+        assert!(client.decrypt_frame(&encrypted).is_err());
+    }
+
+    #[test]
+    fn test_noise_handshake_invalid_certificate() {
+        let mut csprng = rand::rngs::OsRng;
+        let client_ephemeral = KeyPair::generate(&mut csprng);
+        let server_ephemeral = KeyPair::generate(&mut csprng);
+        let server_static = KeyPair::generate(&mut csprng);
+        let server_static_bytes = server_static.public_key.public_key_bytes().unwrap();
+
+        for certificate in [
+            certificate_chain(&[7; 32], 1),
+            certificate_chain(server_static_bytes, 3),
+        ] {
+            let (mut client, hello) =
+                NoiseHandshake::client_hello(&get_wa_header(), &client_ephemeral).unwrap();
+            let (_, _, response) =
+                server_hello(&hello, &server_ephemeral, &server_static, &certificate);
+            assert!(client
+                .process_server_hello(&client_ephemeral, &response)
+                .is_err());
+        }
+    }
 }
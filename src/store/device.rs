@@ -0,0 +1,232 @@
+use libsignal_protocol::{KeyPair, PublicKey, SignedPreKeyRecord};
+use md5::{Digest, Md5};
+use protobuf::{EnumOrUnknown, Message, MessageField};
+use rand::RngCore;
+
+use crate::{
+    binary::proto::{self as wa_proto, client_payload, device_props},
+    new_rhustapp_error,
+    prekeys::DJB_TYPE,
+    types::JID,
+    RhustAppError,
+};
+
+/// The version of WhatsApp web that the client identifies as.
+pub const WA_VERSION: [u32; 3] = [2, 3000, 1015901307];
+
+/// The name of the operating system shown in the list of linked devices on the phone.
+pub const DEVICE_OS: &str = "rhustapp";
+
+/// Returns the MD5 hash of the version string, which is sent as the build hash when
+/// registering a new device.
+pub fn wa_version_hash() -> Vec<u8> {
+    let version = WA_VERSION.map(|part| part.to_string()).join(".");
+    Md5::digest(version.as_bytes()).to_vec()
+}
+
+/// Returns the raw 32-byte form of a Curve25519 public key, as used in the handshake and
+/// the QR code.
+pub fn public_key_bytes(key: &PublicKey) -> Result<&[u8], RhustAppError> {
+    key.public_key_bytes()
+        .map_err(|err| new_rhustapp_error("failed to read public key", Some(err.to_string())))
+}
+
+/// The keys and the account details of this companion device. A new device is generated
+/// with `Device::new`, and gets its `id` and `account` when it's paired.
+#[derive(Clone)]
+pub struct Device {
+    /// The static key of the Noise handshake.
+    pub noise_key: KeyPair,
+    pub identity_key: KeyPair,
+    pub signed_pre_key: SignedPreKeyRecord,
+    pub registration_id: u32,
+    /// The secret shared with the phone through the QR code, which the phone uses to sign
+    /// the device identity.
+    pub adv_secret_key: [u8; 32],
+
+    /// The JID of this device, set once it's paired.
+    pub id: Option<JID>,
+    /// The device identity signed by the phone, set once it's paired.
+    pub account: Option<wa_proto::ADVSignedDeviceIdentity>,
+    pub platform: String,
+    pub business_name: String,
+    pub push_name: String,
+}
+
+impl Default for Device {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Device {
+    /// Generates the keys of a new, unpaired device.
+    pub fn new() -> Self {
+        let mut csprng = rand::rngs::OsRng;
+        let identity_key = KeyPair::generate(&mut csprng);
+        let signed_key = KeyPair::generate(&mut csprng);
+        let signature = identity_key
+            .private_key
+            .calculate_signature(&signed_key.public_key.serialize(), &mut csprng)
+            .expect("signing with a generated key should not fail");
+        let mut adv_secret_key = [0u8; 32];
+        csprng.fill_bytes(&mut adv_secret_key);
+
+        Self {
+            noise_key: KeyPair::generate(&mut csprng),
+            identity_key,
+            signed_pre_key: SignedPreKeyRecord::new(1.into(), 0, &signed_key, &signature),
+            registration_id: csprng.next_u32(),
+            adv_secret_key,
+            id: None,
+            account: None,
+            platform: String::new(),
+            business_name: String::new(),
+            push_name: String::new(),
+        }
+    }
+
+    /// Builds the payload sent at the end of the Noise handshake: the login payload if the
+    /// device is paired, or the registration payload that starts pairing otherwise.
+    pub fn client_payload(&self) -> Result<wa_proto::ClientPayload, RhustAppError> {
+        match &self.id {
+            Some(id) => self.login_payload(id),
+            None => self.registration_payload(),
+        }
+    }
+
+    fn login_payload(&self, id: &JID) -> Result<wa_proto::ClientPayload, RhustAppError> {
+        let username = id.user_int().ok_or_else(|| {
+            new_rhustapp_error(
+                "failed to build login payload",
+                Some(format!("device JID {} doesn't have a numeric user", id)),
+            )
+        })?;
+
+        let mut payload = base_client_payload();
+        payload.username = Some(username);
+        payload.device = Some(id.device.unwrap_or_default().into());
+        payload.passive = Some(true);
+        Ok(payload)
+    }
+
+    fn registration_payload(&self) -> Result<wa_proto::ClientPayload, RhustAppError> {
+        let map_signal_err = |err: libsignal_protocol::SignalProtocolError| {
+            new_rhustapp_error("failed to read signed prekey", Some(err.to_string()))
+        };
+        let signed_pre_key_id: u32 = self.signed_pre_key.id().map_err(map_signal_err)?.into();
+        let signed_pre_key = self.signed_pre_key.public_key().map_err(map_signal_err)?;
+
+        let mut registration = client_payload::DevicePairingRegistrationData::new();
+        registration.eRegid = Some(self.registration_id.to_be_bytes().to_vec());
+        registration.eKeytype = Some(vec![DJB_TYPE]);
+        registration.eIdent = Some(public_key_bytes(&self.identity_key.public_key)?.to_vec());
+        registration.eSkeyId = Some(signed_pre_key_id.to_be_bytes()[1..].to_vec());
+        registration.eSkeyVal = Some(public_key_bytes(&signed_pre_key)?.to_vec());
+        registration.eSkeySig = Some(self.signed_pre_key.signature().map_err(map_signal_err)?);
+        registration.buildHash = Some(wa_version_hash());
+        registration.deviceProps = Some(device_props_bytes()?);
+
+        let mut payload = base_client_payload();
+        payload.devicePairingData = MessageField::some(registration);
+        payload.passive = Some(false);
+        Ok(payload)
+    }
+}
+
+fn base_client_payload() -> wa_proto::ClientPayload {
+    let mut app_version = client_payload::user_agent::AppVersion::new();
+    app_version.primary = Some(WA_VERSION[0]);
+    app_version.secondary = Some(WA_VERSION[1]);
+    app_version.tertiary = Some(WA_VERSION[2]);
+
+    let mut user_agent = client_payload::UserAgent::new();
+    user_agent.platform = Some(EnumOrUnknown::new(
+        client_payload::user_agent::Platform::WEB,
+    ));
+    user_agent.releaseChannel = Some(EnumOrUnknown::new(
+        client_payload::user_agent::ReleaseChannel::RELEASE,
+    ));
+    user_agent.appVersion = MessageField::some(app_version);
+    user_agent.mcc = Some("000".to_string());
+    user_agent.mnc = Some("000".to_string());
+    user_agent.osVersion = Some("0.1".to_string());
+    user_agent.manufacturer = Some(String::new());
+    user_agent.device = Some("Desktop".to_string());
+    user_agent.osBuildNumber = Some("0.1".to_string());
+    user_agent.localeLanguageIso6391 = Some("en".to_string());
+    user_agent.localeCountryIso31661Alpha2 = Some("en".to_string());
+
+    let mut web_info = client_payload::WebInfo::new();
+    web_info.webSubPlatform = Some(EnumOrUnknown::new(
+        client_payload::web_info::WebSubPlatform::WEB_BROWSER,
+    ));
+
+    let mut payload = wa_proto::ClientPayload::new();
+    payload.userAgent = MessageField::some(user_agent);
+    payload.webInfo = MessageField::some(web_info);
+    payload.connectType = Some(EnumOrUnknown::new(
+        client_payload::ConnectType::WIFI_UNKNOWN,
+    ));
+    payload.connectReason = Some(EnumOrUnknown::new(
+        client_payload::ConnectReason::USER_ACTIVATED,
+    ));
+    payload
+}
+
+fn device_props_bytes() -> Result<Vec<u8>, RhustAppError> {
+    let mut version = device_props::AppVersion::new();
+    version.primary = Some(0);
+    version.secondary = Some(1);
+    version.tertiary = Some(0);
+
+    let mut props = wa_proto::DeviceProps::new();
+    props.os = Some(DEVICE_OS.to_string());
+    props.version = MessageField::some(version);
+    props.platformType = Some(EnumOrUnknown::new(device_props::PlatformType::UNKNOWN));
+    props.requireFullSync = Some(false);
+    props
+        .write_to_bytes()
+        .map_err(|err| new_rhustapp_error("failed to marshal device props", Some(err.to_string())))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_registration_payload() {
+        let device = Device::new();
+        let payload = device.client_payload().unwrap();
+        assert!(!payload.passive());
+        assert!(payload.username.is_none());
+
+        let registration = payload.devicePairingData.unwrap();
+        assert_eq!(registration.eRegid(), device.registration_id.to_be_bytes());
+        assert_eq!(registration.eKeytype(), [DJB_TYPE]);
+        assert_eq!(registration.eSkeyId(), [0, 0, 1]);
+        assert_eq!(registration.buildHash().len(), 16);
+
+        // The signed prekey is signed by the identity key.
+        let mut signed_key = vec![DJB_TYPE];
+        signed_key.extend_from_slice(registration.eSkeyVal());
+        assert!(PublicKey::from_djb_public_key_bytes(registration.eIdent())
+            .unwrap()
+            .verify_signature(&signed_key, registration.eSkeySig())
+            .unwrap());
+    }
+
+    #[test]
+    fn test_login_payload() {
+        let mut device = Device::new();
+        device.id = Some(JID::new_ad("919876543210", 0, 12));
+        let payload = device.client_payload().unwrap();
+        assert_eq!(payload.username(), 919876543210);
+        assert_eq!(payload.device(), 12);
+        assert!(payload.passive());
+        assert!(payload.devicePairingData.is_none());
+
+        device.id = Some(JID::new_ad("not a number", 0, 12));
+        assert!(device.client_payload().is_err());
+    }
+}
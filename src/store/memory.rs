@@ -0,0 +1,453 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    str::FromStr,
+    sync::Mutex,
+};
+
+use libsignal_protocol::PreKeyRecord;
+use time::OffsetDateTime;
+
+use crate::{
+    store::{new_pre_key, AppStateMutationMAC, AppStateSyncKey, Device, DeviceStore},
+    types::{ContactInfo, LocalChatSettings, JID},
+    RhustAppError,
+};
+
+#[derive(Default)]
+struct Contents {
+    device: Option<Device>,
+    identities: HashMap<String, [u8; 32]>,
+    sessions: HashMap<String, Vec<u8>>,
+    /// The prekeys by id, along with whether they were uploaded.
+    pre_keys: BTreeMap<u32, (PreKeyRecord, bool)>,
+    /// The sender keys by group and user.
+    sender_keys: HashMap<(String, String), Vec<u8>>,
+    app_state_sync_keys: HashMap<Vec<u8>, AppStateSyncKey>,
+    app_state_versions: HashMap<String, (u64, [u8; 128])>,
+    /// The latest version and value MAC of the mutations by collection and index MAC.
+    app_state_mutation_macs: HashMap<(String, Vec<u8>), (u64, Vec<u8>)>,
+    /// The contacts and the chat settings by the string form of their JID.
+    contacts: HashMap<String, ContactInfo>,
+    chat_settings: HashMap<String, LocalChatSettings>,
+}
+
+/// Returns true if the address is one of the devices of the user.
+fn is_device_of(address: &str, user: &str) -> bool {
+    address
+        .strip_prefix(user)
+        .is_some_and(|device| device.starts_with('.'))
+}
+
+fn default_chat_settings() -> LocalChatSettings {
+    LocalChatSettings {
+        muted_until: OffsetDateTime::UNIX_EPOCH,
+        pinned: false,
+        archived: false,
+    }
+}
+
+/// A `DeviceStore` that only keeps everything in memory, for tests and bots that don't need
+/// to survive a restart.
+#[derive(Default)]
+pub struct MemoryStore {
+    contents: Mutex<Contents>,
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn put_contact_field(
+        &self,
+        user: &JID,
+        value: &str,
+        field: impl Fn(&mut ContactInfo) -> &mut String,
+    ) -> Option<String> {
+        let mut contents = self.contents.lock().unwrap();
+        let contact = field(contents.contacts.entry(user.to_string()).or_default());
+        if contact == value {
+            return None;
+        };
+        Some(std::mem::replace(contact, value.to_string()))
+    }
+
+    fn update_chat_settings(&self, chat: &JID, update: impl FnOnce(&mut LocalChatSettings)) {
+        update(
+            self.contents
+                .lock()
+                .unwrap()
+                .chat_settings
+                .entry(chat.to_string())
+                .or_insert_with(default_chat_settings),
+        );
+    }
+}
+
+impl DeviceStore for MemoryStore {
+    fn load_device(&self) -> Result<Option<Device>, RhustAppError> {
+        Ok(self.contents.lock().unwrap().device.clone())
+    }
+
+    fn save_device(&self, device: &Device) -> Result<(), RhustAppError> {
+        self.contents.lock().unwrap().device = Some(device.clone());
+        Ok(())
+    }
+
+    fn delete_device(&self) -> Result<(), RhustAppError> {
+        *self.contents.lock().unwrap() = Contents::default();
+        Ok(())
+    }
+
+    fn put_identity(&self, address: &str, key: [u8; 32]) -> Result<(), RhustAppError> {
+        self.contents
+            .lock()
+            .unwrap()
+            .identities
+            .insert(address.to_string(), key);
+        Ok(())
+    }
+
+    fn get_identity(&self, address: &str) -> Result<Option<[u8; 32]>, RhustAppError> {
+        Ok(self
+            .contents
+            .lock()
+            .unwrap()
+            .identities
+            .get(address)
+            .copied())
+    }
+
+    fn delete_identity(&self, address: &str) -> Result<(), RhustAppError> {
+        self.contents.lock().unwrap().identities.remove(address);
+        Ok(())
+    }
+
+    fn delete_all_identities(&self, user: &str) -> Result<(), RhustAppError> {
+        self.contents
+            .lock()
+            .unwrap()
+            .identities
+            .retain(|address, _| !is_device_of(address, user));
+        Ok(())
+    }
+
+    fn get_session(&self, address: &str) -> Result<Option<Vec<u8>>, RhustAppError> {
+        Ok(self.contents.lock().unwrap().sessions.get(address).cloned())
+    }
+
+    fn put_session(&self, address: &str, session: &[u8]) -> Result<(), RhustAppError> {
+        self.contents
+            .lock()
+            .unwrap()
+            .sessions
+            .insert(address.to_string(), session.to_vec());
+        Ok(())
+    }
+
+    fn delete_session(&self, address: &str) -> Result<(), RhustAppError> {
+        self.contents.lock().unwrap().sessions.remove(address);
+        Ok(())
+    }
+
+    fn delete_all_sessions(&self, user: &str) -> Result<(), RhustAppError> {
+        self.contents
+            .lock()
+            .unwrap()
+            .sessions
+            .retain(|address, _| !is_device_of(address, user));
+        Ok(())
+    }
+
+    fn get_or_gen_pre_keys(&self, count: u32) -> Result<Vec<PreKeyRecord>, RhustAppError> {
+        let mut contents = self.contents.lock().unwrap();
+        let mut keys: Vec<PreKeyRecord> = contents
+            .pre_keys
+            .values()
+            .filter(|(_, uploaded)| !uploaded)
+            .map(|(key, _)| key.clone())
+            .take(count as usize)
+            .collect();
+
+        let last_id = contents.pre_keys.keys().next_back().copied().unwrap_or(0);
+        for id in (last_id + 1..).take(count as usize - keys.len()) {
+            let key = new_pre_key(id);
+            contents.pre_keys.insert(id, (key.clone(), false));
+            keys.push(key);
+        }
+        Ok(keys)
+    }
+
+    fn get_pre_key(&self, id: u32) -> Result<Option<PreKeyRecord>, RhustAppError> {
+        Ok(self
+            .contents
+            .lock()
+            .unwrap()
+            .pre_keys
+            .get(&id)
+            .map(|(key, _)| key.clone()))
+    }
+
+    fn remove_pre_key(&self, id: u32) -> Result<(), RhustAppError> {
+        self.contents.lock().unwrap().pre_keys.remove(&id);
+        Ok(())
+    }
+
+    fn mark_pre_keys_as_uploaded(&self, up_to_id: u32) -> Result<(), RhustAppError> {
+        for (_, uploaded) in self
+            .contents
+            .lock()
+            .unwrap()
+            .pre_keys
+            .range_mut(..=up_to_id)
+            .map(|(_, key)| key)
+        {
+            *uploaded = true;
+        }
+        Ok(())
+    }
+
+    fn uploaded_pre_key_count(&self) -> Result<usize, RhustAppError> {
+        Ok(self
+            .contents
+            .lock()
+            .unwrap()
+            .pre_keys
+            .values()
+            .filter(|(_, uploaded)| *uploaded)
+            .count())
+    }
+
+    fn put_sender_key(&self, group: &str, user: &str, key: &[u8]) -> Result<(), RhustAppError> {
+        self.contents
+            .lock()
+            .unwrap()
+            .sender_keys
+            .insert((group.to_string(), user.to_string()), key.to_vec());
+        Ok(())
+    }
+
+    fn get_sender_key(&self, group: &str, user: &str) -> Result<Option<Vec<u8>>, RhustAppError> {
+        Ok(self
+            .contents
+            .lock()
+            .unwrap()
+            .sender_keys
+            .get(&(group.to_string(), user.to_string()))
+            .cloned())
+    }
+
+    fn put_app_state_sync_key(&self, id: &[u8], key: AppStateSyncKey) -> Result<(), RhustAppError> {
+        let mut contents = self.contents.lock().unwrap();
+        match contents.app_state_sync_keys.get(id) {
+            Some(saved) if saved.timestamp >= key.timestamp => {}
+            _ => {
+                contents.app_state_sync_keys.insert(id.to_vec(), key);
+            }
+        };
+        Ok(())
+    }
+
+    fn get_app_state_sync_key(&self, id: &[u8]) -> Result<Option<AppStateSyncKey>, RhustAppError> {
+        Ok(self
+            .contents
+            .lock()
+            .unwrap()
+            .app_state_sync_keys
+            .get(id)
+            .cloned())
+    }
+
+    fn put_app_state_version(
+        &self,
+        name: &str,
+        version: u64,
+        hash: &[u8; 128],
+    ) -> Result<(), RhustAppError> {
+        self.contents
+            .lock()
+            .unwrap()
+            .app_state_versions
+            .insert(name.to_string(), (version, *hash));
+        Ok(())
+    }
+
+    fn get_app_state_version(&self, name: &str) -> Result<Option<(u64, [u8; 128])>, RhustAppError> {
+        Ok(self
+            .contents
+            .lock()
+            .unwrap()
+            .app_state_versions
+            .get(name)
+            .copied())
+    }
+
+    fn delete_app_state_version(&self, name: &str) -> Result<(), RhustAppError> {
+        let mut contents = self.contents.lock().unwrap();
+        contents.app_state_versions.remove(name);
+        contents
+            .app_state_mutation_macs
+            .retain(|(collection, _), _| collection != name);
+        Ok(())
+    }
+
+    fn put_app_state_mutation_macs(
+        &self,
+        name: &str,
+        version: u64,
+        macs: &[AppStateMutationMAC],
+    ) -> Result<(), RhustAppError> {
+        let mut contents = self.contents.lock().unwrap();
+        for mac in macs {
+            let key = (name.to_string(), mac.index_mac.clone());
+            match contents.app_state_mutation_macs.get(&key) {
+                Some((saved_version, _)) if *saved_version > version => {}
+                _ => {
+                    contents
+                        .app_state_mutation_macs
+                        .insert(key, (version, mac.value_mac.clone()));
+                }
+            };
+        }
+        Ok(())
+    }
+
+    fn delete_app_state_mutation_macs(
+        &self,
+        name: &str,
+        index_macs: &[Vec<u8>],
+    ) -> Result<(), RhustAppError> {
+        let mut contents = self.contents.lock().unwrap();
+        for index_mac in index_macs {
+            contents
+                .app_state_mutation_macs
+                .remove(&(name.to_string(), index_mac.clone()));
+        }
+        Ok(())
+    }
+
+    fn get_app_state_mutation_mac(
+        &self,
+        name: &str,
+        index_mac: &[u8],
+    ) -> Result<Option<Vec<u8>>, RhustAppError> {
+        Ok(self
+            .contents
+            .lock()
+            .unwrap()
+            .app_state_mutation_macs
+            .get(&(name.to_string(), index_mac.to_vec()))
+            .map(|(_, value_mac)| value_mac.clone()))
+    }
+
+    fn put_push_name(&self, user: &JID, push_name: &str) -> Result<Option<String>, RhustAppError> {
+        Ok(self.put_contact_field(user, push_name, |contact| &mut contact.push_name))
+    }
+
+    fn put_business_name(
+        &self,
+        user: &JID,
+        business_name: &str,
+    ) -> Result<Option<String>, RhustAppError> {
+        Ok(self.put_contact_field(user, business_name, |contact| &mut contact.business_name))
+    }
+
+    fn put_contact_name(
+        &self,
+        user: &JID,
+        first_name: &str,
+        full_name: &str,
+    ) -> Result<(), RhustAppError> {
+        let mut contents = self.contents.lock().unwrap();
+        let contact = contents.contacts.entry(user.to_string()).or_default();
+        contact.first_name = first_name.to_string();
+        contact.full_name = full_name.to_string();
+        Ok(())
+    }
+
+    fn get_contact(&self, user: &JID) -> Result<Option<ContactInfo>, RhustAppError> {
+        Ok(self
+            .contents
+            .lock()
+            .unwrap()
+            .contacts
+            .get(&user.to_string())
+            .cloned())
+    }
+
+    fn get_all_contacts(&self) -> Result<Vec<(JID, ContactInfo)>, RhustAppError> {
+        self.contents
+            .lock()
+            .unwrap()
+            .contacts
+            .iter()
+            .map(|(jid, contact)| Ok((JID::from_str(jid)?, contact.clone())))
+            .collect()
+    }
+
+    fn put_muted_until(
+        &self,
+        chat: &JID,
+        muted_until: OffsetDateTime,
+    ) -> Result<(), RhustAppError> {
+        self.update_chat_settings(chat, |settings| settings.muted_until = muted_until);
+        Ok(())
+    }
+
+    fn put_pinned(&self, chat: &JID, pinned: bool) -> Result<(), RhustAppError> {
+        self.update_chat_settings(chat, |settings| settings.pinned = pinned);
+        Ok(())
+    }
+
+    fn put_archived(&self, chat: &JID, archived: bool) -> Result<(), RhustAppError> {
+        self.update_chat_settings(chat, |settings| settings.archived = archived);
+        Ok(())
+    }
+
+    fn get_chat_settings(&self, chat: &JID) -> Result<Option<LocalChatSettings>, RhustAppError> {
+        Ok(self
+            .contents
+            .lock()
+            .unwrap()
+            .chat_settings
+            .get(&chat.to_string())
+            .cloned())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::testing;
+
+    use super::*;
+
+    #[test]
+    fn test_device() {
+        testing::check_device(&MemoryStore::new());
+    }
+
+    #[test]
+    fn test_identities_and_sessions() {
+        testing::check_identities_and_sessions(&MemoryStore::new());
+    }
+
+    #[test]
+    fn test_pre_keys() {
+        testing::check_pre_keys(&MemoryStore::new());
+    }
+
+    #[test]
+    fn test_sender_and_app_state_sync_keys() {
+        testing::check_sender_and_app_state_sync_keys(&MemoryStore::new());
+    }
+
+    #[test]
+    fn test_contacts_and_chat_settings() {
+        testing::check_contacts_and_chat_settings(&MemoryStore::new());
+    }
+
+    #[test]
+    fn test_app_state() {
+        testing::check_app_state(&MemoryStore::new());
+    }
+}
@@ -0,0 +1,11 @@
+//! `store` contains the credentials and account details of this companion device, and the
+//! `DeviceStore` trait that persists them along with the Signal sessions and keys.
+
+mod device;
+pub use device::*;
+
+pub mod memory;
+pub mod sqlite;
+
+mod traits;
+pub use traits::*;
@@ -0,0 +1,844 @@
+use std::{path::Path, str::FromStr, sync::Mutex};
+
+use libsignal_protocol::{KeyPair, PreKeyRecord, PrivateKey, SignedPreKeyRecord};
+use protobuf::Message;
+use rusqlite::{params, Connection, OptionalExtension};
+use time::OffsetDateTime;
+
+use crate::{
+    binary::proto as wa_proto,
+    new_rhustapp_error,
+    store::{new_pre_key, AppStateMutationMAC, AppStateSyncKey, Device, DeviceStore},
+    types::{ContactInfo, LocalChatSettings, JID},
+    RhustAppError,
+};
+
+/// The statements that upgrade the schema, the version of the schema (`user_version`) is
+/// the number of upgrades that were applied.
+const UPGRADES: &[&str] = &[
+    "
+    CREATE TABLE device (
+        id              INTEGER PRIMARY KEY CHECK (id = 1),
+        jid             TEXT,
+        registration_id INTEGER NOT NULL,
+        noise_key       BLOB NOT NULL,
+        identity_key    BLOB NOT NULL,
+        signed_pre_key  BLOB NOT NULL,
+        adv_secret_key  BLOB NOT NULL CHECK (length(adv_secret_key) = 32),
+        account         BLOB,
+        platform        TEXT NOT NULL,
+        business_name   TEXT NOT NULL,
+        push_name       TEXT NOT NULL
+    );
+    CREATE TABLE identity_keys (
+        their_id TEXT PRIMARY KEY,
+        identity BLOB NOT NULL CHECK (length(identity) = 32)
+    );
+    CREATE TABLE pre_keys (
+        key_id   INTEGER PRIMARY KEY,
+        key      BLOB NOT NULL,
+        uploaded BOOLEAN NOT NULL
+    );
+    CREATE TABLE sessions (
+        their_id TEXT PRIMARY KEY,
+        session  BLOB NOT NULL
+    );
+    CREATE TABLE sender_keys (
+        chat_id    TEXT NOT NULL,
+        sender_id  TEXT NOT NULL,
+        sender_key BLOB NOT NULL,
+        PRIMARY KEY (chat_id, sender_id)
+    );
+    CREATE TABLE app_state_sync_keys (
+        key_id      BLOB PRIMARY KEY,
+        key_data    BLOB NOT NULL,
+        timestamp   INTEGER NOT NULL,
+        fingerprint BLOB NOT NULL
+    );
+    CREATE TABLE contacts (
+        their_jid     TEXT PRIMARY KEY,
+        first_name    TEXT NOT NULL DEFAULT '',
+        full_name     TEXT NOT NULL DEFAULT '',
+        push_name     TEXT NOT NULL DEFAULT '',
+        business_name TEXT NOT NULL DEFAULT ''
+    );
+    CREATE TABLE chat_settings (
+        chat_jid    TEXT PRIMARY KEY,
+        muted_until INTEGER NOT NULL DEFAULT 0,
+        pinned      BOOLEAN NOT NULL DEFAULT false,
+        archived    BOOLEAN NOT NULL DEFAULT false
+    );
+",
+    "
+    CREATE TABLE app_state_versions (
+        name    TEXT PRIMARY KEY,
+        version INTEGER NOT NULL,
+        hash    BLOB NOT NULL CHECK (length(hash) = 128)
+    );
+    CREATE TABLE app_state_mutation_macs (
+        name      TEXT NOT NULL,
+        version   INTEGER NOT NULL,
+        index_mac BLOB NOT NULL,
+        value_mac BLOB NOT NULL,
+        PRIMARY KEY (name, version, index_mac)
+    );
+",
+];
+
+fn sql_error(message: &str) -> impl Fn(rusqlite::Error) -> RhustAppError + '_ {
+    move |err| new_rhustapp_error(message, Some(err.to_string()))
+}
+
+fn signal_error(
+    message: &str,
+) -> impl Fn(libsignal_protocol::SignalProtocolError) -> RhustAppError + '_ {
+    move |err| new_rhustapp_error(message, Some(err.to_string()))
+}
+
+/// Escapes the wildcards of a `LIKE` pattern, with `\` as the escape character.
+fn escape_like(pattern: &str) -> String {
+    pattern
+        .replace('\\', "\\\\")
+        .replace('%', "\\%")
+        .replace('_', "\\_")
+}
+
+fn key_pair_from_private_key(bytes: &[u8]) -> Result<KeyPair, RhustAppError> {
+    let to_err = signal_error("failed to parse stored key");
+    let private_key = PrivateKey::deserialize(bytes).map_err(&to_err)?;
+    Ok(KeyPair::new(
+        private_key.public_key().map_err(&to_err)?,
+        private_key,
+    ))
+}
+
+/// The columns of the `device` table.
+struct DeviceRow {
+    jid: Option<String>,
+    registration_id: u32,
+    noise_key: Vec<u8>,
+    identity_key: Vec<u8>,
+    signed_pre_key: Vec<u8>,
+    adv_secret_key: Vec<u8>,
+    account: Option<Vec<u8>>,
+    platform: String,
+    business_name: String,
+    push_name: String,
+}
+
+impl DeviceRow {
+    fn into_device(self) -> Result<Device, RhustAppError> {
+        let account = match self.account {
+            Some(account) => Some(
+                wa_proto::ADVSignedDeviceIdentity::parse_from_bytes(&account).map_err(|err| {
+                    new_rhustapp_error("failed to parse stored account", Some(err.to_string()))
+                })?,
+            ),
+            None => None,
+        };
+
+        Ok(Device {
+            noise_key: key_pair_from_private_key(&self.noise_key)?,
+            identity_key: key_pair_from_private_key(&self.identity_key)?,
+            signed_pre_key: SignedPreKeyRecord::deserialize(&self.signed_pre_key)
+                .map_err(signal_error("failed to parse stored signed prekey"))?,
+            registration_id: self.registration_id,
+            adv_secret_key: self
+                .adv_secret_key
+                .try_into()
+                .map_err(|_| new_rhustapp_error("failed to parse stored adv secret key", None))?,
+            id: self.jid.as_deref().map(JID::from_str).transpose()?,
+            account,
+            platform: self.platform,
+            business_name: self.business_name,
+            push_name: self.push_name,
+        })
+    }
+}
+
+/// A `DeviceStore` backed by an SQLite database. A database holds a single device.
+pub struct SqliteStore {
+    connection: Mutex<Connection>,
+}
+
+impl SqliteStore {
+    /// Opens the database at the path, creating it if it doesn't exist yet.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, RhustAppError> {
+        Self::new(Connection::open(path).map_err(sql_error("failed to open database"))?)
+    }
+
+    /// Opens a database that only lives in memory, which is mostly useful for tests.
+    pub fn open_in_memory() -> Result<Self, RhustAppError> {
+        Self::new(Connection::open_in_memory().map_err(sql_error("failed to open database"))?)
+    }
+
+    fn new(mut connection: Connection) -> Result<Self, RhustAppError> {
+        let to_err = sql_error("failed to upgrade database");
+        let version: usize = connection
+            .query_row("PRAGMA user_version", [], |row| row.get(0))
+            .map_err(&to_err)?;
+        for (index, upgrade) in UPGRADES.iter().enumerate().skip(version) {
+            let transaction = connection.transaction().map_err(&to_err)?;
+            transaction.execute_batch(upgrade).map_err(&to_err)?;
+            transaction
+                .pragma_update(None, "user_version", index + 1)
+                .map_err(&to_err)?;
+            transaction.commit().map_err(&to_err)?;
+        }
+
+        Ok(Self {
+            connection: Mutex::new(connection),
+        })
+    }
+
+    /// Executes a statement that doesn't return anything.
+    fn execute(
+        &self,
+        message: &str,
+        sql: &str,
+        params: impl rusqlite::Params,
+    ) -> Result<(), RhustAppError> {
+        self.connection
+            .lock()
+            .unwrap()
+            .execute(sql, params)
+            .map_err(sql_error(message))?;
+        Ok(())
+    }
+
+    /// Returns the first column of the row returned by a query, if any.
+    fn query_optional<T: rusqlite::types::FromSql>(
+        &self,
+        message: &str,
+        sql: &str,
+        params: impl rusqlite::Params,
+    ) -> Result<Option<T>, RhustAppError> {
+        self.connection
+            .lock()
+            .unwrap()
+            .query_row(sql, params, |row| row.get(0))
+            .optional()
+            .map_err(sql_error(message))
+    }
+
+    fn put_contact_field(
+        &self,
+        column: &str,
+        user: &JID,
+        value: &str,
+    ) -> Result<Option<String>, RhustAppError> {
+        let to_err = sql_error("failed to save contact");
+        let connection = self.connection.lock().unwrap();
+        let previous: Option<String> = connection
+            .query_row(
+                &format!("SELECT {column} FROM contacts WHERE their_jid = ?1"),
+                [user.to_string()],
+                |row| row.get(0),
+            )
+            .optional()
+            .map_err(&to_err)?;
+        if previous.as_deref() == Some(value) {
+            return Ok(None);
+        };
+
+        connection
+            .execute(
+                &format!(
+                    "INSERT INTO contacts (their_jid, {column}) VALUES (?1, ?2)
+                     ON CONFLICT (their_jid) DO UPDATE SET {column} = excluded.{column}"
+                ),
+                params![user.to_string(), value],
+            )
+            .map_err(&to_err)?;
+        Ok(Some(previous.unwrap_or_default()))
+    }
+
+    fn put_chat_setting(
+        &self,
+        column: &str,
+        chat: &JID,
+        value: impl rusqlite::ToSql,
+    ) -> Result<(), RhustAppError> {
+        self.execute(
+            "failed to save chat settings",
+            &format!(
+                "INSERT INTO chat_settings (chat_jid, {column}) VALUES (?1, ?2)
+                 ON CONFLICT (chat_jid) DO UPDATE SET {column} = excluded.{column}"
+            ),
+            params![chat.to_string(), value],
+        )
+    }
+}
+
+impl DeviceStore for SqliteStore {
+    fn load_device(&self) -> Result<Option<Device>, RhustAppError> {
+        let row = self
+            .connection
+            .lock()
+            .unwrap()
+            .query_row(
+                "SELECT jid, registration_id, noise_key, identity_key, signed_pre_key,
+                        adv_secret_key, account, platform, business_name, push_name
+                 FROM device",
+                [],
+                |row| {
+                    Ok(DeviceRow {
+                        jid: row.get(0)?,
+                        registration_id: row.get(1)?,
+                        noise_key: row.get(2)?,
+                        identity_key: row.get(3)?,
+                        signed_pre_key: row.get(4)?,
+                        adv_secret_key: row.get(5)?,
+                        account: row.get(6)?,
+                        platform: row.get(7)?,
+                        business_name: row.get(8)?,
+                        push_name: row.get(9)?,
+                    })
+                },
+            )
+            .optional()
+            .map_err(sql_error("failed to load device"))?;
+
+        row.map(DeviceRow::into_device).transpose()
+    }
+
+    fn save_device(&self, device: &Device) -> Result<(), RhustAppError> {
+        let account = match &device.account {
+            Some(account) => Some(account.write_to_bytes().map_err(|err| {
+                new_rhustapp_error("failed to marshal account", Some(err.to_string()))
+            })?),
+            None => None,
+        };
+        let signed_pre_key = device
+            .signed_pre_key
+            .serialize()
+            .map_err(signal_error("failed to serialize signed prekey"))?;
+
+        self.execute(
+            "failed to save device",
+            "INSERT OR REPLACE INTO device (id, jid, registration_id, noise_key, identity_key,
+                 signed_pre_key, adv_secret_key, account, platform, business_name, push_name)
+             VALUES (1, ?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)",
+            params![
+                device.id.as_ref().map(JID::to_string),
+                device.registration_id,
+                device.noise_key.private_key.serialize(),
+                device.identity_key.private_key.serialize(),
+                signed_pre_key,
+                device.adv_secret_key,
+                account,
+                device.platform,
+                device.business_name,
+                device.push_name,
+            ],
+        )
+    }
+
+    fn delete_device(&self) -> Result<(), RhustAppError> {
+        self.connection
+            .lock()
+            .unwrap()
+            .execute_batch(
+                "BEGIN;
+                 DELETE FROM device;
+                 DELETE FROM identity_keys;
+                 DELETE FROM pre_keys;
+                 DELETE FROM sessions;
+                 DELETE FROM sender_keys;
+                 DELETE FROM app_state_sync_keys;
+                 DELETE FROM app_state_versions;
+                 DELETE FROM app_state_mutation_macs;
+                 DELETE FROM contacts;
+                 DELETE FROM chat_settings;
+                 COMMIT;",
+            )
+            .map_err(sql_error("failed to delete device"))
+    }
+
+    fn put_identity(&self, address: &str, key: [u8; 32]) -> Result<(), RhustAppError> {
+        self.execute(
+            "failed to save identity key",
+            "INSERT OR REPLACE INTO identity_keys (their_id, identity) VALUES (?1, ?2)",
+            params![address, key],
+        )
+    }
+
+    fn get_identity(&self, address: &str) -> Result<Option<[u8; 32]>, RhustAppError> {
+        let identity: Option<Vec<u8>> = self.query_optional(
+            "failed to load identity key",
+            "SELECT identity FROM identity_keys WHERE their_id = ?1",
+            [address],
+        )?;
+        identity
+            .map(|identity| {
+                identity
+                    .try_into()
+                    .map_err(|_| new_rhustapp_error("failed to parse stored identity key", None))
+            })
+            .transpose()
+    }
+
+    fn delete_identity(&self, address: &str) -> Result<(), RhustAppError> {
+        self.execute(
+            "failed to delete identity key",
+            "DELETE FROM identity_keys WHERE their_id = ?1",
+            [address],
+        )
+    }
+
+    fn delete_all_identities(&self, user: &str) -> Result<(), RhustAppError> {
+        self.execute(
+            "failed to delete identity keys",
+            "DELETE FROM identity_keys WHERE their_id LIKE ?1 ESCAPE '\\'",
+            [format!("{}.%", escape_like(user))],
+        )
+    }
+
+    fn get_session(&self, address: &str) -> Result<Option<Vec<u8>>, RhustAppError> {
+        self.query_optional(
+            "failed to load session",
+            "SELECT session FROM sessions WHERE their_id = ?1",
+            [address],
+        )
+    }
+
+    fn put_session(&self, address: &str, session: &[u8]) -> Result<(), RhustAppError> {
+        self.execute(
+            "failed to save session",
+            "INSERT OR REPLACE INTO sessions (their_id, session) VALUES (?1, ?2)",
+            params![address, session],
+        )
+    }
+
+    fn delete_session(&self, address: &str) -> Result<(), RhustAppError> {
+        self.execute(
+            "failed to delete session",
+            "DELETE FROM sessions WHERE their_id = ?1",
+            [address],
+        )
+    }
+
+    fn delete_all_sessions(&self, user: &str) -> Result<(), RhustAppError> {
+        self.execute(
+            "failed to delete sessions",
+            "DELETE FROM sessions WHERE their_id LIKE ?1 ESCAPE '\\'",
+            [format!("{}.%", escape_like(user))],
+        )
+    }
+
+    fn get_or_gen_pre_keys(&self, count: u32) -> Result<Vec<PreKeyRecord>, RhustAppError> {
+        let to_err = sql_error("failed to load prekeys");
+        let mut connection = self.connection.lock().unwrap();
+        let transaction = connection.transaction().map_err(&to_err)?;
+
+        let mut keys = {
+            let mut statement = transaction
+                .prepare("SELECT key FROM pre_keys WHERE uploaded = false ORDER BY key_id LIMIT ?1")
+                .map_err(&to_err)?;
+            let rows = statement
+                .query_map([count], |row| row.get::<_, Vec<u8>>(0))
+                .map_err(&to_err)?;
+            rows.map(|key| {
+                PreKeyRecord::deserialize(&key.map_err(&to_err)?)
+                    .map_err(signal_error("failed to parse stored prekey"))
+            })
+            .collect::<Result<Vec<PreKeyRecord>, RhustAppError>>()?
+        };
+
+        let last_id: u32 = transaction
+            .query_row("SELECT coalesce(max(key_id), 0) FROM pre_keys", [], |row| {
+                row.get(0)
+            })
+            .map_err(&to_err)?;
+        for id in (last_id + 1..).take(count as usize - keys.len()) {
+            let key = new_pre_key(id);
+            let serialized = key
+                .serialize()
+                .map_err(signal_error("failed to serialize prekey"))?;
+            transaction
+                .execute(
+                    "INSERT INTO pre_keys (key_id, key, uploaded) VALUES (?1, ?2, false)",
+                    params![id, serialized],
+                )
+                .map_err(sql_error("failed to save prekey"))?;
+            keys.push(key);
+        }
+
+        transaction
+            .commit()
+            .map_err(sql_error("failed to save prekeys"))?;
+        Ok(keys)
+    }
+
+    fn get_pre_key(&self, id: u32) -> Result<Option<PreKeyRecord>, RhustAppError> {
+        let key: Option<Vec<u8>> = self.query_optional(
+            "failed to load prekey",
+            "SELECT key FROM pre_keys WHERE key_id = ?1",
+            [id],
+        )?;
+        key.map(|key| {
+            PreKeyRecord::deserialize(&key).map_err(signal_error("failed to parse stored prekey"))
+        })
+        .transpose()
+    }
+
+    fn remove_pre_key(&self, id: u32) -> Result<(), RhustAppError> {
+        self.execute(
+            "failed to remove prekey",
+            "DELETE FROM pre_keys WHERE key_id = ?1",
+            [id],
+        )
+    }
+
+    fn mark_pre_keys_as_uploaded(&self, up_to_id: u32) -> Result<(), RhustAppError> {
+        self.execute(
+            "failed to mark prekeys as uploaded",
+            "UPDATE pre_keys SET uploaded = true WHERE key_id <= ?1",
+            [up_to_id],
+        )
+    }
+
+    fn uploaded_pre_key_count(&self) -> Result<usize, RhustAppError> {
+        Ok(self
+            .query_optional(
+                "failed to count uploaded prekeys",
+                "SELECT count(*) FROM pre_keys WHERE uploaded = true",
+                [],
+            )?
+            .unwrap_or_default())
+    }
+
+    fn put_sender_key(&self, group: &str, user: &str, key: &[u8]) -> Result<(), RhustAppError> {
+        self.execute(
+            "failed to save sender key",
+            "INSERT OR REPLACE INTO sender_keys (chat_id, sender_id, sender_key)
+             VALUES (?1, ?2, ?3)",
+            params![group, user, key],
+        )
+    }
+
+    fn get_sender_key(&self, group: &str, user: &str) -> Result<Option<Vec<u8>>, RhustAppError> {
+        self.query_optional(
+            "failed to load sender key",
+            "SELECT sender_key FROM sender_keys WHERE chat_id = ?1 AND sender_id = ?2",
+            [group, user],
+        )
+    }
+
+    fn put_app_state_sync_key(&self, id: &[u8], key: AppStateSyncKey) -> Result<(), RhustAppError> {
+        self.execute(
+            "failed to save app state sync key",
+            "INSERT INTO app_state_sync_keys (key_id, key_data, timestamp, fingerprint)
+             VALUES (?1, ?2, ?3, ?4)
+             ON CONFLICT (key_id) DO UPDATE
+                 SET key_data = excluded.key_data, timestamp = excluded.timestamp,
+                     fingerprint = excluded.fingerprint
+                 WHERE excluded.timestamp > app_state_sync_keys.timestamp",
+            params![id, key.data, key.timestamp, key.fingerprint],
+        )
+    }
+
+    fn get_app_state_sync_key(&self, id: &[u8]) -> Result<Option<AppStateSyncKey>, RhustAppError> {
+        self.connection
+            .lock()
+            .unwrap()
+            .query_row(
+                "SELECT key_data, timestamp, fingerprint FROM app_state_sync_keys
+                 WHERE key_id = ?1",
+                [id],
+                |row| {
+                    Ok(AppStateSyncKey {
+                        data: row.get(0)?,
+                        timestamp: row.get(1)?,
+                        fingerprint: row.get(2)?,
+                    })
+                },
+            )
+            .optional()
+            .map_err(sql_error("failed to load app state sync key"))
+    }
+
+    fn put_app_state_version(
+        &self,
+        name: &str,
+        version: u64,
+        hash: &[u8; 128],
+    ) -> Result<(), RhustAppError> {
+        self.execute(
+            "failed to save app state version",
+            "INSERT OR REPLACE INTO app_state_versions (name, version, hash) VALUES (?1, ?2, ?3)",
+            params![name, version, &hash[..]],
+        )
+    }
+
+    fn get_app_state_version(&self, name: &str) -> Result<Option<(u64, [u8; 128])>, RhustAppError> {
+        let row: Option<(u64, Vec<u8>)> = self
+            .connection
+            .lock()
+            .unwrap()
+            .query_row(
+                "SELECT version, hash FROM app_state_versions WHERE name = ?1",
+                [name],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .optional()
+            .map_err(sql_error("failed to load app state version"))?;
+
+        row.map(|(version, hash)| {
+            let hash = hash
+                .try_into()
+                .map_err(|_| new_rhustapp_error("failed to parse stored app state hash", None))?;
+            Ok((version, hash))
+        })
+        .transpose()
+    }
+
+    fn delete_app_state_version(&self, name: &str) -> Result<(), RhustAppError> {
+        let to_err = sql_error("failed to delete app state version");
+        let mut connection = self.connection.lock().unwrap();
+        let transaction = connection.transaction().map_err(&to_err)?;
+        transaction
+            .execute("DELETE FROM app_state_versions WHERE name = ?1", [name])
+            .map_err(&to_err)?;
+        transaction
+            .execute(
+                "DELETE FROM app_state_mutation_macs WHERE name = ?1",
+                [name],
+            )
+            .map_err(&to_err)?;
+        transaction.commit().map_err(&to_err)
+    }
+
+    fn put_app_state_mutation_macs(
+        &self,
+        name: &str,
+        version: u64,
+        macs: &[AppStateMutationMAC],
+    ) -> Result<(), RhustAppError> {
+        let to_err = sql_error("failed to save app state mutation MACs");
+        let mut connection = self.connection.lock().unwrap();
+        let transaction = connection.transaction().map_err(&to_err)?;
+        for mac in macs {
+            transaction
+                .execute(
+                    "INSERT OR REPLACE INTO app_state_mutation_macs
+                         (name, version, index_mac, value_mac)
+                     VALUES (?1, ?2, ?3, ?4)",
+                    params![name, version, mac.index_mac, mac.value_mac],
+                )
+                .map_err(&to_err)?;
+        }
+        transaction.commit().map_err(&to_err)
+    }
+
+    fn delete_app_state_mutation_macs(
+        &self,
+        name: &str,
+        index_macs: &[Vec<u8>],
+    ) -> Result<(), RhustAppError> {
+        let to_err = sql_error("failed to delete app state mutation MACs");
+        let mut connection = self.connection.lock().unwrap();
+        let transaction = connection.transaction().map_err(&to_err)?;
+        for index_mac in index_macs {
+            transaction
+                .execute(
+                    "DELETE FROM app_state_mutation_macs WHERE name = ?1 AND index_mac = ?2",
+                    params![name, index_mac],
+                )
+                .map_err(&to_err)?;
+        }
+        transaction.commit().map_err(&to_err)
+    }
+
+    fn get_app_state_mutation_mac(
+        &self,
+        name: &str,
+        index_mac: &[u8],
+    ) -> Result<Option<Vec<u8>>, RhustAppError> {
+        self.query_optional(
+            "failed to load app state mutation MAC",
+            "SELECT value_mac FROM app_state_mutation_macs WHERE name = ?1 AND index_mac = ?2
+             ORDER BY version DESC LIMIT 1",
+            params![name, index_mac],
+        )
+    }
+
+    fn put_push_name(&self, user: &JID, push_name: &str) -> Result<Option<String>, RhustAppError> {
+        self.put_contact_field("push_name", user, push_name)
+    }
+
+    fn put_business_name(
+        &self,
+        user: &JID,
+        business_name: &str,
+    ) -> Result<Option<String>, RhustAppError> {
+        self.put_contact_field("business_name", user, business_name)
+    }
+
+    fn put_contact_name(
+        &self,
+        user: &JID,
+        first_name: &str,
+        full_name: &str,
+    ) -> Result<(), RhustAppError> {
+        self.execute(
+            "failed to save contact",
+            "INSERT INTO contacts (their_jid, first_name, full_name) VALUES (?1, ?2, ?3)
+             ON CONFLICT (their_jid) DO UPDATE
+                 SET first_name = excluded.first_name, full_name = excluded.full_name",
+            params![user.to_string(), first_name, full_name],
+        )
+    }
+
+    fn get_contact(&self, user: &JID) -> Result<Option<ContactInfo>, RhustAppError> {
+        self.connection
+            .lock()
+            .unwrap()
+            .query_row(
+                "SELECT first_name, full_name, push_name, business_name FROM contacts
+                 WHERE their_jid = ?1",
+                [user.to_string()],
+                |row| {
+                    Ok(ContactInfo {
+                        first_name: row.get(0)?,
+                        full_name: row.get(1)?,
+                        push_name: row.get(2)?,
+                        business_name: row.get(3)?,
+                    })
+                },
+            )
+            .optional()
+            .map_err(sql_error("failed to load contact"))
+    }
+
+    fn get_all_contacts(&self) -> Result<Vec<(JID, ContactInfo)>, RhustAppError> {
+        let to_err = sql_error("failed to load contacts");
+        let connection = self.connection.lock().unwrap();
+        let mut statement = connection
+            .prepare(
+                "SELECT their_jid, first_name, full_name, push_name, business_name FROM contacts",
+            )
+            .map_err(&to_err)?;
+        let rows = statement
+            .query_map([], |row| {
+                Ok((
+                    row.get::<_, String>(0)?,
+                    ContactInfo {
+                        first_name: row.get(1)?,
+                        full_name: row.get(2)?,
+                        push_name: row.get(3)?,
+                        business_name: row.get(4)?,
+                    },
+                ))
+            })
+            .map_err(&to_err)?;
+
+        rows.map(|row| {
+            let (jid, contact) = row.map_err(&to_err)?;
+            Ok((JID::from_str(&jid)?, contact))
+        })
+        .collect()
+    }
+
+    fn put_muted_until(
+        &self,
+        chat: &JID,
+        muted_until: OffsetDateTime,
+    ) -> Result<(), RhustAppError> {
+        self.put_chat_setting("muted_until", chat, muted_until.unix_timestamp())
+    }
+
+    fn put_pinned(&self, chat: &JID, pinned: bool) -> Result<(), RhustAppError> {
+        self.put_chat_setting("pinned", chat, pinned)
+    }
+
+    fn put_archived(&self, chat: &JID, archived: bool) -> Result<(), RhustAppError> {
+        self.put_chat_setting("archived", chat, archived)
+    }
+
+    fn get_chat_settings(&self, chat: &JID) -> Result<Option<LocalChatSettings>, RhustAppError> {
+        let settings = self
+            .connection
+            .lock()
+            .unwrap()
+            .query_row(
+                "SELECT muted_until, pinned, archived FROM chat_settings WHERE chat_jid = ?1",
+                [chat.to_string()],
+                |row| Ok((row.get::<_, i64>(0)?, row.get(1)?, row.get(2)?)),
+            )
+            .optional()
+            .map_err(sql_error("failed to load chat settings"))?;
+
+        settings
+            .map(|(muted_until, pinned, archived)| {
+                Ok(LocalChatSettings {
+                    muted_until: OffsetDateTime::from_unix_timestamp(muted_until).map_err(
+                        |err| {
+                            new_rhustapp_error(
+                                "failed to parse stored mute time",
+                                Some(err.to_string()),
+                            )
+                        },
+                    )?,
+                    pinned,
+                    archived,
+                })
+            })
+            .transpose()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::testing;
+
+    use super::*;
+
+    #[test]
+    fn test_device() {
+        testing::check_device(&SqliteStore::open_in_memory().unwrap());
+    }
+
+    #[test]
+    fn test_identities_and_sessions() {
+        testing::check_identities_and_sessions(&SqliteStore::open_in_memory().unwrap());
+    }
+
+    #[test]
+    fn test_pre_keys() {
+        testing::check_pre_keys(&SqliteStore::open_in_memory().unwrap());
+    }
+
+    #[test]
+    fn test_sender_and_app_state_sync_keys() {
+        testing::check_sender_and_app_state_sync_keys(&SqliteStore::open_in_memory().unwrap());
+    }
+
+    #[test]
+    fn test_contacts_and_chat_settings() {
+        testing::check_contacts_and_chat_settings(&SqliteStore::open_in_memory().unwrap());
+    }
+
+    #[test]
+    fn test_app_state() {
+        testing::check_app_state(&SqliteStore::open_in_memory().unwrap());
+    }
+
+    #[test]
+    fn test_reopen() {
+        let path = std::env::temp_dir().join(format!("rhustapp-{}.db", rand::random::<u64>()));
+        let device = Device::new();
+        {
+            let store = SqliteStore::open(&path).unwrap();
+            store.save_device(&device).unwrap();
+            store.put_session("1234.1", &[1]).unwrap();
+        }
+
+        let store = SqliteStore::open(&path).unwrap();
+        let loaded = store.load_device().unwrap().unwrap();
+        assert_eq!(loaded.registration_id, device.registration_id);
+        assert_eq!(store.get_session("1234.1").unwrap(), Some(vec![1]));
+        drop(store);
+        std::fs::remove_file(path).unwrap();
+    }
+}
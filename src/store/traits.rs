@@ -0,0 +1,148 @@
+use libsignal_protocol::{KeyPair, PreKeyRecord};
+use time::OffsetDateTime;
+
+use crate::{
+    store::Device,
+    types::{ContactInfo, LocalChatSettings, JID},
+    RhustAppError,
+};
+
+/// A key that app state patches are encrypted with, shared by the phone in an
+/// `AppStateSyncKeyShare` message.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AppStateSyncKey {
+    pub data: Vec<u8>,
+    pub fingerprint: Vec<u8>,
+    pub timestamp: i64,
+}
+
+/// The MAC of the value that a mutation of an app state collection set an index to, by the
+/// MAC of the index. The value MAC is needed to remove the value from the LTHash of the
+/// collection when the index is set again or removed.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AppStateMutationMAC {
+    pub index_mac: Vec<u8>,
+    pub value_mac: Vec<u8>,
+}
+
+/// Generates a new one-time prekey with the given id.
+pub fn new_pre_key(id: u32) -> PreKeyRecord {
+    PreKeyRecord::new(id.into(), &KeyPair::generate(&mut rand::rngs::OsRng))
+}
+
+/// Persists a single device along with the Signal sessions, keys, contacts and chat
+/// settings needed to keep using it after a restart.
+///
+/// Devices are identified by the string form of their `JID::signal_address`, e.g.
+/// `919876543210.3`, and users by their phone number. Sessions and sender keys are stored
+/// in their serialized form, the store doesn't look into them.
+pub trait DeviceStore: Send + Sync {
+    /// Returns the saved device, or `None` if no device was saved yet.
+    fn load_device(&self) -> Result<Option<Device>, RhustAppError>;
+    /// Saves the device, replacing the saved one.
+    fn save_device(&self, device: &Device) -> Result<(), RhustAppError>;
+    /// Deletes the device along with everything else in the store, e.g. after logging out.
+    fn delete_device(&self) -> Result<(), RhustAppError>;
+
+    /// Saves the identity key of another device.
+    fn put_identity(&self, address: &str, key: [u8; 32]) -> Result<(), RhustAppError>;
+    fn get_identity(&self, address: &str) -> Result<Option<[u8; 32]>, RhustAppError>;
+    fn delete_identity(&self, address: &str) -> Result<(), RhustAppError>;
+    /// Deletes the identity keys of all the devices of the user.
+    fn delete_all_identities(&self, user: &str) -> Result<(), RhustAppError>;
+
+    /// Returns true if the key matches the saved identity key of the device, or if no key
+    /// has been saved for it yet.
+    fn is_trusted_identity(&self, address: &str, key: [u8; 32]) -> Result<bool, RhustAppError> {
+        Ok(self.get_identity(address)?.is_none_or(|saved| saved == key))
+    }
+
+    fn get_session(&self, address: &str) -> Result<Option<Vec<u8>>, RhustAppError>;
+    fn put_session(&self, address: &str, session: &[u8]) -> Result<(), RhustAppError>;
+    fn delete_session(&self, address: &str) -> Result<(), RhustAppError>;
+    /// Deletes the sessions with all the devices of the user.
+    fn delete_all_sessions(&self, user: &str) -> Result<(), RhustAppError>;
+
+    fn has_session(&self, address: &str) -> Result<bool, RhustAppError> {
+        Ok(self.get_session(address)?.is_some())
+    }
+
+    /// Returns `count` prekeys that haven't been uploaded yet, generating new ones if there
+    /// aren't enough.
+    fn get_or_gen_pre_keys(&self, count: u32) -> Result<Vec<PreKeyRecord>, RhustAppError>;
+    fn get_pre_key(&self, id: u32) -> Result<Option<PreKeyRecord>, RhustAppError>;
+    /// Removes a prekey once a session has been established with it.
+    fn remove_pre_key(&self, id: u32) -> Result<(), RhustAppError>;
+    /// Marks the prekeys up to the id (inclusive) as uploaded to the server.
+    fn mark_pre_keys_as_uploaded(&self, up_to_id: u32) -> Result<(), RhustAppError>;
+    fn uploaded_pre_key_count(&self) -> Result<usize, RhustAppError>;
+
+    /// Saves the sender key of a user in a group.
+    fn put_sender_key(&self, group: &str, user: &str, key: &[u8]) -> Result<(), RhustAppError>;
+    fn get_sender_key(&self, group: &str, user: &str) -> Result<Option<Vec<u8>>, RhustAppError>;
+
+    /// Saves an app state sync key. An existing key with the same id is only replaced by a
+    /// newer one.
+    fn put_app_state_sync_key(&self, id: &[u8], key: AppStateSyncKey) -> Result<(), RhustAppError>;
+    fn get_app_state_sync_key(&self, id: &[u8]) -> Result<Option<AppStateSyncKey>, RhustAppError>;
+
+    /// Saves the version of an app state collection and its LTHash at that version.
+    fn put_app_state_version(
+        &self,
+        name: &str,
+        version: u64,
+        hash: &[u8; 128],
+    ) -> Result<(), RhustAppError>;
+    /// Returns the version of an app state collection and its LTHash, or `None` if it was
+    /// never synced.
+    fn get_app_state_version(&self, name: &str) -> Result<Option<(u64, [u8; 128])>, RhustAppError>;
+    /// Deletes the version of an app state collection along with its mutation MACs, so that
+    /// it is synced again from a snapshot.
+    fn delete_app_state_version(&self, name: &str) -> Result<(), RhustAppError>;
+    /// Saves the MACs of the mutations that set values in a patch of an app state collection.
+    fn put_app_state_mutation_macs(
+        &self,
+        name: &str,
+        version: u64,
+        macs: &[AppStateMutationMAC],
+    ) -> Result<(), RhustAppError>;
+    /// Deletes the MACs of the values of the indexes that were removed.
+    fn delete_app_state_mutation_macs(
+        &self,
+        name: &str,
+        index_macs: &[Vec<u8>],
+    ) -> Result<(), RhustAppError>;
+    /// Returns the value MAC that the index was set to by the latest version of the
+    /// collection that set it.
+    fn get_app_state_mutation_mac(
+        &self,
+        name: &str,
+        index_mac: &[u8],
+    ) -> Result<Option<Vec<u8>>, RhustAppError>;
+
+    /// Saves the push name of the user. Returns the previous push name if it changed.
+    fn put_push_name(&self, user: &JID, push_name: &str) -> Result<Option<String>, RhustAppError>;
+    /// Saves the verified business name of the user. Returns the previous business name if
+    /// it changed.
+    fn put_business_name(
+        &self,
+        user: &JID,
+        business_name: &str,
+    ) -> Result<Option<String>, RhustAppError>;
+    /// Saves the name of the user in the contacts of the phone.
+    fn put_contact_name(
+        &self,
+        user: &JID,
+        first_name: &str,
+        full_name: &str,
+    ) -> Result<(), RhustAppError>;
+    fn get_contact(&self, user: &JID) -> Result<Option<ContactInfo>, RhustAppError>;
+    fn get_all_contacts(&self) -> Result<Vec<(JID, ContactInfo)>, RhustAppError>;
+
+    /// Saves until when the chat is muted, `OffsetDateTime::UNIX_EPOCH` unmutes it.
+    fn put_muted_until(&self, chat: &JID, muted_until: OffsetDateTime)
+        -> Result<(), RhustAppError>;
+    fn put_pinned(&self, chat: &JID, pinned: bool) -> Result<(), RhustAppError>;
+    fn put_archived(&self, chat: &JID, archived: bool) -> Result<(), RhustAppError>;
+    fn get_chat_settings(&self, chat: &JID) -> Result<Option<LocalChatSettings>, RhustAppError>;
+}
@@ -0,0 +1,777 @@
+//! `testing` contains the fakes of the WhatsApp server and of the phone that the tests use, and
+//! the checks shared by the tests of the `DeviceStore` implementations.
+
+use std::{
+    future::Future,
+    io::{Read, Write},
+    net::{TcpListener, TcpStream},
+    pin::pin,
+    str::FromStr,
+    sync::Arc,
+    task::{Context, Poll, Wake, Waker},
+    thread::{self, JoinHandle, Thread},
+};
+
+use aes::Aes256;
+use block_modes::{block_padding::Pkcs7, BlockMode, Cbc};
+use libsignal_protocol::{IdentityKey, KeyPair, PreKeyBundle, PreKeyRecord, PublicKey};
+use protobuf::{Message, MessageField};
+use time::OffsetDateTime;
+use tungstenite::WebSocket;
+
+use crate::{
+    appstate::{content_mac, expand_app_state_keys, index_mac, patch_mac, HashState, PatchName},
+    binary::{
+        marshal, proto as wa_proto,
+        proto::cert_chain::noise_certificate::Details as NoiseCertificateDetails,
+        proto::syncd_mutation::SyncdOperation, unmarshal, AttributeTypes, Attrs, Node,
+        NodeContentType,
+    },
+    pair::{compute_adv_sign, ADV_ACCOUNT_SIGNATURE_PREFIX},
+    prekeys::build_set_prekeys_node,
+    socket::{get_wa_header, NoiseHandshake, NoiseSocket, FRAME_LENGTH_SIZE, NOISE_START_PATTERN},
+    store::{public_key_bytes, AppStateMutationMAC, AppStateSyncKey, Device, DeviceStore},
+    types::JID,
+};
+
+/// Wakes a task run by `block_on` by unparking its thread.
+struct ThreadWaker(Thread);
+
+impl Wake for ThreadWaker {
+    fn wake(self: Arc<Self>) {
+        self.0.unpark();
+    }
+}
+
+/// Runs a future to completion on the current thread, parking it while the future is
+/// pending.
+pub(crate) fn block_on<T>(future: impl Future<Output = T>) -> T {
+    let mut future = pin!(future);
+    let waker = Waker::from(Arc::new(ThreadWaker(thread::current())));
+    let mut cx = Context::from_waker(&waker);
+    loop {
+        match future.as_mut().poll(&mut cx) {
+            Poll::Ready(output) => return output,
+            Poll::Pending => thread::park(),
+        }
+    }
+}
+
+/// Builds a node with the attributes and content. Attribute values that parse as a JID with
+/// a server are stored as JIDs, the others as strings.
+pub(crate) fn node(tag: &str, attrs: &[(&str, &str)], content: NodeContentType) -> Node {
+    Node {
+        tag: tag.to_string(),
+        attrs: attrs
+            .iter()
+            .map(|(key, value)| {
+                let value = match JID::from_str(value) {
+                    Ok(jid) if value.contains('@') => AttributeTypes::JID(jid),
+                    _ => AttributeTypes::String(value.to_string()),
+                };
+                (key.to_string(), value)
+            })
+            .collect::<Attrs>(),
+        content,
+    }
+}
+
+/// Builds a certificate chain whose leaf is issued for the given key.
+pub(crate) fn certificate_chain(leaf_key: &[u8], leaf_issuer: u32) -> Vec<u8> {
+    let details = |serial: u32, issuer_serial: u32, key: &[u8]| {
+        let mut details = NoiseCertificateDetails::new();
+        details.serial = Some(serial);
+        details.issuerSerial = Some(issuer_serial);
+        details.key = Some(key.to_vec());
+        let mut certificate = wa_proto::cert_chain::NoiseCertificate::new();
+        certificate.details = Some(details.write_to_bytes().unwrap());
+        certificate
+    };
+
+    let mut chain = wa_proto::CertChain::new();
+    chain.intermediate = MessageField::some(details(1, 0, &[0; 32]));
+    chain.leaf = MessageField::some(details(2, leaf_issuer, leaf_key));
+    chain.write_to_bytes().unwrap()
+}
+
+/// Plays the server side of the handshake: responds to the client hello with a server
+/// hello using the given certificate chain.
+pub(crate) fn server_hello(
+    client_hello: &[u8],
+    server_ephemeral: &KeyPair,
+    server_static: &KeyPair,
+    certificate: &[u8],
+) -> (NoiseHandshake, PublicKey, Vec<u8>) {
+    let mut server = NoiseHandshake::new(NOISE_START_PATTERN, &get_wa_header());
+    let hello = wa_proto::HandshakeMessage::parse_from_bytes(client_hello).unwrap();
+    let client_ephemeral =
+        PublicKey::from_djb_public_key_bytes(hello.clientHello.ephemeral()).unwrap();
+    server.authenticate(client_ephemeral.public_key_bytes().unwrap());
+
+    server.authenticate(server_ephemeral.public_key.public_key_bytes().unwrap());
+    server
+        .mix_shared_secret_into_key(&server_ephemeral.private_key, &client_ephemeral)
+        .unwrap();
+    let encrypted_static = server
+        .encrypt(server_static.public_key.public_key_bytes().unwrap())
+        .unwrap();
+    server
+        .mix_shared_secret_into_key(&server_static.private_key, &client_ephemeral)
+        .unwrap();
+    let encrypted_certificate = server.encrypt(certificate).unwrap();
+
+    let mut response = wa_proto::HandshakeServerHello::new();
+    response.ephemeral = Some(
+        server_ephemeral
+            .public_key
+            .public_key_bytes()
+            .unwrap()
+            .to_vec(),
+    );
+    response.static_ = Some(encrypted_static);
+    response.payload = Some(encrypted_certificate);
+    let mut message = wa_proto::HandshakeMessage::new();
+    message.serverHello = MessageField::some(response);
+
+    (server, client_ephemeral, message.write_to_bytes().unwrap())
+}
+
+/// Reads the next frame, skipping `skip` bytes before its length. The client sends every
+/// frame in its own message. Returns `None` once the connection is closed.
+fn read_frame(websocket: &mut WebSocket<TcpStream>, skip: usize) -> Option<Vec<u8>> {
+    loop {
+        match websocket.read_message() {
+            Ok(tungstenite::Message::Binary(data)) => {
+                return Some(data[skip + FRAME_LENGTH_SIZE..].to_vec())
+            }
+            Ok(_) => continue,
+            Err(_) => return None,
+        }
+    }
+}
+
+fn write_frame(websocket: &mut WebSocket<TcpStream>, data: &[u8]) {
+    let length = data.len().to_be_bytes();
+    let frame = [&length[length.len() - FRAME_LENGTH_SIZE..], data].concat();
+    websocket
+        .write_message(tungstenite::Message::Binary(frame))
+        .unwrap();
+}
+
+/// The server side of a connection after the handshake, see `serve`.
+pub(crate) struct FakeServer {
+    websocket: WebSocket<TcpStream>,
+    noise: NoiseSocket,
+    /// The payload that the client sent at the end of the handshake.
+    pub(crate) client_payload: wa_proto::ClientPayload,
+}
+
+impl FakeServer {
+    pub(crate) fn send_node(&mut self, node: &Node) {
+        let frame = self.noise.encrypt_frame(&marshal(node).unwrap()).unwrap();
+        write_frame(&mut self.websocket, &frame);
+    }
+
+    /// Receives the next node, or `None` once the client closes the connection.
+    pub(crate) fn receive_node(&mut self) -> Option<Node> {
+        let frame = read_frame(&mut self.websocket, 0)?;
+        Some(unmarshal(&self.noise.decrypt_frame(&frame).unwrap()).unwrap())
+    }
+}
+
+/// Starts an HTTP server for a single request, which `handler` is called with (the request
+/// line and headers, and the body) to get the status and body of the response. Returns the
+/// `host:port` of the server and the thread running it.
+pub(crate) fn serve_http(
+    handler: impl FnOnce(String, Vec<u8>) -> (u16, Vec<u8>) + Send + 'static,
+) -> (String, JoinHandle<()>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let host = listener.local_addr().unwrap().to_string();
+
+    let server = thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut request = Vec::new();
+        let mut buffer = [0u8; 4096];
+        let head_end = loop {
+            let read = stream.read(&mut buffer).unwrap();
+            assert!(read > 0, "connection closed before the end of the request");
+            request.extend_from_slice(&buffer[..read]);
+            if let Some(position) = request.windows(4).position(|window| window == b"\r\n\r\n") {
+                break position;
+            };
+        };
+        let head = String::from_utf8(request[..head_end].to_vec()).unwrap();
+        let length = head
+            .lines()
+            .find_map(|line| line.strip_prefix("Content-Length: "))
+            .map(|length| length.parse::<usize>().unwrap())
+            .unwrap_or_default();
+        let mut body = request[head_end + 4..].to_vec();
+        while body.len() < length {
+            let read = stream.read(&mut buffer).unwrap();
+            body.extend_from_slice(&buffer[..read]);
+        }
+
+        let (status, response) = handler(head, body);
+        let mut data = format!(
+            "HTTP/1.1 {status} Status\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+            response.len()
+        )
+        .into_bytes();
+        data.extend_from_slice(&response);
+        stream.write_all(&data).unwrap();
+    });
+
+    (host, server)
+}
+
+/// Starts a websocket server for a single connection that plays the server side of the
+/// Noise handshake, and then passes the connection to `handler`. Returns the URL of the
+/// server and the thread running it.
+pub(crate) fn serve(handler: impl FnOnce(FakeServer) + Send + 'static) -> (String, JoinHandle<()>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let server = thread::spawn(move || handler(accept_connection(&listener)));
+    (format!("ws://127.0.0.1:{port}/ws/chat"), server)
+}
+
+/// Like `serve`, but accepts `count` connections, e.g. to test reconnecting. Each one is
+/// handled on its own thread by `handler`, along with its index.
+pub(crate) fn serve_connections(
+    count: usize,
+    handler: impl Fn(usize, FakeServer) + Send + Sync + 'static,
+) -> (String, JoinHandle<()>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let handler = Arc::new(handler);
+
+    let server = thread::spawn(move || {
+        let connections = (0..count)
+            .map(|index| {
+                let server = accept_connection(&listener);
+                let handler = Arc::clone(&handler);
+                thread::spawn(move || handler(index, server))
+            })
+            .collect::<Vec<JoinHandle<()>>>();
+        for connection in connections {
+            connection.join().unwrap();
+        }
+    });
+
+    (format!("ws://127.0.0.1:{port}/ws/chat"), server)
+}
+
+/// Accepts a websocket connection and plays the server side of the Noise handshake.
+fn accept_connection(listener: &TcpListener) -> FakeServer {
+    let (stream, _) = listener.accept().unwrap();
+    let mut websocket = tungstenite::accept(stream).unwrap();
+    let mut csprng = rand::rngs::OsRng;
+    let server_ephemeral = KeyPair::generate(&mut csprng);
+    let server_static = KeyPair::generate(&mut csprng);
+
+    // The header is sent along with the client hello.
+    let hello = read_frame(&mut websocket, get_wa_header().len()).unwrap();
+    let certificate = certificate_chain(server_static.public_key.public_key_bytes().unwrap(), 1);
+    let (mut handshake, _, response) =
+        server_hello(&hello, &server_ephemeral, &server_static, &certificate);
+    write_frame(&mut websocket, &response);
+
+    let finish = read_frame(&mut websocket, 0).unwrap();
+    let finish = wa_proto::HandshakeMessage::parse_from_bytes(&finish).unwrap();
+    let client_static = handshake.decrypt(finish.clientFinish.static_()).unwrap();
+    handshake
+        .mix_shared_secret_into_key(
+            &server_ephemeral.private_key,
+            &PublicKey::from_djb_public_key_bytes(&client_static).unwrap(),
+        )
+        .unwrap();
+    let payload = handshake.decrypt(finish.clientFinish.payload()).unwrap();
+
+    FakeServer {
+        websocket,
+        noise: handshake.finish().into_responder(),
+        client_payload: wa_proto::ClientPayload::parse_from_bytes(&payload).unwrap(),
+    }
+}
+
+/// Plays the phone: signs the device identity with an account key and the adv secret,
+/// and wraps it in a `pair-success` iq.
+pub(crate) fn pair_success_node(device: &Device, adv_secret: &[u8]) -> Node {
+    let mut csprng = rand::rngs::OsRng;
+    let account_key = KeyPair::generate(&mut csprng);
+
+    let mut details = wa_proto::ADVDeviceIdentity::new();
+    details.rawId = Some(1234);
+    details.keyIndex = Some(3);
+    let details = details.write_to_bytes().unwrap();
+    let message = [
+        &ADV_ACCOUNT_SIGNATURE_PREFIX[..],
+        &details,
+        public_key_bytes(&device.identity_key.public_key).unwrap(),
+    ]
+    .concat();
+
+    let mut identity = wa_proto::ADVSignedDeviceIdentity::new();
+    identity.details = Some(details);
+    identity.accountSignatureKey =
+        Some(public_key_bytes(&account_key.public_key).unwrap().to_vec());
+    identity.accountSignature = Some(
+        account_key
+            .private_key
+            .calculate_signature(&message, &mut csprng)
+            .unwrap()
+            .to_vec(),
+    );
+    let identity = identity.write_to_bytes().unwrap();
+    let mut container = wa_proto::ADVSignedDeviceIdentityHMAC::new();
+    container.hmac = Some(compute_adv_sign(adv_secret, &identity));
+    container.details = Some(identity);
+
+    let child = |tag: &str, attrs: &[(&str, AttributeTypes)], content| Node {
+        tag: tag.to_string(),
+        attrs: attrs
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.clone()))
+            .collect(),
+        content,
+    };
+    Node {
+        tag: "iq".to_string(),
+        attrs: Attrs::from([
+            (
+                "id".to_string(),
+                AttributeTypes::String("pair-1".to_string()),
+            ),
+            (
+                "type".to_string(),
+                AttributeTypes::String("set".to_string()),
+            ),
+        ]),
+        content: NodeContentType::ListOfNodes(vec![child(
+            "pair-success",
+            &[],
+            NodeContentType::ListOfNodes(vec![
+                child(
+                    "device-identity",
+                    &[],
+                    NodeContentType::ByteArray(container.write_to_bytes().unwrap()),
+                ),
+                child(
+                    "device",
+                    &[(
+                        "jid",
+                        AttributeTypes::JID(JID::new_ad("919876543210", 0, 12)),
+                    )],
+                    NodeContentType::None,
+                ),
+                child(
+                    "platform",
+                    &[("name", AttributeTypes::String("android".to_string()))],
+                    NodeContentType::None,
+                ),
+            ]),
+        )]),
+    }
+}
+
+/// Builds the prekey bundle that the server would return for the device, with a new
+/// one-time prekey from its store.
+pub(crate) fn prekey_bundle(device: &Device, store: &dyn DeviceStore, jid: &JID) -> PreKeyBundle {
+    let pre_key = store.get_or_gen_pre_keys(1).unwrap().remove(0);
+    PreKeyBundle::new(
+        device.registration_id,
+        u32::from(jid.device.unwrap_or(0)).into(),
+        Some((pre_key.id().unwrap(), pre_key.public_key().unwrap())),
+        device.signed_pre_key.id().unwrap(),
+        device.signed_pre_key.public_key().unwrap(),
+        device.signed_pre_key.signature().unwrap(),
+        IdentityKey::new(device.identity_key.public_key),
+    )
+    .unwrap()
+}
+
+/// Builds the `<user>` node that the server returns for the device in response to
+/// `prekeys::build_get_prekeys_node`. It has the same children as the upload of the prekeys,
+/// with the single prekey in place of the `<list>`.
+pub(crate) fn prekey_bundle_node(jid: &JID, device: &Device, prekey: &PreKeyRecord) -> Node {
+    let upload = build_set_prekeys_node(
+        device.registration_id,
+        public_key_bytes(&device.identity_key.public_key).unwrap(),
+        &device.signed_pre_key,
+        std::slice::from_ref(prekey),
+    )
+    .unwrap();
+    let children = upload
+        .get_children()
+        .unwrap()
+        .into_iter()
+        .map(|child| match child.tag.as_str() {
+            "list" => child.get_optional_child_by_tag(&["key"]).unwrap(),
+            _ => child,
+        })
+        .collect();
+
+    Node {
+        tag: "user".to_string(),
+        attrs: Attrs::from([("jid".to_string(), AttributeTypes::JID(jid.clone()))]),
+        content: NodeContentType::ListOfNodes(children),
+    }
+}
+
+/// Builds an incoming `<message>` stanza with the given `<enc>` children. `participant` is
+/// the sender of a group message.
+pub(crate) fn message_node(
+    id: &str,
+    from: &JID,
+    participant: Option<&JID>,
+    children: Vec<Node>,
+) -> Node {
+    let mut attrs = Attrs::from([
+        ("id".to_string(), AttributeTypes::String(id.to_string())),
+        ("from".to_string(), AttributeTypes::JID(from.clone())),
+        (
+            "type".to_string(),
+            AttributeTypes::String("text".to_string()),
+        ),
+        (
+            "t".to_string(),
+            AttributeTypes::String("1700000000".to_string()),
+        ),
+    ]);
+    if let Some(participant) = participant {
+        attrs.insert(
+            "participant".to_string(),
+            AttributeTypes::JID(participant.clone()),
+        );
+    };
+    Node {
+        tag: "message".to_string(),
+        attrs,
+        content: NodeContentType::ListOfNodes(children),
+    }
+}
+
+/// Builds the `<usync>` node that the server returns in response to
+/// `usync::build_usync_devices_query`, listing the given devices under their users.
+pub(crate) fn usync_devices_node(devices: &[JID]) -> Node {
+    let mut users: Vec<(JID, Vec<Node>)> = Vec::new();
+    for device in devices {
+        let node = Node {
+            tag: "device".to_string(),
+            attrs: Attrs::from([(
+                "id".to_string(),
+                AttributeTypes::String(device.device.unwrap_or(0).to_string()),
+            )]),
+            content: NodeContentType::None,
+        };
+        match users.iter_mut().find(|(user, _)| user.user == device.user) {
+            Some((_, nodes)) => nodes.push(node),
+            None => users.push((device.to_non_ad(), vec![node])),
+        };
+    }
+
+    let users = users
+        .into_iter()
+        .map(|(user, nodes)| Node {
+            tag: "user".to_string(),
+            attrs: Attrs::from([("jid".to_string(), AttributeTypes::JID(user))]),
+            content: NodeContentType::ListOfNodes(vec![Node {
+                tag: "devices".to_string(),
+                attrs: Attrs::new(),
+                content: NodeContentType::ListOfNodes(vec![Node {
+                    tag: "device-list".to_string(),
+                    attrs: Attrs::new(),
+                    content: NodeContentType::ListOfNodes(nodes),
+                }]),
+            }]),
+        })
+        .collect();
+
+    Node {
+        tag: "usync".to_string(),
+        attrs: Attrs::new(),
+        content: NodeContentType::ListOfNodes(vec![Node {
+            tag: "list".to_string(),
+            attrs: Attrs::new(),
+            content: NodeContentType::ListOfNodes(users),
+        }]),
+    }
+}
+
+/// Checks that the device survives a round trip through the store, and that deleting it clears
+/// the store.
+pub(crate) fn check_device(store: &dyn DeviceStore) {
+    assert!(store.load_device().unwrap().is_none());
+
+    let mut device = Device::new();
+    device.id = Some(JID::from_str("919876543210.0:3@s.whatsapp.net").unwrap());
+    device.account = Some(wa_proto::ADVSignedDeviceIdentity {
+        details: Some(vec![1, 2, 3]),
+        ..Default::default()
+    });
+    device.platform = "android".to_string();
+    device.push_name = "Alice".to_string();
+    store.save_device(&device).unwrap();
+
+    let loaded = store.load_device().unwrap().unwrap();
+    assert_eq!(loaded.noise_key.public_key, device.noise_key.public_key);
+    assert_eq!(
+        loaded.identity_key.private_key.serialize(),
+        device.identity_key.private_key.serialize()
+    );
+    assert_eq!(
+        loaded.signed_pre_key.serialize().unwrap(),
+        device.signed_pre_key.serialize().unwrap()
+    );
+    assert_eq!(loaded.registration_id, device.registration_id);
+    assert_eq!(loaded.adv_secret_key, device.adv_secret_key);
+    assert_eq!(loaded.id, device.id);
+    assert_eq!(loaded.account, device.account);
+    assert_eq!(loaded.platform, "android");
+    assert_eq!(loaded.push_name, "Alice");
+
+    store.put_session("919876543210.3", &[1]).unwrap();
+    store.delete_device().unwrap();
+    assert!(store.load_device().unwrap().is_none());
+    assert!(!store.has_session("919876543210.3").unwrap());
+}
+
+/// Checks the identity keys and sessions of a store, including deleting those of a user.
+pub(crate) fn check_identities_and_sessions(store: &dyn DeviceStore) {
+    assert!(store.is_trusted_identity("1234.1", [1; 32]).unwrap());
+
+    store.put_identity("1234.1", [1; 32]).unwrap();
+    store.put_identity("1234.2", [2; 32]).unwrap();
+    store.put_identity("12345.1", [3; 32]).unwrap();
+    assert_eq!(store.get_identity("1234.1").unwrap(), Some([1; 32]));
+    assert!(store.is_trusted_identity("1234.1", [1; 32]).unwrap());
+    assert!(!store.is_trusted_identity("1234.1", [2; 32]).unwrap());
+
+    store.delete_identity("1234.2").unwrap();
+    assert_eq!(store.get_identity("1234.2").unwrap(), None);
+    store.delete_all_identities("1234").unwrap();
+    assert_eq!(store.get_identity("1234.1").unwrap(), None);
+    assert_eq!(store.get_identity("12345.1").unwrap(), Some([3; 32]));
+
+    store.put_session("1234.1", &[1, 2]).unwrap();
+    store.put_session("1234.1", &[3]).unwrap();
+    store.put_session("1234.2", &[4]).unwrap();
+    store.put_session("12345.1", &[5]).unwrap();
+    assert_eq!(store.get_session("1234.1").unwrap(), Some(vec![3]));
+    store.delete_session("1234.1").unwrap();
+    assert!(!store.has_session("1234.1").unwrap());
+    store.delete_all_sessions("1234").unwrap();
+    assert!(!store.has_session("1234.2").unwrap());
+    assert!(store.has_session("12345.1").unwrap());
+}
+
+/// Checks that a store generates prekeys with increasing ids and tracks which were uploaded.
+pub(crate) fn check_pre_keys(store: &dyn DeviceStore) {
+    let keys = store.get_or_gen_pre_keys(3).unwrap();
+    let ids: Vec<u32> = keys.iter().map(|key| key.id().unwrap().into()).collect();
+    assert_eq!(ids, [1, 2, 3]);
+    assert_eq!(store.uploaded_pre_key_count().unwrap(), 0);
+
+    store.mark_pre_keys_as_uploaded(2).unwrap();
+    assert_eq!(store.uploaded_pre_key_count().unwrap(), 2);
+    let keys = store.get_or_gen_pre_keys(2).unwrap();
+    let ids: Vec<u32> = keys.iter().map(|key| key.id().unwrap().into()).collect();
+    assert_eq!(ids, [3, 4]);
+
+    let key = store.get_pre_key(4).unwrap().unwrap();
+    assert_eq!(key.serialize().unwrap(), keys[1].serialize().unwrap());
+    store.remove_pre_key(4).unwrap();
+    assert!(store.get_pre_key(4).unwrap().is_none());
+}
+
+/// Checks the sender keys and app state sync keys of a store.
+pub(crate) fn check_sender_and_app_state_sync_keys(store: &dyn DeviceStore) {
+    store.put_sender_key("group", "1234.1", &[1]).unwrap();
+    store.put_sender_key("group", "1234.1", &[2]).unwrap();
+    assert_eq!(
+        store.get_sender_key("group", "1234.1").unwrap(),
+        Some(vec![2])
+    );
+    assert_eq!(store.get_sender_key("other", "1234.1").unwrap(), None);
+
+    let key = AppStateSyncKey {
+        data: vec![1],
+        fingerprint: vec![2],
+        timestamp: 10,
+    };
+    store.put_app_state_sync_key(&[7], key.clone()).unwrap();
+    let older = AppStateSyncKey {
+        data: vec![3],
+        timestamp: 5,
+        ..key.clone()
+    };
+    store.put_app_state_sync_key(&[7], older).unwrap();
+    assert_eq!(store.get_app_state_sync_key(&[7]).unwrap(), Some(key));
+    assert_eq!(store.get_app_state_sync_key(&[8]).unwrap(), None);
+}
+
+/// Checks the app state versions and mutation MACs of a store.
+pub(crate) fn check_app_state(store: &dyn DeviceStore) {
+    assert!(store.get_app_state_version("regular").unwrap().is_none());
+    store
+        .put_app_state_version("regular", 3, &[9; 128])
+        .unwrap();
+    assert_eq!(
+        store.get_app_state_version("regular").unwrap(),
+        Some((3, [9; 128]))
+    );
+
+    let mac = |index: u8, value: u8| AppStateMutationMAC {
+        index_mac: vec![index],
+        value_mac: vec![value],
+    };
+    store
+        .put_app_state_mutation_macs("regular", 2, &[mac(1, 10), mac(2, 20)])
+        .unwrap();
+    store
+        .put_app_state_mutation_macs("regular", 3, &[mac(1, 11)])
+        .unwrap();
+    store
+        .put_app_state_mutation_macs("critical_block", 3, &[mac(2, 30)])
+        .unwrap();
+    // The latest version that set the index wins.
+    assert_eq!(
+        store.get_app_state_mutation_mac("regular", &[1]).unwrap(),
+        Some(vec![11])
+    );
+    assert_eq!(
+        store.get_app_state_mutation_mac("regular", &[2]).unwrap(),
+        Some(vec![20])
+    );
+
+    store
+        .delete_app_state_mutation_macs("regular", &[vec![1]])
+        .unwrap();
+    assert!(store
+        .get_app_state_mutation_mac("regular", &[1])
+        .unwrap()
+        .is_none());
+
+    store.delete_app_state_version("regular").unwrap();
+    assert!(store.get_app_state_version("regular").unwrap().is_none());
+    assert!(store
+        .get_app_state_mutation_mac("regular", &[2])
+        .unwrap()
+        .is_none());
+    assert_eq!(
+        store
+            .get_app_state_mutation_mac("critical_block", &[2])
+            .unwrap(),
+        Some(vec![30])
+    );
+}
+
+/// Checks the contacts and chat settings of a store.
+pub(crate) fn check_contacts_and_chat_settings(store: &dyn DeviceStore) {
+    let user = JID::from_str("1234@s.whatsapp.net").unwrap();
+    assert_eq!(
+        store.put_push_name(&user, "Alice").unwrap(),
+        Some(String::new())
+    );
+    assert_eq!(store.put_push_name(&user, "Alice").unwrap(), None);
+    assert_eq!(
+        store.put_push_name(&user, "Bob").unwrap(),
+        Some("Alice".to_string())
+    );
+    store.put_business_name(&user, "Shop").unwrap();
+    store
+        .put_contact_name(&user, "Robert", "Robert Smith")
+        .unwrap();
+
+    let contact = store.get_contact(&user).unwrap().unwrap();
+    assert_eq!(contact.first_name, "Robert");
+    assert_eq!(contact.full_name, "Robert Smith");
+    assert_eq!(contact.push_name, "Bob");
+    assert_eq!(contact.business_name, "Shop");
+    let contacts = store.get_all_contacts().unwrap();
+    assert_eq!(contacts.len(), 1);
+    assert_eq!(contacts[0].0, user);
+
+    let chat = JID::from_str("1234-5678@g.us").unwrap();
+    assert!(store.get_chat_settings(&chat).unwrap().is_none());
+    let muted_until = OffsetDateTime::from_unix_timestamp(1_700_000_000).unwrap();
+    store.put_muted_until(&chat, muted_until).unwrap();
+    store.put_pinned(&chat, true).unwrap();
+    let settings = store.get_chat_settings(&chat).unwrap().unwrap();
+    assert_eq!(settings.muted_until, muted_until);
+    assert!(settings.pinned);
+    assert!(!settings.archived);
+}
+
+/// The ID of the app state sync key saved by `put_app_state_key`.
+pub(crate) const APP_STATE_KEY_ID: &[u8] = &[0, 0, 1];
+
+/// The data of the app state sync key saved by `put_app_state_key`.
+const APP_STATE_KEY_DATA: [u8; 32] = [5; 32];
+
+/// Saves the app state sync key that the patches built by the tests are encrypted with, as
+/// if the phone had shared it.
+pub(crate) fn put_app_state_key(store: &dyn DeviceStore) {
+    store
+        .put_app_state_sync_key(
+            APP_STATE_KEY_ID,
+            AppStateSyncKey {
+                data: APP_STATE_KEY_DATA.to_vec(),
+                fingerprint: Vec::new(),
+                timestamp: 0,
+            },
+        )
+        .unwrap();
+}
+
+/// Encrypts an app state mutation like the phone does.
+pub(crate) fn encrypt_app_state_mutation(
+    operation: SyncdOperation,
+    index: &[&str],
+    action: wa_proto::SyncActionValue,
+) -> wa_proto::SyncdMutation {
+    let keys = expand_app_state_keys(&APP_STATE_KEY_DATA);
+    let index = serde_json::to_vec(index).unwrap();
+    let mut data = wa_proto::SyncActionData::new();
+    data.index = Some(index.clone());
+    data.value = Some(action).into();
+    data.version = Some(2);
+    let iv = [3u8; 16];
+    let ciphertext = Cbc::<Aes256, Pkcs7>::new_from_slices(&keys.value_encryption, &iv)
+        .unwrap()
+        .encrypt_vec(&data.write_to_bytes().unwrap());
+    let content = [iv.as_slice(), &ciphertext].concat();
+    let mac = content_mac(operation, &content, APP_STATE_KEY_ID, &keys.value_mac);
+
+    let mut record = wa_proto::SyncdRecord::new();
+    record.index.mut_or_insert_default().blob = Some(index_mac(&index, &keys.index));
+    record.value.mut_or_insert_default().blob = Some([content, mac].concat());
+    record.keyId.mut_or_insert_default().id = Some(APP_STATE_KEY_ID.to_vec());
+    let mut mutation = wa_proto::SyncdMutation::new();
+    mutation.operation = Some(operation.into());
+    mutation.record = Some(record).into();
+    mutation
+}
+
+/// Builds the next patch of an app state collection with the mutations, with valid MACs.
+/// `previous` returns the value MAC that an index was set to before.
+pub(crate) fn build_app_state_patch(
+    name: PatchName,
+    state: &mut HashState,
+    mutations: Vec<wa_proto::SyncdMutation>,
+    previous: impl Fn(&[u8]) -> Option<Vec<u8>>,
+) -> wa_proto::SyncdPatch {
+    let keys = expand_app_state_keys(&APP_STATE_KEY_DATA);
+    state.version += 1;
+    state
+        .update_hash(&mutations, |index_mac, _| Ok(previous(index_mac)))
+        .unwrap();
+
+    let mut patch = wa_proto::SyncdPatch::new();
+    patch.version.mut_or_insert_default().version = Some(state.version);
+    patch.mutations = mutations;
+    patch.keyId.mut_or_insert_default().id = Some(APP_STATE_KEY_ID.to_vec());
+    patch.snapshotMac = Some(state.snapshot_mac(name, &keys.snapshot_mac));
+    patch.patchMac = Some(patch_mac(&patch, name, &keys.patch_mac, state.version).unwrap());
+    patch
+}
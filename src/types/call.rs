@@ -1,10 +1,20 @@
+use std::{fmt, str::FromStr};
+
+use crate::RhustAppError;
+
 use super::JID;
 
 /// This contains the basic common metadata about different call events.
+#[derive(Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 pub struct BasicCallMetadata {
     /// This is the chat (user/group) in which the call was created.
     pub from: JID,
     /// This is the timestamp at which the event started.
+    #[cfg_attr(
+        feature = "serde",
+        serde(serialize_with = "time::serde::rfc3339::serialize")
+    )]
     pub timestamp: time::OffsetDateTime,
     /// This is the user who initiated the call.
     pub call_creator: JID,
@@ -13,9 +23,81 @@ pub struct BasicCallMetadata {
 }
 
 /// This contains the metadata about the caller's WhatsApp client
+#[derive(Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 pub struct CallRemoteMetadata {
     /// The platform of the caller's client
     pub remote_platform: String,
     /// The version of the caller's client
     pub remote_version: String,
 }
+
+/// The reason included in a call termination.
+#[derive(Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub enum CallTerminateReason {
+    /// "timeout"
+    Timeout,
+    /// "reject"
+    Reject,
+    /// "busy"
+    Busy,
+    /// "accepted_elsewhere"
+    AcceptedElsewhere,
+    Value(String),
+}
+
+impl FromStr for CallTerminateReason {
+    type Err = RhustAppError;
+
+    fn from_str(input: &str) -> Result<Self, RhustAppError> {
+        match input {
+            "timeout" => Ok(Self::Timeout),
+            "reject" => Ok(Self::Reject),
+            "busy" => Ok(Self::Busy),
+            "accepted_elsewhere" => Ok(Self::AcceptedElsewhere),
+            _ => Ok(Self::Value(input.to_string())),
+        }
+    }
+}
+
+impl fmt::Display for CallTerminateReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Timeout => write!(f, "timeout"),
+            Self::Reject => write!(f, "reject"),
+            Self::Busy => write!(f, "busy"),
+            Self::AcceptedElsewhere => write!(f, "accepted_elsewhere"),
+            Self::Value(value) => write!(f, "{value}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_call_terminate_reason_known() {
+        for (input, expected) in [
+            ("timeout", CallTerminateReason::Timeout),
+            ("reject", CallTerminateReason::Reject),
+            ("busy", CallTerminateReason::Busy),
+            ("accepted_elsewhere", CallTerminateReason::AcceptedElsewhere),
+        ] {
+            let reason = CallTerminateReason::from_str(input).unwrap();
+            assert_eq!(
+                std::mem::discriminant(&reason),
+                std::mem::discriminant(&expected)
+            );
+            assert_eq!(reason.to_string(), input);
+        }
+    }
+
+    #[test]
+    fn test_call_terminate_reason_unknown() {
+        let reason = CallTerminateReason::from_str("something_new").unwrap();
+        assert!(matches!(&reason, CallTerminateReason::Value(v) if v == "something_new"));
+        assert_eq!(reason.to_string(), "something_new");
+    }
+}
@@ -1,9 +1,20 @@
-use std::fmt::Display;
+use std::{fmt::Display, str::FromStr};
 
 use time::{Duration, OffsetDateTime};
 
-use crate::{types::JID, RhustAppError};
+use crate::{
+    binary::{proto as wa_proto, Node, NodeContentType},
+    new_rhustapp_error,
+    types::{
+        self, BasicCallMetadata, CallRemoteMetadata, CallTerminateReason, ChatPresenceMedia,
+        GroupAnnounce, GroupDelete, GroupEphemeral, GroupLocked, GroupName, MessageID, MessageInfo,
+        MessageSource, PrivacySetting, PrivacySettingType, JID,
+    },
+    RhustAppError,
+};
 
+#[derive(Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize), serde(tag = "type"))]
 pub enum RhustAppEventType {
     /// It is emitted after connecting when there's no session data in the device store.
     ///
@@ -35,6 +46,11 @@ pub enum RhustAppEventType {
     /// store at this point, which is why this event doesn't contain any data.
     Connected,
 
+    /// It is emitted when the connection is closed by the server, or fails, without
+    /// `Client::disconnect` having been called. It is not emitted when the client reconnects
+    /// because the server asked for a restart.
+    Disconnected,
+
     /// It is emitted when the keepalive ping request to WhatsApp web servers time out.
     ///
     /// Currently, there's no automatic handling for these, but it's expected that the TCP
@@ -64,18 +80,106 @@ pub enum RhustAppEventType {
 
     /// It is emitted when there's a connection failure with the `ConnectFailureReason::TempBanned` reason code.
     TemporaryBan(TemporaryBan),
+
+    /// It is emitted when the user changes a privacy setting, either from this client or
+    /// from another device.
+    PrivacySettingsChange(PrivacySettingsChange),
+
+    /// It is emitted when the other party terminates a call.
+    CallTerminate(CallTerminate),
+
+    /// It is emitted when an outgoing message is delivered to or read by another user, or
+    /// when another device of the current user reads an incoming message.
+    Receipt(Receipt),
+
+    /// It is emitted when the server sends a stream error with code 515, which it does e.g.
+    /// right after pairing. It is not a failure: the client should disconnect and reconnect
+    /// immediately instead of treating it as a logout.
+    StreamRestartRequired,
+
+    /// It is emitted when the server sends an unknown stream error.
+    StreamError(StreamError),
+
+    /// It is emitted when events were dropped because the application didn't consume them
+    /// fast enough and the event queue uses the `DropOldest` overflow policy.
+    EventsDropped(EventsDropped),
+
+    /// It is emitted when a user adds or removes a device (i.e. links or unlinks a companion
+    /// device). The cached device list of the user should be updated accordingly.
+    DeviceListUpdate(DeviceListUpdate),
+
+    /// It is emitted when the server reports that few prekeys are left, so more prekeys
+    /// should be generated and uploaded.
+    PreKeysLow(PreKeysLow),
+
+    /// It is emitted when receiving a new message.
+    Message(Box<Message>),
+
+    /// It is emitted when a user's online status changes. Presence updates are only received
+    /// for users whose presence the client has subscribed to.
+    Presence(Presence),
+
+    /// It is emitted when a user starts or stops typing or recording in a chat.
+    ChatPresence(ChatPresence),
+
+    /// It is emitted when the metadata or participants of a group change.
+    GroupInfo(GroupInfo),
+
+    /// It is emitted when the user receives a call.
+    CallOffer(CallOffer),
+
+    /// It is emitted when a call is accepted.
+    CallAccept(CallAccept),
+
+    /// It is emitted when the server rejects the connection with an unknown reason code.
+    /// Logouts and temporary bans are emitted as `LoggedOut` and `TemporaryBan` instead.
+    ConnectFailure(ConnectFailure),
+
+    /// It is emitted when the server rejects the connection because the client is outdated.
+    ClientOutdated,
+    /// It is emitted when a user or group changes or removes its profile picture.
+    PictureChange(PictureChange),
+
+    /// It is emitted when an entry in the contacts of the phone is changed, as synced by
+    /// the app state.
+    Contact(Contact),
+
+    /// It is emitted when a chat is pinned or unpinned from another device.
+    Pin(Pin),
+
+    /// It is emitted when a chat is muted or unmuted from another device.
+    Mute(Mute),
+
+    /// It is emitted when a chat is archived or unarchived from another device.
+    Archive(Archive),
+
+    /// It is emitted when a message is starred or unstarred from another device.
+    Star(Star),
+
+    /// It is emitted when a message is deleted for the current user from another device.
+    DeleteForMe(DeleteForMe),
+
+    /// It is emitted when a message is received from a user whose push name differs from
+    /// the one in the store, e.g. because it's the first message from them.
+    PushNameUpdated(Box<PushNameUpdated>),
 }
 
+#[derive(Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 pub struct QR {
     pub codes: Vec<String>,
 }
 
+#[derive(Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 pub struct PairSuccess {
     pub id: JID,
     pub business_name: String,
     pub platform: String,
 }
 
+#[derive(Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 pub struct PairError {
     pub id: JID,
     pub business_name: String,
@@ -83,17 +187,33 @@ pub struct PairError {
     pub error: RhustAppError,
 }
 
+#[derive(Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 pub struct KeepAliveTimeout {
     pub error_count: i32,
+    #[cfg_attr(
+        feature = "serde",
+        serde(serialize_with = "time::serde::rfc3339::serialize")
+    )]
     pub last_success: OffsetDateTime,
 }
 
+impl KeepAliveTimeout {
+    /// Returns how long the connection has been unhealthy at `now`, i.e. the time since the
+    /// last successful keepalive. It is zero if `now` is before the last success.
+    pub fn downtime(&self, now: OffsetDateTime) -> Duration {
+        (now - self.last_success).max(Duration::ZERO)
+    }
+}
+
 /// It is an error code included in the connection failure events.
 ///
 /// 400, 500 and 501 are also existing codes, but the meaning is unknown
 ///
 /// 503 doesn't seem to be included in the web app JS with the other codes, and its
 /// very rare, but does happen after a 503 stream error sometimes.
+#[derive(Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 pub enum ConnectFailureReason {
     /// 401
     LoggedOut,
@@ -168,6 +288,12 @@ impl ConnectFailureReason {
             _ => false,
         }
     }
+
+    /// Returns true if the failure is a temporary problem on the server (503 or any other
+    /// 5xx code), after which the client should reconnect instead of giving up.
+    pub fn is_transient(&self) -> bool {
+        (500..600).contains(&self.to_error_code())
+    }
 }
 
 impl Display for ConnectFailureReason {
@@ -176,6 +302,8 @@ impl Display for ConnectFailureReason {
     }
 }
 
+#[derive(Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 pub struct LoggedOut {
     /// It is true if the event was triggered by a connect failure message.
     /// If it's false, the event was triggered by a stream:error message.
@@ -184,6 +312,8 @@ pub struct LoggedOut {
     pub reason: ConnectFailureReason,
 }
 
+#[derive(Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 pub enum TempBanReason {
     /// 101
     SentToTooManyPeople,
@@ -251,8 +381,15 @@ impl Display for TempBanReason {
     }
 }
 
+#[derive(Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 pub struct TemporaryBan {
     pub code: TempBanReason,
+    /// Serialized as the whole number of seconds.
+    #[cfg_attr(
+        feature = "serde",
+        serde(serialize_with = "serialize_duration_seconds")
+    )]
     pub expire: Duration,
 }
 
@@ -269,4 +406,1100 @@ impl TemporaryBan {
     }
 }
 
+/// A privacy setting that was changed, with its new value.
+#[derive(Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct ChangedPrivacySetting {
+    /// The privacy setting that was changed.
+    pub setting: PrivacySettingType,
+    /// The new value of the setting.
+    pub value: PrivacySetting,
+}
+
+#[derive(Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct PrivacySettingsChange {
+    /// The settings that were changed. Changing a setting usually only changes one, but a
+    /// notification can contain several.
+    pub changes: Vec<ChangedPrivacySetting>,
+}
+
+impl PrivacySettingsChange {
+    /// Parses the `<notification type="privacy">` node with the changed settings.
+    pub fn from_node(node: &Node) -> Result<Self, RhustAppError> {
+        if !node.tag.eq("notification") {
+            return Err(new_rhustapp_error(
+                &format!("expected <notification>, got <{}>", node.tag),
+                None,
+            ));
+        };
+        let mut ag = node.attr_getter();
+        match ag.string("type") {
+            Some(t) if t.eq("privacy") => {}
+            _ => {
+                return Err(new_rhustapp_error(
+                    "expected notification of type 'privacy'",
+                    ag.error().map(|err| err.to_string()),
+                ))
+            }
+        }
+
+        let privacy = node
+            .get_optional_child_by_tag(&["privacy"])
+            .ok_or_else(|| {
+                new_rhustapp_error("didn't find <privacy> in privacy notification", None)
+            })?;
+
+        let mut changes = Vec::new();
+        for category in privacy.get_children_by_tag("category").unwrap_or_default() {
+            let mut ag = category.attr_getter();
+            let name = ag.string("name");
+            let value = ag.string("value");
+            if let Some(err) = ag.error() {
+                return Err(new_rhustapp_error(
+                    "failed to parse privacy setting category",
+                    Some(err.to_string()),
+                ));
+            };
+
+            changes.push(ChangedPrivacySetting {
+                setting: PrivacySettingType::from_str(&name.unwrap())?,
+                value: PrivacySetting::from_str(&value.unwrap())?,
+            });
+        }
+
+        Ok(Self { changes })
+    }
+}
+
+#[derive(Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct CallTerminate {
+    pub metadata: BasicCallMetadata,
+    /// The reason why the call was terminated.
+    pub reason: CallTerminateReason,
+}
+
+#[derive(Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct StreamError {
+    /// The `code` attribute of the `<stream:error>` node.
+    pub code: String,
+}
+
+#[derive(Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct EventsDropped {
+    /// The number of events that were dropped.
+    pub count: usize,
+}
+
+/// The type of a receipt.
+#[derive(Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub enum ReceiptType {
+    /// ("") The message was delivered to the device (but the user might not have noticed).
+    Delivered,
+    /// ("sender") Sent by your other devices when a message you sent is delivered to them.
+    Sender,
+    /// ("retry") The message was delivered to the device, but decrypting the message failed.
+    Retry,
+    /// ("read") The user opened the chat and saw the message.
+    Read,
+    /// ("read-self") The current user read a message from a different device, and read
+    /// receipts are disabled in privacy settings.
+    ReadSelf,
+    /// ("played") The user opened a view-once media message.
+    Played,
+    Value(String),
+}
+
+impl Display for ReceiptType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Delivered => write!(f, ""),
+            Self::Sender => write!(f, "sender"),
+            Self::Retry => write!(f, "retry"),
+            Self::Read => write!(f, "read"),
+            Self::ReadSelf => write!(f, "read-self"),
+            Self::Played => write!(f, "played"),
+            Self::Value(value) => write!(f, "{value}"),
+        }
+    }
+}
+
+impl FromStr for ReceiptType {
+    type Err = RhustAppError;
+
+    fn from_str(input: &str) -> Result<Self, RhustAppError> {
+        match input {
+            "" => Ok(Self::Delivered),
+            "sender" => Ok(Self::Sender),
+            "retry" => Ok(Self::Retry),
+            "read" => Ok(Self::Read),
+            "read-self" => Ok(Self::ReadSelf),
+            "played" => Ok(Self::Played),
+            _ => Ok(Self::Value(input.to_string())),
+        }
+    }
+}
+
+#[derive(Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct Receipt {
+    pub source: MessageSource,
+    /// The IDs of the messages this receipt is for.
+    pub message_ids: Vec<MessageID>,
+    #[cfg_attr(
+        feature = "serde",
+        serde(serialize_with = "time::serde::rfc3339::serialize")
+    )]
+    pub timestamp: OffsetDateTime,
+    /// Serialized as `receipt_type`, since `type` is the event discriminator.
+    #[cfg_attr(feature = "serde", serde(rename = "receipt_type"))]
+    pub r#type: ReceiptType,
+    /// The sender of the messages in group receipts, from the `participant` attribute.
+    pub participant: Option<JID>,
+}
+
+impl Receipt {
+    /// Parses a `<receipt>` node. The receipt may refer to a single message using the `id`
+    /// attribute, or to multiple messages with an additional `<list>` of `<item id="...">`.
+    pub fn from_node(node: &Node, own_jid: &JID) -> Result<Self, RhustAppError> {
+        if !node.tag.eq("receipt") {
+            return Err(new_rhustapp_error(
+                &format!("expected <receipt>, got <{}>", node.tag),
+                None,
+            ));
+        };
+
+        let mut source = MessageSource::from_node(node, own_jid, false)?;
+
+        let mut ag = node.attr_getter();
+        let main_id = ag.string("id");
+        let timestamp = ag.unix_time("t");
+        let r#type = ReceiptType::from_str(&ag.optional_string("type").unwrap_or_default())?;
+        let participant = ag.optional_jid("participant");
+        if let Some(err) = ag.error() {
+            return Err(new_rhustapp_error(
+                "failed to parse receipt",
+                Some(err.to_string()),
+            ));
+        };
+
+        // Some group and broadcast receipts don't name a participant, in which case the
+        // chat itself is the sender.
+        if source.is_group && participant.is_none() {
+            source.sender = source.chat.clone();
+        };
+
+        let mut message_ids = vec![main_id.unwrap()];
+        if let Some(list) = node.get_optional_child_by_tag(&["list"]) {
+            for item in list.get_children_by_tag("item").unwrap_or_default() {
+                if let Some(id) = item.attr_getter().optional_string("id") {
+                    message_ids.push(id);
+                };
+            }
+        };
+
+        Ok(Self {
+            source,
+            message_ids,
+            timestamp: timestamp.unwrap(),
+            r#type,
+            participant,
+        })
+    }
+}
+
+#[derive(Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct DeviceListUpdate {
+    /// The user whose device list changed.
+    pub jid: JID,
+    /// The devices that were added.
+    pub added: Vec<JID>,
+    /// The devices that were removed.
+    pub removed: Vec<JID>,
+    /// The hash of the new device list, if the server sent one.
+    pub device_hash: Option<String>,
+    /// The raw signed key index list, if the server sent one.
+    pub key_index: Option<Vec<u8>>,
+}
+
+impl DeviceListUpdate {
+    /// Parses the `<notification type="devices">` node.
+    pub fn from_node(node: &Node) -> Result<Self, RhustAppError> {
+        node.expect_tag("notification")?;
+        node.expect_attr("type", "devices")?;
+
+        let mut ag = node.attr_getter();
+        let jid = ag.jid("from");
+        if let Some(err) = ag.error() {
+            return Err(new_rhustapp_error(
+                "failed to parse device notification",
+                Some(err.to_string()),
+            ));
+        };
+
+        let mut update = Self {
+            jid: jid.unwrap(),
+            added: Vec::new(),
+            removed: Vec::new(),
+            device_hash: None,
+            key_index: None,
+        };
+
+        for child in node.get_children().unwrap_or_default() {
+            if !child.tag.eq("add") && !child.tag.eq("remove") {
+                continue;
+            };
+            if let Some(hash) = child.attr_getter().optional_string("device_hash") {
+                update.device_hash = Some(hash);
+            };
+
+            let mut devices = Vec::new();
+            for device in child.get_children_by_tag("device").unwrap_or_default() {
+                let mut ag = device.attr_getter();
+                let device_jid = ag.jid("jid");
+                if let Some(err) = ag.error() {
+                    return Err(new_rhustapp_error(
+                        &format!("failed to parse device in <{}>", child.tag),
+                        Some(err.to_string()),
+                    ));
+                };
+                devices.push(device_jid.unwrap());
+            }
+            if child.tag.eq("add") {
+                update.added.append(&mut devices);
+            } else {
+                update.removed.append(&mut devices);
+            };
+
+            if let Some(key_index) = child.get_optional_child_by_tag(&["key-index-list"]) {
+                if let NodeContentType::ByteArray(bytes) = key_index.content {
+                    update.key_index = Some(bytes);
+                };
+            };
+        }
+
+        Ok(update)
+    }
+}
+
+#[derive(Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct PreKeysLow {
+    /// The number of prekeys the server still has.
+    pub remaining: i32,
+}
+
+impl PreKeysLow {
+    /// Parses the `<notification type="encrypt">` node with a `<count value="N">` child.
+    pub fn from_node(node: &Node) -> Result<Self, RhustAppError> {
+        node.expect_tag("notification")?;
+        node.expect_attr("type", "encrypt")?;
+
+        let count = node.get_optional_child_by_tag(&["count"]).ok_or_else(|| {
+            new_rhustapp_error("didn't find <count> in encrypt notification", None)
+        })?;
+        let mut ag = count.attr_getter();
+        let remaining = ag.i32("value");
+        if let Some(err) = ag.error() {
+            return Err(new_rhustapp_error(
+                "failed to parse prekey count",
+                Some(err.to_string()),
+            ));
+        };
+
+        Ok(Self {
+            remaining: remaining.unwrap(),
+        })
+    }
+}
+
+#[derive(Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct Message {
+    pub info: MessageInfo,
+    /// The decrypted content of the message. It is `None` if the `<enc>` payloads of the
+    /// message node haven't been decrypted.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub message: Option<Box<wa_proto::Message>>,
+}
+
+#[derive(Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct Presence {
+    /// The user whose presence changed.
+    pub from: JID,
+    /// True if the user is now offline.
+    pub unavailable: bool,
+    /// When the user was last online. It is `None` if the user is online, or if they hide
+    /// their last seen time.
+    #[cfg_attr(
+        feature = "serde",
+        serde(serialize_with = "time::serde::rfc3339::option::serialize")
+    )]
+    pub last_seen: Option<OffsetDateTime>,
+}
+
+impl Presence {
+    /// Parses the `<presence>` node.
+    pub fn from_node(node: &Node) -> Result<Self, RhustAppError> {
+        node.expect_tag("presence")?;
+
+        let mut ag = node.attr_getter();
+        let from = ag.jid("from");
+        let unavailable = ag.optional_string("type").as_deref() == Some("unavailable");
+        let last_seen = match ag.optional_string("last") {
+            Some(last) if !last.is_empty() && !last.eq("deny") => ag.unix_time("last"),
+            _ => None,
+        };
+        if let Some(err) = ag.error() {
+            return Err(new_rhustapp_error(
+                "failed to parse presence",
+                Some(err.to_string()),
+            ));
+        };
+
+        Ok(Self {
+            from: from.unwrap(),
+            unavailable,
+            last_seen,
+        })
+    }
+}
+
+#[derive(Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct ChatPresence {
+    pub source: MessageSource,
+    /// Whether the user is typing (composing) or stopped typing (paused).
+    pub state: types::ChatPresence,
+    /// Whether the user is typing a text message or recording audio.
+    pub media: ChatPresenceMedia,
+}
+
+impl ChatPresence {
+    /// Parses the `<chatstate>` node, which has a `<composing>` or `<paused>` child.
+    pub fn from_node(node: &Node, own_jid: &JID) -> Result<Self, RhustAppError> {
+        node.expect_tag("chatstate")?;
+
+        let source = MessageSource::from_node(node, own_jid, true)?;
+        let child = node
+            .get_children()
+            .and_then(|children| children.into_iter().next())
+            .ok_or_else(|| new_rhustapp_error("missing child in chatstate", None))?;
+        let media = child
+            .attr_getter()
+            .optional_string("media")
+            .unwrap_or_default();
+
+        Ok(Self {
+            source,
+            state: types::ChatPresence::from_str(&child.tag)?,
+            media: ChatPresenceMedia::from_str(&media)?,
+        })
+    }
+}
+
+#[derive(Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct GroupInfo {
+    /// The group whose info changed.
+    pub jid: JID,
+    /// The push name of the user who made the change.
+    pub notify: Option<String>,
+    /// The user who made the change.
+    pub sender: Option<JID>,
+    #[cfg_attr(
+        feature = "serde",
+        serde(serialize_with = "time::serde::rfc3339::serialize")
+    )]
+    pub timestamp: OffsetDateTime,
+
+    /// The new name of the group, if it changed.
+    pub name: Option<GroupName>,
+    /// The new locked status of the group, if it changed.
+    pub locked: Option<GroupLocked>,
+    /// The new announce status of the group, if it changed.
+    pub announce: Option<GroupAnnounce>,
+    /// The new disappearing messages setting of the group, if it changed.
+    pub ephemeral: Option<GroupEphemeral>,
+    /// Set if the group was deleted.
+    pub delete: Option<GroupDelete>,
+
+    /// The users who joined or were added to the group.
+    pub join: Vec<JID>,
+    /// The users who left or were removed from the group.
+    pub leave: Vec<JID>,
+    /// The users who were made admins.
+    pub promote: Vec<JID>,
+    /// The users who were removed from the admins.
+    pub demote: Vec<JID>,
+}
+
+impl GroupInfo {
+    /// Parses the `<notification type="w:gp2">` node. Each child of the notification is
+    /// one change, unknown changes are ignored.
+    pub fn from_node(node: &Node) -> Result<Self, RhustAppError> {
+        node.expect_tag("notification")?;
+        node.expect_attr("type", "w:gp2")?;
+
+        let mut ag = node.attr_getter();
+        let jid = ag.jid("from");
+        let notify = ag.optional_string("notify");
+        let sender = ag.optional_jid("participant");
+        let timestamp = ag.unix_time("t");
+        if let Some(err) = ag.error() {
+            return Err(new_rhustapp_error(
+                "failed to parse group notification",
+                Some(err.to_string()),
+            ));
+        };
+
+        let mut info = Self {
+            jid: jid.unwrap(),
+            notify,
+            sender,
+            timestamp: timestamp.unwrap(),
+            name: None,
+            locked: None,
+            announce: None,
+            ephemeral: None,
+            delete: None,
+            join: Vec::new(),
+            leave: Vec::new(),
+            promote: Vec::new(),
+            demote: Vec::new(),
+        };
+
+        for child in node.get_children().unwrap_or_default() {
+            let mut ag = child.attr_getter();
+            match child.tag.as_str() {
+                "add" => info.join = parse_participant_list(&child)?,
+                "remove" => info.leave = parse_participant_list(&child)?,
+                "promote" => info.promote = parse_participant_list(&child)?,
+                "demote" => info.demote = parse_participant_list(&child)?,
+                "locked" | "unlocked" => {
+                    info.locked = Some(GroupLocked {
+                        is_locked: child.tag.eq("locked"),
+                    })
+                }
+                "announcement" | "not_announcement" => {
+                    info.announce = Some(GroupAnnounce {
+                        is_announce: child.tag.eq("announcement"),
+                        announce_version_id: ag.optional_string("v_id").unwrap_or_default(),
+                    })
+                }
+                "ephemeral" => {
+                    info.ephemeral = Some(GroupEphemeral {
+                        is_ephemeral: true,
+                        disappearing_timer: ag.u64("expiration").unwrap_or_default() as u32,
+                    })
+                }
+                "not_ephemeral" => {
+                    info.ephemeral = Some(GroupEphemeral {
+                        is_ephemeral: false,
+                        disappearing_timer: 0,
+                    })
+                }
+                "delete" => {
+                    info.delete = Some(GroupDelete {
+                        deleted: true,
+                        deleted_reason: ag.optional_string("reason").unwrap_or_default(),
+                    })
+                }
+                "subject" => {
+                    let name = ag.string("subject");
+                    let name_set_at = ag.unix_time("s_t");
+                    let name_set_by = ag.optional_jid_or_empty("s_o");
+                    if let (Some(name), Some(name_set_at)) = (name, name_set_at) {
+                        info.name = Some(GroupName {
+                            name,
+                            name_set_at,
+                            name_set_by,
+                        });
+                    };
+                }
+                _ => {}
+            };
+            if let Some(err) = ag.error() {
+                return Err(new_rhustapp_error(
+                    &format!("failed to parse <{}> in group notification", child.tag),
+                    Some(err.to_string()),
+                ));
+            };
+        }
+
+        Ok(info)
+    }
+}
+
+/// Parses the `<participant jid="...">` children of a group change.
+fn parse_participant_list(node: &Node) -> Result<Vec<JID>, RhustAppError> {
+    let mut participants = Vec::new();
+    for participant in node.get_children_by_tag("participant").unwrap_or_default() {
+        let mut ag = participant.attr_getter();
+        let jid = ag.jid("jid");
+        if let Some(err) = ag.error() {
+            return Err(new_rhustapp_error(
+                "failed to parse group participant",
+                Some(err.to_string()),
+            ));
+        };
+        participants.push(jid.unwrap());
+    }
+    Ok(participants)
+}
+
+#[derive(Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct CallOffer {
+    pub metadata: BasicCallMetadata,
+    pub remote: CallRemoteMetadata,
+}
+
+#[derive(Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct CallAccept {
+    pub metadata: BasicCallMetadata,
+    pub remote: CallRemoteMetadata,
+}
+
+#[derive(Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct ConnectFailure {
+    pub reason: ConnectFailureReason,
+    /// The `message` attribute of the `<failure>` node, if the server sent one.
+    pub message: String,
+}
+
+#[derive(Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct PictureChange {
+    /// The user or group whose profile picture changed.
+    pub jid: JID,
+    /// The user who changed the picture, e.g. the admin who changed a group picture.
+    pub author: Option<JID>,
+    #[cfg_attr(
+        feature = "serde",
+        serde(serialize_with = "time::serde::rfc3339::serialize")
+    )]
+    pub timestamp: OffsetDateTime,
+    /// The ID of the new picture. It is `None` if the picture was removed, and may also be
+    /// `None` if the server only sent a hash of the new picture.
+    pub picture_id: Option<String>,
+    /// True if the picture was removed instead of changed.
+    pub remove: bool,
+}
+
+impl PictureChange {
+    /// Parses the `<notification type="picture">` node, which has a `<set>`, `<add>` or
+    /// `<delete>` child with the JID whose picture changed.
+    pub fn from_node(node: &Node) -> Result<Self, RhustAppError> {
+        node.expect_tag("notification")?;
+        node.expect_attr("type", "picture")?;
+
+        let child = node
+            .get_optional_child_by_tag(&["set"])
+            .or_else(|| node.get_optional_child_by_tag(&["add"]))
+            .or_else(|| node.get_optional_child_by_tag(&["delete"]))
+            .ok_or_else(|| {
+                new_rhustapp_error("didn't find picture change in picture notification", None)
+            })?;
+        let remove = child.tag.eq("delete");
+
+        let mut nag = node.attr_getter();
+        let timestamp = nag.unix_time("t");
+        let mut ag = child.attr_getter();
+        let jid = ag.jid("jid");
+        let author = ag.optional_jid("author");
+        let picture_id = if remove {
+            None
+        } else {
+            ag.optional_string("id").filter(|id| !id.is_empty())
+        };
+        ag.merge(nag);
+        if let Some(err) = ag.error() {
+            return Err(new_rhustapp_error(
+                "failed to parse picture change",
+                Some(err.to_string()),
+            ));
+        };
+
+        Ok(Self {
+            jid: jid.unwrap(),
+            author,
+            timestamp: timestamp.unwrap(),
+            picture_id,
+            remove,
+        })
+    }
+}
+
+#[derive(Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct Contact {
+    /// The user whose contact entry changed.
+    pub jid: JID,
+    #[cfg_attr(
+        feature = "serde",
+        serde(serialize_with = "time::serde::rfc3339::serialize")
+    )]
+    pub timestamp: OffsetDateTime,
+    /// The new contact entry. The names are empty if the contact was removed.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub action: wa_proto::ContactAction,
+    /// True if the change came from a full sync of the app state instead of a new patch.
+    pub from_full_sync: bool,
+}
+
+#[derive(Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct Pin {
+    /// The chat that was pinned or unpinned.
+    pub jid: JID,
+    #[cfg_attr(
+        feature = "serde",
+        serde(serialize_with = "time::serde::rfc3339::serialize")
+    )]
+    pub timestamp: OffsetDateTime,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub action: wa_proto::PinAction,
+    /// True if the change came from a full sync of the app state instead of a new patch.
+    pub from_full_sync: bool,
+}
+
+#[derive(Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct Mute {
+    /// The chat that was muted or unmuted.
+    pub jid: JID,
+    #[cfg_attr(
+        feature = "serde",
+        serde(serialize_with = "time::serde::rfc3339::serialize")
+    )]
+    pub timestamp: OffsetDateTime,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub action: wa_proto::MuteAction,
+    /// True if the change came from a full sync of the app state instead of a new patch.
+    pub from_full_sync: bool,
+}
+
+#[derive(Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct Archive {
+    /// The chat that was archived or unarchived.
+    pub jid: JID,
+    #[cfg_attr(
+        feature = "serde",
+        serde(serialize_with = "time::serde::rfc3339::serialize")
+    )]
+    pub timestamp: OffsetDateTime,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub action: wa_proto::ArchiveChatAction,
+    /// True if the change came from a full sync of the app state instead of a new patch.
+    pub from_full_sync: bool,
+}
+
+#[derive(Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct Star {
+    pub chat_jid: JID,
+    /// The sender of the message in group chats.
+    pub sender_jid: Option<JID>,
+    pub is_from_me: bool,
+    pub message_id: MessageID,
+    #[cfg_attr(
+        feature = "serde",
+        serde(serialize_with = "time::serde::rfc3339::serialize")
+    )]
+    pub timestamp: OffsetDateTime,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub action: wa_proto::StarAction,
+    /// True if the change came from a full sync of the app state instead of a new patch.
+    pub from_full_sync: bool,
+}
+
+#[derive(Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct DeleteForMe {
+    pub chat_jid: JID,
+    /// The sender of the message in group chats.
+    pub sender_jid: Option<JID>,
+    pub is_from_me: bool,
+    pub message_id: MessageID,
+    #[cfg_attr(
+        feature = "serde",
+        serde(serialize_with = "time::serde::rfc3339::serialize")
+    )]
+    pub timestamp: OffsetDateTime,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub action: wa_proto::DeleteMessageForMeAction,
+    /// True if the change came from a full sync of the app state instead of a new patch.
+    pub from_full_sync: bool,
+}
+
+#[derive(Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct PushNameUpdated {
+    /// The user whose push name changed.
+    pub jid: JID,
+    /// The message that the new push name was received with.
+    pub message: MessageInfo,
+    /// The previous push name, empty if the user didn't have one in the store.
+    pub old_push_name: String,
+    pub new_push_name: String,
+}
+
+#[cfg(feature = "serde")]
+fn serialize_duration_seconds<S: serde::Serializer>(
+    duration: &Duration,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.serialize_i64(duration.whole_seconds())
+}
+
 // TODO: implement the remaining things after `Node`.
+
+#[cfg(test)]
+mod tests {
+    use crate::binary::{AttributeTypes, Attrs};
+
+    use super::*;
+
+    fn privacy_notification(name: &str, value: &str) -> Node {
+        Node {
+            tag: "notification".to_string(),
+            attrs: Attrs::from([(
+                "type".to_string(),
+                AttributeTypes::String("privacy".to_string()),
+            )]),
+            content: NodeContentType::ListOfNodes(vec![Node {
+                tag: "privacy".to_string(),
+                attrs: Attrs::new(),
+                content: NodeContentType::ListOfNodes(vec![Node {
+                    tag: "category".to_string(),
+                    attrs: Attrs::from([
+                        ("name".to_string(), AttributeTypes::String(name.to_string())),
+                        (
+                            "value".to_string(),
+                            AttributeTypes::String(value.to_string()),
+                        ),
+                    ]),
+                    content: NodeContentType::None,
+                }]),
+            }]),
+        }
+    }
+
+    fn string_attr(value: &str) -> AttributeTypes {
+        AttributeTypes::String(value.to_string())
+    }
+
+    fn jid_attr(value: &str) -> AttributeTypes {
+        AttributeTypes::JID(JID::from_str(value).unwrap())
+    }
+
+    #[test]
+    fn test_receipt_single_id() {
+        let node = Node {
+            tag: "receipt".to_string(),
+            attrs: Attrs::from([
+                ("from".to_string(), jid_attr("919876543210@s.whatsapp.net")),
+                ("id".to_string(), string_attr("3EB0ABCDEF")),
+                ("t".to_string(), string_attr("1678000000")),
+            ]),
+            content: NodeContentType::None,
+        };
+        let own_jid = JID::from_str("911234567890@s.whatsapp.net").unwrap();
+
+        let receipt = Receipt::from_node(&node, &own_jid).unwrap();
+        assert_eq!(receipt.message_ids, vec!["3EB0ABCDEF"]);
+        assert!(matches!(receipt.r#type, ReceiptType::Delivered));
+        assert_eq!(receipt.timestamp.unix_timestamp(), 1678000000);
+        assert_eq!(
+            receipt.source.chat.to_string(),
+            "919876543210@s.whatsapp.net"
+        );
+        assert!(!receipt.source.is_group);
+        assert!(!receipt.source.is_from_me);
+        assert!(receipt.participant.is_none());
+    }
+
+    #[test]
+    fn test_receipt_group_list() {
+        let node = Node {
+            tag: "receipt".to_string(),
+            attrs: Attrs::from([
+                ("from".to_string(), jid_attr("120363000000000000@g.us")),
+                (
+                    "participant".to_string(),
+                    jid_attr("919876543210@s.whatsapp.net"),
+                ),
+                ("id".to_string(), string_attr("ID1")),
+                ("type".to_string(), string_attr("read")),
+                ("t".to_string(), string_attr("1678000000")),
+            ]),
+            content: NodeContentType::ListOfNodes(vec![Node {
+                tag: "list".to_string(),
+                attrs: Attrs::new(),
+                content: NodeContentType::ListOfNodes(
+                    ["ID2", "ID3"]
+                        .iter()
+                        .map(|id| Node {
+                            tag: "item".to_string(),
+                            attrs: Attrs::from([("id".to_string(), string_attr(id))]),
+                            content: NodeContentType::None,
+                        })
+                        .collect(),
+                ),
+            }]),
+        };
+        let own_jid = JID::from_str("911234567890@s.whatsapp.net").unwrap();
+
+        let receipt = Receipt::from_node(&node, &own_jid).unwrap();
+        assert_eq!(receipt.message_ids, vec!["ID1", "ID2", "ID3"]);
+        assert!(matches!(receipt.r#type, ReceiptType::Read));
+        assert!(receipt.source.is_group);
+        assert_eq!(receipt.source.chat.to_string(), "120363000000000000@g.us");
+        assert_eq!(
+            receipt.participant.unwrap().to_string(),
+            "919876543210@s.whatsapp.net"
+        );
+    }
+
+    #[test]
+    fn test_receipt_group_without_participant() {
+        let node = Node {
+            tag: "receipt".to_string(),
+            attrs: Attrs::from([
+                ("from".to_string(), jid_attr("120363000000000000@g.us")),
+                ("id".to_string(), string_attr("ID1")),
+                ("t".to_string(), string_attr("1678000000")),
+            ]),
+            content: NodeContentType::None,
+        };
+        let own_jid = JID::from_str("911234567890@s.whatsapp.net").unwrap();
+
+        let receipt = Receipt::from_node(&node, &own_jid).unwrap();
+        assert!(receipt.source.is_group);
+        assert!(receipt.participant.is_none());
+        assert_eq!(receipt.source.sender.to_string(), "120363000000000000@g.us");
+        assert!(!receipt.source.is_from_me);
+    }
+
+    #[test]
+    fn test_privacy_settings_change_last_seen() {
+        let changes = PrivacySettingsChange::from_node(&privacy_notification("last", "contacts"))
+            .unwrap()
+            .changes;
+        assert_eq!(changes.len(), 1);
+        assert!(matches!(changes[0].setting, PrivacySettingType::LastSeen));
+        assert!(matches!(changes[0].value, PrivacySetting::Contacts));
+    }
+
+    #[test]
+    fn test_privacy_settings_change_profile() {
+        let changes = PrivacySettingsChange::from_node(&privacy_notification("profile", "all"))
+            .unwrap()
+            .changes;
+        assert_eq!(changes.len(), 1);
+        assert!(matches!(changes[0].setting, PrivacySettingType::Profile));
+        assert!(matches!(changes[0].value, PrivacySetting::All));
+    }
+
+    fn device_notification(action: &str, device: &str) -> Node {
+        Node {
+            tag: "notification".to_string(),
+            attrs: Attrs::from([
+                ("from".to_string(), jid_attr("919876543210@s.whatsapp.net")),
+                ("type".to_string(), string_attr("devices")),
+                ("id".to_string(), string_attr("1234")),
+            ]),
+            content: NodeContentType::ListOfNodes(vec![Node {
+                tag: action.to_string(),
+                attrs: Attrs::from([("device_hash".to_string(), string_attr("2:abcdef"))]),
+                content: NodeContentType::ListOfNodes(vec![
+                    Node {
+                        tag: "device".to_string(),
+                        attrs: Attrs::from([("jid".to_string(), jid_attr(device))]),
+                        content: NodeContentType::None,
+                    },
+                    Node {
+                        tag: "key-index-list".to_string(),
+                        attrs: Attrs::from([("ts".to_string(), string_attr("1678000000"))]),
+                        content: NodeContentType::ByteArray(vec![1, 2, 3]),
+                    },
+                ]),
+            }]),
+        }
+    }
+
+    #[test]
+    fn test_device_list_update_add() {
+        let node = device_notification("add", "919876543210.0:3@s.whatsapp.net");
+        let update = DeviceListUpdate::from_node(&node).unwrap();
+
+        assert_eq!(update.jid.to_string(), "919876543210@s.whatsapp.net");
+        assert_eq!(update.added.len(), 1);
+        assert_eq!(update.added[0].user, "919876543210");
+        assert_eq!(update.added[0].device, Some(3));
+        assert!(update.removed.is_empty());
+        assert_eq!(update.device_hash.as_deref(), Some("2:abcdef"));
+        assert_eq!(update.key_index, Some(vec![1, 2, 3]));
+    }
+
+    #[test]
+    fn test_device_list_update_remove() {
+        let node = device_notification("remove", "919876543210.0:5@s.whatsapp.net");
+        let update = DeviceListUpdate::from_node(&node).unwrap();
+
+        assert!(update.added.is_empty());
+        assert_eq!(update.removed.len(), 1);
+        assert_eq!(update.removed[0].user, "919876543210");
+        assert_eq!(update.removed[0].device, Some(5));
+    }
+
+    fn picture_notification(change: &str, attrs: &[(&str, AttributeTypes)]) -> Node {
+        Node {
+            tag: "notification".to_string(),
+            attrs: Attrs::from([
+                ("type".to_string(), string_attr("picture")),
+                ("from".to_string(), jid_attr("919876543210@s.whatsapp.net")),
+                ("t".to_string(), string_attr("1678000000")),
+            ]),
+            content: NodeContentType::ListOfNodes(vec![Node {
+                tag: change.to_string(),
+                attrs: attrs
+                    .iter()
+                    .map(|(key, value)| (key.to_string(), value.clone()))
+                    .collect(),
+                content: NodeContentType::None,
+            }]),
+        }
+    }
+
+    #[test]
+    fn test_picture_change_set() {
+        let node = picture_notification(
+            "set",
+            &[
+                ("jid", jid_attr("120363000000000000@g.us")),
+                ("author", jid_attr("919876543210@s.whatsapp.net")),
+                ("id", string_attr("1678000001")),
+            ],
+        );
+        let change = PictureChange::from_node(&node).unwrap();
+        assert_eq!(change.jid.to_string(), "120363000000000000@g.us");
+        assert_eq!(
+            change.author.unwrap().to_string(),
+            "919876543210@s.whatsapp.net"
+        );
+        assert_eq!(change.picture_id.as_deref(), Some("1678000001"));
+        assert!(!change.remove);
+        assert_eq!(change.timestamp.unix_timestamp(), 1678000000);
+    }
+
+    #[test]
+    fn test_picture_change_remove() {
+        let node = picture_notification(
+            "delete",
+            &[("jid", jid_attr("919876543210@s.whatsapp.net"))],
+        );
+        let change = PictureChange::from_node(&node).unwrap();
+        assert_eq!(change.jid.to_string(), "919876543210@s.whatsapp.net");
+        assert!(change.author.is_none());
+        assert!(change.picture_id.is_none());
+        assert!(change.remove);
+
+        assert!(PictureChange::from_node(&picture_notification("unknown", &[])).is_err());
+    }
+
+    #[test]
+    fn test_prekeys_low() {
+        let node = Node {
+            tag: "notification".to_string(),
+            attrs: Attrs::from([
+                ("from".to_string(), jid_attr("s.whatsapp.net")),
+                ("type".to_string(), string_attr("encrypt")),
+                ("id".to_string(), string_attr("1234")),
+            ]),
+            content: NodeContentType::ListOfNodes(vec![Node {
+                tag: "count".to_string(),
+                attrs: Attrs::from([("value".to_string(), string_attr("5"))]),
+                content: NodeContentType::None,
+            }]),
+        };
+        assert_eq!(PreKeysLow::from_node(&node).unwrap().remaining, 5);
+
+        let mut wrong_type = node.clone();
+        wrong_type
+            .attrs
+            .insert("type".to_string(), string_attr("devices"));
+        assert!(PreKeysLow::from_node(&wrong_type).is_err());
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_serialize_temporary_ban() {
+        let event = RhustAppEventType::TemporaryBan(TemporaryBan {
+            code: TempBanReason::BlockedByUsers,
+            expire: Duration::hours(2),
+        });
+        assert_eq!(
+            serde_json::to_value(&event).unwrap(),
+            serde_json::json!({
+                "type": "TemporaryBan",
+                "code": "BlockedByUsers",
+                "expire": 7200,
+            })
+        );
+
+        let dropped = RhustAppEventType::EventsDropped(EventsDropped { count: 3 });
+        assert_eq!(
+            serde_json::to_string(&dropped).unwrap(),
+            r#"{"type":"EventsDropped","count":3}"#
+        );
+        assert_eq!(
+            serde_json::to_string(&RhustAppEventType::Connected).unwrap(),
+            r#"{"type":"Connected"}"#
+        );
+    }
+
+    #[test]
+    fn test_connect_failure_reason_is_transient() {
+        let unavailable = ConnectFailureReason::from(503);
+        assert!(matches!(
+            unavailable,
+            ConnectFailureReason::ServiceUnavailable
+        ));
+        assert!(unavailable.is_transient());
+        assert!(!unavailable.is_logged_out());
+
+        let logged_out = ConnectFailureReason::from(401);
+        assert!(!logged_out.is_transient());
+        assert!(logged_out.is_logged_out());
+
+        let unknown = ConnectFailureReason::from(500);
+        assert!(matches!(unknown, ConnectFailureReason::Value(500)));
+        assert!(unknown.is_transient());
+        assert!(!ConnectFailureReason::from(409).is_transient());
+    }
+
+    #[test]
+    fn test_keep_alive_timeout_downtime() {
+        let timeout = KeepAliveTimeout {
+            error_count: 3,
+            last_success: OffsetDateTime::from_unix_timestamp(1677913600).unwrap(),
+        };
+
+        let now = OffsetDateTime::from_unix_timestamp(1677913600 + 95).unwrap();
+        assert_eq!(timeout.downtime(now), Duration::seconds(95));
+
+        let before = OffsetDateTime::from_unix_timestamp(1677913600 - 5).unwrap();
+        assert_eq!(timeout.downtime(before), Duration::ZERO);
+    }
+}
@@ -2,9 +2,12 @@ use std::str::FromStr;
 
 use time::OffsetDateTime;
 
-use crate::RhustAppError;
+use crate::{
+    binary::{Node, NodeContentType},
+    new_rhustapp_error, RhustAppError,
+};
 
-use super::JID;
+use super::{GROUP_SERVER, JID};
 
 pub enum GroupMemberAddMode {
     /// ("admin_add") If added by the admin.
@@ -47,6 +50,134 @@ pub struct GroupInfo {
     pub member_add_mode: GroupMemberAddMode,
 }
 
+impl GroupInfo {
+    /// Parses a `<group id="..." subject="...">` node, e.g. the one in the response to a
+    /// group info query. The node describes the whole group, so the locked, announce and
+    /// ephemeral settings are always set, while the community fields are only set if the
+    /// group is part of a community.
+    pub fn from_node(node: &Node) -> Result<Self, RhustAppError> {
+        node.expect_tag("group")?;
+
+        let mut ag = node.attr_getter();
+        let id = ag.string("id");
+        let owner_jid = ag.optional_jid_or_empty("creator");
+        let name = ag.string("subject");
+        let name_set_at = ag.unix_time("s_t");
+        let name_set_by = ag.optional_jid_or_empty("s_o");
+        let creation_time = ag.unix_time("creation");
+        let announce_version_id = ag.optional_string("a_v_id").unwrap_or_default();
+        let participant_version_id = ag.optional_string("p_v_id").unwrap_or_default();
+
+        let mut group_topic = None;
+        let mut is_locked = false;
+        let mut is_announce = false;
+        let mut group_ephemeral = GroupEphemeral {
+            is_ephemeral: false,
+            disappearing_timer: 0,
+        };
+        let mut group_parent = None;
+        let mut group_linked_parent = None;
+        let mut group_is_default_sub = None;
+        let mut member_add_mode = GroupMemberAddMode::Value(String::new());
+        let mut participants = Vec::new();
+        for child in node.get_children().unwrap_or_default() {
+            let mut child_ag = child.attr_getter();
+            match child.tag.as_str() {
+                "participant" => participants.push(GroupParticipant::from_node(&child)?),
+                "description" => {
+                    if let Some(body) = child.get_optional_child_by_tag(&["body"]) {
+                        group_topic = Some(GroupTopic {
+                            topic: content_string(&body),
+                            topic_id: child_ag.string("id").unwrap_or_default(),
+                            topic_set_at: child_ag
+                                .unix_time("t")
+                                .unwrap_or(OffsetDateTime::UNIX_EPOCH),
+                            topic_set_by: child_ag.optional_jid_or_empty("participant"),
+                            topic_deleted: false,
+                        });
+                    };
+                }
+                "locked" => is_locked = true,
+                "announcement" => is_announce = true,
+                "ephemeral" => {
+                    group_ephemeral = GroupEphemeral {
+                        is_ephemeral: true,
+                        disappearing_timer: child_ag
+                            .u64("expiration")
+                            .map(|expiration| u32::try_from(expiration).unwrap_or(u32::MAX))
+                            .unwrap_or_default(),
+                    };
+                }
+                "member_add_mode" => {
+                    member_add_mode = GroupMemberAddMode::from_str(&content_string(&child))?;
+                }
+                "parent" => {
+                    group_parent = Some(GroupParent {
+                        is_parent: true,
+                        default_membership_approval_mode: MembershipApprovalMode::from_str(
+                            &child_ag
+                                .optional_string("default_membership_approval_mode")
+                                .unwrap_or_default(),
+                        )?,
+                    });
+                }
+                "linked_parent" => {
+                    if let Some(jid) = child_ag.jid("jid") {
+                        group_linked_parent = Some(GroupLinkedParent {
+                            linked_parent_jid: jid,
+                        });
+                    };
+                }
+                "default_sub_group" => {
+                    group_is_default_sub = Some(GroupIsDefaultSub {
+                        is_default_sub_group: true,
+                    });
+                }
+                _ => {}
+            };
+            ag.merge(child_ag);
+        }
+        if let Some(err) = ag.error() {
+            return Err(new_rhustapp_error(
+                "failed to parse group info",
+                Some(err.to_string()),
+            ));
+        };
+
+        Ok(Self {
+            jid: JID::new(&id.unwrap(), GROUP_SERVER),
+            owner_jid,
+            group_name: Some(GroupName {
+                name: name.unwrap(),
+                name_set_at: name_set_at.unwrap(),
+                name_set_by,
+            }),
+            group_topic,
+            group_locked: Some(GroupLocked { is_locked }),
+            group_announce: Some(GroupAnnounce {
+                is_announce,
+                announce_version_id,
+            }),
+            group_ephemeral: Some(group_ephemeral),
+            group_parent,
+            group_linked_parent,
+            group_is_default_sub,
+            creation_time: creation_time.unwrap(),
+            participant_version_id,
+            participants,
+            member_add_mode,
+        })
+    }
+}
+
+/// Returns the byte or string content of a node as a string.
+fn content_string(node: &Node) -> String {
+    match &node.content {
+        NodeContentType::ByteArray(bytes) => String::from_utf8_lossy(bytes).to_string(),
+        content => content.other_types_to_string(),
+    }
+}
+
 /// Contains information about a participant of a WhatsApp group chat.
 pub struct GroupParticipant {
     pub jid: JID,
@@ -59,8 +190,59 @@ pub struct GroupParticipant {
     pub add_request: Option<GroupParticipantAddRequest>,
 }
 
+impl GroupParticipant {
+    /// Parses a `<participant jid="..." type="...">` node of a group info or of the response
+    /// to creating a group or adding participants.
+    ///
+    /// If adding the participant failed because of their privacy settings, the node has an
+    /// `error` attribute and an `<add_request code="..." expiration="...">` child, with which
+    /// the participant can be invited instead.
+    pub fn from_node(node: &Node) -> Result<Self, RhustAppError> {
+        node.expect_tag("participant")?;
+
+        let mut ag = node.attr_getter();
+        let jid = ag.jid("jid");
+        let participant_type = ag.optional_string("type").unwrap_or_default();
+        let error_code = ag.optional_i32("error").unwrap_or_default();
+        if let Some(err) = ag.error() {
+            return Err(new_rhustapp_error(
+                "failed to parse group participant",
+                Some(err.to_string()),
+            ));
+        };
+
+        let add_request = match node.get_optional_child_by_tag(&["add_request"]) {
+            Some(add_request) if error_code != 0 => {
+                let mut ag = add_request.attr_getter();
+                let code = ag.string("code");
+                let expiration = ag.unix_time("expiration");
+                if let Some(err) = ag.error() {
+                    return Err(new_rhustapp_error(
+                        "failed to parse group participant add request",
+                        Some(err.to_string()),
+                    ));
+                };
+                Some(GroupParticipantAddRequest {
+                    code: code.unwrap(),
+                    expiration: expiration.unwrap(),
+                })
+            }
+            _ => None,
+        };
+
+        Ok(Self {
+            jid: jid.unwrap(),
+            is_admin: participant_type.eq("admin") || participant_type.eq("superadmin"),
+            is_super_admin: participant_type.eq("superadmin"),
+            error_code,
+            add_request,
+        })
+    }
+}
+
 pub struct GroupParticipantAddRequest {
     pub code: String,
+    /// When the invite code expires, in UTC.
     pub expiration: OffsetDateTime,
 }
 
@@ -96,8 +278,14 @@ pub struct GroupIsDefaultSub {
 }
 
 /// Contains the name of a group along with metadata of who set it and when.
+#[derive(Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 pub struct GroupName {
     pub name: String,
+    #[cfg_attr(
+        feature = "serde",
+        serde(serialize_with = "time::serde::rfc3339::serialize")
+    )]
     pub name_set_at: OffsetDateTime,
     pub name_set_by: JID,
 }
@@ -112,22 +300,30 @@ pub struct GroupTopic {
 }
 
 /// Specifies whether the group information can only be edited by admins.
+#[derive(Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 pub struct GroupLocked {
     pub is_locked: bool,
 }
 
 /// Specifies whether only admins can send messages in the group.
+#[derive(Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 pub struct GroupAnnounce {
     pub is_announce: bool,
     pub announce_version_id: String,
 }
 
 /// Contains the group's disappearing messages settings.
+#[derive(Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 pub struct GroupEphemeral {
     pub is_ephemeral: bool,
     pub disappearing_timer: u32,
 }
 
+#[derive(Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 pub struct GroupDelete {
     pub deleted: bool,
     pub deleted_reason: String,
@@ -186,3 +382,221 @@ pub struct GroupLinkChange {
     pub r#type: GroupLinkChangeType,
     pub unlink_reason: GroupUnlinkReason,
 }
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use time::{Month, UtcOffset};
+
+    use crate::binary::{AttributeTypes, Attrs, NodeContentType};
+
+    use super::*;
+
+    fn participant_node(attrs: &[(&str, &str)], children: Vec<Node>) -> Node {
+        let mut node_attrs = Attrs::from([(
+            "jid".to_string(),
+            AttributeTypes::JID(JID::from_str("919876543210@s.whatsapp.net").unwrap()),
+        )]);
+        for (key, value) in attrs {
+            node_attrs.insert(key.to_string(), AttributeTypes::String(value.to_string()));
+        }
+        Node {
+            tag: "participant".to_string(),
+            attrs: node_attrs,
+            content: NodeContentType::ListOfNodes(children),
+        }
+    }
+
+    fn add_request_node(expiration: &str) -> Node {
+        Node {
+            tag: "add_request".to_string(),
+            attrs: Attrs::from([
+                (
+                    "code".to_string(),
+                    AttributeTypes::String("AbCdEf123456".to_string()),
+                ),
+                (
+                    "expiration".to_string(),
+                    AttributeTypes::String(expiration.to_string()),
+                ),
+            ]),
+            content: NodeContentType::None,
+        }
+    }
+
+    fn child(tag: &str, attrs: &[(&str, &str)], content: NodeContentType) -> Node {
+        Node {
+            tag: tag.to_string(),
+            attrs: attrs
+                .iter()
+                .map(|(key, value)| (key.to_string(), AttributeTypes::String(value.to_string())))
+                .collect(),
+            content,
+        }
+    }
+
+    fn group_node(children: Vec<Node>) -> Node {
+        let mut node = child(
+            "group",
+            &[
+                ("id", "120363000000000000"),
+                ("subject", "Weekend plans"),
+                ("s_t", "1700000000"),
+                ("creation", "1690000000"),
+                ("p_v_id", "AbCd"),
+            ],
+            NodeContentType::ListOfNodes(children),
+        );
+        for (key, jid) in [
+            ("creator", "919876543210@s.whatsapp.net"),
+            ("s_o", "911234567890@s.whatsapp.net"),
+        ] {
+            node.attrs.insert(
+                key.to_string(),
+                AttributeTypes::JID(JID::from_str(jid).unwrap()),
+            );
+        }
+        node
+    }
+
+    #[test]
+    fn test_group_info() {
+        let mut description = child(
+            "description",
+            &[("id", "DESC1"), ("t", "1695000000")],
+            NodeContentType::ListOfNodes(vec![child(
+                "body",
+                &[],
+                NodeContentType::ByteArray(b"Plans for the weekend".to_vec()),
+            )]),
+        );
+        description.attrs.insert(
+            "participant".to_string(),
+            AttributeTypes::JID(JID::from_str("919876543210@s.whatsapp.net").unwrap()),
+        );
+        let mut linked_parent = child("linked_parent", &[], NodeContentType::None);
+        linked_parent.attrs.insert(
+            "jid".to_string(),
+            AttributeTypes::JID(JID::from_str("120363111111111111@g.us").unwrap()),
+        );
+        let node = group_node(vec![
+            participant_node(&[("type", "superadmin")], vec![]),
+            description,
+            child("locked", &[], NodeContentType::None),
+            child(
+                "ephemeral",
+                &[("expiration", "604800")],
+                NodeContentType::None,
+            ),
+            child(
+                "member_add_mode",
+                &[],
+                NodeContentType::ByteArray(b"admin_add".to_vec()),
+            ),
+            linked_parent,
+            child("default_sub_group", &[], NodeContentType::None),
+        ]);
+
+        let info = GroupInfo::from_node(&node).unwrap();
+        assert_eq!(info.jid, JID::from_str("120363000000000000@g.us").unwrap());
+        assert_eq!(info.owner_jid.user, "919876543210");
+        let name = info.group_name.unwrap();
+        assert_eq!(name.name, "Weekend plans");
+        assert_eq!(name.name_set_at.unix_timestamp(), 1700000000);
+        assert_eq!(name.name_set_by.user, "911234567890");
+        let topic = info.group_topic.unwrap();
+        assert_eq!(topic.topic, "Plans for the weekend");
+        assert_eq!(topic.topic_id, "DESC1");
+        assert_eq!(topic.topic_set_by.user, "919876543210");
+        assert!(info.group_locked.unwrap().is_locked);
+        assert!(!info.group_announce.unwrap().is_announce);
+        let ephemeral = info.group_ephemeral.unwrap();
+        assert!(ephemeral.is_ephemeral);
+        assert_eq!(ephemeral.disappearing_timer, 604800);
+        assert!(matches!(info.member_add_mode, GroupMemberAddMode::AdminAdd));
+        assert!(info.group_parent.is_none());
+        assert_eq!(
+            info.group_linked_parent.unwrap().linked_parent_jid.user,
+            "120363111111111111"
+        );
+        assert!(info.group_is_default_sub.unwrap().is_default_sub_group);
+        assert_eq!(info.creation_time.unix_timestamp(), 1690000000);
+        assert_eq!(info.participant_version_id, "AbCd");
+        assert_eq!(info.participants.len(), 1);
+        assert!(info.participants[0].is_super_admin);
+    }
+
+    #[test]
+    fn test_group_info_community_parent() {
+        let node = group_node(vec![
+            child(
+                "parent",
+                &[("default_membership_approval_mode", "request_required")],
+                NodeContentType::None,
+            ),
+            child("announcement", &[], NodeContentType::None),
+        ]);
+        let info = GroupInfo::from_node(&node).unwrap();
+        let parent = info.group_parent.unwrap();
+        assert!(parent.is_parent);
+        assert!(matches!(
+            parent.default_membership_approval_mode,
+            MembershipApprovalMode::RequestRequired
+        ));
+        assert!(info.group_announce.unwrap().is_announce);
+        assert!(!info.group_locked.unwrap().is_locked);
+        assert!(!info.group_ephemeral.unwrap().is_ephemeral);
+        assert!(info.group_topic.is_none());
+        assert!(info.participants.is_empty());
+
+        let mut missing_subject = group_node(Vec::new());
+        missing_subject.attrs.remove("subject");
+        assert!(GroupInfo::from_node(&missing_subject).is_err());
+        let invalid_timer = group_node(vec![child(
+            "ephemeral",
+            &[("expiration", "soon")],
+            NodeContentType::None,
+        )]);
+        assert!(GroupInfo::from_node(&invalid_timer).is_err());
+    }
+
+    #[test]
+    fn test_participant_add_request_expiration() {
+        let node = participant_node(&[("error", "403")], vec![add_request_node("1700000000")]);
+        let participant = GroupParticipant::from_node(&node).unwrap();
+        assert_eq!(participant.error_code, 403);
+
+        let add_request = participant.add_request.unwrap();
+        assert_eq!(add_request.code, "AbCdEf123456");
+        let expiration = add_request.expiration;
+        assert_eq!(expiration.unix_timestamp(), 1700000000);
+        // 2023-11-14 22:13:20 UTC, regardless of the local timezone.
+        assert_eq!(expiration.offset(), UtcOffset::UTC);
+        assert_eq!(
+            (expiration.year(), expiration.month(), expiration.day()),
+            (2023, Month::November, 14)
+        );
+        assert_eq!(
+            (expiration.hour(), expiration.minute(), expiration.second()),
+            (22, 13, 20)
+        );
+
+        let out_of_range = participant_node(
+            &[("error", "403")],
+            vec![add_request_node("99999999999999")],
+        );
+        assert!(GroupParticipant::from_node(&out_of_range).is_err());
+    }
+
+    #[test]
+    fn test_participant_admin() {
+        let participant =
+            GroupParticipant::from_node(&participant_node(&[("type", "superadmin")], vec![]))
+                .unwrap();
+        assert!(participant.is_admin);
+        assert!(participant.is_super_admin);
+        assert_eq!(participant.error_code, 0);
+        assert!(participant.add_request.is_none());
+    }
+}
@@ -13,6 +13,14 @@ pub const LEGACY_USER_SERVER: &str = "c.us";
 pub const BROADCAST_SERVER: &str = "broadcast";
 /// Server for hidden users (?)
 pub const HIDDEN_USER_SERVER: &str = "lid";
+/// Server for newsletters (channels)
+pub const NEWSLETTER_SERVER: &str = "newsletter";
+/// The largest device ID of an AD-JID. The device is encoded as a single byte on the wire.
+pub const MAX_AD_DEVICE: u8 = u8::MAX;
+/// The largest agent of an AD-JID. The agent is encoded as a single byte on the wire, which
+/// doubles as the domain type, so an agent of 1 (`token::AD_JID_DOMAIN_LID`) can only be
+/// written for JIDs on the `lid` server.
+pub const MAX_AD_AGENT: u8 = u8::MAX;
 
 lazy_static! {
     /// Empty JID
@@ -35,24 +43,28 @@ lazy_static! {
 /// There are two types of JIDs: regular JID pairs (user and server)
 /// and AD-JIDs (user, agent, device and server).
 /// AD JIDs are only used to refer to specific devices of users, so
-/// the server is always `s.whatsapp.net` (`DEFAULT_USER_SERVER`).
+/// the server is always `s.whatsapp.net` (`DEFAULT_USER_SERVER`), or `lid`
+/// (`HIDDEN_USER_SERVER`) for hidden users.
 /// Regular JIDs can be used for entities on any servers (users, groups, broadcasts).
 #[derive(Default, PartialEq, Clone)]
 pub struct JID {
     pub user: String,
+    /// At most `MAX_AD_AGENT`.
     pub agent: Option<u8>,
+    /// At most `MAX_AD_DEVICE`.
     pub device: Option<u8>,
     pub server: String,
 }
 
 impl JID {
-    /// Creates a new regular JID.
+    /// Creates a JID with the given user and server. Servers are case-insensitive, so the
+    /// server is stored in lowercase.
     pub fn new(user: &str, server: &str) -> Self {
         Self {
             user: user.to_string(),
             agent: None,
             device: None,
-            server: server.to_string(),
+            server: server.to_lowercase(),
         }
     }
 
@@ -75,9 +87,46 @@ impl JID {
         self.server.eq(BROADCAST_SERVER) && !self.user.eq(&STATUS_BROADCAST_JID.user)
     }
 
-    /// Returns true if JID has no server (which is required for all JIDs).
+    /// Returns true if the JID has neither a user nor a server, like `EMPTY_JID` (which
+    /// `read_jid_pair` returns for missing JIDs).
+    ///
+    /// A server-only JID like `SERVER_JID` is not empty, since it refers to the server itself.
+    /// Use `is_sendable` to check whether a JID can be the recipient of a message.
     pub fn is_empty(&self) -> bool {
-        self.server.len() != 0
+        self.user.is_empty() && self.server.is_empty()
+    }
+
+    /// Returns an error describing why the JID can't be the recipient of a message, if it
+    /// can't be. Server-only JIDs (like `SERVER_JID`) and JIDs on unknown servers can't be
+    /// sent to, and neither can broadcast lists other than `STATUS_BROADCAST_JID`. Note
+    /// that only status updates can be sent to `STATUS_BROADCAST_JID`.
+    pub fn is_sendable(&self) -> Result<(), RhustAppError> {
+        if self.server.is_empty() {
+            return Err(new_rhustapp_error(
+                &format!("can't send to '{self}'"),
+                Some("JID has no server".to_string()),
+            ));
+        };
+        if self.user.is_empty() {
+            return Err(new_rhustapp_error(
+                &format!("can't send to '{self}'"),
+                Some("JID has no user, it only refers to a server".to_string()),
+            ));
+        };
+        if self.is_broadcast_list() {
+            return Err(new_rhustapp_error(
+                &format!("can't send to '{self}'"),
+                Some("sending to broadcast lists is not supported".to_string()),
+            ));
+        };
+        match self.server.as_str() {
+            DEFAULT_USER_SERVER | LEGACY_USER_SERVER | GROUP_SERVER | BROADCAST_SERVER
+            | HIDDEN_USER_SERVER => Ok(()),
+            server => Err(new_rhustapp_error(
+                &format!("can't send to '{self}'"),
+                Some(format!("unknown server '{server}'")),
+            )),
+        }
     }
 
     /// Returns the JID's user as an optional u64.
@@ -90,6 +139,18 @@ impl JID {
         }
     }
 
+    /// Returns the JID in the AD form ("user.agent:device@server") even if the agent and
+    /// device aren't set, which is the form used for hashing participant lists.
+    pub fn ad_string(&self) -> String {
+        format!(
+            "{}.{}:{}@{}",
+            self.user,
+            self.agent.unwrap_or(0),
+            self.device.unwrap_or(0),
+            self.server
+        )
+    }
+
     /// Returns a version of JID struct that doesn't have the agent
     /// and device set.
     pub fn to_non_ad(&self) -> Self {
@@ -98,24 +159,70 @@ impl JID {
                 user: self.user.to_string(),
                 agent: None,
                 device: None,
-                server: DEFAULT_USER_SERVER.to_string(),
+                server: self.server.to_string(),
             }
         } else {
             self.clone()
         }
     }
 
-    /// Returns the Signal Protocol address for the user.
+    /// Returns whether both JIDs refer to the same user or entity. Unlike `==`, the legacy
+    /// user server (`c.us`) and the default user server (`s.whatsapp.net`) are treated as
+    /// equal, and the agent and device are ignored. Other servers are still compared
+    /// strictly, so e.g. a group never has the same identity as a user.
+    pub fn same_identity(&self, other: &JID) -> bool {
+        fn normalized_server(server: &str) -> &str {
+            if server.eq(LEGACY_USER_SERVER) {
+                DEFAULT_USER_SERVER
+            } else {
+                server
+            }
+        }
+
+        self.user.eq(&other.user)
+            && normalized_server(&self.server).eq(normalized_server(&other.server))
+    }
+
+    /// Returns the Signal Protocol address for the user. The default agent (0) is left out,
+    /// so that the primary device has the same address whether or not its JID is in the AD
+    /// form.
     pub fn signal_address(&self) -> ProtocolAddress {
         let mut user = self.user.to_string();
 
-        if let Some(agent) = self.agent {
+        if let Some(agent) = self.agent.filter(|agent| *agent != 0) {
             user = format!("{}_{}", user, agent);
         };
 
         ProtocolAddress::new(user, DeviceId::from(self.device.unwrap_or(0) as u32))
     }
 
+    /// Returns the string representation of the JID with the middle of the user masked, so
+    /// that it can be logged without leaking the full phone number. The first character and
+    /// the last three characters of the user are kept, as are the agent, device and server,
+    /// e.g. `1••••212@s.whatsapp.net`. Users with 4 characters or less are masked entirely.
+    pub fn anonymized(&self) -> String {
+        const MASK: &str = "••••";
+
+        let chars = self.user.chars().collect::<Vec<char>>();
+        let user = if chars.is_empty() {
+            String::new()
+        } else if chars.len() <= 4 {
+            MASK.to_string()
+        } else {
+            format!(
+                "{}{MASK}{}",
+                chars[0],
+                chars[chars.len() - 3..].iter().collect::<String>()
+            )
+        };
+
+        Self {
+            user,
+            ..self.clone()
+        }
+        .to_string()
+    }
+
     /// Converts the JID into a string representation. The output can be parsed
     /// with `JID::from`, except for JIDs with no user part specified.
     pub fn to_string(&self) -> String {
@@ -147,26 +254,59 @@ impl fmt::Debug for JID {
     }
 }
 
+/// JIDs are serialized in their string form, e.g. "919876543210@s.whatsapp.net".
+#[cfg(feature = "serde")]
+impl serde::Serialize for JID {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
 impl FromStr for JID {
     type Err = RhustAppError;
 
+    /// Parses a JID string. Surrounding whitespace and a leading `+` on the user part (as in
+    /// phone numbers copied from a UI) are ignored.
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let parts: Vec<&str> = s.split("@").collect();
+        let parts: Vec<&str> = s.trim().split("@").collect();
         if parts.len() == 0 {
-            Err(new_rhustapp_error("failed to split string on '@'", None))
+            return Err(new_rhustapp_error("failed to split string on '@'", None));
         } else if parts.len() == 1 {
-            Ok(JID::new("", parts[0]))
-        } else if parts[0].contains(":")
-            && parts[0].contains(".")
-            && parts[1].eq(DEFAULT_USER_SERVER)
+            return Ok(JID::new("", parts[0]));
+        };
+
+        let user = parts[0].strip_prefix('+').unwrap_or(parts[0]);
+        if user.contains(":")
+            && user.contains(".")
+            && parts[1].eq_ignore_ascii_case(DEFAULT_USER_SERVER)
         {
-            parse_ad_jid(parts[0])
+            parse_ad_jid(user)
         } else {
-            Ok(JID::new(parts[0], parts[1]))
+            Ok(JID::new(user, parts[1]))
         }
     }
 }
 
+/// Parses all the given strings into JIDs. Unlike parsing them one by one with `?`, a bad
+/// entry doesn't stop the parsing: the JIDs that could be parsed are returned along with the
+/// index and error of every entry that couldn't. Empty entries are errors.
+pub fn parse_jids(inputs: &[&str]) -> (Vec<JID>, Vec<(usize, RhustAppError)>) {
+    let mut jids = Vec::new();
+    let mut errors = Vec::new();
+    for (index, input) in inputs.iter().enumerate() {
+        let input = input.trim();
+        if input.is_empty() {
+            errors.push((index, new_rhustapp_error("JID string is empty", None)));
+            continue;
+        };
+        match JID::from_str(input) {
+            Ok(jid) => jids.push(jid),
+            Err(err) => errors.push((index, err)),
+        }
+    }
+    (jids, errors)
+}
+
 fn parse_ad_jid(user: &str) -> Result<JID, RhustAppError> {
     let mut jid = JID::default();
     jid.server = DEFAULT_USER_SERVER.to_string();
@@ -204,3 +344,166 @@ fn parse_ad_jid(user: &str) -> Result<JID, RhustAppError> {
 
     Ok(jid)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_same_identity_legacy_and_default() {
+        let legacy = JID::new("12345", LEGACY_USER_SERVER);
+        let default = JID::new("12345", DEFAULT_USER_SERVER);
+        assert!(legacy != default);
+        assert!(legacy.same_identity(&default));
+        assert!(default.same_identity(&legacy));
+        assert!(legacy.same_identity(&JID::new_ad("12345", 0, 2)));
+        assert!(!legacy.same_identity(&JID::new("54321", DEFAULT_USER_SERVER)));
+    }
+
+    #[test]
+    fn test_same_identity_distinct_servers() {
+        let legacy = JID::new("12345", LEGACY_USER_SERVER);
+        assert!(!legacy.same_identity(&JID::new("12345", GROUP_SERVER)));
+        assert!(!legacy.same_identity(&JID::new("12345", BROADCAST_SERVER)));
+        assert!(!legacy.same_identity(&JID::new("12345", HIDDEN_USER_SERVER)));
+    }
+
+    #[test]
+    fn test_anonymized() {
+        let jid = JID::new("15551234212", DEFAULT_USER_SERVER);
+        let anonymized = jid.anonymized();
+        assert_eq!(anonymized, "1••••212@s.whatsapp.net");
+        assert!(!anonymized.contains("555123"));
+
+        assert_eq!(
+            JID::new_ad("15551234212", 0, 3).anonymized(),
+            "1••••212.0:3@s.whatsapp.net"
+        );
+        assert_eq!(JID::new("1234", GROUP_SERVER).anonymized(), "••••@g.us");
+        assert_eq!(SERVER_JID.anonymized(), "s.whatsapp.net");
+    }
+
+    #[test]
+    fn test_signal_address() {
+        let primary = JID::new("919876543210", DEFAULT_USER_SERVER);
+        assert_eq!(primary.signal_address().to_string(), "919876543210.0");
+        assert_eq!(
+            JID::new_ad("919876543210", 0, 0).signal_address(),
+            primary.signal_address()
+        );
+        assert_eq!(
+            JID::new_ad("919876543210", 0, 3)
+                .signal_address()
+                .to_string(),
+            "919876543210.3"
+        );
+        assert_eq!(
+            JID::new_ad("919876543210", 1, 3)
+                .signal_address()
+                .to_string(),
+            "919876543210_1.3"
+        );
+    }
+
+    #[test]
+    fn test_is_sendable() {
+        assert!(JID::new("919876543210", DEFAULT_USER_SERVER)
+            .is_sendable()
+            .is_ok());
+        assert!(JID::new("120363000000000000", GROUP_SERVER)
+            .is_sendable()
+            .is_ok());
+        assert!(STATUS_BROADCAST_JID.is_sendable().is_ok());
+
+        let err = EMPTY_JID.is_sendable().unwrap_err();
+        assert!(err.to_string().contains("no server"));
+        let err = SERVER_JID.is_sendable().unwrap_err();
+        assert!(err.to_string().contains("no user"));
+        let err = JID::new("1678000000", BROADCAST_SERVER)
+            .is_sendable()
+            .unwrap_err();
+        assert!(err.to_string().contains("broadcast lists"));
+        let err = JID::new("1234", "example.com").is_sendable().unwrap_err();
+        assert!(err.to_string().contains("unknown server"));
+    }
+
+    #[test]
+    fn test_mixed_case_server() {
+        let jid = JID::from_str("919876543210@S.WhatsApp.Net").unwrap();
+        assert_eq!(jid, JID::new("919876543210", DEFAULT_USER_SERVER));
+        assert_eq!(jid.to_string(), "919876543210@s.whatsapp.net");
+
+        let group = JID::from_str("120363000000000000@G.US").unwrap();
+        assert_eq!(group.server, GROUP_SERVER);
+
+        let ad = JID::from_str("919876543210.0:2@S.WHATSAPP.NET").unwrap();
+        assert_eq!(
+            ad.to_string(),
+            JID::new_ad("919876543210", 0, 2).to_string()
+        );
+
+        let user = JID::new("AbC", "Broadcast");
+        assert_eq!(user.user, "AbC");
+        assert_eq!(user.server, BROADCAST_SERVER);
+    }
+
+    #[test]
+    fn test_plus_prefixed_user() {
+        let jid = JID::from_str("+919876543210@s.whatsapp.net").unwrap();
+        assert_eq!(jid.user, "919876543210");
+        assert_eq!(jid.to_string(), "919876543210@s.whatsapp.net");
+
+        let ad = JID::from_str("+919876543210.0:2@s.whatsapp.net").unwrap();
+        assert_eq!(ad, JID::new_ad("919876543210", 0, 2));
+    }
+
+    #[test]
+    fn test_whitespace_padded() {
+        let jid = JID::from_str(" +919876543210@s.whatsapp.net \n").unwrap();
+        assert_eq!(jid.to_string(), "919876543210@s.whatsapp.net");
+
+        let group = JID::from_str("\t120363000000000000@g.us ").unwrap();
+        assert_eq!(group.server, GROUP_SERVER);
+        assert_eq!(group.user, "120363000000000000");
+    }
+
+    #[test]
+    fn test_is_empty() {
+        assert!(EMPTY_JID.is_empty());
+        assert!(JID::default().is_empty());
+        // A server-only JID refers to the server, so it isn't empty.
+        assert!(!SERVER_JID.is_empty());
+        assert!(!JID::from_str("919876543210@s.whatsapp.net")
+            .unwrap()
+            .is_empty());
+        assert!(!JID::new_ad("919876543210", 0, 2).is_empty());
+    }
+
+    #[test]
+    fn test_parse_jids_partial() {
+        let (jids, errors) = parse_jids(&[
+            "919876543210@s.whatsapp.net",
+            "",
+            "919876543210.0:x@s.whatsapp.net",
+            "120363000000000000@g.us",
+            "919876543210.0:300@s.whatsapp.net",
+            "919876543210.0:2@s.whatsapp.net",
+        ]);
+
+        assert_eq!(
+            jids.iter().map(JID::to_string).collect::<Vec<String>>(),
+            vec![
+                "919876543210@s.whatsapp.net",
+                "120363000000000000@g.us",
+                "919876543210.0:2@s.whatsapp.net",
+            ]
+        );
+        assert_eq!(
+            errors
+                .iter()
+                .map(|(index, _)| *index)
+                .collect::<Vec<usize>>(),
+            vec![1, 2, 4]
+        );
+    }
+}
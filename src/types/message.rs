@@ -1,8 +1,21 @@
+use std::{fmt, str::FromStr};
+
+use protobuf::Message;
 use time::OffsetDateTime;
 
-use super::{VerifiedName, JID};
+use crate::{
+    binary::{proto as wa_proto, Node, NodeContentType},
+    new_rhustapp_error, RhustAppError,
+};
+
+use super::{VerifiedName, BROADCAST_SERVER, GROUP_SERVER, HIDDEN_USER_SERVER, JID};
+
+/// The ID of a message, unique within its chat.
+pub type MessageID = String;
 
 /// Contains basic sender and chat information about a message.
+#[derive(Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 pub struct MessageSource {
     /// The chat where the message was sent.
     pub chat: JID,
@@ -16,13 +29,123 @@ pub struct MessageSource {
     /// When sending a read receipt to a broadcast list message, the Chat is the broadcast
     /// list and Sender is you, so this field contains the recipeint of the read receipt.
     pub broadcast_list_owner: Option<JID>,
+    /// The `recipient` attribute of the node. This is set on messages sent by the current
+    /// user from another device, both to direct chats and to broadcast lists, and on
+    /// receipts for broadcast list messages.
+    pub recipient: Option<JID>,
+
+    /// Whether `sender` is a phone number JID or a hidden user (LID) JID.
+    pub addressing_mode: AddressingMode,
+    /// The other JID of the sender: the LID if `sender` is a phone number JID, and the
+    /// phone number JID if `sender` is a LID. Only set if the server included it.
+    pub sender_alt: Option<JID>,
 }
 
 impl MessageSource {
+    /// Parses the chat and sender information from the attributes of a `<message>`,
+    /// `<receipt>` or similar node. `own_jid` is the JID of the current user, used to
+    /// detect messages sent by another one of the user's devices.
+    ///
+    /// If `require_participant` is true, a group node without a `participant` attribute is
+    /// an error.
+    pub fn from_node(
+        node: &Node,
+        own_jid: &JID,
+        require_participant: bool,
+    ) -> Result<Self, RhustAppError> {
+        let mut ag = node.attr_getter();
+        let from = ag.jid("from").unwrap_or_default();
+        let recipient = ag.optional_jid("recipient");
+
+        let mut source = MessageSource {
+            chat: from.to_non_ad(),
+            sender: from.clone(),
+            is_from_me: false,
+            is_group: false,
+            broadcast_list_owner: None,
+            recipient: recipient.clone(),
+            addressing_mode: AddressingMode::from_str(
+                &ag.optional_string("addressing_mode").unwrap_or_default(),
+            )?,
+            sender_alt: None,
+        };
+
+        if from.server.eq(GROUP_SERVER) || from.server.eq(BROADCAST_SERVER) {
+            source.is_group = true;
+            source.chat = from.clone();
+            source.sender = if require_participant {
+                ag.jid("participant").unwrap_or_default()
+            } else {
+                ag.optional_jid_or_empty("participant")
+            };
+            source.sender_alt = match source.addressing_mode {
+                AddressingMode::Lid => ag.optional_jid("participant_pn"),
+                _ => ag.optional_jid("participant_lid"),
+            };
+            source.is_from_me = source.sender_user_is(&own_jid.user);
+            if from.server.eq(BROADCAST_SERVER) {
+                source.broadcast_list_owner = recipient;
+            };
+        } else {
+            source.sender_alt = if from.server.eq(HIDDEN_USER_SERVER) {
+                ag.optional_jid("sender_pn")
+            } else {
+                ag.optional_jid("sender_lid")
+            };
+            if source.sender_user_is(&own_jid.user) {
+                source.is_from_me = true;
+                if let Some(recipient) = recipient {
+                    source.chat = recipient;
+                };
+            };
+        };
+
+        match ag.error() {
+            Some(err) => Err(new_rhustapp_error(
+                "failed to parse message source",
+                Some(err.to_string()),
+            )),
+            None => Ok(source),
+        }
+    }
+
+    /// Returns true if either the sender or the alternative JID of the sender belongs to
+    /// the given user.
+    fn sender_user_is(&self, user: &str) -> bool {
+        self.sender.user.eq(user)
+            || self
+                .sender_alt
+                .as_ref()
+                .is_some_and(|alt| alt.user.eq(user))
+    }
+
     /// Returns true if the message was sent to a broadcast list instead of directly to
-    /// the user.
+    /// the user. Messages sent by the current user are only incoming if they name the
+    /// broadcast list owner in `recipient`.
     pub fn is_incoming_broadcast(&self) -> bool {
-        (!self.is_from_me || self.broadcast_list_owner.is_some()) && self.chat.is_broadcast_list()
+        let has_owner = self.broadcast_list_owner.is_some() || self.recipient.is_some();
+        (!self.is_from_me || has_owner) && self.chat.is_broadcast_list()
+    }
+
+    /// Returns the JID that replies and receipts for the message should be addressed to.
+    /// For incoming broadcast list messages, this is the owner of the broadcast list
+    /// (`broadcast_list_owner` or `recipient` if known, otherwise the sender), for direct
+    /// chats of the current user it is the `recipient` and for everything else it is the
+    /// chat.
+    pub fn reply_recipient(&self) -> JID {
+        if self.is_incoming_broadcast() {
+            self.broadcast_list_owner
+                .as_ref()
+                .or(self.recipient.as_ref())
+                .unwrap_or(&self.sender)
+                .to_non_ad()
+        } else if self.is_from_me && !self.is_group {
+            self.recipient
+                .as_ref()
+                .map_or_else(|| self.chat.clone(), JID::to_non_ad)
+        } else {
+            self.chat.clone()
+        }
     }
 
     /// Returns a log-friendly representation of who sent the message and where.
@@ -35,24 +158,534 @@ impl MessageSource {
     }
 }
 
+/// The `addressing_mode` attribute of a message stanza, which tells whether the `from` and
+/// `participant` attributes are phone number JIDs or hidden user (LID) JIDs.
+#[derive(Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub enum AddressingMode {
+    /// ("pn") Phone number JIDs. Stanzas without an `addressing_mode` use this.
+    Pn,
+    /// ("lid") Hidden user JIDs, the phone numbers are in the `*_pn` attributes.
+    Lid,
+    Value(String),
+}
+
+impl FromStr for AddressingMode {
+    type Err = RhustAppError;
+
+    fn from_str(input: &str) -> Result<Self, RhustAppError> {
+        match input {
+            "" | "pn" => Ok(Self::Pn),
+            "lid" => Ok(Self::Lid),
+            _ => Ok(Self::Value(input.to_string())),
+        }
+    }
+}
+
+impl fmt::Display for AddressingMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Pn => write!(f, "pn"),
+            Self::Lid => write!(f, "lid"),
+            Self::Value(value) => write!(f, "{value}"),
+        }
+    }
+}
+
 /// Contains the metadata from messages sent by another one of the user's own devices.
+#[derive(Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 pub struct DeviceSentMeta {
     /// The destination user. This should match the `MessageInfo.recipient` field.
     pub destination_jid: String,
     pub phash: String,
 }
 
+impl DeviceSentMeta {
+    /// Returns the metadata of the `deviceSentMessage` wrapper, if the message has one.
+    pub fn from_message(message: &wa_proto::Message) -> Option<Self> {
+        let device_sent = message.deviceSentMessage.as_ref()?;
+        Some(Self {
+            destination_jid: device_sent.destinationJid().to_string(),
+            phash: device_sent.phash().to_string(),
+        })
+    }
+}
+
+/// The `edit` attribute of a message stanza, which marks edits and revokes.
+#[derive(Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub enum MessageEditType {
+    /// ("1") The sender edited the message.
+    Edit,
+    /// ("3") An admin edited the message.
+    AdminEdit,
+    /// ("7") The sender deleted the message for everyone.
+    SenderRevoke,
+    /// ("8") A group admin deleted someone else's message for everyone.
+    AdminRevoke,
+    Value(String),
+}
+
+impl FromStr for MessageEditType {
+    type Err = RhustAppError;
+
+    fn from_str(input: &str) -> Result<Self, RhustAppError> {
+        match input {
+            "1" => Ok(Self::Edit),
+            "3" => Ok(Self::AdminEdit),
+            "7" => Ok(Self::SenderRevoke),
+            "8" => Ok(Self::AdminRevoke),
+            _ => Ok(Self::Value(input.to_string())),
+        }
+    }
+}
+
+impl fmt::Display for MessageEditType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Edit => write!(f, "1"),
+            Self::AdminEdit => write!(f, "3"),
+            Self::SenderRevoke => write!(f, "7"),
+            Self::AdminRevoke => write!(f, "8"),
+            Self::Value(value) => write!(f, "{value}"),
+        }
+    }
+}
+
 /// Contains metadata about an incoming message
+#[derive(Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 pub struct MessageInfo {
     pub id: String,
     pub source: MessageSource,
     pub r#type: String,
+    /// The name that the sender set for themselves, from the `notify` attribute. It is empty
+    /// if the server didn't include it, e.g. in messages from the user's own devices.
+    pub push_name: String,
+    /// When the message was sent. For messages delivered from the offline queue, this is
+    /// still the original send time, not the time of delivery.
+    #[cfg_attr(
+        feature = "serde",
+        serde(serialize_with = "time::serde::rfc3339::serialize")
+    )]
     pub timestamp: OffsetDateTime,
+    /// Whether the message was queued on the server while the client was offline and
+    /// delivered after connecting.
+    pub is_offline: bool,
     pub category: String,
     pub multicast: bool,
     pub media_type: String,
+    /// Set if the message is an edit or a revoke of an earlier message.
+    pub edit: Option<MessageEditType>,
 
+    #[cfg_attr(feature = "serde", serde(skip))]
     pub verified_name: Option<VerifiedName>,
     /// Metadata for direct messages sent from another one of the user's own devices.
     pub device_sent_meta: Option<DeviceSentMeta>,
+    /// The disappearing message timer of the message in seconds, if it's ephemeral. This
+    /// can differ from the current setting of the chat, see `expires_at`.
+    pub ephemeral_expiration: Option<u32>,
+}
+
+impl MessageInfo {
+    /// Parses the metadata of an incoming `<message>` node. The encrypted content of the
+    /// message is not touched.
+    pub fn from_node(node: &Node, own_jid: &JID) -> Result<Self, RhustAppError> {
+        let source = MessageSource::from_node(node, own_jid, true)?;
+
+        let mut ag = node.attr_getter();
+        let id = ag.string("id");
+        let timestamp = ag.unix_time("t");
+        let r#type = ag.optional_string("type").unwrap_or_default();
+        let push_name = ag.optional_string("notify").unwrap_or_default();
+        let category = ag.optional_string("category").unwrap_or_default();
+        let is_offline = ag.optional_string("offline").is_some();
+        let edit = match ag.optional_string("edit") {
+            Some(edit) if !edit.is_empty() => Some(MessageEditType::from_str(&edit)?),
+            _ => None,
+        };
+        // Older stanzas use `ephemeral` instead of `expiration`.
+        let ephemeral_expiration = match ["expiration", "ephemeral"]
+            .into_iter()
+            .find(|key| ag.optional_string(key).is_some())
+        {
+            Some(key) => ag
+                .u64(key)
+                .filter(|expiration| *expiration > 0)
+                .map(|expiration| u32::try_from(expiration).unwrap_or(u32::MAX)),
+            None => None,
+        };
+        if let Some(err) = ag.error() {
+            return Err(new_rhustapp_error(
+                "failed to parse message info",
+                Some(err.to_string()),
+            ));
+        };
+
+        let mut info = Self {
+            id: id.unwrap(),
+            source,
+            r#type,
+            push_name,
+            timestamp: timestamp.unwrap(),
+            is_offline,
+            category,
+            multicast: false,
+            media_type: String::new(),
+            edit,
+            verified_name: None,
+            device_sent_meta: None,
+            ephemeral_expiration,
+        };
+
+        for child in node.get_children().unwrap_or_default() {
+            if child.tag.eq("multicast") {
+                info.multicast = true;
+            } else if child.tag.eq("verified_name") {
+                info.verified_name = Some(parse_verified_name(&child)?);
+            } else if let Some(media_type) = child.attr_getter().optional_string("mediatype") {
+                info.media_type = media_type;
+            };
+        }
+
+        Ok(info)
+    }
+
+    /// Returns when the message should be deleted because of its disappearing message
+    /// timer, or `None` if the message isn't ephemeral.
+    pub fn expires_at(&self) -> Option<OffsetDateTime> {
+        self.ephemeral_expiration
+            .map(|expiration| self.timestamp + time::Duration::seconds(expiration.into()))
+    }
+}
+
+/// Returns the plain text of a text message, which is either a `conversation` or the `text`
+/// of an `extendedTextMessage` (used e.g. for replies and messages with link previews).
+/// Returns `None` for any other kind of message, including media messages with captions.
+pub fn extract_text(message: &wa_proto::Message) -> Option<String> {
+    if let Some(conversation) = &message.conversation {
+        return Some(conversation.to_string());
+    };
+    message
+        .extendedTextMessage
+        .as_ref()
+        .and_then(|extended| extended.text.clone())
+}
+
+/// Unwraps a message sent by another one of the user's own devices, which is wrapped in a
+/// `deviceSentMessage`. Returns the destination JID (the `DeviceSentMeta.destination_jid`)
+/// and the inner message, or `None` if the message isn't wrapped.
+pub fn unwrap_device_sent(message: &wa_proto::Message) -> Option<(String, &wa_proto::Message)> {
+    let device_sent = message.deviceSentMessage.as_ref()?;
+    let inner = device_sent.message.as_ref()?;
+    Some((device_sent.destinationJid().to_string(), inner))
+}
+
+/// Parses the `<verified_name>` node holding a business' verified name certificate.
+pub(crate) fn parse_verified_name(node: &Node) -> Result<VerifiedName, RhustAppError> {
+    let raw_certificate = match &node.content {
+        NodeContentType::ByteArray(bytes) => bytes,
+        _ => {
+            return Err(new_rhustapp_error(
+                "verified name content is not a byte array",
+                None,
+            ))
+        }
+    };
+
+    let certificate = wa_proto::VerifiedNameCertificate::parse_from_bytes(raw_certificate)
+        .map_err(|err| {
+            new_rhustapp_error(
+                "failed to unmarshal verified name certificate",
+                Some(err.to_string()),
+            )
+        })?;
+    let details =
+        wa_proto::verified_name_certificate::Details::parse_from_bytes(certificate.details())
+            .map_err(|err| {
+                new_rhustapp_error(
+                    "failed to unmarshal verified name details",
+                    Some(err.to_string()),
+                )
+            })?;
+
+    Ok(VerifiedName {
+        certificate,
+        details,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::binary::{AttributeTypes, Attrs};
+
+    use super::*;
+
+    fn message_node(extra_attrs: &[(&str, &str)]) -> Node {
+        let mut attrs = Attrs::from([
+            (
+                "from".to_string(),
+                AttributeTypes::JID(JID::from_str("919876543210@s.whatsapp.net").unwrap()),
+            ),
+            (
+                "id".to_string(),
+                AttributeTypes::String("3EB0ABCDEF".to_string()),
+            ),
+            (
+                "type".to_string(),
+                AttributeTypes::String("text".to_string()),
+            ),
+            (
+                "t".to_string(),
+                AttributeTypes::String("1678000000".to_string()),
+            ),
+        ]);
+        for (key, value) in extra_attrs {
+            attrs.insert(key.to_string(), AttributeTypes::String(value.to_string()));
+        }
+
+        Node {
+            tag: "message".to_string(),
+            attrs,
+            content: NodeContentType::None,
+        }
+    }
+
+    fn source_node(jid_attrs: &[(&str, &str)]) -> Node {
+        let mut node = message_node(&[]);
+        for (key, value) in jid_attrs {
+            node.attrs.insert(
+                key.to_string(),
+                AttributeTypes::JID(JID::from_str(value).unwrap()),
+            );
+        }
+        node
+    }
+
+    fn own_jid() -> JID {
+        JID::from_str("911234567890@s.whatsapp.net").unwrap()
+    }
+
+    #[test]
+    fn test_message_info_edit() {
+        let info = MessageInfo::from_node(&message_node(&[("edit", "1")]), &own_jid()).unwrap();
+        assert!(matches!(info.edit, Some(MessageEditType::Edit)));
+        assert_eq!(info.id, "3EB0ABCDEF");
+    }
+
+    #[test]
+    fn test_message_info_not_edit() {
+        let info = MessageInfo::from_node(&message_node(&[]), &own_jid()).unwrap();
+        assert!(info.edit.is_none());
+        assert_eq!(info.r#type, "text");
+        assert_eq!(info.timestamp.unix_timestamp(), 1678000000);
+        assert!(!info.source.is_from_me);
+    }
+
+    #[test]
+    fn test_message_info_push_name() {
+        let info =
+            MessageInfo::from_node(&message_node(&[("notify", "John")]), &own_jid()).unwrap();
+        assert_eq!(info.push_name, "John");
+
+        let info = MessageInfo::from_node(&message_node(&[]), &own_jid()).unwrap();
+        assert!(info.push_name.is_empty());
+    }
+
+    #[test]
+    fn test_message_info_ephemeral() {
+        // A 7 day timer.
+        let info =
+            MessageInfo::from_node(&message_node(&[("expiration", "604800")]), &own_jid()).unwrap();
+        assert_eq!(info.ephemeral_expiration, Some(604800));
+        assert_eq!(info.expires_at().unwrap().unix_timestamp(), 1678604800);
+
+        let info =
+            MessageInfo::from_node(&message_node(&[("ephemeral", "86400")]), &own_jid()).unwrap();
+        assert_eq!(info.ephemeral_expiration, Some(86400));
+
+        assert!(
+            MessageInfo::from_node(&message_node(&[("expiration", "soon")]), &own_jid()).is_err()
+        );
+    }
+
+    #[test]
+    fn test_message_info_not_ephemeral() {
+        let info = MessageInfo::from_node(&message_node(&[]), &own_jid()).unwrap();
+        assert!(info.ephemeral_expiration.is_none());
+        assert!(info.expires_at().is_none());
+
+        let info =
+            MessageInfo::from_node(&message_node(&[("expiration", "0")]), &own_jid()).unwrap();
+        assert!(info.ephemeral_expiration.is_none());
+    }
+
+    #[test]
+    fn test_message_info_online() {
+        let info = MessageInfo::from_node(&message_node(&[]), &own_jid()).unwrap();
+        assert!(!info.is_offline);
+        assert_eq!(info.timestamp.unix_timestamp(), 1678000000);
+    }
+
+    #[test]
+    fn test_message_info_offline() {
+        // Sent a day before it was delivered from the offline queue.
+        let info = MessageInfo::from_node(
+            &message_node(&[("t", "1677913600"), ("offline", "1")]),
+            &own_jid(),
+        )
+        .unwrap();
+        assert!(info.is_offline);
+        assert_eq!(info.timestamp.unix_timestamp(), 1677913600);
+    }
+
+    #[test]
+    fn test_message_source_with_recipient() {
+        let node = source_node(&[
+            ("from", "911234567890.0:3@s.whatsapp.net"),
+            ("recipient", "919876543210@s.whatsapp.net"),
+        ]);
+        let source = MessageSource::from_node(&node, &own_jid(), false).unwrap();
+        let recipient = JID::from_str("919876543210@s.whatsapp.net").unwrap();
+        assert!(source.is_from_me);
+        assert_eq!(source.recipient, Some(recipient.clone()));
+        assert_eq!(source.chat, recipient);
+        assert!(!source.is_incoming_broadcast());
+        assert_eq!(source.reply_recipient(), recipient);
+
+        let node = source_node(&[
+            ("from", "1678000000@broadcast"),
+            ("participant", "911234567890@s.whatsapp.net"),
+            ("recipient", "919876543210@s.whatsapp.net"),
+        ]);
+        let source = MessageSource::from_node(&node, &own_jid(), true).unwrap();
+        assert!(source.is_from_me);
+        assert!(source.is_incoming_broadcast());
+        assert_eq!(source.reply_recipient(), recipient);
+    }
+
+    #[test]
+    fn test_message_source_without_recipient() {
+        let source = MessageSource::from_node(&message_node(&[]), &own_jid(), false).unwrap();
+        assert!(source.recipient.is_none());
+        assert_eq!(
+            source.reply_recipient(),
+            JID::from_str("919876543210@s.whatsapp.net").unwrap()
+        );
+
+        let node = source_node(&[
+            ("from", "1678000000@broadcast"),
+            ("participant", "911234567890@s.whatsapp.net"),
+        ]);
+        let source = MessageSource::from_node(&node, &own_jid(), true).unwrap();
+        assert!(source.recipient.is_none());
+        assert!(!source.is_incoming_broadcast());
+        assert_eq!(source.reply_recipient(), source.chat);
+    }
+
+    #[test]
+    fn test_message_source_pn_addressing() {
+        let mut node = source_node(&[
+            ("from", "120363000000000000@g.us"),
+            ("participant", "919876543210.0:2@s.whatsapp.net"),
+            ("participant_lid", "123456789012345@lid"),
+        ]);
+        node.attrs.insert(
+            "addressing_mode".to_string(),
+            AttributeTypes::String("pn".to_string()),
+        );
+        let source = MessageSource::from_node(&node, &own_jid(), true).unwrap();
+        assert!(matches!(source.addressing_mode, AddressingMode::Pn));
+        assert!(source.is_group);
+        assert_eq!(source.chat.to_string(), "120363000000000000@g.us");
+        assert_eq!(source.sender.user, "919876543210");
+        assert_eq!(
+            source.sender_alt.unwrap().to_string(),
+            "123456789012345@lid"
+        );
+        assert!(!source.is_from_me);
+    }
+
+    #[test]
+    fn test_message_source_lid_addressing() {
+        let mut node = source_node(&[
+            ("from", "120363000000000000@g.us"),
+            ("participant", "123456789012345@lid"),
+            ("participant_pn", "911234567890@s.whatsapp.net"),
+        ]);
+        node.attrs.insert(
+            "addressing_mode".to_string(),
+            AttributeTypes::String("lid".to_string()),
+        );
+        let source = MessageSource::from_node(&node, &own_jid(), true).unwrap();
+        assert!(matches!(source.addressing_mode, AddressingMode::Lid));
+        assert_eq!(source.chat.to_string(), "120363000000000000@g.us");
+        assert_eq!(source.sender.to_string(), "123456789012345@lid");
+        assert_eq!(source.sender_alt.unwrap(), own_jid());
+        // The participant is a LID, but the phone number shows it's the current user.
+        assert!(source.is_from_me);
+    }
+
+    #[test]
+    fn test_extract_text_conversation() {
+        let mut message = wa_proto::Message::new();
+        message.conversation = Some("hello".to_string());
+        assert_eq!(extract_text(&message).as_deref(), Some("hello"));
+    }
+
+    #[test]
+    fn test_extract_text_extended_text() {
+        let mut extended = wa_proto::ExtendedTextMessage::new();
+        extended.text = Some("see https://example.com".to_string());
+        extended.matchedText = Some("https://example.com".to_string());
+        let mut message = wa_proto::Message::new();
+        message.extendedTextMessage = protobuf::MessageField::some(extended);
+
+        assert_eq!(
+            extract_text(&message).as_deref(),
+            Some("see https://example.com")
+        );
+    }
+
+    #[test]
+    fn test_extract_text_image() {
+        let mut image = wa_proto::ImageMessage::new();
+        image.caption = Some("a caption".to_string());
+        let mut message = wa_proto::Message::new();
+        message.imageMessage = protobuf::MessageField::some(image);
+
+        assert_eq!(extract_text(&message), None);
+    }
+
+    #[test]
+    fn test_unwrap_device_sent() {
+        let mut inner = wa_proto::Message::new();
+        inner.conversation = Some("sent from my phone".to_string());
+        let mut device_sent = wa_proto::DeviceSentMessage::new();
+        device_sent.destinationJid = Some("919876543210@s.whatsapp.net".to_string());
+        device_sent.phash = Some("2:abcdef".to_string());
+        device_sent.message = protobuf::MessageField::some(inner);
+        let mut message = wa_proto::Message::new();
+        message.deviceSentMessage = protobuf::MessageField::some(device_sent);
+
+        let (destination, unwrapped) = unwrap_device_sent(&message).unwrap();
+        assert_eq!(destination, "919876543210@s.whatsapp.net");
+        assert_eq!(
+            extract_text(unwrapped).as_deref(),
+            Some("sent from my phone")
+        );
+
+        let meta = DeviceSentMeta::from_message(&message).unwrap();
+        assert_eq!(meta.destination_jid, destination);
+        assert_eq!(meta.phash, "2:abcdef");
+    }
+
+    #[test]
+    fn test_unwrap_device_sent_normal_message() {
+        let mut message = wa_proto::Message::new();
+        message.conversation = Some("hello".to_string());
+        assert!(unwrap_device_sent(&message).is_none());
+        assert!(DeviceSentMeta::from_message(&message).is_none());
+    }
 }
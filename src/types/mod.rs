@@ -12,8 +12,14 @@ pub use jid::*;
 mod message;
 pub use message::*;
 
+mod newsletter;
+pub use newsletter::*;
+
 mod presence;
 pub use presence::*;
 
+mod props;
+pub use props::*;
+
 mod user;
 pub use user::*;
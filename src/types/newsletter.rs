@@ -0,0 +1,256 @@
+use std::{fmt, str::FromStr};
+
+use crate::{
+    binary::{Node, NodeContentType},
+    new_rhustapp_error, RhustAppError,
+};
+
+use super::JID;
+
+/// The role of the current user in a newsletter.
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub enum NewsletterRole {
+    /// ("subscriber") The user follows the newsletter.
+    Subscriber,
+    /// ("guest") The user doesn't follow the newsletter.
+    Guest,
+    /// ("admin") The user can post to the newsletter.
+    Admin,
+    /// ("owner") The user created the newsletter.
+    Owner,
+    Value(String),
+}
+
+impl FromStr for NewsletterRole {
+    type Err = RhustAppError;
+
+    fn from_str(input: &str) -> Result<Self, RhustAppError> {
+        match input {
+            "subscriber" => Ok(Self::Subscriber),
+            "guest" => Ok(Self::Guest),
+            "admin" => Ok(Self::Admin),
+            "owner" => Ok(Self::Owner),
+            _ => Ok(Self::Value(input.to_string())),
+        }
+    }
+}
+
+impl fmt::Display for NewsletterRole {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Subscriber => write!(f, "subscriber"),
+            Self::Guest => write!(f, "guest"),
+            Self::Admin => write!(f, "admin"),
+            Self::Owner => write!(f, "owner"),
+            Self::Value(value) => write!(f, "{value}"),
+        }
+    }
+}
+
+/// Whether the current user muted the notifications of a newsletter.
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub enum NewsletterMuteState {
+    /// ("on") Notifications are muted.
+    On,
+    /// ("off") Notifications are not muted.
+    Off,
+    Value(String),
+}
+
+impl FromStr for NewsletterMuteState {
+    type Err = RhustAppError;
+
+    fn from_str(input: &str) -> Result<Self, RhustAppError> {
+        match input {
+            "on" => Ok(Self::On),
+            "off" => Ok(Self::Off),
+            _ => Ok(Self::Value(input.to_string())),
+        }
+    }
+}
+
+impl fmt::Display for NewsletterMuteState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::On => write!(f, "on"),
+            Self::Off => write!(f, "off"),
+            Self::Value(value) => write!(f, "{value}"),
+        }
+    }
+}
+
+/// Contains the metadata of a newsletter (channel).
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct NewsletterInfo {
+    pub jid: JID,
+    pub name: String,
+    pub description: String,
+    pub subscriber_count: u64,
+    /// The mute state of the current user, if the server included the viewer metadata.
+    pub mute: Option<NewsletterMuteState>,
+    /// The role of the current user, if the server included the viewer metadata.
+    pub role: Option<NewsletterRole>,
+}
+
+impl NewsletterInfo {
+    /// Parses the `<newsletter jid="...">` node of the response to the newsletter metadata
+    /// query. It has a `<metadata>` child with the `<name>`, `<description>` and
+    /// `<subscribers count="...">` of the newsletter, and optionally a
+    /// `<viewer_metadata mute="..." role="...">` child about the current user.
+    pub fn from_node(node: &Node) -> Result<Self, RhustAppError> {
+        node.expect_tag("newsletter")?;
+
+        let mut ag = node.attr_getter();
+        let jid = ag.jid("jid");
+        if let Some(err) = ag.error() {
+            return Err(new_rhustapp_error(
+                "failed to parse newsletter",
+                Some(err.to_string()),
+            ));
+        };
+
+        let metadata = node
+            .get_optional_child_by_tag(&["metadata"])
+            .ok_or_else(|| new_rhustapp_error("didn't find <metadata> in newsletter", None))?;
+        let name = match metadata.get_optional_child_by_tag(&["name"]) {
+            Some(name) => text_content(&name)?,
+            None => String::new(),
+        };
+        let description = match metadata.get_optional_child_by_tag(&["description"]) {
+            Some(description) => text_content(&description)?,
+            None => String::new(),
+        };
+        let subscriber_count = match metadata.get_optional_child_by_tag(&["subscribers"]) {
+            Some(subscribers) => {
+                let mut ag = subscribers.attr_getter();
+                let count = ag.optional_u64_radix("count", 10).unwrap_or_default();
+                if let Some(err) = ag.error() {
+                    return Err(new_rhustapp_error(
+                        "failed to parse newsletter subscriber count",
+                        Some(err.to_string()),
+                    ));
+                };
+                count
+            }
+            None => 0,
+        };
+
+        let (mute, role) = match node.get_optional_child_by_tag(&["viewer_metadata"]) {
+            Some(viewer) => {
+                let mut ag = viewer.attr_getter();
+                let mute = match ag.optional_string("mute") {
+                    Some(mute) => Some(NewsletterMuteState::from_str(&mute)?),
+                    None => None,
+                };
+                let role = match ag.optional_string("role") {
+                    Some(role) => Some(NewsletterRole::from_str(&role)?),
+                    None => None,
+                };
+                if let Some(err) = ag.error() {
+                    return Err(new_rhustapp_error(
+                        "failed to parse newsletter viewer metadata",
+                        Some(err.to_string()),
+                    ));
+                };
+                (mute, role)
+            }
+            None => (None, None),
+        };
+
+        Ok(Self {
+            jid: jid.unwrap(),
+            name,
+            description,
+            subscriber_count,
+            mute,
+            role,
+        })
+    }
+}
+
+/// Returns the text content of a node, which may be decoded either as a string or as bytes.
+fn text_content(node: &Node) -> Result<String, RhustAppError> {
+    match &node.content {
+        NodeContentType::None => Ok(String::new()),
+        NodeContentType::String(text) => Ok(text.to_string()),
+        NodeContentType::ByteArray(bytes) => String::from_utf8(bytes.to_vec()).map_err(|err| {
+            new_rhustapp_error(
+                &format!("<{}> content is not valid UTF-8", node.tag),
+                Some(err.to_string()),
+            )
+        }),
+        _ => Err(new_rhustapp_error(
+            &format!("<{}> content is not text", node.tag),
+            None,
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        binary::{AttributeTypes, Attrs},
+        testing::node,
+        types::NEWSLETTER_SERVER,
+    };
+
+    use super::*;
+
+    #[test]
+    fn test_newsletter_info_from_node() {
+        let metadata = node(
+            "metadata",
+            &[],
+            NodeContentType::ListOfNodes(vec![
+                node(
+                    "name",
+                    &[("id", "1")],
+                    NodeContentType::ByteArray(b"Rust News".to_vec()),
+                ),
+                node(
+                    "description",
+                    &[("id", "2")],
+                    NodeContentType::ByteArray(b"Weekly updates".to_vec()),
+                ),
+                node("subscribers", &[("count", "12345")], NodeContentType::None),
+            ]),
+        );
+        let viewer = node(
+            "viewer_metadata",
+            &[("mute", "on"), ("role", "subscriber")],
+            NodeContentType::None,
+        );
+        let mut newsletter = node(
+            "newsletter",
+            &[],
+            NodeContentType::ListOfNodes(vec![metadata.clone(), viewer]),
+        );
+        newsletter.attrs.insert(
+            "jid".to_string(),
+            AttributeTypes::JID(JID::new("120363000000000000", NEWSLETTER_SERVER)),
+        );
+
+        let info = NewsletterInfo::from_node(&newsletter).unwrap();
+        assert_eq!(info.jid.to_string(), "120363000000000000@newsletter");
+        assert_eq!(info.name, "Rust News");
+        assert_eq!(info.description, "Weekly updates");
+        assert_eq!(info.subscriber_count, 12345);
+        assert!(matches!(info.mute, Some(NewsletterMuteState::On)));
+        assert!(matches!(info.role, Some(NewsletterRole::Subscriber)));
+
+        // The viewer metadata is left out for newsletters the user doesn't follow.
+        newsletter.content = NodeContentType::ListOfNodes(vec![metadata]);
+        let info = NewsletterInfo::from_node(&newsletter).unwrap();
+        assert!(info.mute.is_none());
+        assert!(info.role.is_none());
+
+        newsletter.content = NodeContentType::None;
+        assert!(NewsletterInfo::from_node(&newsletter).is_err());
+        let no_jid = Node {
+            tag: "newsletter".to_string(),
+            attrs: Attrs::new(),
+            content: NodeContentType::None,
+        };
+        assert!(NewsletterInfo::from_node(&no_jid).is_err());
+    }
+}
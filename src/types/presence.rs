@@ -1,4 +1,4 @@
-use std::str::FromStr;
+use std::{fmt, str::FromStr};
 
 use crate::RhustAppError;
 
@@ -22,6 +22,8 @@ impl FromStr for Presence {
     }
 }
 
+#[derive(Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 pub enum ChatPresence {
     /// "composing"
     Composing,
@@ -30,6 +32,16 @@ pub enum ChatPresence {
     Value(String),
 }
 
+impl fmt::Display for ChatPresence {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Composing => write!(f, "composing"),
+            Self::Paused => write!(f, "paused"),
+            Self::Value(value) => write!(f, "{value}"),
+        }
+    }
+}
+
 impl FromStr for ChatPresence {
     type Err = RhustAppError;
 
@@ -42,6 +54,8 @@ impl FromStr for ChatPresence {
     }
 }
 
+#[derive(Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 pub enum ChatPresenceMedia {
     /// ""
     Text,
@@ -50,6 +64,16 @@ pub enum ChatPresenceMedia {
     Value(String),
 }
 
+impl fmt::Display for ChatPresenceMedia {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Text => write!(f, ""),
+            Self::Audio => write!(f, "audio"),
+            Self::Value(value) => write!(f, "{value}"),
+        }
+    }
+}
+
 impl FromStr for ChatPresenceMedia {
     type Err = RhustAppError;
 
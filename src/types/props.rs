@@ -0,0 +1,159 @@
+use std::collections::HashMap;
+
+use crate::{binary::Node, new_rhustapp_error, RhustAppError};
+
+/// Contains the server-side configuration (feature toggles and limits) that the server sends
+/// in a `<props>` node.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct ServerProps {
+    /// The protocol version of the props, if given.
+    pub protocol: Option<String>,
+    /// The hash of the props, which can be sent back to only fetch the changed props.
+    pub hash: Option<String>,
+    /// All the props, keyed by their name.
+    pub props: HashMap<String, String>,
+}
+
+impl ServerProps {
+    /// The maximum number of participants in a group.
+    pub const MAX_PARTICIPANTS: &'static str = "max_participants";
+    /// The maximum length of a group subject.
+    pub const MAX_SUBJECT: &'static str = "max_subject";
+    /// The maximum size of a media upload, in megabytes.
+    pub const MEDIA: &'static str = "media";
+    /// The maximum size of an image, in kilobytes.
+    pub const IMAGE_MAX_KBYTES: &'static str = "image_max_kbytes";
+
+    /// Parses a `<props>` node. Each `<prop>` child is read either as `name` / `value` or as
+    /// `config_code` / `config_value`, as both layouts are used by the server.
+    pub fn from_node(node: &Node) -> Result<Self, RhustAppError> {
+        node.expect_tag("props")?;
+
+        let mut ag = node.attr_getter();
+        let mut server_props = Self {
+            protocol: ag.optional_string("protocol"),
+            hash: ag.optional_string("hash"),
+            props: HashMap::new(),
+        };
+
+        for child in node.get_children_by_tag("prop").unwrap_or_default() {
+            let mut ag = child.attr_getter();
+            let (key, value) = match ag.optional_string("config_code") {
+                Some(code) => (Some(code), ag.string("config_value")),
+                None => (ag.string("name"), ag.string("value")),
+            };
+            if let Some(err) = ag.error() {
+                return Err(new_rhustapp_error(
+                    "failed to parse server prop",
+                    Some(err.to_string()),
+                ));
+            };
+            server_props.props.insert(key.unwrap(), value.unwrap());
+        }
+
+        Ok(server_props)
+    }
+
+    /// Returns the raw value of the prop with the given name.
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.props.get(key).map(|value| value.as_str())
+    }
+
+    /// Returns the value of the prop with the given name as a number, if it is one.
+    pub fn get_u64(&self, key: &str) -> Option<u64> {
+        self.get(key).and_then(|value| value.parse().ok())
+    }
+
+    /// Returns the value of the prop with the given name as a boolean. Both `true` / `false`
+    /// and `1` / `0` are accepted.
+    pub fn get_bool(&self, key: &str) -> Option<bool> {
+        match self.get(key)? {
+            "true" | "1" => Some(true),
+            "false" | "0" => Some(false),
+            _ => None,
+        }
+    }
+
+    pub fn max_participants(&self) -> Option<u64> {
+        self.get_u64(Self::MAX_PARTICIPANTS)
+    }
+
+    pub fn max_subject_length(&self) -> Option<u64> {
+        self.get_u64(Self::MAX_SUBJECT)
+    }
+
+    /// Returns the maximum size of a media upload in bytes.
+    pub fn max_media_size(&self) -> Option<u64> {
+        self.get_u64(Self::MEDIA).map(|mb| mb * 1024 * 1024)
+    }
+
+    /// Returns the maximum size of an image in bytes.
+    pub fn max_image_size(&self) -> Option<u64> {
+        self.get_u64(Self::IMAGE_MAX_KBYTES).map(|kb| kb * 1024)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::binary::{AttributeTypes, Attrs, NodeContentType};
+
+    use super::*;
+
+    fn prop(attrs: &[(&str, &str)]) -> Node {
+        Node {
+            tag: "prop".to_string(),
+            attrs: attrs
+                .iter()
+                .map(|(k, v)| (k.to_string(), AttributeTypes::String(v.to_string())))
+                .collect::<Attrs>(),
+            content: NodeContentType::None,
+        }
+    }
+
+    #[test]
+    fn test_from_node() {
+        let node = Node {
+            tag: "props".to_string(),
+            attrs: Attrs::from([
+                (
+                    "protocol".to_string(),
+                    AttributeTypes::String("1".to_string()),
+                ),
+                (
+                    "hash".to_string(),
+                    AttributeTypes::String("abcd".to_string()),
+                ),
+            ]),
+            content: NodeContentType::ListOfNodes(vec![
+                prop(&[("name", "max_participants"), ("value", "1024")]),
+                prop(&[("name", "media"), ("value", "16")]),
+                prop(&[
+                    ("config_code", "image_max_kbytes"),
+                    ("config_value", "1024"),
+                ]),
+                prop(&[("name", "web_voip_enabled"), ("value", "true")]),
+            ]),
+        };
+
+        let props = ServerProps::from_node(&node).unwrap();
+        assert_eq!(props.protocol.as_deref(), Some("1"));
+        assert_eq!(props.hash.as_deref(), Some("abcd"));
+        assert_eq!(props.props.len(), 4);
+        assert_eq!(props.max_participants(), Some(1024));
+        assert_eq!(props.max_media_size(), Some(16 * 1024 * 1024));
+        assert_eq!(props.max_image_size(), Some(1024 * 1024));
+        assert_eq!(props.max_subject_length(), None);
+        assert_eq!(props.get_bool("web_voip_enabled"), Some(true));
+        assert_eq!(props.get("unknown"), None);
+    }
+
+    #[test]
+    fn test_from_node_missing_value() {
+        let node = Node {
+            tag: "props".to_string(),
+            attrs: Attrs::new(),
+            content: NodeContentType::ListOfNodes(vec![prop(&[("name", "media")])]),
+        };
+        assert!(ServerProps::from_node(&node).is_err());
+    }
+}
@@ -5,6 +5,7 @@ use crate::{binary::proto as wa_proto, new_rhustapp_error, RhustAppError};
 use super::JID;
 
 /// Contains verified WhatsApp Business details.
+#[derive(Clone)]
 pub struct VerifiedName {
     pub certificate: wa_proto::VerifiedNameCertificate,
     pub details: wa_proto::verified_name_certificate::Details,
@@ -57,6 +58,7 @@ pub struct ProfilePictureInfo {
 }
 
 /// Contains the cached names of a WhatsApp user.
+#[derive(Clone, Default)]
 pub struct ContactInfo {
     pub first_name: String,
     pub full_name: String,
@@ -65,6 +67,7 @@ pub struct ContactInfo {
 }
 
 /// Contains the cached local settings for a chat.
+#[derive(Clone)]
 pub struct LocalChatSettings {
     pub muted_until: time::OffsetDateTime,
     pub pinned: bool,
@@ -113,6 +116,8 @@ pub struct ContactQRLinkTarget {
 }
 
 /// Possible privacy setting values.
+#[derive(Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 pub enum PrivacySetting {
     /// ""
     Undefined,
@@ -141,6 +146,38 @@ impl FromStr for PrivacySetting {
     }
 }
 
+/// The privacy settings that can be changed.
+#[derive(Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub enum PrivacySettingType {
+    /// "groupadd"
+    GroupAdd,
+    /// "last"
+    LastSeen,
+    /// "status"
+    Status,
+    /// "profile"
+    Profile,
+    /// "readreceipts"
+    ReadReceipts,
+    Value(String),
+}
+
+impl FromStr for PrivacySettingType {
+    type Err = RhustAppError;
+
+    fn from_str(input: &str) -> Result<Self, RhustAppError> {
+        match input {
+            "groupadd" => Ok(Self::GroupAdd),
+            "last" => Ok(Self::LastSeen),
+            "status" => Ok(Self::Status),
+            "profile" => Ok(Self::Profile),
+            "readreceipts" => Ok(Self::ReadReceipts),
+            _ => Ok(Self::Value(input.to_string())),
+        }
+    }
+}
+
 /// Contains the user's privacy settings.
 pub struct PrivacySettings {
     pub group_add: PrivacySetting,
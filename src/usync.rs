@@ -0,0 +1,552 @@
+//! `usync` contains the builders for the user sync (`usync`) queries, which are used to
+//! check which phone numbers are on WhatsApp and to fetch info about users.
+
+use std::fmt;
+
+use crate::{
+    binary::{AttributeTypes, Attrs, Node, NodeContentType},
+    new_rhustapp_error,
+    types::{parse_verified_name, IsOnWhatsAppResponse, UserInfo, VerifiedName, JID, SERVER_JID},
+    RhustAppError,
+};
+
+/// The context in which a usync query is sent.
+pub enum UsyncContext {
+    /// ("interactive") The query was triggered by the user, e.g. checking a number.
+    Interactive,
+    /// ("background") The query is part of a background sync.
+    Background,
+    /// ("message") The query fetches the devices that a message is encrypted for.
+    Message,
+}
+
+impl fmt::Display for UsyncContext {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Interactive => write!(f, "interactive"),
+            Self::Background => write!(f, "background"),
+            Self::Message => write!(f, "message"),
+        }
+    }
+}
+
+/// Builds the `<iq xmlns="usync">` query that checks whether the given phone numbers are
+/// registered on WhatsApp.
+///
+/// The numbers may be given with or without the leading `+`. The `id` of the `<iq>` is not
+/// set here, it is assigned when the query is sent.
+pub fn build_usync_query(numbers: &[String], context: UsyncContext) -> Node {
+    let users = numbers
+        .iter()
+        .map(|number| {
+            let number = if number.starts_with('+') {
+                number.to_string()
+            } else {
+                format!("+{number}")
+            };
+
+            Node {
+                tag: "user".to_string(),
+                attrs: Attrs::new(),
+                content: NodeContentType::ListOfNodes(vec![Node {
+                    tag: "contact".to_string(),
+                    attrs: Attrs::new(),
+                    content: NodeContentType::String(number),
+                }]),
+            }
+        })
+        .collect::<Vec<Node>>();
+
+    let query = vec![
+        Node {
+            tag: "business".to_string(),
+            attrs: Attrs::new(),
+            content: NodeContentType::ListOfNodes(vec![Node {
+                tag: "verified_name".to_string(),
+                ..Default::default()
+            }]),
+        },
+        Node {
+            tag: "contact".to_string(),
+            ..Default::default()
+        },
+    ];
+
+    build_usync_iq(query, users, context)
+}
+
+/// Builds the `<iq xmlns="usync">` query that fetches the list of devices of each of the
+/// given users, which messages to them are encrypted for.
+pub fn build_usync_devices_query(users: &[JID], context: UsyncContext) -> Node {
+    let users = users
+        .iter()
+        .map(|user| Node {
+            tag: "user".to_string(),
+            attrs: Attrs::from([("jid".to_string(), AttributeTypes::JID(user.to_non_ad()))]),
+            content: NodeContentType::None,
+        })
+        .collect();
+
+    let query = vec![Node {
+        tag: "devices".to_string(),
+        attrs: Attrs::from([(
+            "version".to_string(),
+            AttributeTypes::String("2".to_string()),
+        )]),
+        content: NodeContentType::None,
+    }];
+
+    build_usync_iq(query, users, context)
+}
+
+/// Parses the response to `build_usync_query`, in the order of the users in the response.
+pub fn parse_is_on_whatsapp(response: &Node) -> Result<Vec<IsOnWhatsAppResponse>, RhustAppError> {
+    usync_users(response)?
+        .iter()
+        .map(|user| {
+            let jid = user_jid(user)?;
+            let contact = user
+                .get_optional_child_by_tag(&["contact"])
+                .ok_or_else(|| new_rhustapp_error("missing <contact> in usync response", None))?;
+            Ok(IsOnWhatsAppResponse {
+                query: content_text(&contact),
+                is_in: contact.attr_getter().optional_string("type").as_deref() == Some("in"),
+                verified_name: user_verified_name(user, &jid),
+                jid,
+            })
+        })
+        .collect()
+}
+
+/// Builds the `<iq xmlns="usync">` query that fetches the verified business name, the
+/// about status, the profile picture ID and the devices of each of the given users.
+pub fn build_usync_user_info_query(users: &[JID], context: UsyncContext) -> Node {
+    let users = users
+        .iter()
+        .map(|user| Node {
+            tag: "user".to_string(),
+            attrs: Attrs::from([("jid".to_string(), AttributeTypes::JID(user.to_non_ad()))]),
+            content: NodeContentType::None,
+        })
+        .collect();
+
+    let query = vec![
+        Node {
+            tag: "business".to_string(),
+            attrs: Attrs::new(),
+            content: NodeContentType::ListOfNodes(vec![Node {
+                tag: "verified_name".to_string(),
+                ..Default::default()
+            }]),
+        },
+        Node {
+            tag: "status".to_string(),
+            ..Default::default()
+        },
+        Node {
+            tag: "picture".to_string(),
+            ..Default::default()
+        },
+        Node {
+            tag: "devices".to_string(),
+            attrs: Attrs::from([(
+                "version".to_string(),
+                AttributeTypes::String("2".to_string()),
+            )]),
+            content: NodeContentType::None,
+        },
+    ];
+
+    build_usync_iq(query, users, context)
+}
+
+/// Parses the response to `build_usync_user_info_query` into the info of each user.
+pub fn parse_usync_user_info(response: &Node) -> Result<Vec<(JID, UserInfo)>, RhustAppError> {
+    let mut infos = Vec::new();
+    for user in usync_users(response)? {
+        let jid = user_jid(&user)?;
+        let info = UserInfo {
+            verified_name: user_verified_name(&user, &jid),
+            status: user
+                .get_optional_child_by_tag(&["status"])
+                .map(|status| content_text(&status))
+                .unwrap_or_default(),
+            picture_id: user
+                .get_optional_child_by_tag(&["picture"])
+                .and_then(|picture| picture.attr_getter().optional_string("id"))
+                .unwrap_or_default(),
+            devices: parse_device_list(&user, &jid)?,
+        };
+        infos.push((jid, info));
+    }
+    Ok(infos)
+}
+
+/// Parses the response to `build_usync_devices_query` into the JIDs of all the devices of
+/// the users, including their primary device (the phone) with device ID 0.
+pub fn parse_usync_devices(response: &Node) -> Result<Vec<JID>, RhustAppError> {
+    let mut devices = Vec::new();
+    for user in usync_users(response)? {
+        let jid = user_jid(&user)?;
+        devices.append(&mut parse_device_list(&user, &jid)?);
+    }
+    Ok(devices)
+}
+
+fn usync_users(response: &Node) -> Result<Vec<Node>, RhustAppError> {
+    Ok(response
+        .get_optional_child_by_tag(&["usync", "list"])
+        .ok_or_else(|| new_rhustapp_error("didn't find <list> in usync response", None))?
+        .get_children_by_tag("user")
+        .unwrap_or_default())
+}
+
+fn user_jid(user: &Node) -> Result<JID, RhustAppError> {
+    user.attr_getter()
+        .jid("jid")
+        .ok_or_else(|| new_rhustapp_error("missing jid of user in usync response", None))
+}
+
+fn parse_device_list(user: &Node, jid: &JID) -> Result<Vec<JID>, RhustAppError> {
+    let device_list = user
+        .get_optional_child_by_tag(&["devices", "device-list"])
+        .and_then(|list| list.get_children_by_tag("device"))
+        .unwrap_or_default();
+
+    let mut devices = Vec::new();
+    for device in device_list {
+        let mut ag = device.attr_getter();
+        let id = ag.u64("id");
+        if let Some(err) = ag.error() {
+            return Err(err);
+        };
+        let id = id.unwrap();
+        let id = u8::try_from(id).map_err(|_| {
+            new_rhustapp_error(&format!("invalid device ID {id} in usync response"), None)
+        })?;
+        devices.push(JID::new_ad(&jid.user, 0, id));
+    }
+    Ok(devices)
+}
+
+/// Returns the verified business name of the user, if any. An invalid certificate is only
+/// logged, as the rest of the user's info is still usable.
+fn user_verified_name(user: &Node, jid: &JID) -> Option<VerifiedName> {
+    let node = user.get_optional_child_by_tag(&["business", "verified_name"])?;
+    match parse_verified_name(&node) {
+        Ok(verified_name) => Some(verified_name),
+        Err(err) => {
+            log::warn!(
+                "failed to parse verified name of {}: {err}",
+                jid.anonymized()
+            );
+            None
+        }
+    }
+}
+
+fn content_text(node: &Node) -> String {
+    match &node.content {
+        NodeContentType::ByteArray(bytes) => String::from_utf8_lossy(bytes).to_string(),
+        content => content.other_types_to_string(),
+    }
+}
+
+/// Wraps the `<query>` and `<list>` children of a usync query in the `<usync>` and `<iq>`
+/// nodes. The `id` of the `<iq>` is not set here, it is assigned when the query is sent.
+fn build_usync_iq(query: Vec<Node>, users: Vec<Node>, context: UsyncContext) -> Node {
+    let usync = Node {
+        tag: "usync".to_string(),
+        attrs: Attrs::from([
+            ("sid".to_string(), AttributeTypes::String(generate_sid())),
+            (
+                "mode".to_string(),
+                AttributeTypes::String("query".to_string()),
+            ),
+            (
+                "last".to_string(),
+                AttributeTypes::String("true".to_string()),
+            ),
+            ("index".to_string(), AttributeTypes::String("0".to_string())),
+            (
+                "context".to_string(),
+                AttributeTypes::String(context.to_string()),
+            ),
+        ]),
+        content: NodeContentType::ListOfNodes(vec![
+            Node {
+                tag: "query".to_string(),
+                attrs: Attrs::new(),
+                content: NodeContentType::ListOfNodes(query),
+            },
+            Node {
+                tag: "list".to_string(),
+                attrs: Attrs::new(),
+                content: NodeContentType::ListOfNodes(users),
+            },
+        ]),
+    };
+
+    Node {
+        tag: "iq".to_string(),
+        attrs: Attrs::from([
+            (
+                "xmlns".to_string(),
+                AttributeTypes::String("usync".to_string()),
+            ),
+            (
+                "type".to_string(),
+                AttributeTypes::String("get".to_string()),
+            ),
+            ("to".to_string(), AttributeTypes::JID(SERVER_JID.clone())),
+        ]),
+        content: NodeContentType::ListOfNodes(vec![usync]),
+    }
+}
+
+/// Generates a session ID for a usync query.
+fn generate_sid() -> String {
+    (time::OffsetDateTime::now_utc().unix_timestamp_nanos() / 1000).to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{testing::usync_devices_node, types::DEFAULT_USER_SERVER};
+
+    use super::*;
+
+    #[test]
+    fn test_build_usync_query_two_numbers() {
+        let node = build_usync_query(
+            &["+911234567890".to_string(), "14155552671".to_string()],
+            UsyncContext::Interactive,
+        );
+
+        assert_eq!(node.tag, "iq");
+        let mut attrs = node.attr_getter();
+        assert_eq!(attrs.string("xmlns").unwrap(), "usync");
+        assert_eq!(attrs.string("type").unwrap(), "get");
+        assert_eq!(attrs.jid("to").unwrap(), *SERVER_JID);
+
+        let usync = node.get_optional_child_by_tag(&["usync"]).unwrap();
+        let mut attrs = usync.attr_getter();
+        assert!(attrs.string("sid").is_some());
+        assert_eq!(attrs.string("mode").unwrap(), "query");
+        assert_eq!(attrs.string("last").unwrap(), "true");
+        assert_eq!(attrs.string("context").unwrap(), "interactive");
+        assert!(attrs.ok());
+
+        let query = usync.get_optional_child_by_tag(&["query"]).unwrap();
+        let query_tags = query
+            .get_children()
+            .unwrap()
+            .iter()
+            .map(|n| n.tag.to_string())
+            .collect::<Vec<String>>();
+        assert_eq!(query_tags, vec!["business", "contact"]);
+
+        let users = usync
+            .get_optional_child_by_tag(&["list"])
+            .unwrap()
+            .get_children_by_tag("user")
+            .unwrap();
+        assert_eq!(users.len(), 2);
+
+        let numbers = users
+            .iter()
+            .map(|user| {
+                match user
+                    .get_optional_child_by_tag(&["contact"])
+                    .unwrap()
+                    .content
+                {
+                    NodeContentType::String(s) => s,
+                    _ => panic!("contact content is not a string"),
+                }
+            })
+            .collect::<Vec<String>>();
+        assert_eq!(numbers, vec!["+911234567890", "+14155552671"]);
+    }
+
+    #[test]
+    fn test_build_usync_devices_query() {
+        let node = build_usync_devices_query(
+            &[
+                JID::new_ad("919876543210", 0, 3),
+                JID::new("911234567890", DEFAULT_USER_SERVER),
+            ],
+            UsyncContext::Message,
+        );
+
+        assert_eq!(node.attr_getter().string("xmlns").unwrap(), "usync");
+        let usync = node.get_optional_child_by_tag(&["usync"]).unwrap();
+        assert_eq!(usync.attr_getter().string("context").unwrap(), "message");
+        let devices = usync
+            .get_optional_child_by_tag(&["query", "devices"])
+            .unwrap();
+        assert_eq!(devices.attr_getter().string("version").unwrap(), "2");
+
+        // The device lists are fetched for the users, not for single devices.
+        let users = usync
+            .get_optional_child_by_tag(&["list"])
+            .unwrap()
+            .get_children_by_tag("user")
+            .unwrap()
+            .iter()
+            .map(|user| user.attr_getter().jid("jid").unwrap())
+            .collect::<Vec<JID>>();
+        assert_eq!(
+            users,
+            vec![
+                JID::new("919876543210", DEFAULT_USER_SERVER),
+                JID::new("911234567890", DEFAULT_USER_SERVER)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_usync_devices() {
+        let devices = vec![
+            JID::new_ad("919876543210", 0, 0),
+            JID::new_ad("919876543210", 0, 3),
+            JID::new_ad("911234567890", 0, 0),
+        ];
+        let response = Node {
+            tag: "iq".to_string(),
+            attrs: Attrs::new(),
+            content: NodeContentType::ListOfNodes(vec![usync_devices_node(&devices)]),
+        };
+        assert_eq!(parse_usync_devices(&response).unwrap(), devices);
+
+        assert!(parse_usync_devices(&Node::default()).is_err());
+    }
+
+    fn usync_response(users: Vec<Node>) -> Node {
+        Node {
+            tag: "iq".to_string(),
+            attrs: Attrs::new(),
+            content: NodeContentType::ListOfNodes(vec![Node {
+                tag: "usync".to_string(),
+                attrs: Attrs::new(),
+                content: NodeContentType::ListOfNodes(vec![Node {
+                    tag: "list".to_string(),
+                    attrs: Attrs::new(),
+                    content: NodeContentType::ListOfNodes(users),
+                }]),
+            }]),
+        }
+    }
+
+    fn user_node(jid: &JID, children: Vec<Node>) -> Node {
+        Node {
+            tag: "user".to_string(),
+            attrs: Attrs::from([("jid".to_string(), AttributeTypes::JID(jid.clone()))]),
+            content: NodeContentType::ListOfNodes(children),
+        }
+    }
+
+    #[test]
+    fn test_parse_is_on_whatsapp() {
+        let registered = JID::new("911234567890", DEFAULT_USER_SERVER);
+        let unregistered = JID::new("14155552671", DEFAULT_USER_SERVER);
+        let contact = |number: &str, r#type: &str| Node {
+            tag: "contact".to_string(),
+            attrs: Attrs::from([(
+                "type".to_string(),
+                AttributeTypes::String(r#type.to_string()),
+            )]),
+            content: NodeContentType::ByteArray(number.as_bytes().to_vec()),
+        };
+        let response = usync_response(vec![
+            user_node(
+                &registered,
+                vec![
+                    contact("+911234567890", "in"),
+                    // An invalid certificate doesn't fail the whole response.
+                    Node {
+                        tag: "business".to_string(),
+                        attrs: Attrs::new(),
+                        content: NodeContentType::ListOfNodes(vec![Node {
+                            tag: "verified_name".to_string(),
+                            attrs: Attrs::new(),
+                            content: NodeContentType::ByteArray(vec![0xff; 4]),
+                        }]),
+                    },
+                ],
+            ),
+            user_node(&unregistered, vec![contact("+14155552671", "out")]),
+        ]);
+
+        let results = parse_is_on_whatsapp(&response).unwrap();
+        assert_eq!(results.len(), 2);
+        assert_eq!(results[0].query, "+911234567890");
+        assert_eq!(results[0].jid, registered);
+        assert!(results[0].is_in);
+        assert!(results[0].verified_name.is_none());
+        assert_eq!(results[1].query, "+14155552671");
+        assert!(!results[1].is_in);
+
+        let without_contact = usync_response(vec![user_node(&registered, vec![])]);
+        assert!(parse_is_on_whatsapp(&without_contact).is_err());
+    }
+
+    #[test]
+    fn test_parse_usync_user_info() {
+        let jid = JID::new("911234567890", DEFAULT_USER_SERVER);
+        let devices = usync_devices_node(&[JID::new_ad("911234567890", 0, 0)])
+            .get_optional_child_by_tag(&["list", "user", "devices"])
+            .unwrap();
+        let response = usync_response(vec![user_node(
+            &jid,
+            vec![
+                Node {
+                    tag: "status".to_string(),
+                    attrs: Attrs::new(),
+                    content: NodeContentType::ByteArray(b"Hey there!".to_vec()),
+                },
+                Node {
+                    tag: "picture".to_string(),
+                    attrs: Attrs::from([(
+                        "id".to_string(),
+                        AttributeTypes::String("1234".to_string()),
+                    )]),
+                    content: NodeContentType::None,
+                },
+                devices,
+            ],
+        )]);
+
+        let infos = parse_usync_user_info(&response).unwrap();
+        assert_eq!(infos.len(), 1);
+        let (user, info) = &infos[0];
+        assert_eq!(*user, jid);
+        assert_eq!(info.status, "Hey there!");
+        assert_eq!(info.picture_id, "1234");
+        assert_eq!(info.devices, vec![JID::new_ad("911234567890", 0, 0)]);
+        assert!(info.verified_name.is_none());
+    }
+
+    #[test]
+    fn test_build_usync_user_info_query() {
+        let node = build_usync_user_info_query(
+            &[JID::new_ad("911234567890", 0, 2)],
+            UsyncContext::Background,
+        );
+        let usync = node.get_optional_child_by_tag(&["usync"]).unwrap();
+        assert_eq!(usync.attr_getter().string("context").unwrap(), "background");
+        let query_tags = usync
+            .get_optional_child_by_tag(&["query"])
+            .unwrap()
+            .get_children()
+            .unwrap()
+            .iter()
+            .map(|n| n.tag.to_string())
+            .collect::<Vec<String>>();
+        assert_eq!(query_tags, vec!["business", "status", "picture", "devices"]);
+        let user = usync.get_optional_child_by_tag(&["list", "user"]).unwrap();
+        assert_eq!(
+            user.attr_getter().jid("jid").unwrap(),
+            JID::new("911234567890", DEFAULT_USER_SERVER)
+        );
+    }
+}